@@ -1,8 +1,9 @@
-use std::{fmt};
+use std::{fmt, str};
 use std::cmp::{Ord, Ordering};
 
 use raw_cbor::{self, de::RawCbor};
-use types::{HeaderHash, SlotId, EpochId};
+use wallet_crypto::config::{ProtocolMagic};
+use types::{HeaderHash, SlotId, EpochId, ChainDifficulty};
 use genesis;
 use normal;
 
@@ -107,6 +108,23 @@ impl fmt::Display for BlockDate {
     }
 }
 
+impl str::FromStr for BlockDate {
+    type Err = &'static str;
+    /// parse a date as displayed: `<epoch>.<slot>` or `<epoch>.GENESIS`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '.');
+        let epoch = parts.next().and_then(|e| e.parse().ok()).ok_or("invalid epoch")?;
+        match parts.next() {
+            None => Err("missing slot, expected <epoch>.<slot>"),
+            Some(slot) if slot.eq_ignore_ascii_case("GENESIS") => Ok(BlockDate::Genesis(epoch)),
+            Some(slot) => {
+                let slotid = slot.parse().map_err(|_| "invalid slot")?;
+                Ok(BlockDate::Normal(SlotId { epoch: epoch, slotid: slotid }))
+            },
+        }
+    }
+}
+
 impl BlockHeader {
     pub fn get_previous_header(&self) -> HeaderHash {
         match self {
@@ -121,6 +139,20 @@ impl BlockHeader {
             &BlockHeader::MainBlockHeader(ref blo) => BlockDate::Normal(blo.consensus.slot_id.clone()),
         }
     }
+    pub fn get_chain_difficulty(&self) -> ChainDifficulty {
+        match self {
+            &BlockHeader::GenesisBlockHeader(ref blo) => blo.consensus.chain_difficulty,
+            &BlockHeader::MainBlockHeader(ref blo) => blo.consensus.chain_difficulty,
+        }
+    }
+
+    pub fn get_protocol_magic(&self) -> ProtocolMagic {
+        match self {
+            &BlockHeader::GenesisBlockHeader(ref blo) => blo.protocol_magic,
+            &BlockHeader::MainBlockHeader(ref blo) => blo.protocol_magic,
+        }
+    }
+
     // TODO: TO REMOVE deprecated use get_blockdate
     pub fn get_slotid(&self) -> BlockDate {
         self.get_blockdate()
@@ -329,6 +361,16 @@ mod test {
     fn check_main_block() {
         check_blockheader_serialization(&MAINBLOCK_HEX[..], MAINBLOCK_HASH);
     }
+
+    #[test]
+    fn parse_blockdate() {
+        use super::{BlockDate, SlotId};
+        assert_eq!("42.1234".parse(), Ok(BlockDate::Normal(SlotId { epoch: 42, slotid: 1234 })));
+        assert_eq!("3.GENESIS".parse(), Ok(BlockDate::Genesis(3)));
+        assert_eq!(BlockDate::Genesis(3).to_string().parse(), Ok(BlockDate::Genesis(3)));
+        assert!("42".parse::<BlockDate>().is_err());
+        assert!("42.x".parse::<BlockDate>().is_err());
+    }
 }
 
 #[cfg(test)]
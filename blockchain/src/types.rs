@@ -12,6 +12,12 @@ impl Version {
     pub fn new(major: u32, minor: u32, revision: u32) -> Self {
         Version { major: major, minor: minor, revision: revision }
     }
+
+    /// tell if the peers of the given protocol version can talk to the
+    /// peers of this version: only the revisions may differ
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        self.major == other.major && self.minor == other.minor
+    }
 }
 impl Default for Version {
     fn default() -> Self { Version::new(0,1,0) }
@@ -113,7 +119,7 @@ pub enum SscProof {
     Certificate(Blake2b256)
 }
 
-#[derive(Debug,Clone,Copy)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
 pub struct ChainDifficulty(u64);
 
 impl fmt::Display for ChainDifficulty {
@@ -121,6 +127,12 @@ impl fmt::Display for ChainDifficulty {
         write!(f, "{}", self.0)
     }
 }
+impl From<u64> for ChainDifficulty {
+    fn from(v: u64) -> Self { ChainDifficulty(v) }
+}
+impl From<ChainDifficulty> for u64 {
+    fn from(cd: ChainDifficulty) -> u64 { cd.0 }
+}
 
 pub type EpochId = u32;
 
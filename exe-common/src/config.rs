@@ -3,6 +3,8 @@ pub mod net {
     use wallet_crypto::config::{ProtocolMagic};
     use std::{path::{Path}, fs::{self, File}, fmt, slice::{Iter}, ops::{Deref, DerefMut}};
     use storage::tmpfile::{TmpFile};
+    use protocol::packet;
+    use std::collections::{BTreeMap};
     use serde_yaml;
     use serde;
 
@@ -174,6 +176,19 @@ pub mod net {
         /// add a new peer in the `Peers` set
         pub fn push(&mut self, name: String, peer: Peer) { self.0.push(NamedPeer::new(name, peer)) }
 
+        /// get the peer of the given name
+        pub fn get(&self, name: &str) -> Option<&Peer> {
+            self.iter().find(|np| np.name() == name).map(|np| np.peer())
+        }
+
+        /// remove the peer of the given name, return `false` if there
+        /// is no such peer
+        pub fn remove(&mut self, name: &str) -> bool {
+            let len = self.0.len();
+            self.0.retain(|np| np.name() != name);
+            self.0.len() != len
+        }
+
         /// get an iterator over the peers
         pub fn iter(&self) -> Iter<NamedPeer> { self.0.iter() }
 
@@ -182,13 +197,40 @@ pub mod net {
         }
     }
 
+    /// the handler specs announced in the handshake with the peers, by
+    /// message code (see `protocol::packet::HandlerSpecs`)
+    pub type HandlerSpecs = BTreeMap<u32, u16>;
+
+    fn handler_specs(specs: &HandlerSpecs) -> packet::HandlerSpecs {
+        let mut handler_specs = packet::HandlerSpecs::new();
+        for (code, spec) in specs.iter() {
+            handler_specs.insert(*code, packet::HandlerSpec::new(*spec));
+        }
+        handler_specs
+    }
+
+    /// the number of slots of an epoch of the mainnet and the testnet
+    /// (10 times the security parameter k = 2160)
+    pub const EPOCH_SLOTS : u32 = 21600;
+
+    fn default_epoch_slots() -> u32 { EPOCH_SLOTS }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Config {
         pub genesis: HeaderHash,
         pub genesis_prev: HeaderHash,
         pub protocol_magic: ProtocolMagic,
         pub epoch_start: EpochId,
-        pub peers: Peers
+        /// the number of slots of an epoch, its genesis block excluded
+        #[serde(default = "default_epoch_slots")]
+        pub epoch_slots: u32,
+        pub peers: Peers,
+        /// the messages we handle, the defaults of the protocol if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub in_handlers: Option<HandlerSpecs>,
+        /// the messages we may send, the defaults of the protocol if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub out_handlers: Option<HandlerSpecs>,
     }
     impl Config {
         pub fn mainnet() -> Self {
@@ -200,7 +242,10 @@ pub mod net {
                 genesis_prev: HeaderHash::from_hex(&"5f20df933584822601f9e3f8c024eb5eb252fe8cefb24d1317dc3d432e940ebb").unwrap(),
                 protocol_magic: ProtocolMagic::default(),
                 epoch_start: 0,
-                peers: peers
+                epoch_slots: EPOCH_SLOTS,
+                peers: peers,
+                in_handlers: None,
+                out_handlers: None,
             }
         }
 
@@ -213,10 +258,22 @@ pub mod net {
                 genesis_prev: HeaderHash::from_hex(&"c6a004d3d178f600cd8caa10abbebe1549bef878f0665aea2903472d5abf7323").unwrap(),
                 protocol_magic: ProtocolMagic::new(633343913),
                 epoch_start: 0,
-                peers: peers
+                epoch_slots: EPOCH_SLOTS,
+                peers: peers,
+                in_handlers: None,
+                out_handlers: None,
             }
         }
 
+        /// the handshake to send to the peers of this network
+        pub fn handshake(&self) -> packet::Handshake {
+            let mut hs = packet::Handshake::default();
+            hs.protocol_magic = self.protocol_magic;
+            if let Some(ref specs) = self.in_handlers { hs.in_handlers = handler_specs(specs) }
+            if let Some(ref specs) = self.out_handlers { hs.out_handlers = handler_specs(specs) }
+            hs
+        }
+
         pub fn from_file<P: AsRef<Path>>(p: P) -> Option<Self> {
             let path = p.as_ref();
             if ! path.is_file() {
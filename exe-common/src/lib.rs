@@ -15,6 +15,7 @@ extern crate serde_yaml;
 extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
+extern crate flate2;
 
 mod mstream;
 pub mod network;
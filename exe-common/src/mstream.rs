@@ -4,6 +4,9 @@ use std::io::{Read,Write};
 use std::io;
 use std::fmt;
 
+use protocol::{WaitReadable};
+use protocol::capture::{Tap};
+
 use network::{Result, Error};
 
 pub struct MetricStart {
@@ -54,6 +57,7 @@ pub struct MStream {
     stream: TcpStream,
     read_sz: u64,
     write_sz: u64,
+    capture: Option<Tap>,
 }
 
 const TIMEOUT_SECONDS      : u64 = 30;
@@ -79,6 +83,7 @@ impl MStream {
             stream: stream,
             read_sz: 0,
             write_sz: 0,
+            capture: None,
         })
     }
 
@@ -89,12 +94,18 @@ impl MStream {
     pub fn get_write_sz(&self) -> u64 {
         self.write_sz
     }
+
+    /// record the ntt frames going through the stream from now on
+    pub fn set_capture(&mut self, tap: Tap) {
+        self.capture = Some(tap)
+    }
 }
 
 impl Read for MStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let sz = self.stream.read(buf)?;
         self.read_sz += sz as u64;
+        if let Some(ref mut tap) = self.capture { tap.received(&buf[..sz]) }
         Ok(sz)
     }
 }
@@ -103,7 +114,14 @@ impl Write for MStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let sz = self.stream.write(buf)?;
         self.write_sz += sz as u64;
+        if let Some(ref mut tap) = self.capture { tap.sent(&buf[..sz]) }
         Ok(sz)
     }
     fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
 }
+
+impl WaitReadable for MStream {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        self.stream.wait_readable(timeout)
+    }
+}
@@ -1,7 +1,9 @@
 use std::{io};
 use protocol::{self, ntt};
 use hyper;
+use serde_yaml;
 use raw_cbor;
+use storage::{self, types::{PackHash}};
 
 #[derive(Debug)]
 pub enum Error {
@@ -9,7 +11,16 @@ pub enum Error {
     NttError(ntt::Error),
     ProtocolError(protocol::Error),
     CborError(raw_cbor::Error),
+    StorageError(storage::Error),
     HyperError(hyper::Error),
+    HttpError(String, hyper::StatusCode),
+    InvalidPackHash(PackHash, PackHash),
+    /// the ETag of a pack served by hermes is not a pack hash
+    InvalidETag(String),
+    YamlError(serde_yaml::Error),
+    /// the peer answered a request of block headers with none
+    NoBlockHeaders,
+    NoPeerAvailable,
     ConnectionTimedOut,
 }
 impl From<io::Error> for Error {
@@ -27,3 +38,24 @@ impl From<ntt::Error> for Error {
 impl From<raw_cbor::Error> for Error {
     fn from(e: raw_cbor::Error) -> Self { Error::CborError(e) }
 }
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self { Error::StorageError(e) }
+}
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self { Error::YamlError(e) }
+}
+impl Error {
+    /// tell if the error is a network failure, the operation may then
+    /// succeed on a new connection (or with another peer) whereas the
+    /// other errors are protocol violations or invalid data
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::IoError(_) => true,
+            Error::NttError(ntt::Error::IOError(_)) => true,
+            Error::ProtocolError(e) => e.is_retryable(),
+            Error::HyperError(_) => true,
+            Error::ConnectionTimedOut => true,
+            _ => false,
+        }
+    }
+}
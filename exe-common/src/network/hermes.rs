@@ -1,15 +1,18 @@
-use blockchain::{BlockHeader, Block, HeaderHash};
-use storage::{self, Storage, tmpfile::{TmpFile}};
-use std::io::{Write, Seek, SeekFrom};
+use blockchain::{BlockHeader, Block, HeaderHash, RawBlockHeader, RawBlock};
+use storage::{self, Storage, types::{StorageFileType, PackHash}};
+use wallet_crypto::util::{hex};
+use std::io::{self, Write};
 use std::time::{SystemTime};
 
 use config::net;
 
-use futures::{Future, Stream};
-use hyper::Client;
+use futures::{Stream};
+use hyper::{Client, Request, Method, StatusCode};
+use hyper::header::{Range, ByteRangeSpec, IfRange, EntityTag, ETag, AcceptEncoding, ContentEncoding, Encoding, qitem};
+use flate2::write::{GzDecoder, ZlibDecoder};
 use tokio_core::reactor::Core;
 
-use network::{Result};
+use network::{Result, Error};
 use network::api::{Api, FetchEpochParams, FetchEpochResult};
 
 
@@ -28,52 +31,141 @@ impl HermesEndPoint {
     pub fn uri(& mut self, path: &str) -> String {
         format!("{}/{}/{}", self.url, self.blockchain, path)
     }
+
+    /// query the given path and collect the whole response body
+    ///
+    /// any HTTP status other than a success is reported as an `Error::HttpError`.
+    fn get_bytes(&mut self, path: &str) -> Result<Vec<u8>> {
+        let uri_str = self.uri(path);
+        let uri = uri_str.as_str().parse().unwrap();
+        info!("querying uri: {}", uri);
+        let client = Client::new(&self.core.handle());
+        let res = self.core.run(client.get(uri))?;
+        if ! res.status().is_success() {
+            return Err(Error::HttpError(uri_str, res.status()));
+        }
+        let body = self.core.run(res.body().concat2())?;
+        Ok(body.to_vec())
+    }
 }
 
 impl Api for HermesEndPoint {
     fn get_tip(&mut self) -> Result<BlockHeader> {
-        unimplemented!()
+        let bytes = self.get_bytes("tip")?;
+        Ok(RawBlockHeader::from_dat(bytes).decode()?)
     }
 
-    fn get_block(&mut self, _hash: HeaderHash) -> Result<Block> {
-        unimplemented!()
+    fn get_block(&mut self, hash: HeaderHash) -> Result<Block> {
+        let path = format!("block/{}", hash);
+        let bytes = self.get_bytes(&path)?;
+        Ok(RawBlock::from_dat(bytes).decode()?)
     }
 
     fn fetch_epoch(&mut self, _config: &net::Config, storage: &mut Storage, fep: FetchEpochParams) -> Result<FetchEpochResult> {
         let path = format!("epoch/{}", fep.epoch_id);
 
-        let mut tmppack = TmpFile::create(storage.config.get_filetype_dir(storage::types::StorageFileType::Pack))?;
+        // the pack is downloaded in a partial object of the storage, kept
+        // across calls so an interrupted download can be resumed where it
+        // stopped.
+        let partial_name = format!(".partial.epoch.{}", fep.epoch_id);
+        let etag_name = format!(".partial.epoch.{}.etag", fep.epoch_id);
         {
+            let backend = storage.get_backend();
+            let downloaded = match backend.read(StorageFileType::Pack, &partial_name) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+                Ok(content) => Some(content),
+            };
+            let downloaded_len = downloaded.as_ref().map(|content| content.len() as u64).unwrap_or(0);
+            let known_etag = backend.read(StorageFileType::Pack, &etag_name).ok()
+                                    .and_then(|content| String::from_utf8(content.to_vec()).ok());
+
             let uri = self.uri(&path).as_str().parse().unwrap();
             info!("querying uri: {}", uri);
+            let mut req = Request::new(Method::Get, uri);
+            match known_etag {
+                Some(ref etag) if downloaded_len > 0 => {
+                    info!("resuming download from byte {}", downloaded_len);
+                    req.headers_mut().set(Range::Bytes(vec![ByteRangeSpec::AllFrom(downloaded_len)]));
+                    req.headers_mut().set(IfRange::EntityTag(EntityTag::strong(etag.clone())));
+                },
+                _ => {
+                    req.headers_mut().set(AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)]));
+                },
+            }
+
             let client = Client::new(&self.core.handle());
-            let work = client.get(uri).and_then(|res| {
-                res.body().for_each(|chunk| {
-                    tmppack.write_all(&chunk).map_err(From::from)
-                })
-            });
             let now = SystemTime::now();
-            self.core.run(work)?;
+            let res = self.core.run(client.request(req))?;
+            let mut partial = backend.create(StorageFileType::Pack)?;
+            match res.status() {
+                StatusCode::PartialContent => {
+                    if let Some(ref downloaded) = downloaded { partial.write_all(downloaded)?; }
+                },
+                StatusCode::RangeNotSatisfiable => {
+                    // we already have the whole pack, the hash check will tell if it is valid
+                    info!("pack already downloaded");
+                    if let Some(ref downloaded) = downloaded { partial.write_all(downloaded)?; }
+                },
+                status if status.is_success() => {},
+                status => return Err(Error::HttpError(self.uri(&path), status)),
+            }
+            // only the pack hash of the ETag is kept: the partial content is
+            // decoded, a download is resumed from the identity content
+            if let Some(etag) = res.headers().get::<ETag>() {
+                let packhash = etag.tag().split('-').next().unwrap_or("");
+                backend.write(StorageFileType::Pack, &etag_name, packhash.as_bytes())?;
+            }
+
+            let mut result = Ok(());
+            if res.status() != StatusCode::RangeNotSatisfiable {
+                let encoding = res.headers().get::<ContentEncoding>().and_then(|encs| encs.last().cloned());
+                let mut writer = decoder(encoding, &mut partial);
+                let work = res.body().for_each(|chunk| {
+                    writer.write_all(&chunk).map_err(From::from)
+                });
+                result = self.core.run(work).map_err(Error::from).and_then(|()| writer.flush().map_err(Error::from));
+            }
+            // what has been downloaded is kept, even on error, for the
+            // next call to resume the download
+            partial.commit(&partial_name)?;
+            result?;
             let time_elapsed = now.elapsed().unwrap();
             info!("Downloaded EPOCH in {}sec", time_elapsed.as_secs());
-
         }
+
+        // verify the downloaded pack against the expected pack hash (the ETag)
+        // before making it permanent, and build its index on the way.
         let now = SystemTime::now();
-        tmppack.seek(SeekFrom::Start(0))?;
-        let mut packfile = storage::pack::PackReader::from(tmppack);
-        let mut packwriter = storage::pack::PackWriter::init(&storage.config);
-        let mut last = None;
-        while let Some(rblock) = packfile.get_next() {
-            let rhdr = rblock.to_header();
-            // TODO: do some checks: let block = rblock.decode()?;
-            last = Some(rhdr.decode()?);
-            packwriter.append(rhdr.compute_hash().bytes(), rblock.as_ref());
+        let content = storage.get_backend().read(StorageFileType::Pack, &partial_name)?;
+        let (packhash, index, last) = match read_downloaded_pack(&content[..]) {
+            Ok(r) => r,
+            Err(err) => {
+                // corrupted or truncated: start the download again next time
+                error!("invalid downloaded pack for epoch {}: {:?}", fep.epoch_id, err);
+                remove_partial(storage, &partial_name, &etag_name)?;
+                return Err(err);
+            },
+        };
+        if let Ok(etag) = storage.get_backend().read(StorageFileType::Pack, &etag_name) {
+            let etag = String::from_utf8_lossy(&etag).into_owned();
+            let expected = match etag_packhash(&etag) {
+                Some(expected) => expected,
+                None => {
+                    remove_partial(storage, &partial_name, &etag_name)?;
+                    return Err(Error::InvalidETag(etag));
+                },
+            };
+            if expected != packhash {
+                remove_partial(storage, &partial_name, &etag_name)?;
+                return Err(Error::InvalidPackHash(expected, packhash));
+            }
         }
+        storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), &content)?;
+        remove_partial(storage, &partial_name, &etag_name)?;
 
-        let (packhash, index) = packwriter.finalize();
-        let (_, tmpfile) = storage::pack::create_index(storage, &index);
-        tmpfile.render_permanent(&storage.config.get_index_filepath(&packhash))?;
-        storage::epoch::epoch_create(&storage.config, &packhash, fep.epoch_id);
+        storage.add_pack(&packhash, &index)?;
+        storage::epoch::epoch_create(storage, &packhash, fep.epoch_id);
 
         let last_hdr = match last {
             None => { panic!("no last block found, error.") },
@@ -89,3 +181,70 @@ impl Api for HermesEndPoint {
         })
     }
 }
+
+// the writer decoding the content of the given encoding into `writer`
+fn decoder<'a, W: Write + 'a>(encoding: Option<Encoding>, writer: W) -> Box<Write + 'a> {
+    match encoding {
+        Some(Encoding::Gzip)    => Box::new(GzDecoder::new(writer)),
+        Some(Encoding::Deflate) => Box::new(ZlibDecoder::new(writer)),
+        _                       => Box::new(writer),
+    }
+}
+
+// the pack hash of the ETag of a pack served by hermes: the hexadecimal
+// pack hash, followed by the content coding for the compressed contents
+fn etag_packhash(etag: &str) -> Option<PackHash> {
+    let bytes = hex::decode(etag.split('-').next().unwrap_or("")).ok()?;
+    if bytes.len() != storage::types::HASH_SIZE {
+        return None;
+    }
+    let mut packhash = [0;storage::types::HASH_SIZE];
+    packhash.clone_from_slice(&bytes);
+    Some(packhash)
+}
+
+// remove the partial download and its etag
+fn remove_partial(storage: &Storage, partial_name: &str, etag_name: &str) -> Result<()> {
+    for name in [partial_name, etag_name].iter() {
+        match storage.get_backend().remove(StorageFileType::Pack, name) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            r => r?,
+        }
+    }
+    Ok(())
+}
+
+// read the downloaded pack, checking its blocks, and build its index
+fn read_downloaded_pack(bytes: &[u8]) -> Result<(PackHash, storage::pack::Index, Option<blockchain::BlockHeader>)> {
+    let mut packfile = storage::pack::PackReader::open(bytes)?;
+    let mut index = storage::pack::Index::new();
+    let mut last = None;
+    loop {
+        let ofs = packfile.pos;
+        let rblock = match packfile.get_next()? {
+            None => break,
+            Some(rblock) => rblock,
+        };
+        let rhdr = rblock.to_header();
+        index.append(rhdr.compute_hash().bytes(), ofs);
+        last = Some(rhdr.decode()?);
+    }
+    Ok((packfile.finalize(), index, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packhash_of_the_etags() {
+        let packhash = [0x2a;storage::types::HASH_SIZE];
+        let hex_packhash = hex::encode(&packhash);
+        assert_eq!(etag_packhash(&hex_packhash), Some(packhash));
+        assert_eq!(etag_packhash(&format!("{}-gzip", hex_packhash)), Some(packhash));
+        assert_eq!(etag_packhash(&hex_packhash[..62]), None);
+        assert_eq!(etag_packhash(&format!("{}00", hex_packhash)), None);
+        assert_eq!(etag_packhash("not-a-packhash"), None);
+        assert_eq!(etag_packhash(""), None);
+    }
+}
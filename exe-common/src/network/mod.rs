@@ -3,10 +3,13 @@ pub mod result;
 pub mod native;
 pub mod hermes;
 pub mod peer;
+pub mod peerdb;
 pub mod api;
+pub mod responder;
 
 pub use self::error::{Error};
 pub use self::result::{Result};
 pub use self::api::{*};
 pub use self::peer::{Peer};
+pub use self::peerdb::{PeerDb};
 pub use self::hermes::{HermesEndPoint};
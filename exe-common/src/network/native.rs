@@ -1,16 +1,18 @@
 use protocol;
 use mstream::{MStream, MetricStart, MetricStats};
-use wallet_crypto::{config::{ProtocolMagic}, util::{hex}};
+use wallet_crypto::{util::{hex}};
 use rand;
-use std::{net::{SocketAddr, ToSocketAddrs}, ops::{Deref, DerefMut}};
+use std::{net::{SocketAddr, ToSocketAddrs}, ops::{Deref, DerefMut}, path::{Path}};
 use blockchain::{self, BlockHeader, Block, HeaderHash, EpochId, BlockDate, SlotId};
 use storage::{self, Storage, types::{PackHash}};
 use protocol::command::*;
-use std::time::{SystemTime, Duration};
+use protocol::capture::{Recorder, Tap};
+use protocol::packet::{Handshake};
+use std::time::{SystemTime, Duration, Instant};
 use raw_cbor::{de::{RawCbor}};
 
 use config::net;
-use network::{Error, Result};
+use network::{Error, Result, PeerDb};
 use network::api::{Api, FetchEpochParams, FetchEpochResult};
 
 /// native peer
@@ -27,10 +29,20 @@ pub struct PeerPool {
     pub connections: Vec<Connection>
 }
 impl PeerPool {
-    pub fn new(name: String, address: String, protocol_magic: ProtocolMagic) -> Result<Self> {
+    pub fn new(name: String, address: String, handshake: &Handshake) -> Result<Self> {
+        PeerPool::new_with_capture(name, address, handshake, None)
+    }
+
+    /// connect to the peer, recording the ntt frames exchanged on the
+    /// first connection in the given capture file (see `protocol::capture`)
+    pub fn new_with_capture(name: String, address: String, handshake: &Handshake, capture: Option<&Path>) -> Result<Self> {
         let mut connections = Vec::new();
         for sockaddr in address.to_socket_addrs()? {
-            match Connection::new(sockaddr, protocol_magic) {
+            let recorder = match capture {
+                Some(path) if connections.is_empty() => Some(Recorder::create(path)?),
+                _ => None,
+            };
+            match Connection::new_with_capture(sockaddr, handshake, recorder) {
                 Ok(connection) => connections.push(connection),
                 Err(Error::ConnectionTimedOut) => {
                     warn!("connection peer `{}' address {} timedout, ignoring for now.", name, sockaddr)
@@ -43,6 +55,50 @@ impl PeerPool {
         }
         Ok(PeerPool { name, address, connections })
     }
+
+    /// connect to the best native peer of the given set, as ranked by
+    /// the `PeerDb` (see `PeerDb::candidates`), the outcome of every
+    /// connection attempt is recorded in the `PeerDb`.
+    pub fn connect_best(peers: &net::Peers, handshake: &Handshake, peerdb: &mut PeerDb) -> Result<Self> {
+        PeerPool::connect_many(peers, handshake, peerdb, 1).pop().ok_or(Error::NoPeerAvailable)
+    }
+
+    /// like `connect_best`, connecting to up to `count` peers: one pool
+    /// per address, the best peers first.
+    pub fn connect_many(peers: &net::Peers, handshake: &Handshake, peerdb: &mut PeerDb, count: usize) -> Vec<Self> {
+        let mut pools = Vec::new();
+        for (name, sockaddr) in peerdb.candidates(peers) {
+            if pools.len() >= count { break }
+            let start = Instant::now();
+            match Connection::new(sockaddr, handshake) {
+                Ok(connection) => {
+                    peerdb.connected(&name, sockaddr, start.elapsed());
+                    info!("connected to peer `{}' address {}", name, sockaddr);
+                    pools.push(PeerPool { name: name, address: sockaddr.to_string(), connections: vec![connection] });
+                },
+                Err(err) => {
+                    warn!("connection peer `{}' address {} failed: {:?}", name, sockaddr, err);
+                    peerdb.report(&name, sockaddr, &err);
+                },
+            }
+        }
+        pools
+    }
+
+    /// the address of the first connection of the pool
+    pub fn get_sockaddr(&self) -> Option<SocketAddr> {
+        self.connections.get(0).map(|conn| conn.0)
+    }
+
+    /// follow the tip of the peer, see `OpenPeer::follow`
+    pub fn follow<F>(&mut self, f: F) -> Result<()>
+        where F: FnMut(&BlockHeader) -> bool
+    {
+        match self.connections.get_mut(0) {
+            None => panic!("We expect at lease one connection on any native peer"),
+            Some(conn) => conn.follow(f)
+        }
+    }
 }
 
 // TODO: this is not necessarily what we want to do here,
@@ -74,8 +130,12 @@ impl Api for PeerPool {
 
 pub struct Connection(pub SocketAddr, pub OpenPeer);
 impl Connection {
-    pub fn new(sockaddr: SocketAddr, protocol_magic: ProtocolMagic) -> Result<Self> {
-        let network = OpenPeer::new(protocol_magic, &sockaddr)?;
+    pub fn new(sockaddr: SocketAddr, handshake: &Handshake) -> Result<Self> {
+        Connection::new_with_capture(sockaddr, handshake, None)
+    }
+
+    pub fn new_with_capture(sockaddr: SocketAddr, handshake: &Handshake, capture: Option<Recorder>) -> Result<Self> {
+        let network = OpenPeer::new_with_capture(handshake, &sockaddr, capture)?;
         Ok(Connection (sockaddr, network))
     }
 }
@@ -90,19 +150,37 @@ impl DerefMut for Connection {
 pub struct OpenPeer(pub protocol::Connection<MStream>);
 
 impl OpenPeer {
-    pub fn new(protocol_magic: ProtocolMagic, host: &SocketAddr) -> Result<Self> {
+    pub fn new(handshake: &Handshake, host: &SocketAddr) -> Result<Self> {
+        OpenPeer::new_with_capture(handshake, host, None)
+    }
+
+    /// connect to the peer, recording the ntt frames exchanged with it
+    /// if a `Recorder` is given
+    pub fn new_with_capture(handshake: &Handshake, host: &SocketAddr, capture: Option<Recorder>) -> Result<Self> {
         let drg_seed = rand::random();
-        let mut hs = protocol::packet::Handshake::default();
-        hs.protocol_magic = protocol_magic;
 
-        let stream = MStream::init(host)?;
+        let mut stream = MStream::init(host)?;
+        if let Some(recorder) = capture {
+            stream.set_capture(Tap::client(recorder));
+        }
 
         let conn = protocol::ntt::Connection::handshake(drg_seed, stream)?;
         let mut conne = protocol::Connection::new(conn);
-        conne.handshake(&hs)?;
+        conne.handshake(handshake)?;
         Ok(OpenPeer(conne))
     }
 
+    /// follow the tip of the peer: call `f` on the header of every new
+    /// block announced by the peer, oldest first, until it returns `false`
+    pub fn follow<F>(&mut self, f: F) -> Result<()>
+        where F: FnMut(&BlockHeader) -> bool
+    {
+        let mut subscription = Subscription::start(&mut self.0, SUBSCRIPTION_KEEP_ALIVE)?;
+        subscription.for_each(&mut self.0, f)?;
+        subscription.stop(&mut self.0)?;
+        Ok(())
+    }
+
     pub fn read_start(&self) -> MetricStart {
         MetricStart::new(self.0.get_backend().get_read_sz())
     }
@@ -113,7 +191,7 @@ impl OpenPeer {
 }
 impl Api for OpenPeer {
     fn get_tip(&mut self) -> Result<BlockHeader> {
-        let block_headers_raw = GetBlockHeader::tip().execute(&mut self.0).expect("to get one header at least");
+        let block_headers_raw = GetBlockHeader::tip().execute(&mut self.0)?;
 
         let block_headers = block_headers_raw.decode()?;
 
@@ -124,14 +202,13 @@ impl Api for OpenPeer {
     }
 
     fn get_block(&mut self, hash: HeaderHash) -> Result<Block> {
-        let b = GetBlock::only(&hash).execute(&mut self.0)
-            .expect("to get one block at least");
+        let b = GetBlock::only(&hash).execute(&mut self.0)?;
 
         Ok(RawCbor::from(b[0].as_ref()).deserialize()?)
     }
 
     fn fetch_epoch(&mut self, _config: &net::Config, storage: &mut Storage, fep: FetchEpochParams) -> Result<FetchEpochResult> {
-        let result = download_epoch(storage, self, fep.epoch_id, &fep.start_header_hash, &fep.previous_header_hash, &fep.upper_bound_hash);
+        let result = download_epoch(storage, self, fep.epoch_id, &fep.start_header_hash, &fep.previous_header_hash, &fep.upper_bound_hash)?;
         Ok(FetchEpochResult {
             last_header_hash: result.0,
             next_epoch_hash: Some(result.1),
@@ -140,19 +217,19 @@ impl Api for OpenPeer {
     }
 }
 
-fn network_get_blocks_headers(net: &mut OpenPeer, from: &blockchain::HeaderHash, to: &blockchain::HeaderHash) -> blockchain::RawBlockHeaderMultiple {
-    let mbh = GetBlockHeader::range(&vec![from.clone()], to.clone()).execute(&mut net.0).expect("to get one header at least");
-    mbh
+fn network_get_blocks_headers(net: &mut OpenPeer, from: &blockchain::HeaderHash, to: &blockchain::HeaderHash) -> Result<blockchain::RawBlockHeaderMultiple> {
+    let mbh = GetBlockHeader::range(&vec![from.clone()], to.clone()).execute(&mut net.0)?;
+    Ok(mbh)
 }
 
-fn download_epoch(storage: &Storage, net: &mut OpenPeer,
+fn download_epoch(storage: &mut Storage, net: &mut OpenPeer,
                   epoch_id: EpochId,
                   x_start_hash: &HeaderHash,
                   x_previous_headerhash: &HeaderHash,
-                  tip_hash: &HeaderHash) -> (HeaderHash, HeaderHash, PackHash) {
+                  tip_hash: &HeaderHash) -> Result<(HeaderHash, HeaderHash, PackHash)> {
     let mut start_hash = x_start_hash.clone();
     let mut found_epoch_boundary = None;
-    let mut writer = storage::pack::PackWriter::init(&storage.config);
+    let mut writer = storage::pack::PackWriter::init(storage);
     let mut previous_headerhash = x_previous_headerhash.clone();
     let epoch_time_start = SystemTime::now();
     let mut expected_slotid = blockchain::BlockDate::Genesis(epoch_id);
@@ -160,10 +237,13 @@ fn download_epoch(storage: &Storage, net: &mut OpenPeer,
     loop {
         info!("  ### slotid={} from={}", expected_slotid, start_hash);
         let metrics = net.read_start();
-        let block_headers_raw = network_get_blocks_headers(net, &start_hash, tip_hash);
+        let block_headers_raw = network_get_blocks_headers(net, &start_hash, tip_hash)?;
         let hdr_metrics = net.read_elapsed(&metrics);
-        let block_headers = block_headers_raw.decode().unwrap();
+        let block_headers = block_headers_raw.decode()?;
         info!("  got {} headers  ( {} )", block_headers.len(), hdr_metrics);
+        if block_headers.is_empty() {
+            return Err(Error::NoBlockHeaders);
+        }
 
         let mut start = 0;
         let mut end = block_headers.len() - 1;
@@ -209,17 +289,16 @@ fn download_epoch(storage: &Storage, net: &mut OpenPeer,
 
         let metrics = net.read_start();
         let blocks_raw = GetBlock::from(&download_start_hash, &latest_block.compute_hash())
-                                .execute(&mut net.0)
-                                .expect("to get one block at least");
+                                .execute(&mut net.0)?;
         let blocks_metrics = net.read_elapsed(&metrics);
         info!("  got {} blocks  ( {} )", blocks_raw.len(), blocks_metrics);
 
-        let first_block = blocks_raw[0].decode().unwrap();
+        let first_block = blocks_raw[0].decode()?;
         let first_block_hdr = first_block.get_header();
         debug!("first block {} {} prev {}", first_block_hdr.compute_hash(), first_block_hdr.get_blockdate(), first_block_hdr.get_previous_header());
 
         for block_raw in blocks_raw.iter() {
-            let block = block_raw.decode().unwrap();
+            let block = block_raw.decode()?;
             let hdr = block.get_header();
             let date = hdr.get_blockdate();
             let blockhash = hdr.compute_hash();
@@ -259,12 +338,11 @@ fn download_epoch(storage: &Storage, net: &mut OpenPeer,
                 info!("=> packing finished {} slotids", expected_slotid);
                 // write packfile
                 let (packhash, index) = writer.finalize();
-                let (_, tmpfile) = storage::pack::create_index(storage, &index);
-                tmpfile.render_permanent(&storage.config.get_index_filepath(&packhash)).unwrap();
-                let epoch_time_elapsed = epoch_time_start.elapsed().unwrap();
+                storage.add_pack(&packhash, &index)?;
+                let epoch_time_elapsed = epoch_time_start.elapsed().unwrap_or_default();
                 info!("=> pack {} written for epoch {} in {}", hex::encode(&packhash[..]), epoch_id, duration_print(epoch_time_elapsed));
                 storage::tag::write(storage, &storage::tag::get_epoch_tag(epoch_id), &packhash[..]);
-                return (previous_headerhash, b, packhash)
+                return Ok((previous_headerhash, b, packhash))
             },
         }
     }
@@ -273,3 +351,110 @@ fn download_epoch(storage: &Storage, net: &mut OpenPeer,
 fn duration_print(d: Duration) -> String {
     format!("{}.{:03} seconds", d.as_secs(), d.subsec_nanos() / 1_000_000)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::mock::{Chain, MockPeer};
+    use wallet_crypto::config::{ProtocolMagic};
+    use storage::{StorageConfig, backend::{MemoryBackend}, types::{header_to_blockhash}};
+    use protocol::server::{Listener};
+    use network::responder::{self, MAX_BLOCKS};
+    use std::{env, fs, process, thread, sync::Arc};
+
+    #[test]
+    fn download_epochs_from_mock_peer() {
+        // 2 full epochs of 10 slots, and the beginning of the third one
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 30);
+        let tip = chain.hash(29);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut net = OpenPeer::new(&Handshake::default(), &peer.local_addr()).unwrap();
+        assert_eq!(net.get_tip().unwrap().compute_hash(), tip);
+
+        let dir = env::temp_dir().join(format!("exe-common-download-epochs-{}", process::id()));
+        let mut storage = Storage::init(&StorageConfig::new(&dir)).unwrap();
+
+        let (last, next_epoch, _) = download_epoch(&mut storage, &mut net, 0, &chain.hash(0), &chain.header(0).get_previous_header(), &tip).unwrap();
+        assert_eq!(last, chain.hash(10));
+        assert_eq!(next_epoch, chain.hash(11));
+
+        let (last, next_epoch, packhash) = download_epoch(&mut storage, &mut net, 1, &next_epoch, &last, &tip).unwrap();
+        assert_eq!(last, chain.hash(21));
+        assert_eq!(next_epoch, chain.hash(22));
+        assert_eq!(storage::tag::read(&storage, &storage::tag::get_epoch_tag(1)), Some(packhash.to_vec()));
+
+        // the chain is followed with the header indexes of the packs
+        let storage = Storage::init(&StorageConfig::new(&dir)).unwrap();
+        let hdr = storage.get_header(&header_to_blockhash(&chain.hash(15))).unwrap();
+        assert_eq!(hdr.previous_header, chain.hash(14));
+        assert_eq!(hdr.date, chain.header(15).get_blockdate());
+        let range = storage.range(header_to_blockhash(&chain.hash(11)), header_to_blockhash(&chain.hash(21))).unwrap();
+        assert_eq!(range.iter().count(), 11);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_epoch_in_memory() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 15);
+        let tip = chain.hash(14);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut net = OpenPeer::new(&Handshake::default(), &peer.local_addr()).unwrap();
+
+        let backend = MemoryBackend::new();
+        let mut storage = Storage::memory(backend.clone()).unwrap();
+        let (last, _, packhash) = download_epoch(&mut storage, &mut net, 0, &chain.hash(0), &chain.header(0).get_previous_header(), &tip).unwrap();
+        assert_eq!(last, chain.hash(10));
+        // the pack is mapped by the storage which downloaded it
+        let hash = header_to_blockhash(&chain.hash(7));
+        assert_eq!(storage.get_header(&hash).unwrap().previous_header, chain.hash(6));
+        match storage::block_location(&storage, &hash) {
+            Some(storage::BlockLocation::Packed(p, _)) => assert_eq!(p, packhash),
+            _ => panic!("block not found in the downloaded pack"),
+        }
+
+        // the pack and its indexes are found by a storage sharing the backend
+        let storage = Storage::memory(backend).unwrap();
+        assert_eq!(storage::tag::read(&storage, &storage::tag::get_epoch_tag(0)), Some(packhash.to_vec()));
+        let hash = header_to_blockhash(&chain.hash(7));
+        assert_eq!(storage::block_read(&storage, &hash).unwrap().as_ref(), chain.block(7).as_ref());
+        assert_eq!(storage.get_header(&hash).unwrap().previous_header, chain.hash(6));
+    }
+
+    #[test]
+    fn download_epochs_from_responder() {
+        // a chain longer than the window of headers served at once
+        let chain = Chain::generate(ProtocolMagic::default(), 100, 2500);
+        assert!(chain.len() > MAX_BLOCKS);
+        let tip = chain.hash(2499);
+
+        let served = Storage::memory(MemoryBackend::new()).unwrap();
+        for height in 0..chain.len() {
+            storage::blob::write(&served, &header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref()).unwrap();
+        }
+        storage::tag::write_hash(&served, &storage::tag::HEAD, &tip);
+        let mut listener = Listener::bind("127.0.0.1:0", Handshake::default()).unwrap();
+        responder::register(&mut listener, Arc::new(served));
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.serve());
+        let mut net = OpenPeer::new(&Handshake::default(), &addr).unwrap();
+        assert_eq!(net.get_tip().unwrap().compute_hash(), tip);
+
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let (last, next_epoch, _) = download_epoch(&mut storage, &mut net, 0, &chain.hash(0), &chain.header(0).get_previous_header(), &tip).unwrap();
+        assert_eq!(last, chain.hash(100));
+        assert_eq!(next_epoch, chain.hash(101));
+
+        let (last, next_epoch, _) = download_epoch(&mut storage, &mut net, 1, &next_epoch, &last, &tip).unwrap();
+        assert_eq!(last, chain.hash(201));
+        assert_eq!(next_epoch, chain.hash(202));
+
+        let (last, next_epoch, packhash) = download_epoch(&mut storage, &mut net, 23, &chain.hash(2323), &chain.hash(2322), &tip).unwrap();
+        assert_eq!(last, chain.hash(2423));
+        assert_eq!(next_epoch, chain.hash(2424));
+        assert_eq!(storage::tag::read(&storage, &storage::tag::get_epoch_tag(23)), Some(packhash.to_vec()));
+
+        // nothing follows the tip, an error is answered
+        assert!(network_get_blocks_headers(&mut net, &tip, &tip).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use config;
 use network::{native, Result, hermes};
 use network::api::{*};
-use wallet_crypto::config::{ProtocolMagic};
+use protocol::packet::{Handshake};
 use blockchain::{BlockHeader, Block, HeaderHash};
 use storage::{Storage};
 
@@ -12,10 +12,10 @@ pub enum Peer {
     Http(hermes::HermesEndPoint)
 }
 impl Peer {
-    pub fn new(network: String, name: String, cfg: config::net::Peer, protocol_magic: ProtocolMagic) -> Result<Self> {
+    pub fn new(network: String, name: String, cfg: config::net::Peer, handshake: &Handshake) -> Result<Self> {
         match cfg {
             config::net::Peer::Native(addr) => {
-                Ok(Peer::Native(native::PeerPool::new(name, addr, protocol_magic)?))
+                Ok(Peer::Native(native::PeerPool::new(name, addr, handshake)?))
             },
             config::net::Peer::Http(addr) => {
                 Ok(Peer::Http(hermes::HermesEndPoint::new(addr, network)))
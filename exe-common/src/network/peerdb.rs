@@ -0,0 +1,256 @@
+//! persistent database of the native peers
+//!
+//! The named peers of the blockchain configuration (`net::Config::peers`)
+//! are usually DNS names resolving to many relays. The `PeerDb` keeps the
+//! statistics of every address the names resolve to (latency of the
+//! connection, failures, last tip seen) and the bans of the addresses
+//! which misbehaved, so the best peers are tried first.
+//!
+//! The database is stored in the blockchain directory
+//! (see `StorageConfig::get_peers_file`).
+
+use std::{fs::{self, File}, path::{Path, PathBuf}, collections::{BTreeMap}};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use blockchain::{BlockHeader, HeaderHash};
+use storage::tmpfile::{TmpFile};
+use serde_yaml;
+
+use config::net;
+use network::{Error, Result};
+
+/// the default duration of the ban of a peer violating the protocol
+pub const BAN_DURATION : Duration = Duration::from_secs(24 * 3600);
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// resolve the address of a native peer (`host:port`) to all the
+/// addresses (A and AAAA records) of the host
+pub fn resolve(address: &str) -> Result<Vec<SocketAddr>> {
+    Ok(address.to_socket_addrs()?.collect())
+}
+
+/// the tip announced by a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tip {
+    pub hash: HeaderHash,
+    pub date: String,
+    #[serde(default)]
+    pub difficulty: u64,
+    /// when the tip was seen, in seconds since the UNIX epoch
+    pub seen: u64,
+}
+
+/// the ban of a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    /// end of the ban, in seconds since the UNIX epoch
+    pub until: u64,
+    pub reason: String,
+}
+
+/// the statistics of a peer address
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerStats {
+    /// the named peer the address was resolved from
+    pub name: String,
+    /// latency of the last connection (connection and handshake), in milliseconds
+    pub latency: Option<u64>,
+    pub connections: u64,
+    pub failures: u64,
+    /// last successful connection, in seconds since the UNIX epoch
+    pub last_connected: Option<u64>,
+    pub last_tip: Option<Tip>,
+    pub ban: Option<Ban>,
+}
+impl PeerStats {
+    fn new(name: &str) -> Self {
+        PeerStats { name: name.to_owned(), .. PeerStats::default() }
+    }
+
+    /// tell if the peer is banned at the given time (in seconds since
+    /// the UNIX epoch)
+    pub fn is_banned_at(&self, time: u64) -> bool {
+        self.ban.as_ref().map(|ban| ban.until > time).unwrap_or(false)
+    }
+
+    /// ratio of the failed connections, `0` for a peer never tried
+    pub fn failure_rate(&self) -> f64 {
+        let attempts = self.connections + self.failures;
+        if attempts == 0 { 0.0 } else { self.failures as f64 / attempts as f64 }
+    }
+}
+
+/// the statistics of the peer addresses, see module documentation
+#[derive(Debug, Clone)]
+pub struct PeerDb {
+    path: PathBuf,
+    peers: BTreeMap<SocketAddr, PeerStats>,
+}
+impl PeerDb {
+    /// load the database from the given file, an empty database if
+    /// the file does not exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let peers = if path.is_file() {
+            let mut file = File::open(&path)?;
+            serde_yaml::from_reader(&mut file)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(PeerDb { path: path, peers: peers })
+    }
+
+    /// write the database back to its file
+    pub fn save(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap().to_path_buf();
+        fs::DirBuilder::new().recursive(true).create(dir.clone())?;
+        let mut file = TmpFile::create(dir)?;
+        serde_yaml::to_writer(&mut file, &self.peers)?;
+        file.render_permanent(&self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerStats> { self.peers.get(addr) }
+
+    pub fn iter(&self) -> ::std::collections::btree_map::Iter<SocketAddr, PeerStats> { self.peers.iter() }
+
+    fn entry(&mut self, name: &str, addr: SocketAddr) -> &mut PeerStats {
+        self.peers.entry(addr).or_insert_with(|| PeerStats::new(name))
+    }
+
+    /// resolve the native peers of the configuration, returning the
+    /// addresses which are not banned, the best peers first: the least
+    /// failing then the fastest to connect to.
+    ///
+    /// The names which cannot be resolved are ignored (with a warning).
+    pub fn candidates(&mut self, peers: &net::Peers) -> Vec<(String, SocketAddr)> {
+        let mut candidates = Vec::new();
+        for peer in peers.iter() {
+            let address = match peer.get_native() { None => continue, Some(address) => address };
+            match resolve(address) {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        self.entry(peer.name(), addr);
+                        candidates.push((peer.name().to_owned(), addr));
+                    }
+                },
+                Err(err) => warn!("cannot resolve peer `{}' ({}): {:?}", peer.name(), address, err),
+            }
+        }
+
+        let time = now();
+        candidates.retain(|&(_, ref addr)| ! self.peers[addr].is_banned_at(time));
+        candidates.sort_by(|&(_, ref a), &(_, ref b)| {
+            let (a, b) = (&self.peers[a], &self.peers[b]);
+            a.failure_rate().partial_cmp(&b.failure_rate()).unwrap()
+                .then(a.latency.unwrap_or(u64::max_value()).cmp(&b.latency.unwrap_or(u64::max_value())))
+        });
+        candidates
+    }
+
+    /// record a successful connection to the peer
+    pub fn connected(&mut self, name: &str, addr: SocketAddr, latency: Duration) {
+        let stats = self.entry(name, addr);
+        stats.latency = Some(latency.as_secs() * 1000 + latency.subsec_millis() as u64);
+        stats.connections += 1;
+        stats.last_connected = Some(now());
+    }
+
+    /// record a failure of the peer (connection or request)
+    pub fn failed(&mut self, name: &str, addr: SocketAddr) {
+        self.entry(name, addr).failures += 1;
+    }
+
+    /// record the tip announced by the peer
+    pub fn tip_seen(&mut self, name: &str, addr: SocketAddr, tip: &BlockHeader) {
+        self.entry(name, addr).last_tip = Some(Tip {
+            hash: tip.compute_hash(),
+            date: format!("{}", tip.get_blockdate()),
+            difficulty: tip.get_chain_difficulty().into(),
+            seen: now(),
+        });
+    }
+
+    /// record the error of the peer: a network failure is counted as a
+    /// failure, a protocol violation bans the peer for `BAN_DURATION`
+    pub fn report(&mut self, name: &str, addr: SocketAddr, error: &Error) {
+        if error.is_retryable() {
+            self.failed(name, addr)
+        } else {
+            self.ban(name, addr, BAN_DURATION, format!("{:?}", error))
+        }
+    }
+
+    /// ban the peer for the given duration
+    pub fn ban(&mut self, name: &str, addr: SocketAddr, duration: Duration, reason: String) {
+        warn!("banning peer `{}' ({}) for {}s: {}", name, addr, duration.as_secs(), reason);
+        let stats = self.entry(name, addr);
+        stats.failures += 1;
+        stats.ban = Some(Ban { until: now() + duration.as_secs(), reason: reason });
+    }
+
+    pub fn unban(&mut self, addr: &SocketAddr) {
+        if let Some(stats) = self.peers.get_mut(addr) {
+            stats.ban = None
+        }
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).map(|stats| stats.is_banned_at(now())).unwrap_or(false)
+    }
+
+    /// forget the addresses resolved from the given named peer
+    pub fn forget(&mut self, name: &str) {
+        let addrs : Vec<SocketAddr> = self.peers.iter()
+            .filter(|&(_, stats)| stats.name == name)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in addrs {
+            self.peers.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn rank_ban_and_persist() {
+        let mut peers = net::Peers::new();
+        peers.push("slow".to_string(), net::Peer::native("127.0.0.1:3001".to_string()));
+        peers.push("fast".to_string(), net::Peer::native("127.0.0.2:3001".to_string()));
+        peers.push("bad".to_string(), net::Peer::native("127.0.0.3:3001".to_string()));
+        peers.push("hermes".to_string(), net::Peer::http("http://127.0.0.4".to_string()));
+        let (slow, fast, bad) = ("127.0.0.1:3001".parse().unwrap(), "127.0.0.2:3001".parse().unwrap(), "127.0.0.3:3001".parse().unwrap());
+
+        let path = env::temp_dir().join(format!("exe-common-peerdb-{}", process::id())).join("peers.yml");
+        let mut peerdb = PeerDb::load(&path).unwrap();
+        assert_eq!(peerdb.candidates(&peers).len(), 3);
+
+        peerdb.connected("slow", slow, Duration::from_millis(300));
+        peerdb.connected("fast", fast, Duration::from_millis(20));
+        peerdb.ban("bad", bad, Duration::from_secs(60), "invalid node id".to_string());
+        peerdb.save().unwrap();
+
+        let mut peerdb = PeerDb::load(&path).unwrap();
+        assert_eq!(peerdb.get(&fast).unwrap().latency, Some(20));
+        assert!(peerdb.is_banned(&bad));
+        let candidates : Vec<SocketAddr> = peerdb.candidates(&peers).into_iter().map(|(_, addr)| addr).collect();
+        assert_eq!(candidates, vec![fast, slow]);
+
+        peerdb.failed("fast", fast);
+        peerdb.unban(&bad);
+        let candidates : Vec<SocketAddr> = peerdb.candidates(&peers).into_iter().map(|(_, addr)| addr).collect();
+        assert_eq!(candidates, vec![slow, fast, bad]);
+
+        peerdb.forget("fast");
+        assert!(peerdb.get(&fast).is_none());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+//! answer the requests of the peers from the local storage
+//!
+//! `MsgGetHeaders` is answered with a bounded window of headers following
+//! the most recent known checkpoint and `MsgGetBlocks` by walking the
+//! chain stored locally backward, from the requested block.
+//! Register the responders on a `protocol::server::Listener` to serve
+//! the local chain to other peers.
+
+use std::sync::Arc;
+
+use blockchain::{BlockHeader, HeaderHash};
+use protocol::{self, packet::{self, MsgType, GetHeaders, GetBlocks, BlockHeaderResponse, BlockResponse}};
+use protocol::server::{Handler, Listener, Reply};
+use raw_cbor::{se, de::RawCbor};
+use storage::{self, Storage, tag, headerindex::{CompactHeader}, types::{header_to_blockhash}};
+
+/// the maximum number of headers or blocks served in one response
+pub const MAX_BLOCKS : usize = 2200;
+
+/// register the responders to serve the given storage
+pub fn register(listener: &mut Listener, storage: Arc<Storage>) {
+    listener.register(MsgType::MsgGetHeaders.to_u8(), HeadersResponder(storage.clone()));
+    listener.register(MsgType::MsgGetBlocks.to_u8(), BlocksResponder(storage));
+}
+
+fn tip(storage: &Storage) -> Result<HeaderHash, String> {
+    tag::read(storage, &tag::HEAD)
+        .and_then(|bytes| HeaderHash::from_slice(&bytes).ok())
+        .ok_or(String::from("no tip available"))
+}
+
+fn compact_header(storage: &Storage, hash: &HeaderHash) -> Result<CompactHeader, String> {
+    storage.get_header(&header_to_blockhash(hash)).ok_or(format!("unknown block {}", hash))
+}
+
+fn read_header(storage: &Storage, hash: &HeaderHash) -> Result<BlockHeader, String> {
+    match storage::block_read(storage, &header_to_blockhash(hash)) {
+        None => Err(format!("block {} not found", hash)),
+        Some(rblk) => Ok(rblk.decode().map_err(|err| format!("cannot decode block {}: {:?}", hash, err))?.get_header()),
+    }
+}
+
+// the most recent ancestor of `hdr` (or `hdr` itself) of difficulty at
+// most `difficulty`. The ancestors on the `HEAD` chain are found with
+// `Storage::block_at_height`, the others by walking the chain backward.
+fn ancestor_at(storage: &Storage, hdr: CompactHeader, difficulty: u64) -> Result<CompactHeader, String> {
+    let height : u64 = hdr.difficulty.into();
+    if height <= difficulty { return Ok(hdr); }
+    let on_head = ! hdr.date.is_genesis()
+               && storage.block_at_height(height) == Some(header_to_blockhash(&hdr.hash));
+    if on_head {
+        if let Some(hash) = storage.block_at_height(difficulty) {
+            return compact_header(storage, &HeaderHash::from_bytes(hash));
+        }
+    }
+    let mut current = hdr;
+    while u64::from(current.difficulty) > difficulty {
+        current = compact_header(storage, &current.previous_header)?;
+    }
+    Ok(current)
+}
+
+// the headers from `to` going backward, down to `from` (excluded), the
+// most recent header first.
+//
+// fails if `from` is not an ancestor of `to` within `limit` headers.
+fn headers_back(storage: &Storage, from: &CompactHeader, to: CompactHeader, limit: usize) -> Result<Vec<CompactHeader>, String> {
+    let to_hash = to.hash.clone();
+    let mut headers = Vec::new();
+    let mut current = to;
+    while current.hash != from.hash {
+        if headers.len() == limit || current.difficulty < from.difficulty {
+            return Err(format!("block {} is not an ancestor of block {} within {} blocks", from.hash, to_hash, limit));
+        }
+        let previous = compact_header(storage, &current.previous_header)?;
+        headers.push(current);
+        current = previous;
+    }
+    Ok(headers)
+}
+
+// the headers following the most recent of the given checkpoints known
+// locally, up to `to` and at most `MAX_BLOCKS - 1` of them (so that the
+// blocks of the window and the checkpoint can be requested at once), the
+// most recent header first.
+//
+// like cardano-sl, a request too far behind `to` is answered with the
+// window just after the checkpoint, the peer is expected to ask for the
+// next window starting from the last header received.
+fn headers_after(storage: &Storage, checkpoints: &[HeaderHash], to: &CompactHeader) -> Result<Vec<CompactHeader>, String> {
+    let mut known : Vec<CompactHeader> = checkpoints.iter()
+        .filter_map(|hash| storage.get_header(&header_to_blockhash(hash)))
+        .filter(|hdr| hdr.difficulty <= to.difficulty)
+        .collect();
+    known.sort_by(|a, b| b.difficulty.cmp(&a.difficulty));
+    for checkpoint in known {
+        if checkpoint.hash == to.hash {
+            return Err(format!("no block after the checkpoint {}", to.hash));
+        }
+        let end = ancestor_at(storage, to.clone(), u64::from(checkpoint.difficulty) + MAX_BLOCKS as u64)?;
+        // the genesis blocks do not increase the difficulty, the window
+        // may hold a few more headers than the difference of difficulties
+        match headers_back(storage, &checkpoint, end, MAX_BLOCKS + MAX_BLOCKS / 10) {
+            Err(_) => continue,
+            Ok(mut headers) => {
+                if headers.len() >= MAX_BLOCKS {
+                    headers.drain(..headers.len() - (MAX_BLOCKS - 1));
+                }
+                return Ok(headers);
+            },
+        }
+    }
+    Err(format!("none of the {} checkpoints is an ancestor of block {}", checkpoints.len(), to.hash))
+}
+
+fn headers(storage: &Storage, req: &GetHeaders) -> Result<Vec<BlockHeader>, String> {
+    let to = match req.to {
+        None => tip(storage)?,
+        Some(ref to) => to.clone(),
+    };
+    if req.from.is_empty() {
+        // no checkpoint, only the requested header
+        return Ok(vec![read_header(storage, &to)?]);
+    }
+    let to = compact_header(storage, &to)?;
+    headers_after(storage, &req.from, &to)?.iter().map(|hdr| read_header(storage, &hdr.hash)).collect()
+}
+
+/// answer `MsgGetHeaders` with the headers following the most recent
+/// of the given checkpoints, up to the requested block (or the tip).
+pub struct HeadersResponder(pub Arc<Storage>);
+impl Handler for HeadersResponder {
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> protocol::Result<()> {
+        let response = RawCbor::from(msg).deserialize()
+            .map_err(|err| format!("invalid request: {:?}", err))
+            .and_then(|req: GetHeaders| headers(&self.0, &req));
+        let response = match response {
+            Ok(headers) => BlockHeaderResponse::Ok(headers),
+            Err(err) => {
+                warn!("cannot serve headers: {}", err);
+                BlockHeaderResponse::Err(err)
+            },
+        };
+        reply.send_bytes(&se::Serializer::new().serialize(&response)?.finalize())?;
+        reply.close();
+        Ok(())
+    }
+}
+
+/// answer `MsgGetBlocks` with the blocks between the requested hashes,
+/// both included, one `BlockResponse` per block.
+pub struct BlocksResponder(pub Arc<Storage>);
+impl BlocksResponder {
+    fn send_blocks(&self, reply: &mut Reply, msg: &[u8]) -> protocol::Result<Result<(), String>> {
+        let req : GetBlocks = match RawCbor::from(msg).deserialize() {
+            Err(err) => return Ok(Err(format!("invalid request: {:?}", err))),
+            Ok(req) => req,
+        };
+        let headers = compact_header(&self.0, &req.from).and_then(|from| {
+            let to = compact_header(&self.0, &req.to)?;
+            headers_back(&self.0, &from, to, MAX_BLOCKS - 1)
+        });
+        let headers = match headers {
+            Err(err) => return Ok(Err(err)),
+            Ok(headers) => headers,
+        };
+        // blocks are read one at a time while sending, oldest first
+        let hashes = ::std::iter::once(&req.from).chain(headers.iter().rev().map(|hdr| &hdr.hash));
+        for hash in hashes {
+            match storage::block_read(&self.0, &header_to_blockhash(hash)) {
+                None => return Ok(Err(format!("block {} not found", hash))),
+                Some(rblk) => reply.send_bytes(&packet::raw_block_response(&rblk))?,
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+impl Handler for BlocksResponder {
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> protocol::Result<()> {
+        if let Err(err) = self.send_blocks(reply, msg)? {
+            warn!("cannot serve blocks: {}", err);
+            let response = BlockResponse::NoBlock(err);
+            reply.send_bytes(&se::Serializer::new().serialize(&response)?.finalize())?;
+        }
+        reply.close();
+        Ok(())
+    }
+}
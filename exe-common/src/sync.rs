@@ -1,18 +1,69 @@
 use blockchain;
 use config::net;
-use network::{api, Peer, api::Api};
+use network::{self, api, native, Peer, PeerDb, peerdb::{BAN_DURATION}, api::Api};
+use std::cmp::{Ordering};
+use std::path::{Path};
+use std::time::{Instant};
 use storage;
+use storage::config::{StorageConfig};
+use storage::{lock};
 use storage::types::PackHash;
 
+// lock the storage for writing, waiting for the other writers up to
+// `lock::DEFAULT_TIMEOUT`
+fn lock_storage(network: &str, storage: &storage::Storage) -> Option<lock::StorageLock> {
+    match storage.lock(lock::Access::Write, Some(lock::DEFAULT_TIMEOUT)) {
+        Ok(lock) => Some(lock),
+        Err(err) => {
+            println!("Error: cannot sync blockchain `{}': {}", network, err);
+            None
+        },
+    }
+}
+
 pub fn net_sync_fast(network: String, mut storage: storage::Storage) {
+    let _lock = match lock_storage(&network, &storage) { None => return, Some(lock) => lock };
     let netcfg_file = storage.config.get_config_file();
     let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
-    let mut net = get_native_peer(network, &net_cfg);
+    let mut peerdb = PeerDb::load(storage.config.get_peers_file()).expect("invalid peer database");
+    let selected = select_native_peers(&net_cfg, &mut peerdb, SELECTION_PEERS);
+    peerdb.save().unwrap();
+    let result = selected.and_then(|candidates| sync_from_native_peers(&network, &mut storage, &net_cfg, &mut peerdb, candidates));
+    if let Err(err) = result {
+        println!("Error: cannot sync blockchain `{}': {:?}", network, err);
+    }
+}
+
+// sync from the first of the candidate peers (see `select_native_peers`)
+// which does not fail, returning the error of the last one if they all do.
+//
+// The candidates stay connected while the previous ones are syncing, a
+// candidate disconnected meanwhile fails in turn.
+fn sync_from_native_peers(network: &str, storage: &mut storage::Storage, net_cfg: &net::Config, peerdb: &mut PeerDb,
+                          candidates: Vec<(native::PeerPool, blockchain::BlockHeader)>) -> network::Result<()> {
+    let mut result = Err(network::Error::NoPeerAvailable);
+    for (net, mbh) in candidates {
+        let name = net.name.clone();
+        result = sync_from_native_peer(network, storage, net_cfg, peerdb, net, mbh);
+        match result {
+            Ok(()) => break,
+            Err(ref err) => warn!("syncing blockchain `{}' from peer `{}' failed: {:?}", network, name, err),
+        }
+    }
+    result
+}
+
+// download the complete epochs of the chain of the given native peer
+// (of tip `mbh`) after the latest epoch of the storage, the failure of
+// the peer is reported in the `PeerDb`.
+fn sync_from_native_peer(network: &str, storage: &mut storage::Storage, net_cfg: &net::Config, peerdb: &mut PeerDb,
+                         mut net: native::PeerPool, mbh: blockchain::BlockHeader) -> network::Result<()> {
+    let peer_addr = net.get_sockaddr().unwrap();
+    info!("syncing blockchain `{}' from peer `{}' ({})", network, net.name, peer_addr);
 
     //let mut our_tip = tag::read_hash(&storage, &"TIP".to_string()).unwrap_or(genesis.clone());
 
-    // recover and print the TIP of the network
-    let mbh = net.get_tip().unwrap();
+    // the TIP of the network
     let network_tip = mbh.compute_hash();
     let network_slotid = mbh.get_blockdate();
 
@@ -31,7 +82,7 @@ pub fn net_sync_fast(network: String, mut storage: storage::Storage) {
 
     // find the earliest epoch we know about starting from network_slotid
     let (latest_known_epoch_id, mstart_hash, prev_hash) =
-        match find_earliest_epoch(&storage, net_cfg.epoch_start, network_slotid.get_epochid()) {
+        match find_earliest_epoch(storage, net_cfg.epoch_start, network_slotid.get_epochid()) {
             None => (
                 net_cfg.epoch_start,
                 Some(net_cfg.genesis.clone()),
@@ -40,7 +91,7 @@ pub fn net_sync_fast(network: String, mut storage: storage::Storage) {
             Some((found_epoch_id, packhash)) => (
                 found_epoch_id + 1,
                 None,
-                get_last_blockid(&storage.config, &packhash).unwrap(),
+                get_last_blockid(storage, &packhash).unwrap(),
             ),
         };
     println!(
@@ -63,27 +114,43 @@ pub fn net_sync_fast(network: String, mut storage: storage::Storage) {
             previous_header_hash: download_prev_hash,
             upper_bound_hash: network_tip.clone(),
         };
-        let result = net.fetch_epoch(&net_cfg, &mut storage, fep).unwrap();
+        let result = match net.fetch_epoch(net_cfg, storage, fep) {
+            Ok(result) => result,
+            Err(err) => {
+                peerdb.report(&net.name, peer_addr, &err);
+                peerdb.save()?;
+                return Err(err);
+            },
+        };
+        storage::tag::write_hash(storage, &storage::tag::HEAD, &result.last_header_hash);
         download_prev_hash = result.last_header_hash.clone();
         download_start_hash = result.next_epoch_hash.unwrap_or(result.last_header_hash);
         download_epoch_id += 1;
     }
+    Ok(())
 }
 
 pub fn net_sync_faster(network: String, mut storage: storage::Storage) {
+    let _lock = match lock_storage(&network, &storage) { None => return, Some(lock) => lock };
     let netcfg_file = storage.config.get_config_file();
     let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
-    let mut net = get_http_peer(network, &net_cfg);
+    let mut net = get_http_peer(network.clone(), &net_cfg);
 
     //let mut our_tip = tag::read_hash(&storage, &"TIP".to_string()).unwrap_or(genesis.clone());
 
+    // recover and print the TIP of the network
+    let mbh = net.get_tip().unwrap();
+    let network_tip = mbh.compute_hash();
+    let network_slotid = mbh.get_blockdate();
+
     println!("Configured genesis   : {}", net_cfg.genesis);
     println!("Configured genesis-1 : {}", net_cfg.genesis_prev);
+    println!("Network TIP is       : {}", network_tip);
+    println!("Network TIP slotid   : {}", network_slotid);
 
     // find the earliest epoch we know about starting from network_slotid
     let (latest_known_epoch_id, mstart_hash, prev_hash) =
-        match find_earliest_epoch(&storage, net_cfg.epoch_start, 100) {
-            // TODO
+        match find_earliest_epoch(&storage, net_cfg.epoch_start, network_slotid.get_epochid()) {
             None => (
                 net_cfg.epoch_start,
                 Some(net_cfg.genesis.clone()),
@@ -92,7 +159,7 @@ pub fn net_sync_faster(network: String, mut storage: storage::Storage) {
             Some((found_epoch_id, packhash)) => (
                 found_epoch_id + 1,
                 None,
-                get_last_blockid(&storage.config, &packhash).unwrap(),
+                get_last_blockid(&storage, &packhash).unwrap(),
             ),
         };
     println!(
@@ -104,7 +171,9 @@ pub fn net_sync_faster(network: String, mut storage: storage::Storage) {
     let mut download_prev_hash = prev_hash.clone();
     let mut download_start_hash = mstart_hash.or(Some(prev_hash)).unwrap();
 
-    while download_epoch_id < 46 {
+    // hermes only serves packed epochs, so its tip is the last block
+    // of the latest complete epoch: this epoch is to download too.
+    while download_epoch_id <= network_slotid.get_epochid() {
         println!(
             "downloading epoch {} {}",
             download_epoch_id, download_start_hash
@@ -113,13 +182,140 @@ pub fn net_sync_faster(network: String, mut storage: storage::Storage) {
             epoch_id: download_epoch_id,
             start_header_hash: download_start_hash,
             previous_header_hash: download_prev_hash,
-            upper_bound_hash: net_cfg.genesis_prev.clone(),
+            upper_bound_hash: network_tip.clone(),
         };
         let result = net.fetch_epoch(&net_cfg, &mut storage, fep).unwrap();
+        storage::tag::write_hash(&storage, &storage::tag::HEAD, &result.last_header_hash);
         download_prev_hash = result.last_header_hash.clone();
         download_start_hash = result.next_epoch_hash.unwrap_or(result.last_header_hash);
         download_epoch_id += 1;
     }
+
+    // the epochs completed since hermes packed its latest epoch are
+    // downloaded from the best native peer, if any
+    let mut peerdb = PeerDb::load(storage.config.get_peers_file()).expect("invalid peer database");
+    let selected = select_native_peers(&net_cfg, &mut peerdb, SELECTION_PEERS);
+    peerdb.save().unwrap();
+    match selected {
+        Err(err) => warn!("no native peer to sync the latest epochs from: {:?}", err),
+        Ok(candidates) => if let Err(err) = sync_from_native_peers(&network, &mut storage, &net_cfg, &mut peerdb, candidates) {
+            warn!("cannot sync the latest epochs from the native peers: {:?}", err)
+        },
+    }
+}
+
+/// the number of native peers queried for their tip before syncing
+pub const SELECTION_PEERS : usize = 3;
+
+/// the difference of chain difficulty (i.e. the number of blocks) between
+/// the tips of two peers above which the peers are considered in disagreement
+pub const DISAGREEMENT_THRESHOLD : u64 = 10;
+
+/// compare the tips of two peers: the best tip has the highest chain
+/// difficulty, and of two tips of same difficulty the earliest is the
+/// densest chain.
+fn compare_tips(a: &blockchain::BlockHeader, b: &blockchain::BlockHeader) -> Ordering {
+    a.get_chain_difficulty().cmp(&b.get_chain_difficulty())
+        .then(b.get_blockdate().cmp(&a.get_blockdate()))
+}
+
+/// select the best of the given tips (see `compare_tips`)
+pub fn select_tip(tips: &[blockchain::BlockHeader]) -> Option<usize> {
+    (0..tips.len()).max_by(|&a, &b| compare_tips(&tips[a], &tips[b]))
+}
+
+/// query the tip of up to `count` of the best native peers of the `PeerDb`
+/// and select the peer with the densest chain, returning the peer and its tip.
+///
+/// See `select_native_peers`.
+pub fn select_native_peer(cfg: &net::Config, peerdb: &mut PeerDb, count: usize) -> network::Result<(native::PeerPool, blockchain::BlockHeader)> {
+    let mut candidates = select_native_peers(cfg, peerdb, count)?;
+    Ok(candidates.swap_remove(0))
+}
+
+/// like `select_native_peer`, returning all the peers answering with a tip
+/// of the blockchain and their tip, the densest chain first (see
+/// `compare_tips`).
+///
+/// The peers failing to return their tip are reported in the `PeerDb`, the
+/// ones returning a tip of another blockchain are banned. A warning is
+/// logged for the peers whose tip is `DISAGREEMENT_THRESHOLD` blocks behind
+/// the selected tip or forking from it.
+pub fn select_native_peers(cfg: &net::Config, peerdb: &mut PeerDb, count: usize) -> network::Result<Vec<(native::PeerPool, blockchain::BlockHeader)>> {
+    let mut candidates = Vec::new();
+    for mut pool in native::PeerPool::connect_many(&cfg.peers, &cfg.handshake(), peerdb, count) {
+        let addr = pool.get_sockaddr().unwrap();
+        match pool.get_tip() {
+            Err(err) => peerdb.report(&pool.name, addr, &err),
+            Ok(tip) => {
+                peerdb.tip_seen(&pool.name, addr, &tip);
+                if tip.get_protocol_magic() != cfg.protocol_magic {
+                    let reason = format!("tip {} of protocol magic {}", tip.compute_hash(), tip.get_protocol_magic());
+                    peerdb.ban(&pool.name, addr, BAN_DURATION, reason);
+                    continue;
+                }
+                info!("peer `{}' ({}) tip {} {} difficulty {}", pool.name, addr, tip.get_blockdate(), tip.compute_hash(), tip.get_chain_difficulty());
+                candidates.push((pool, tip));
+            },
+        }
+    }
+    if candidates.is_empty() {
+        return Err(network::Error::NoPeerAvailable);
+    }
+    candidates.sort_by(|a, b| compare_tips(&b.1, &a.1));
+
+    let best_difficulty : u64 = candidates[0].1.get_chain_difficulty().into();
+    let best_hash = candidates[0].1.compute_hash();
+    for &(ref peer, ref tip) in candidates.iter() {
+        let difficulty : u64 = tip.get_chain_difficulty().into();
+        if best_difficulty - difficulty > DISAGREEMENT_THRESHOLD {
+            warn!("peer `{}' ({}) is {} blocks behind the selected tip {}", peer.name, peer.address, best_difficulty - difficulty, best_hash);
+        } else if difficulty == best_difficulty && tip.compute_hash() != best_hash {
+            warn!("peer `{}' ({}) is on a fork: tip {} instead of {}", peer.name, peer.address, tip.compute_hash(), best_hash);
+        }
+    }
+
+    info!("selected peer `{}' ({}) out of {}", candidates[0].0.name, candidates[0].0.address, candidates.len());
+    Ok(candidates)
+}
+
+/// follow the tip of the best native peer (see `select_native_peer`) until
+/// it announces a block of an epoch after the epoch of its tip, i.e. until
+/// `net_sync_fast` has a new complete epoch to download. Returns the header
+/// of this block.
+///
+/// The failure of the peer while following its tip is reported in the
+/// `PeerDb`.
+pub fn wait_new_epoch(storage: &storage::Storage) -> network::Result<blockchain::BlockHeader> {
+    let netcfg_file = storage.config.get_config_file();
+    let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
+    let mut peerdb = PeerDb::load(storage.config.get_peers_file())?;
+    let selected = select_native_peer(&net_cfg, &mut peerdb, SELECTION_PEERS);
+    peerdb.save()?;
+    let (mut pool, tip) = selected?;
+    let peer_addr = pool.get_sockaddr().unwrap();
+    let tip_epoch = tip.get_blockdate().get_epochid();
+    info!("following peer `{}' ({}) from epoch {}", pool.name, peer_addr, tip_epoch);
+
+    let mut announced = None;
+    let result = pool.follow(|hdr| {
+        if hdr.get_blockdate().get_epochid() > tip_epoch {
+            announced = Some(hdr.clone());
+        }
+        announced.is_none()
+    });
+    match result {
+        Ok(()) => {
+            peerdb.tip_seen(&pool.name, peer_addr, announced.as_ref().unwrap());
+            peerdb.save()?;
+            Ok(announced.unwrap())
+        },
+        Err(err) => {
+            peerdb.report(&pool.name, peer_addr, &err);
+            peerdb.save()?;
+            Err(err)
+        },
+    }
 }
 
 pub fn get_http_peer(blockchain: String, cfg: &net::Config) -> Peer {
@@ -129,7 +325,7 @@ pub fn get_http_peer(blockchain: String, cfg: &net::Config) -> Peer {
                 blockchain,
                 peer.name().to_owned(),
                 peer.peer().clone(),
-                cfg.protocol_magic,
+                &cfg.handshake(),
             ).unwrap();
         }
     }
@@ -137,19 +333,48 @@ pub fn get_http_peer(blockchain: String, cfg: &net::Config) -> Peer {
     panic!("no http peer to connect to")
 }
 
-pub fn get_native_peer(blockchain: String, cfg: &net::Config) -> Peer {
-    for peer in cfg.peers.iter() {
-        if peer.is_native() {
-            return Peer::new(
-                blockchain,
-                peer.name().to_owned(),
-                peer.peer().clone(),
-                cfg.protocol_magic,
-            ).unwrap();
-        }
+/// connect to the best native peer of the blockchain of the given storage,
+/// see `select_native_peer`. The `PeerDb` of the storage is returned to
+/// record the outcome of the requests to the peer (see `record_outcome`).
+pub fn get_native_peer(storage_config: &StorageConfig, cfg: &net::Config) -> network::Result<(native::PeerPool, PeerDb)> {
+    let mut peerdb = PeerDb::load(storage_config.get_peers_file())?;
+    let selected = select_native_peer(cfg, &mut peerdb, SELECTION_PEERS);
+    peerdb.save()?;
+    Ok((selected?.0, peerdb))
+}
+
+/// like `get_native_peer`, recording the ntt frames exchanged with the peer
+/// in the given capture file. The best candidate of the `PeerDb` is taken
+/// without comparing the tips of the peers.
+pub fn get_native_peer_with_capture(storage_config: &StorageConfig, cfg: &net::Config, capture: &Path) -> network::Result<(native::PeerPool, PeerDb)> {
+    let mut peerdb = PeerDb::load(storage_config.get_peers_file())?;
+    let (name, sockaddr) = match peerdb.candidates(&cfg.peers).into_iter().next() {
+        None => return Err(network::Error::NoPeerAvailable),
+        Some(candidate) => candidate,
+    };
+    let start = Instant::now();
+    let connected = native::PeerPool::new_with_capture(name.clone(), sockaddr.to_string(), &cfg.handshake(), Some(capture));
+    match connected {
+        Ok(_) => peerdb.connected(&name, sockaddr, start.elapsed()),
+        Err(ref err) => peerdb.report(&name, sockaddr, err),
     }
+    peerdb.save()?;
+    Ok((connected?, peerdb))
+}
 
-    panic!("no native peer to connect to")
+/// record the outcome of a request to the given native peer in the
+/// `PeerDb`: the failure of the peer is reported (see `PeerDb::report`),
+/// the tip of the peer is recorded if the request returned it.
+pub fn record_outcome<T>(peerdb: &mut PeerDb, pool: &native::PeerPool, result: &network::Result<T>, tip: Option<&blockchain::BlockHeader>) {
+    let addr = match pool.get_sockaddr() { None => return, Some(addr) => addr };
+    match (result, tip) {
+        (Err(err), _) => peerdb.report(&pool.name, addr, err),
+        (Ok(_), Some(tip)) => peerdb.tip_seen(&pool.name, addr, tip),
+        (Ok(_), None) => {},
+    }
+    if let Err(err) = peerdb.save() {
+        warn!("cannot save the peer database: {:?}", err);
+    }
 }
 
 // Return the chain of block headers starting at from's next block
@@ -163,7 +388,7 @@ fn find_earliest_epoch(
     let mut epoch_id = start_epochid;
     loop {
         match storage::tag::read_hash(storage, &storage::tag::get_epoch_tag(epoch_id)) {
-            None => match storage::epoch::epoch_read_pack(&storage.config, epoch_id).ok() {
+            None => match storage::epoch::epoch_read_pack(storage, epoch_id).ok() {
                 None => {}
                 Some(h) => {
                     return Some((epoch_id, h));
@@ -184,13 +409,13 @@ fn find_earliest_epoch(
 }
 
 fn get_last_blockid(
-    storage_config: &storage::config::StorageConfig,
+    storage: &storage::Storage,
     packref: &PackHash,
 ) -> Option<blockchain::HeaderHash> {
-    let mut reader = storage::pack::PackReader::init(&storage_config, packref);
+    let mut reader = storage::pack::PackReader::init(storage, packref).ok()?;
     let mut last_blk_raw = None;
 
-    while let Some(blk_raw) = reader.get_next() {
+    while let Some(blk_raw) = reader.get_next().unwrap() {
         last_blk_raw = Some(blk_raw);
     }
     if let Some(blk_raw) = last_blk_raw {
@@ -202,3 +427,141 @@ fn get_last_blockid(
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::mock::{Action, Chain, MockPeer};
+    use wallet_crypto::config::{ProtocolMagic};
+    use std::{env, fs, process, thread};
+    use std::time::{Duration};
+
+    #[test]
+    fn select_the_densest_chain() {
+        let pm = ProtocolMagic::default();
+        let main = Chain::generate(pm, 10, 40);
+        // far behind the main chain
+        let stale = main.fork(20);
+        // as long as the main chain, but sparser
+        let mut fork = main.fork(30);
+        fork.skip_slots(1);
+        fork.extend(10);
+        // longer, but of another blockchain
+        let other = Chain::generate(ProtocolMagic::new(1), 10, 50);
+
+        let mut peers = net::Peers::new();
+        let mut mocks = Vec::new();
+        for (name, chain) in vec![("stale", stale), ("fork", fork), ("other", other), ("main", main.clone())] {
+            let mock = MockPeer::start(chain).unwrap();
+            peers.push(name.to_string(), net::Peer::native(mock.local_addr().to_string()));
+            mocks.push(mock);
+        }
+        let mut cfg = net::Config::mainnet();
+        cfg.peers = peers;
+
+        let dir = env::temp_dir().join(format!("exe-common-select-{}", process::id()));
+        let mut peerdb = PeerDb::load(dir.join("peers.yml")).unwrap();
+        let (pool, tip) = select_native_peer(&cfg, &mut peerdb, 4).unwrap();
+        assert_eq!(pool.name, "main");
+        assert_eq!(tip.compute_hash(), main.tip().unwrap().compute_hash());
+        peerdb.save().unwrap();
+
+        let peerdb = PeerDb::load(dir.join("peers.yml")).unwrap();
+        assert!(peerdb.is_banned(&mocks[2].local_addr()));
+        assert!(peerdb.get(&mocks[0].local_addr()).unwrap().last_tip.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fall_back_to_the_next_peer() {
+        let pm = ProtocolMagic::default();
+        // the tips are in the epoch 3, the epochs 0 to 2 are synced
+        let main = Chain::generate(pm, 10, 40);
+        let behind = main.fork(35);
+        let failing = MockPeer::start(main.clone()).unwrap();
+        let fallback = MockPeer::start(behind).unwrap();
+        // the tip is served, not the download of the first epoch
+        failing.script(Action::Serve);
+        failing.script(Action::Disconnect);
+
+        let mut cfg = net::Config::mainnet();
+        cfg.genesis = main.hash(0);
+        cfg.genesis_prev = main.header(0).get_previous_header();
+        cfg.epoch_start = 0;
+        cfg.peers = net::Peers::new();
+        cfg.peers.push("failing".to_string(), net::Peer::native(failing.local_addr().to_string()));
+        cfg.peers.push("fallback".to_string(), net::Peer::native(fallback.local_addr().to_string()));
+        let dir = env::temp_dir().join(format!("exe-common-fall-back-{}", process::id()));
+        let storage = storage::Storage::init(&StorageConfig::new(&dir)).unwrap();
+        cfg.to_file(storage.config.get_config_file());
+
+        net_sync_fast("test".to_string(), storage);
+        let storage = storage::Storage::init(&StorageConfig::new(&dir)).unwrap();
+        assert_eq!(storage::tag::read_hash(&storage, &storage::tag::HEAD), Some(main.hash(32)));
+        for epochid in 0..3 {
+            assert!(storage::tag::read_hash(&storage, &storage::tag::get_epoch_tag(epochid)).is_some());
+        }
+        let peerdb = PeerDb::load(storage.config.get_peers_file()).unwrap();
+        assert_eq!(peerdb.get(&failing.local_addr()).unwrap().failures, 1);
+        assert_eq!(peerdb.get(&fallback.local_addr()).unwrap().failures, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn native_peer_from_the_peer_database() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 15);
+        let mock = MockPeer::start(chain.clone()).unwrap();
+        let mut cfg = net::Config::mainnet();
+        cfg.peers = net::Peers::new();
+        cfg.peers.push("mock".to_string(), net::Peer::native(mock.local_addr().to_string()));
+        let dir = env::temp_dir().join(format!("exe-common-native-peer-{}", process::id()));
+        let storage_config = StorageConfig::new(&dir);
+
+        let (mut pool, mut peerdb) = get_native_peer(&storage_config, &cfg).unwrap();
+        assert_eq!(pool.name, "mock");
+        let result = pool.get_block(chain.header(0).get_previous_header());
+        assert!(result.is_err());
+        record_outcome(&mut peerdb, &pool, &result, None);
+
+        // the connection and the failure are recorded in the storage
+        let peerdb = PeerDb::load(storage_config.get_peers_file()).unwrap();
+        let stats = peerdb.get(&mock.local_addr()).unwrap();
+        assert_eq!(stats.connections, 1);
+        assert!(stats.failures >= 1 && stats.last_tip.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wait_for_a_new_epoch() {
+        // the tip is in the epoch 1
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 15);
+        let mock = MockPeer::start(chain.clone()).unwrap();
+        let mut cfg = net::Config::mainnet();
+        cfg.peers = net::Peers::new();
+        cfg.peers.push("mock".to_string(), net::Peer::native(mock.local_addr().to_string()));
+        let dir = env::temp_dir().join(format!("exe-common-wait-epoch-{}", process::id()));
+        let storage = storage::Storage::init(&StorageConfig::new(&dir)).unwrap();
+        cfg.to_file(storage.config.get_config_file());
+
+        // the genesis block of the epoch 2 is announced once followed
+        let mut next = chain.clone();
+        next.extend(8);
+        let announcer = {
+            let (mock, next) = (mock.clone(), next.clone());
+            thread::spawn(move || {
+                while mock.subscriptions() == 0 {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                mock.set_chain(next);
+            })
+        };
+        let header = wait_new_epoch(&storage).unwrap();
+        announcer.join().unwrap();
+        assert_eq!(header.compute_hash(), next.hash(22));
+
+        let peerdb = PeerDb::load(storage.config.get_peers_file()).unwrap();
+        let stats = peerdb.get(&mock.local_addr()).unwrap();
+        assert_eq!(stats.last_tip.as_ref().unwrap().hash, next.hash(22));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,6 +13,7 @@ pub enum Error {
     YamlError(serde_yaml::Error),
     ParseIntError(ParseIntError),
     StorageError(storage::Error),
+    LockError(storage::lock::Error),
     BlockchainConfigError(&'static str)
 }
 impl From<VarError> for Error {
@@ -27,6 +28,9 @@ impl From<io::Error> for Error {
 impl From<storage::Error> for Error {
     fn from(e: storage::Error) -> Error { Error::StorageError(e) }
 }
+impl From<storage::lock::Error> for Error {
+    fn from(e: storage::lock::Error) -> Error { Error::LockError(e) }
+}
 impl From<serde_yaml::Error> for Error {
     fn from(e: serde_yaml::Error) -> Error { Error::YamlError(e) }
 }
@@ -76,10 +80,13 @@ impl Config {
             if ! entry.file_type()?.is_dir() { continue; }
             let name = entry.file_name();
             if let Some(name) = name.to_str() {
+                let storage = self.get_storage(name)?;
+                let lock = storage.lock(storage::lock::Access::Read, Some(storage::lock::DEFAULT_TIMEOUT))?;
                 let network = Network {
                     path: entry.path().to_path_buf(),
                     config: self.get_network_config(name)?,
-                    storage: Arc::new(self.get_storage(name)?)
+                    storage: Arc::new(storage),
+                    lock: lock,
                 };
                 networks.insert(name.to_owned(), network);
             }
@@ -135,6 +142,9 @@ pub struct Network {
     pub path: PathBuf,
     pub config: net::Config,
     pub storage: Arc<storage::Storage>,
+    /// the storage is locked for reading for as long as the network is
+    /// served, see `storage::lock::StorageLock`
+    pub lock: storage::lock::StorageLock,
 }
 pub type Networks = BTreeMap<String, Network>;
 
@@ -0,0 +1,214 @@
+//! the unspent outputs of an address
+//!
+//! The chain is replayed from the genesis: the epoch packs, skipping the
+//! ones which certainly don't pay to the address nor spend its outputs
+//! (see `storage::addrindex`), then the blocks not packed yet (the
+//! current epoch, and the previous one until it is packed) from `HEAD`.
+//! The cost is then bounded by the number of epochs affecting the address
+//! and the number of the blocks not packed.
+
+use config::{Networks};
+use wallet_crypto::{tx::{TxIn}, coin::{Coin}, address::{ExtendedAddr}};
+use wallet_crypto::util::{base58};
+use blockchain::{Block, HeaderHash};
+use storage::{tag, epoch, addrindex, block_read, pack::{MappedPack}};
+use std::sync::{Arc};
+use std::collections::{BTreeMap};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use handlers::{common, views};
+
+pub struct Handler {
+    networks: Arc<Networks>
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        router.get(":network/address/:address/utxos", self, "address_utxos")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let ref address_str = req.extensions.get::<router::Router>().unwrap().find("address").unwrap();
+        let address = match base58::decode(address_str).ok().and_then(|bytes| ExtendedAddr::from_bytes(&bytes).ok()) {
+            None => {
+                error!("invalid address: {}", address_str);
+                return Ok(Response::with(status::BadRequest));
+            },
+            Some(address) => address
+        };
+        info!("querying utxos of address: {}", address);
+
+        // replay the chain, keeping track of the outputs paying to the
+        // given address and dropping them as soon as they are spent.
+        let mut utxos = Utxos::new();
+        let mut epoch_id = 0;
+        while let Ok(packref) = epoch::epoch_read_pack(&net.storage, epoch_id) {
+            epoch_id += 1;
+
+            // skip the packs that certainly don't pay to the address nor
+            // spend any of the outputs found so far.
+            if addrindex::exist(&net.storage, &packref) {
+                match addrindex::read(&net.storage, &packref) {
+                    Err(err) => warn!("cannot read the address index of epoch {}: {:?}", epoch_id - 1, err),
+                    Ok(lookup) => {
+                        if ! lookup.search_address(&address) && ! utxos.keys().any(|txin| lookup.search_input(txin)) {
+                            continue;
+                        }
+                    },
+                }
+            }
+
+            let pack = match MappedPack::open(&net.storage, &packref) {
+                Err(err) => {
+                    error!("cannot open the pack of epoch {}: {:?}", epoch_id - 1, err);
+                    return Ok(Response::with(status::InternalServerError));
+                },
+                Ok(pack) => pack
+            };
+            let mut blocks = pack.iter();
+            while let Some(entry) = blocks.next_raw() {
+                let blk = match entry.map(|(_, rblk)| rblk.decode()) {
+                    Err(err) => {
+                        error!("error while reading the blocks of epoch {}: {:?}", epoch_id - 1, err);
+                        return Ok(Response::with(status::InternalServerError));
+                    },
+                    Ok(Err(err)) => {
+                        error!("error while decoding a block of epoch {}: {:?}", epoch_id - 1, err);
+                        return Ok(Response::with(status::InternalServerError));
+                    },
+                    Ok(Ok(blk)) => blk
+                };
+                replay(&mut utxos, &address, &blk);
+            }
+        }
+
+        // then the blocks not packed yet, from `HEAD` back to the last
+        // packed epoch
+        let mut unpacked = Vec::new();
+        let mut next = tag::read_hash(&net.storage, &tag::HEAD);
+        while let Some(hash) = next {
+            let rblk = match block_read(&net.storage, hash.bytes()) {
+                None => break,
+                Some(rblk) => rblk
+            };
+            let blk = match rblk.decode() {
+                Err(err) => {
+                    error!("error while decoding block {}: {:?}", hash, err);
+                    return Ok(Response::with(status::InternalServerError));
+                },
+                Ok(blk) => blk
+            };
+            if blk.get_header().get_blockdate().get_epochid() < epoch_id { break; }
+            next = Some(blk.get_header().get_previous_header());
+            unpacked.push(blk);
+        }
+        for blk in unpacked.iter().rev() {
+            replay(&mut utxos, &address, blk);
+        }
+
+        let mut total = Coin::zero();
+        let mut views = Vec::with_capacity(utxos.len());
+        for (txin, (blk_hash, value)) in utxos {
+            total = match total + value {
+                Err(err) => {
+                    error!("error while computing the balance of {}: {}", address, err);
+                    return Ok(Response::with(status::InternalServerError));
+                },
+                Ok(total) => total
+            };
+            views.push(views::Utxo {
+                id: format!("{}", txin.id),
+                index: txin.index,
+                block: format!("{}", blk_hash),
+                value: value,
+            });
+        }
+
+        common::json_response(&views::Utxos { address: address, total: total, utxos: views })
+    }
+}
+
+// the outputs paying to the address, with the block of their transaction
+type Utxos = BTreeMap<TxIn, (HeaderHash, Coin)>;
+
+// update the outputs of the address with the transactions of the block
+fn replay(utxos: &mut Utxos, address: &ExtendedAddr, blk: &Block) {
+    if let Block::MainBlock(ref mblk) = blk {
+        let blk_hash = blk.get_header().compute_hash();
+        for txaux in mblk.body.tx.iter() {
+            for txin in txaux.tx.inputs.iter() {
+                utxos.remove(txin);
+            }
+            let txid = txaux.tx.id();
+            for (index, txout) in txaux.tx.outputs.iter().enumerate() {
+                if &txout.address != address { continue; }
+                utxos.insert(TxIn::new(txid, index as u32), (blk_hash.clone(), txout.value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::fixture;
+    use iron::{Iron};
+    use protocol::mock::{fixture_address};
+
+    #[test]
+    fn utxos_of_the_addresses() {
+        let (chain, txs) = fixture::chain();
+        let mut router = Router::new();
+        Handler::new(fixture::networks(fixture::storage(&chain, 2))).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+
+        // the outputs, spent or paid, of the packed epochs and of the
+        // epoch not packed yet
+        let utxos = |n: u8| {
+            let reply = fixture::get(&server.socket, &format!("/test/address/{}/utxos", fixture_address(n)), &[]);
+            assert_eq!(reply.status, 200);
+            let json = reply.json();
+            assert_eq!(json["address"], format!("{}", fixture_address(n)));
+            let mut utxos : Vec<_> = json["utxos"].as_array().unwrap().iter().map(|utxo| {
+                (utxo["id"].as_str().unwrap().to_owned(), utxo["index"].as_u64().unwrap(),
+                 utxo["block"].as_str().unwrap().to_owned(), utxo["value"].as_u64().unwrap())
+            }).collect();
+            utxos.sort();
+            (json["total"].as_u64().unwrap(), utxos)
+        };
+        let utxo = |tx: usize, index: u64, height: usize, value: u64| {
+            (format!("{}", txs[tx].tx.id()), index, format!("{}", chain.hash(height)), value)
+        };
+        let sorted = |mut utxos: Vec<_>| { utxos.sort(); utxos };
+        assert_eq!(utxos(1), (40, sorted(vec![utxo(1, 0, 12, 30), utxo(2, 1, 23, 10)])));
+        assert_eq!(utxos(2), (0, vec![]));
+        assert_eq!(utxos(3), (110, sorted(vec![utxo(1, 1, 12, 20), utxo(2, 0, 23, 90)])));
+        assert_eq!(utxos(5), (0, vec![]));
+
+        assert_eq!(fixture::get(&server.socket, "/test/address/xyz/utxos", &[]).status, 400);
+        server.close().unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use config::{Networks};
+use storage::{block_read};
+use storage::types::{BlockHash};
+use blockchain::{BlockDate};
+use wallet_crypto::util::{hex};
+use std::sync::{Arc};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use handlers::common;
+
+// how the block is looked for
+#[derive(Clone, Copy)]
+enum Lookup {
+    Date,
+    Height,
+}
+
+/// the raw block at a given date (`<epoch>.<slot>`) or height, see
+/// `Storage::block_at_date` and `Storage::block_at_height`
+pub struct Handler {
+    networks: Arc<Networks>,
+    lookup: Lookup,
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks,
+            lookup: Lookup::Date,
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        let by_height = Handler { networks: self.networks.clone(), lookup: Lookup::Height };
+        router.get(":network/date/:position", self, "block_at_date")
+              .get(":network/height/:position", by_height, "block_at_height")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let ref position = req.extensions.get::<router::Router>().unwrap().find("position").unwrap();
+        let hash : Option<BlockHash> = match self.lookup {
+            Lookup::Date => match position.parse::<BlockDate>() {
+                Err(err) => {
+                    error!("invalid date {}: {}", position, err);
+                    return Ok(Response::with(status::BadRequest));
+                },
+                Ok(date) => {
+                    info!("querying block at date: {}", date);
+                    net.storage.block_at_date(&date)
+                },
+            },
+            Lookup::Height => match position.parse::<u64>() {
+                Err(_) => {
+                    error!("invalid height: {}", position);
+                    return Ok(Response::with(status::BadRequest));
+                },
+                Ok(height) => {
+                    info!("querying block at height: {}", height);
+                    net.storage.block_at_height(height)
+                },
+            },
+        };
+
+        match hash {
+            None => {
+                warn!("no block at {}", position);
+                Ok(Response::with((status::NotFound, "Not Found")))
+            },
+            Some(hash) => match block_read(&net.storage, &hash) {
+                None => {
+                    error!("error while reading block {}", hex::encode(&hash));
+                    Ok(Response::with(status::InternalServerError))
+                },
+                Some(rblk) => Ok(Response::with((status::Ok, rblk.as_ref()))),
+            },
+        }
+    }
+}
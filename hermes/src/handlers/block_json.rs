@@ -0,0 +1,124 @@
+use config::{Networks};
+use storage::{tag, block_read};
+use wallet_crypto::util::{hex};
+use blockchain;
+use std::sync::{Arc};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use handlers::{common, views};
+
+pub struct Handler {
+    networks: Arc<Networks>
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        router.get(":network/block/:blockid/json", self, "block_json")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let ref blockid = req.extensions.get::<router::Router>().unwrap().find("blockid").unwrap();
+        if ! blockid.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            error!("invalid blockid: {}", blockid);
+            return Ok(Response::with(status::BadRequest));
+        }
+        let hh_bytes = match tag::read(&net.storage, &blockid) {
+            None => match hex::decode(&blockid) {
+                Err(_) => {
+                    error!("invalid blockid: {}", blockid);
+                    return Ok(Response::with(status::BadRequest));
+                },
+                Ok(bytes) => bytes
+            },
+            Some(t) => t
+        };
+        let hh = match blockchain::HeaderHash::from_slice(&hh_bytes) {
+            Err(_) => {
+                error!("invalid blockid: {}", blockid);
+                return Ok(Response::with(status::BadRequest));
+            },
+            Ok(hh) => hh
+        };
+        info!("querying block: {}", hh);
+
+        match block_read(&net.storage, hh.bytes()) {
+            None => {
+                warn!("block `{}' does not exist", hh);
+                Ok(Response::with((status::NotFound, "Not Found")))
+            },
+            Some(rblk) => {
+                match rblk.decode() {
+                    Err(err) => {
+                        error!("error while decoding block {}: {:?}", hh, err);
+                        Ok(Response::with(status::InternalServerError))
+                    },
+                    Ok(blk) => common::json_response(&views::Block::from(&blk))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::fixture;
+    use iron::{Iron};
+
+    #[test]
+    fn blocks_as_json() {
+        let (chain, txs) = fixture::chain();
+        let mut router = Router::new();
+        Handler::new(fixture::networks(fixture::storage(&chain, 2))).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+        let get = |path: &str| fixture::get(&server.socket, path, &[]);
+
+        let reply = get(&format!("/test/block/{}/json", chain.hash(12)));
+        assert_eq!(reply.status, 200);
+        let json = reply.json();
+        assert_eq!(json["hash"], format!("{}", chain.hash(12)));
+        assert_eq!(json["previous"], format!("{}", chain.hash(11)));
+        assert_eq!(json["date"], format!("{}", chain.header(12).get_blockdate()));
+        assert_eq!(json["epoch"], 1);
+        assert_eq!(json["is_genesis"], false);
+        assert_eq!(json["transactions"][0]["id"], format!("{}", txs[1].tx.id()));
+        assert_eq!(json["transactions"][0]["inputs"][0]["id"], format!("{}", txs[0].tx.id()));
+        assert_eq!(json["transactions"][0]["inputs"][0]["index"], 1);
+        assert_eq!(json["transactions"][0]["outputs"][1]["value"], 20);
+
+        // the loose blocks, by hash or by tag
+        let json = get(&format!("/test/block/{}/json", chain.hash(22))).json();
+        assert_eq!(json["is_genesis"], true);
+        assert_eq!(json["transactions"].as_array().map(|txs| txs.len()), Some(0));
+        let json = get("/test/block/HEAD/json").json();
+        assert_eq!(json["hash"], format!("{}", chain.hash(26)));
+
+        assert_eq!(get(&format!("/test/block/{}/json", hex::encode(&[0;32]))).status, 404);
+        assert_eq!(get("/test/block/zz/json").status, 400);
+        assert_eq!(get("/other/block/HEAD/json").status, 400);
+        server.close().unwrap();
+    }
+}
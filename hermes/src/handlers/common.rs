@@ -1,5 +1,12 @@
 
 use blockchain::EpochId;
+use serde::{Serialize};
+use serde_json;
+
+use iron::{Response, IronResult};
+use iron::status;
+use iron::headers::{ContentType};
+use iron::modifiers::{Header};
 
 pub fn validate_network_name(v: &&str) -> bool {
     v.chars().all(|c| c.is_ascii_alphanumeric())
@@ -12,3 +19,13 @@ pub fn validate_epochid(v: &&str) -> Option<EpochId> {
         Some(v.parse::<EpochId>().unwrap())
     }
 }
+
+pub fn json_response<T: Serialize>(value: &T) -> IronResult<Response> {
+    match serde_json::to_string(value) {
+        Err(err) => {
+            error!("error while serialising json response: {:?}", err);
+            Ok(Response::with(status::InternalServerError))
+        },
+        Ok(json) => Ok(Response::with((status::Ok, Header(ContentType::json()), json)))
+    }
+}
@@ -10,7 +10,7 @@ use router;
 use router::{Router};
 
 use config::{Networks};
-use handlers::common;
+use handlers::{common, packfile};
 
 pub struct Handler {
     networks: Arc<Networks>
@@ -47,15 +47,101 @@ impl iron::Handler for Handler {
                         Some(e) => e,
         };
 
-        let opackref = storage::epoch::epoch_read_pack(&net.storage.config, epochid);
+        let opackref = storage::epoch::epoch_read_pack(&net.storage, epochid);
         match opackref {
             Err(_) => {
                 return Ok(Response::with(status::NotFound));
             },
-            Ok(packref) => {
-                let path = net.storage.config.get_pack_filepath(&packref);
-                Ok(Response::with((status::Ok, path)))
-            },
+            Ok(packref) => packfile::serve(req, &net.storage, &packref),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Network};
+    use exe_common::config::{net};
+    use exe_common::network::{Api, Error, FetchEpochParams, HermesEndPoint};
+    use protocol::mock::{Chain};
+    use storage::{Storage, backend::{MemoryBackend}, pack::{PackWriter}, types::{PackHash, StorageFileType, header_to_blockhash}};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::util::{hex};
+    use std::path::{PathBuf};
+
+    // write the blocks of the epoch (of 11 blocks) in its epoch pack
+    fn write_epoch(storage: &Storage, chain: &Chain, epochid: usize) -> PackHash {
+        let mut writer = PackWriter::init(storage);
+        for height in (epochid * 11)..(epochid * 11 + 11) {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, _) = writer.finalize();
+        storage::epoch::epoch_create(storage, &packhash, epochid as u32);
+        packhash
+    }
+
+    fn fetch_params(chain: &Chain, epochid: usize) -> FetchEpochParams {
+        FetchEpochParams {
+            epoch_id: epochid as u32,
+            start_header_hash: chain.hash(epochid * 11),
+            previous_header_hash: if epochid == 0 { chain.header(0).get_previous_header() } else { chain.hash(epochid * 11 - 1) },
+            upper_bound_hash: chain.hash(chain.len() - 1),
+        }
+    }
+
+    #[test]
+    fn serve_epochs_from_memory() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let served = Storage::memory(MemoryBackend::new()).unwrap();
+        let epoch0 = write_epoch(&served, &chain, 0);
+        let epoch1 = write_epoch(&served, &chain, 1);
+        let epoch1_content = served.get_backend().read(StorageFileType::Pack, &hex::encode(&epoch1)).unwrap().to_vec();
+
+        let mut networks = Networks::new();
+        let lock = served.lock(storage::lock::Access::Read, None).unwrap();
+        networks.insert("test".to_owned(), Network {
+            path: PathBuf::new(),
+            config: net::Config::testnet(),
+            storage: Arc::new(served),
+            lock: lock,
+        });
+        let mut router = Router::new();
+        Handler::new(Arc::new(networks)).route(&mut router);
+        let mut server = iron::Iron::new(router).http("127.0.0.1:0").unwrap();
+
+        let mut hermes = HermesEndPoint::new(format!("http://{}", server.socket), "test".to_owned());
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let result = hermes.fetch_epoch(&net::Config::testnet(), &mut storage, fetch_params(&chain, 0)).unwrap();
+        assert_eq!(result.packhash, epoch0);
+        assert_eq!(result.last_header_hash, chain.hash(10));
+        assert_eq!(storage::epoch::epoch_read_pack(&storage, 0).unwrap(), epoch0);
+
+        // a partial download is resumed where it stopped
+        storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.1", &epoch1_content[..100]).unwrap();
+        storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.1.etag", hex::encode(&epoch1).as_bytes()).unwrap();
+        let result = hermes.fetch_epoch(&net::Config::testnet(), &mut storage, fetch_params(&chain, 1)).unwrap();
+        assert_eq!(result.packhash, epoch1);
+        assert_eq!(&storage.get_backend().read(StorageFileType::Pack, &hex::encode(&epoch1)).unwrap()[..], &epoch1_content[..]);
+        assert!(! storage.get_backend().exist(StorageFileType::Pack, ".partial.epoch.1"));
+        assert!(! storage.get_backend().exist(StorageFileType::Pack, ".partial.epoch.1.etag"));
+
+        // a pack already fully downloaded (the range is not satisfiable) is
+        // kept, a partial download of another pack (the ETag doesn't match)
+        // is replaced by the whole pack
+        let garbage = [0u8;100];
+        for (partial, etag) in vec![(&epoch1_content[..], epoch1), (&garbage[..], epoch0)] {
+            let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+            storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.1", partial).unwrap();
+            storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.1.etag", hex::encode(&etag).as_bytes()).unwrap();
+            let result = hermes.fetch_epoch(&net::Config::testnet(), &mut storage, fetch_params(&chain, 1)).unwrap();
+            assert_eq!(result.packhash, epoch1);
+            assert_eq!(&storage.get_backend().read(StorageFileType::Pack, &hex::encode(&epoch1)).unwrap()[..], &epoch1_content[..]);
+        }
+
+        match hermes.fetch_epoch(&net::Config::testnet(), &mut storage, fetch_params(&chain, 2)) {
+            Err(Error::HttpError(_, status)) => assert_eq!(status.as_u16(), 404),
+            r => panic!("expected the epoch not to be found, got {:?}", r.map(|r| r.packhash)),
         }
+        server.close().unwrap();
     }
 }
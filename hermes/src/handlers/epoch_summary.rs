@@ -0,0 +1,144 @@
+use storage;
+use storage::pack::{MappedPack};
+use wallet_crypto::util::{hex};
+use wallet_crypto::coin::{Coin};
+use blockchain::{Block};
+
+use std::sync::{Arc};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use config::{Networks};
+use handlers::{common, views};
+
+pub struct Handler {
+    networks: Arc<Networks>
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        router.get(":network/epoch/:epochid/summary", self, "epoch_summary")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+        let ref epochid_str = req.extensions.get::<router::Router>().unwrap().find("epochid").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let epochid = match common::validate_epochid (epochid_str) {
+                        None => {
+                            error!("invalid epochid: {}", epochid_str);
+                            return Ok(Response::with(status::BadRequest));
+                        },
+                        Some(e) => e,
+        };
+
+        let packref = match storage::epoch::epoch_read_pack(&net.storage, epochid) {
+            Err(_) => return Ok(Response::with(status::NotFound)),
+            Ok(packref) => packref,
+        };
+
+        let mut summary = views::EpochSummary {
+            epoch: epochid,
+            pack: hex::encode(&packref),
+            number_of_blocks: 0,
+            number_of_transactions: 0,
+            total_output: Coin::zero(),
+            first_block: None,
+            last_block: None,
+        };
+
+        let pack = match MappedPack::open(&net.storage, &packref) {
+            Err(err) => {
+                error!("error while opening the pack of epoch {}: {:?}", epochid, err);
+                return Ok(Response::with(status::InternalServerError));
+            },
+            Ok(pack) => pack
+        };
+        let mut blocks = pack.iter();
+        while let Some(rblk) = blocks.next_raw() {
+            let blk = match rblk.map_err(storage::Error::from).and_then(|(_, rblk)| rblk.decode().map_err(storage::Error::from)) {
+                Err(err) => {
+                    error!("error while decoding block of epoch {}: {:?}", epochid, err);
+                    return Ok(Response::with(status::InternalServerError));
+                },
+                Ok(blk) => blk
+            };
+            let hash = format!("{}", blk.get_header().compute_hash());
+            if summary.first_block.is_none() { summary.first_block = Some(hash.clone()); }
+            summary.last_block = Some(hash);
+            summary.number_of_blocks += 1;
+
+            if let Block::MainBlock(ref mblk) = blk {
+                for txaux in mblk.body.tx.iter() {
+                    summary.number_of_transactions += 1;
+                    for txout in txaux.tx.outputs.iter() {
+                        summary.total_output = match summary.total_output + txout.value {
+                            Err(err) => {
+                                error!("error while summing the outputs of epoch {}: {}", epochid, err);
+                                return Ok(Response::with(status::InternalServerError));
+                            },
+                            Ok(total) => total
+                        };
+                    }
+                }
+            }
+        }
+
+        common::json_response(&summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::fixture;
+    use iron::{Iron};
+
+    #[test]
+    fn summaries_of_the_epochs() {
+        let (chain, _) = fixture::chain();
+        let networks = fixture::networks(fixture::storage(&chain, 2));
+        let mut router = Router::new();
+        Handler::new(networks.clone()).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+        let get = |path: &str| fixture::get(&server.socket, path, &[]);
+
+        for (epochid, total_output) in vec![(0, 150), (1, 50)] {
+            let reply = get(&format!("/test/epoch/{}/summary", epochid));
+            assert_eq!(reply.status, 200);
+            let json = reply.json();
+            let packref = storage::epoch::epoch_read_pack(&networks["test"].storage, epochid).unwrap();
+            assert_eq!(json["epoch"], epochid);
+            assert_eq!(json["pack"], hex::encode(&packref));
+            assert_eq!(json["number_of_blocks"], 11);
+            assert_eq!(json["number_of_transactions"], 1);
+            assert_eq!(json["total_output"], total_output);
+            assert_eq!(json["first_block"], format!("{}", chain.hash(epochid as usize * 11)));
+            assert_eq!(json["last_block"], format!("{}", chain.hash(epochid as usize * 11 + 10)));
+        }
+
+        // the epoch 2 is not packed yet
+        assert_eq!(get("/test/epoch/2/summary").status, 404);
+        assert_eq!(get("/test/epoch/x/summary").status, 400);
+        server.close().unwrap();
+    }
+}
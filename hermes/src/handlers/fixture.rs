@@ -0,0 +1,129 @@
+//! the networks and the requests of the tests of the handlers
+//!
+//! The fixture chain has epochs of 10 slots (11 blocks with the genesis
+//! block) and three transactions:
+//!
+//! * `txs[0]` in the block 3 (epoch 0) pays 100 and 50 to the wallets 1 and 2;
+//! * `txs[1]` in the block 12 (epoch 1) spends the output 1 of `txs[0]` and
+//!   pays 30 and 20 to the wallets 1 and 3;
+//! * `txs[2]` in the block 23 (epoch 2) spends the output 0 of `txs[0]` and
+//!   pays 90 and 10 to the wallets 3 and 1.
+//!
+//! The chain ends at the block 26, the epochs 0 and 1 are packed and the
+//! blocks of the epoch 2 are loose.
+
+use config::{Network, Networks};
+use exe_common::config::{net};
+use protocol::mock::{Chain, fixture_transaction};
+use storage::{self, Storage, backend::{MemoryBackend}, pack::{PackWriter}, types::{header_to_blockhash}};
+use wallet_crypto::config::{ProtocolMagic};
+use wallet_crypto::tx::{TxAux, TxId, TxIn};
+use serde_json;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{PathBuf};
+use std::sync::{Arc};
+
+/// the fixture chain and its transactions, see module documentation
+pub fn chain() -> (Chain, Vec<TxAux>) {
+    let mut chain = Chain::new(ProtocolMagic::default(), 10);
+    let pm = chain.get_protocol_magic();
+    let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 4)], &[(1, 100), (2, 50)]);
+    let tx2 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 1), 2)], &[(1, 30), (3, 20)]);
+    let tx3 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 1)], &[(3, 90), (1, 10)]);
+    chain.extend(3);
+    chain.push_transactions(vec![tx1.clone()]);
+    chain.extend(7);
+    chain.push_transactions(vec![tx2.clone()]);
+    chain.extend(9);
+    chain.push_transactions(vec![tx3.clone()]);
+    chain.extend(3);
+    (chain, vec![tx1, tx2, tx3])
+}
+
+/// a storage of the chain, its first `packed` epochs packed and indexed,
+/// the following blocks loose and `HEAD` at its tip
+pub fn storage(chain: &Chain, packed: usize) -> Storage {
+    let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+    for epochid in 0..packed {
+        let mut writer = PackWriter::init(&storage);
+        for height in (epochid * 11)..(epochid * 11 + 11) {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        storage::epoch::epoch_create(&storage, &packhash, epochid as u32);
+    }
+    for height in (packed * 11)..chain.len() {
+        storage::blob::write(&storage, &header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref()).unwrap();
+    }
+    storage::tag::write_hash(&storage, &storage::tag::HEAD, &chain.hash(chain.len() - 1));
+    storage
+}
+
+/// the storage served as the `test` network
+pub fn networks(storage: Storage) -> Arc<Networks> {
+    let mut networks = Networks::new();
+    let lock = storage.lock(storage::lock::Access::Read, None).unwrap();
+    networks.insert("test".to_owned(), Network {
+        path: PathBuf::new(),
+        config: net::Config::testnet(),
+        storage: Arc::new(storage),
+        lock: lock,
+    });
+    Arc::new(networks)
+}
+
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// send a GET request of the path with the given headers on a new
+/// connection and read the whole response, decoding the chunked body
+pub fn get(addr: &SocketAddr, path: &str, headers: &[&str]) -> Reply {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", path);
+    for header in headers {
+        req.push_str(header);
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).unwrap();
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes).unwrap();
+
+    let head_len = bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(bytes[..head_len].to_vec()).unwrap();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers = lines.map(|line| {
+        let mut kv = line.splitn(2, ':');
+        (kv.next().unwrap().to_owned(), kv.next().unwrap().trim().to_owned())
+    }).collect();
+    let mut reply = Reply { status: status, headers: headers, body: Vec::new() };
+    let mut body = &bytes[head_len + 4..];
+    if reply.header("Transfer-Encoding") == Some("chunked") {
+        loop {
+            let eol = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(::std::str::from_utf8(&body[..eol]).unwrap(), 16).unwrap();
+            if size == 0 { break; }
+            reply.body.extend_from_slice(&body[eol + 2..eol + 2 + size]);
+            body = &body[eol + 4 + size..];
+        }
+    } else {
+        reply.body.extend_from_slice(body);
+    }
+    reply
+}
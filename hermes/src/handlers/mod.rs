@@ -1,4 +1,15 @@
 pub mod common;
+pub mod views;
 pub mod block;
+pub mod block_json;
+pub mod block_at;
 pub mod pack;
+pub mod packfile;
 pub mod epoch;
+pub mod epoch_summary;
+pub mod tip;
+pub mod tx;
+pub mod address;
+
+#[cfg(test)]
+pub mod fixture;
@@ -11,7 +11,7 @@ use iron::status;
 use router;
 use router::{Router};
 
-use handlers::common;
+use handlers::{common, packfile};
 
 pub struct Handler {
     networks: Arc<Networks>
@@ -52,8 +52,6 @@ impl iron::Handler for Handler {
 
         let mut packhash = [0;storage::types::HASH_SIZE];
         packhash[..].clone_from_slice(packhash_vec.as_slice());
-        let path = net.storage.config.get_pack_filepath(&packhash);
-
-        Ok(Response::with((status::Ok, path)))
+        packfile::serve(req, &net.storage, &packhash)
     }
 }
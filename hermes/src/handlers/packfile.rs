@@ -0,0 +1,226 @@
+//! serve the content of a pack
+//!
+//! The pack hash is used as the `ETag` of the response, allowing the
+//! clients to resume partial downloads with `Range` (and `If-Range`)
+//! requests. When the whole pack is requested, the content can be
+//! compressed with gzip or deflate depending on the `Accept-Encoding`
+//! of the request: the compressed contents are other representations of
+//! the pack, their `ETag` is the pack hash followed by the content coding
+//! (e.g. `"<packhash>-gzip"`) and the responses `Vary` on the
+//! `Accept-Encoding`. The ranges are only served from the identity
+//! content.
+
+use storage::{Storage};
+use storage::types::{PackHash, StorageFileType};
+use wallet_crypto::util::{hex};
+
+use std::io::{self, Read};
+
+use flate2::Compression;
+use flate2::read::{GzEncoder, ZlibEncoder};
+
+use iron::{Request, Response, IronResult};
+use iron::status;
+use iron::response::{BodyReader};
+use iron::headers::{ETag, EntityTag, IfNoneMatch, IfRange, Range, ByteRangeSpec, ContentRange, ContentRangeSpec,
+                    ContentLength, AcceptRanges, RangeUnit, AcceptEncoding, ContentEncoding, Encoding};
+
+pub fn serve(req: &Request, storage: &Storage, packhash: &PackHash) -> IronResult<Response> {
+    let etag = entity_tag(packhash, &Encoding::Identity);
+    let encoding = accepted_encoding(req);
+
+    if let Some(if_none_match) = req.headers.get::<IfNoneMatch>() {
+        let encoded_etag = entity_tag(packhash, &encoding);
+        let matched = match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(&encoded_etag)),
+        };
+        if matched {
+            let mut res = Response::with(status::NotModified);
+            res.headers.set(ETag(encoded_etag));
+            set_vary(&mut res);
+            return Ok(res);
+        }
+    }
+
+    let content = match storage.get_backend().read(StorageFileType::Pack, &hex::encode(packhash)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            warn!("pack {} not found", hex::encode(packhash));
+            return Ok(Response::with(status::NotFound));
+        },
+        Err(err) => {
+            error!("cannot read pack {}: {}", hex::encode(packhash), err);
+            return Ok(Response::with(status::InternalServerError));
+        },
+        Ok(content) => content
+    };
+    let len = content.len() as u64;
+    let mut reader = io::Cursor::new(content);
+
+    let mut res = Response::with(status::Ok);
+    res.headers.set(ETag(etag.clone()));
+    res.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+    set_vary(&mut res);
+
+    if let Some((start, end)) = requested_range(req, &etag, len) {
+        if start > end || start >= len {
+            res.status = Some(status::RangeNotSatisfiable);
+            res.headers.set(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(len) }));
+            return Ok(res);
+        }
+        reader.set_position(start);
+        let size = end - start + 1;
+        res.status = Some(status::PartialContent);
+        res.headers.set(ContentRange(ContentRangeSpec::Bytes { range: Some((start, end)), instance_length: Some(len) }));
+        res.headers.set(ContentLength(size));
+        res.body = Some(Box::new(BodyReader(reader.take(size))));
+        return Ok(res);
+    }
+
+    match encoding {
+        Encoding::Gzip => {
+            res.headers.set(ETag(entity_tag(packhash, &encoding)));
+            res.headers.set(ContentEncoding(vec![Encoding::Gzip]));
+            res.body = Some(Box::new(BodyReader(GzEncoder::new(reader, Compression::default()))));
+        },
+        Encoding::Deflate => {
+            res.headers.set(ETag(entity_tag(packhash, &encoding)));
+            res.headers.set(ContentEncoding(vec![Encoding::Deflate]));
+            res.body = Some(Box::new(BodyReader(ZlibEncoder::new(reader, Compression::default()))));
+        },
+        _ => {
+            res.headers.set(ContentLength(len));
+            res.body = Some(Box::new(BodyReader(reader)));
+        },
+    }
+    Ok(res)
+}
+
+// the ETag of the pack served with the given content coding
+fn entity_tag(packhash: &PackHash, encoding: &Encoding) -> EntityTag {
+    match encoding {
+        Encoding::Gzip | Encoding::Deflate => EntityTag::strong(format!("{}-{}", hex::encode(packhash), encoding)),
+        _ => EntityTag::strong(hex::encode(packhash)),
+    }
+}
+
+// the content served depends on the `Accept-Encoding` of the request
+fn set_vary(res: &mut Response) {
+    res.headers.set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+}
+
+// the inclusive byte range requested, if any.
+//
+// only single ranges are supported, multiple ranges are ignored and the
+// whole content is served instead. The range is ignored too if `If-Range`
+// does not match the given `ETag`.
+fn requested_range(req: &Request, etag: &EntityTag, len: u64) -> Option<(u64, u64)> {
+    match req.headers.get::<IfRange>() {
+        None => {},
+        Some(IfRange::EntityTag(tag)) => if ! tag.strong_eq(etag) { return None; },
+        Some(IfRange::Date(_)) => return None,
+    }
+    let specs = match req.headers.get::<Range>() {
+        Some(Range::Bytes(specs)) => specs,
+        _ => return None,
+    };
+    if specs.len() != 1 { return None; }
+    let last = if len == 0 { 0 } else { len - 1 };
+    match specs[0] {
+        ByteRangeSpec::FromTo(from, to) => Some((from, ::std::cmp::min(to, last))),
+        ByteRangeSpec::AllFrom(from) => Some((from, last)),
+        ByteRangeSpec::Last(n) => Some((len - ::std::cmp::min(n, len), last)),
+    }
+}
+
+// the preferred content encoding of the client, gzip over deflate
+fn accepted_encoding(req: &Request) -> Encoding {
+    let accepted = match req.headers.get::<AcceptEncoding>() {
+        None => return Encoding::Identity,
+        Some(accepted) => accepted,
+    };
+    let accepts = |encoding: Encoding| {
+        accepted.iter().any(|qitem| qitem.item == encoding && qitem.quality.0 > 0)
+    };
+    if accepts(Encoding::Gzip) {
+        Encoding::Gzip
+    } else if accepts(Encoding::Deflate) {
+        Encoding::Deflate
+    } else {
+        Encoding::Identity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::backend::{MemoryBackend};
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use handlers::fixture::{get};
+    use iron::{Iron};
+    use std::sync::{Arc};
+
+    #[test]
+    fn serve_ranges_and_encodings() {
+        let content : Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let packhash = [0x2a;32];
+        let storage = Arc::new(Storage::memory(MemoryBackend::new()).unwrap());
+        storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), &content).unwrap();
+        let mut server = Iron::new(move |req: &mut Request| serve(req, &storage, &packhash)).http("127.0.0.1:0").unwrap();
+        let addr = server.socket;
+        let etag = format!("\"{}\"", hex::encode(&packhash));
+        let gzip_etag = format!("\"{}-gzip\"", hex::encode(&packhash));
+
+        let reply = get(&addr, "/", &[]);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, content);
+        assert_eq!(reply.header("ETag"), Some(etag.as_str()));
+        assert_eq!(reply.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(reply.header("Content-Encoding"), None);
+
+        // the ranges, served if the If-Range matches the ETag
+        let reply = get(&addr, "/", &["Range: bytes=100-", "Accept-Encoding: gzip"]);
+        assert_eq!(reply.status, 206);
+        assert_eq!(reply.header("Content-Range"), Some("bytes 100-999/1000"));
+        assert_eq!(reply.header("ETag"), Some(etag.as_str()));
+        assert_eq!(reply.body, &content[100..]);
+        let reply = get(&addr, "/", &["Range: bytes=100-199", &format!("If-Range: {}", etag)]);
+        assert_eq!(reply.status, 206);
+        assert_eq!(reply.body, &content[100..200]);
+        let reply = get(&addr, "/", &["Range: bytes=-10"]);
+        assert_eq!(reply.status, 206);
+        assert_eq!(reply.body, &content[990..]);
+        let reply = get(&addr, "/", &["Range: bytes=100-", &format!("If-Range: {}", gzip_etag)]);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, content);
+        let reply = get(&addr, "/", &["Range: bytes=1000-"]);
+        assert_eq!(reply.status, 416);
+        assert_eq!(reply.header("Content-Range"), Some("bytes */1000"));
+
+        // the compressed contents have their own ETag
+        let reply = get(&addr, "/", &["Accept-Encoding: gzip, deflate"]);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(reply.header("ETag"), Some(gzip_etag.as_str()));
+        assert_eq!(reply.header("Vary"), Some("Accept-Encoding"));
+        let mut decoded = Vec::new();
+        GzDecoder::new(&reply.body[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+        let reply = get(&addr, "/", &["Accept-Encoding: deflate"]);
+        assert_eq!(reply.header("Content-Encoding"), Some("deflate"));
+        assert_eq!(reply.header("ETag"), Some(format!("\"{}-deflate\"", hex::encode(&packhash)).as_str()));
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&reply.body[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+
+        let reply = get(&addr, "/", &[&format!("If-None-Match: {}", etag)]);
+        assert_eq!(reply.status, 304);
+        let reply = get(&addr, "/", &["Accept-Encoding: gzip", &format!("If-None-Match: {}", etag)]);
+        assert_eq!(reply.status, 200);
+        let reply = get(&addr, "/", &["Accept-Encoding: gzip", &format!("If-None-Match: {}", gzip_etag)]);
+        assert_eq!(reply.status, 304);
+        assert_eq!(reply.header("ETag"), Some(gzip_etag.as_str()));
+
+        server.close().unwrap();
+    }
+}
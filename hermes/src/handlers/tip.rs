@@ -0,0 +1,124 @@
+use config::{Networks};
+use storage::{tag, block_read};
+use std::sync::{Arc};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use handlers::common;
+
+pub struct Handler {
+    networks: Arc<Networks>
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        router.get(":network/tip", self, "tip")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let tip = match tag::read_hash(&net.storage, &tag::HEAD) {
+            None => {
+                warn!("no tip known for network `{}'", network_name);
+                return Ok(Response::with((status::NotFound, "Not Found")));
+            },
+            Some(hh) => hh
+        };
+        info!("querying tip: {}", tip);
+
+        match block_read(&net.storage, tip.bytes()) {
+            None => {
+                error!("error while reading tip block: {}", tip);
+                Ok(Response::with(status::InternalServerError))
+            },
+            Some(rblk) => {
+                match rblk.decode() {
+                    Err(err) => {
+                        error!("error while decoding tip block {}: {:?}", tip, err);
+                        Ok(Response::with(status::InternalServerError))
+                    },
+                    Ok(blk) => {
+                        let hdr = blk.get_header().to_raw();
+                        Ok(Response::with((status::Ok, hdr.as_ref())))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::{block, fixture};
+    use exe_common::network::{Api, Error, HermesEndPoint};
+    use storage::{Storage, backend::{MemoryBackend}};
+    use blockchain::{HeaderHash};
+    use iron::{Iron};
+
+    #[test]
+    fn serve_the_tip() {
+        let (chain, _) = fixture::chain();
+        let mut router = Router::new();
+        let networks = fixture::networks(fixture::storage(&chain, 2));
+        Handler::new(networks.clone()).route(&mut router);
+        block::Handler::new(networks).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+
+        let reply = fixture::get(&server.socket, "/test/tip", &[]);
+        assert_eq!(reply.status, 200);
+        assert_eq!(&reply.body[..], chain.header(26).to_raw().as_ref());
+        assert_eq!(fixture::get(&server.socket, "/other/tip", &[]).status, 400);
+
+        // the tip and the blocks, packed or loose, queried by the client
+        let mut hermes = HermesEndPoint::new(format!("http://{}", server.socket), "test".to_owned());
+        assert_eq!(hermes.get_tip().unwrap().compute_hash(), chain.hash(26));
+        for height in vec![0, 12, 23, 26] {
+            let blk = hermes.get_block(chain.hash(height)).unwrap();
+            assert_eq!(blk.get_header().compute_hash(), chain.hash(height));
+        }
+        let txs = hermes.get_block(chain.hash(23)).unwrap().get_transactions().map(|txs| txs.iter().count());
+        assert_eq!(txs, Some(1));
+        match hermes.get_block(HeaderHash::from_slice(&[0;32]).unwrap()) {
+            Err(Error::HttpError(_, status)) => assert_eq!(status.as_u16(), 404),
+            r => panic!("expected the block not to be found, got {:?}", r.map(|blk| blk.get_header().compute_hash())),
+        }
+        server.close().unwrap();
+    }
+
+    #[test]
+    fn no_tip_without_head() {
+        let mut router = Router::new();
+        Handler::new(fixture::networks(Storage::memory(MemoryBackend::new()).unwrap())).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+
+        assert_eq!(fixture::get(&server.socket, "/test/tip", &[]).status, 404);
+        let mut hermes = HermesEndPoint::new(format!("http://{}", server.socket), "test".to_owned());
+        match hermes.get_tip() {
+            Err(Error::HttpError(_, status)) => assert_eq!(status.as_u16(), 404),
+            r => panic!("expected no tip, got {:?}", r.map(|hdr| hdr.compute_hash())),
+        }
+        server.close().unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use config::{Networks};
+use wallet_crypto::tx::{TxId};
+use storage;
+use wallet_crypto::util::{hex};
+use std::sync::{Arc};
+
+use iron;
+use iron::{Request, Response, IronResult};
+use iron::status;
+
+use router;
+use router::{Router};
+
+use handlers::{common, views};
+
+pub struct Handler {
+    networks: Arc<Networks>
+}
+impl Handler {
+    pub fn new(networks: Arc<Networks>) -> Self {
+        Handler {
+            networks: networks
+        }
+    }
+    pub fn route(self, router: &mut Router) -> &mut Router {
+        router.get(":network/tx/:txid", self, "tx")
+    }
+}
+
+impl iron::Handler for Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let ref network_name = req.extensions.get::<router::Router>().unwrap().find("network").unwrap();
+
+        if ! common::validate_network_name (network_name) {
+            return Ok(Response::with(status::BadRequest));
+        }
+
+        let net = match self.networks.get(network_name.to_owned()) {
+            None => return Ok(Response::with(status::BadRequest)),
+            Some(net) => net
+        };
+
+        let ref txid_str = req.extensions.get::<router::Router>().unwrap().find("txid").unwrap();
+        let txid = match TxId::from_hex(txid_str) {
+            Err(_) => {
+                error!("invalid txid: {}", txid_str);
+                return Ok(Response::with(status::BadRequest));
+            },
+            Ok(txid) => txid
+        };
+        info!("querying transaction: {}", txid);
+
+        let loc = match storage::tx_location(&net.storage, &txid) {
+            None => {
+                warn!("transaction `{}' does not exist", txid);
+                return Ok(Response::with((status::NotFound, "Not Found")));
+            },
+            Some(loc) => loc
+        };
+        debug!("tx location: {:?}", loc);
+
+        let hdr = match net.storage.get_header(&loc.block) {
+            None => {
+                error!("cannot find the header of the block {}", hex::encode(&loc.block));
+                return Ok(Response::with(status::InternalServerError));
+            },
+            Some(hdr) => hdr
+        };
+
+        match storage::tx_read_location(&net.storage, &loc) {
+            None => {
+                error!("error while reading transaction at location: {:?}", loc);
+                Ok(Response::with(status::InternalServerError))
+            },
+            Some(txaux) => {
+                let view = views::TxInBlock {
+                    block: hex::encode(&loc.block),
+                    date: format!("{}", hdr.date),
+                    tx: views::Tx::from(&txaux.tx),
+                };
+                common::json_response(&view)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::fixture;
+    use iron::{Iron};
+
+    #[test]
+    fn transactions_as_json() {
+        let (chain, txs) = fixture::chain();
+        let mut router = Router::new();
+        Handler::new(fixture::networks(fixture::storage(&chain, 2))).route(&mut router);
+        let mut server = Iron::new(router).http("127.0.0.1:0").unwrap();
+        let get = |path: &str| fixture::get(&server.socket, path, &[]);
+
+        for (tx, height) in vec![(&txs[0], 3), (&txs[1], 12)] {
+            let reply = get(&format!("/test/tx/{}", tx.tx.id()));
+            assert_eq!(reply.status, 200);
+            let json = reply.json();
+            assert_eq!(json["block"], format!("{}", chain.hash(height)));
+            assert_eq!(json["date"], format!("{}", chain.header(height).get_blockdate()));
+            assert_eq!(json["tx"]["id"], format!("{}", tx.tx.id()));
+            assert_eq!(json["tx"]["outputs"].as_array().map(|outs| outs.len()), Some(2));
+        }
+        let json = get(&format!("/test/tx/{}", txs[1].tx.id())).json();
+        assert_eq!(json["tx"]["inputs"][0]["id"], format!("{}", txs[0].tx.id()));
+        assert_eq!(json["tx"]["outputs"][0]["value"], 30);
+
+        assert_eq!(get(&format!("/test/tx/{}", TxId::new(&[9]))).status, 404);
+        assert_eq!(get("/test/tx/xyz").status, 400);
+        server.close().unwrap();
+    }
+}
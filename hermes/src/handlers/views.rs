@@ -0,0 +1,106 @@
+//! serialisable views of the blockchain objects, served as JSON
+//! by the query handlers.
+//!
+
+use blockchain;
+use wallet_crypto::{tx, coin::{Coin}, address::{ExtendedAddr}};
+
+#[derive(Serialize, Debug)]
+pub struct TxIn {
+    pub id: String,
+    pub index: u32,
+}
+impl<'a> From<&'a tx::TxIn> for TxIn {
+    fn from(txin: &'a tx::TxIn) -> Self {
+        TxIn { id: format!("{}", txin.id), index: txin.index }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct TxOut {
+    pub address: ExtendedAddr,
+    pub value: Coin,
+}
+impl<'a> From<&'a tx::TxOut> for TxOut {
+    fn from(txout: &'a tx::TxOut) -> Self {
+        TxOut { address: txout.address.clone(), value: txout.value }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Tx {
+    pub id: String,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+}
+impl<'a> From<&'a tx::Tx> for Tx {
+    fn from(tx: &'a tx::Tx) -> Self {
+        Tx {
+            id: format!("{}", tx.id()),
+            inputs: tx.inputs.iter().map(TxIn::from).collect(),
+            outputs: tx.outputs.iter().map(TxOut::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Block {
+    pub hash: String,
+    pub previous: String,
+    pub date: String,
+    pub epoch: blockchain::EpochId,
+    pub is_genesis: bool,
+    pub transactions: Vec<Tx>,
+}
+impl<'a> From<&'a blockchain::Block> for Block {
+    fn from(blk: &'a blockchain::Block) -> Self {
+        let hdr = blk.get_header();
+        let transactions = match blk.get_transactions() {
+            None => Vec::new(),
+            Some(txs) => txs.iter().map(|txaux| Tx::from(&txaux.tx)).collect(),
+        };
+        Block {
+            hash: format!("{}", hdr.compute_hash()),
+            previous: format!("{}", hdr.get_previous_header()),
+            date: format!("{}", hdr.get_blockdate()),
+            epoch: hdr.get_blockdate().get_epochid(),
+            is_genesis: hdr.is_genesis_block(),
+            transactions: transactions,
+        }
+    }
+}
+
+/// a transaction and the block it has been found in
+#[derive(Serialize, Debug)]
+pub struct TxInBlock {
+    pub block: String,
+    pub date: String,
+    pub tx: Tx,
+}
+
+/// an unspent output, as returned by the address query
+#[derive(Serialize, Debug)]
+pub struct Utxo {
+    pub id: String,
+    pub index: u32,
+    pub block: String,
+    pub value: Coin,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Utxos {
+    pub address: ExtendedAddr,
+    pub total: Coin,
+    pub utxos: Vec<Utxo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EpochSummary {
+    pub epoch: blockchain::EpochId,
+    pub pack: String,
+    pub number_of_blocks: u32,
+    pub number_of_transactions: u32,
+    pub total_output: Coin,
+    pub first_block: Option<String>,
+    pub last_block: Option<String>,
+}
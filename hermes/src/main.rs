@@ -1,12 +1,15 @@
 #[macro_use]
 extern crate clap;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_yaml;
+extern crate serde_json;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
+extern crate flate2;
 extern crate iron;
 extern crate router;
 
@@ -14,6 +17,8 @@ extern crate storage;
 extern crate wallet_crypto;
 extern crate blockchain;
 extern crate exe_common;
+#[cfg(test)]
+extern crate protocol;
 
 use std::path::{PathBuf};
 
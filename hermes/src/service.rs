@@ -3,12 +3,17 @@ use exe_common::sync;
 use handlers;
 use iron;
 use router::Router;
-use std::sync::Arc;
+use std::sync::{Arc, mpsc};
 use std::thread;
 use std::time::Duration;
 
+/// the delay between two refreshes when no new epoch is announced by
+/// the peers (e.g. no native peer can be followed)
 static NETWORK_REFRESH_FREQUENCY: Duration = Duration::from_secs(60 * 10);
 
+/// the delay before following the peers of a network again after a failure
+static FOLLOW_RETRY_DELAY: Duration = Duration::from_secs(60);
+
 pub fn start(cfg: Config) {
     let _refresher = start_networks_refresher(cfg.clone());
     let _server = start_http_server(&cfg, Arc::new(cfg.get_networks().unwrap()));
@@ -22,6 +27,12 @@ fn start_http_server(cfg: &Config, networks: Arc<Networks>) -> iron::Listening {
     handlers::block::Handler::new(networks.clone()).route(&mut router);
     handlers::pack::Handler::new(networks.clone()).route(&mut router);
     handlers::epoch::Handler::new(networks.clone()).route(&mut router);
+    handlers::tip::Handler::new(networks.clone()).route(&mut router);
+    handlers::block_json::Handler::new(networks.clone()).route(&mut router);
+    handlers::block_at::Handler::new(networks.clone()).route(&mut router);
+    handlers::tx::Handler::new(networks.clone()).route(&mut router);
+    handlers::address::Handler::new(networks.clone()).route(&mut router);
+    handlers::epoch_summary::Handler::new(networks.clone()).route(&mut router);
     info!("listenting to port {}", cfg.port);
     iron::Iron::new(router)
         .http(format!("0.0.0.0:{}", cfg.port))
@@ -31,7 +42,14 @@ fn start_http_server(cfg: &Config, networks: Arc<Networks>) -> iron::Listening {
 // TODO: make this a struct which receives a shutdown message on a channel and then wraps itself up
 fn start_networks_refresher(cfg: Config) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        info!("Refreshing every {:?}", NETWORK_REFRESH_FREQUENCY);
+        let (announces, new_epochs) = mpsc::channel();
+        match cfg.get_networks() {
+            Err(err) => warn!("Cannot follow the networks: {:?}", err),
+            Ok(networks) => for label in networks.keys() {
+                start_network_follower(cfg.clone(), label.clone(), announces.clone());
+            },
+        }
+        info!("Refreshing on the new epochs announced, or every {:?}", NETWORK_REFRESH_FREQUENCY);
         loop {
             match cfg.get_networks() {
                 Err(err) => warn!("Refresh failed: {:?}", err),
@@ -40,7 +58,35 @@ fn start_networks_refresher(cfg: Config) -> thread::JoinHandle<()> {
                     info!("Refresh completed")
                 }
             }
-            thread::sleep(NETWORK_REFRESH_FREQUENCY);
+            match new_epochs.recv_timeout(NETWORK_REFRESH_FREQUENCY) {
+                Ok(label) => info!("New epoch announced on network {}", label),
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(NETWORK_REFRESH_FREQUENCY),
+            }
+            // the refresh covers all the networks
+            while let Ok(_) = new_epochs.try_recv() {}
+        }
+    })
+}
+
+// follow the tip of the peers of the network, telling the refresher when
+// a new epoch can be synced (see `sync::wait_new_epoch`)
+fn start_network_follower(cfg: Config, label: String, announces: mpsc::Sender<String>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            let result = cfg.get_storage(&label)
+                .map_err(|err| format!("{:?}", err))
+                .and_then(|storage| sync::wait_new_epoch(&storage).map_err(|err| format!("{:?}", err)));
+            match result {
+                Ok(hdr) => {
+                    info!("Network {}: block {} announced", label, hdr.get_blockdate());
+                    if announces.send(label.clone()).is_err() { return; }
+                },
+                Err(err) => {
+                    warn!("Following network {} failed: {}", label, err);
+                    thread::sleep(FOLLOW_RETRY_DELAY);
+                },
+            }
         }
     })
 }
@@ -59,3 +105,49 @@ fn refresh_networks(networks: Networks) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exe_common::config::{net};
+    use protocol::mock::{Chain, MockPeer};
+    use wallet_crypto::config::{ProtocolMagic};
+    use std::{env, fs, process};
+
+    #[test]
+    fn follower_announces_the_new_epochs() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 15);
+        let mock = MockPeer::start(chain.clone()).unwrap();
+        let dir = env::temp_dir().join(format!("hermes-follower-{}", process::id()));
+        let cfg = Config::new(dir.clone(), 0);
+        let mut net_cfg = net::Config::mainnet();
+        net_cfg.peers = net::Peers::new();
+        net_cfg.peers.push("mock".to_string(), net::Peer::native(mock.local_addr().to_string()));
+        net_cfg.to_file(cfg.get_storage_config("test").get_config_file());
+
+        let (announces, new_epochs) = mpsc::channel();
+        let follower = start_network_follower(cfg, "test".to_owned(), announces);
+        while mock.subscriptions() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // a block of the epoch 1 then the genesis block of the epoch 2
+        let mut next = chain.clone();
+        next.extend(1);
+        mock.set_chain(next.clone());
+        assert!(new_epochs.recv_timeout(Duration::from_millis(200)).is_err());
+        next.extend(7);
+        mock.set_chain(next.clone());
+        assert_eq!(new_epochs.recv_timeout(Duration::from_secs(10)).unwrap(), "test");
+
+        // the follower stops on the next new epoch once the refresher is gone
+        drop(new_epochs);
+        let (stopped, follower_stopped) = mpsc::channel();
+        thread::spawn(move || { follower.join().unwrap(); stopped.send(()).unwrap() });
+        loop {
+            next.extend(11);
+            mock.set_chain(next.clone());
+            if follower_stopped.recv_timeout(Duration::from_millis(50)).is_ok() { break }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
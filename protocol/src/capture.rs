@@ -0,0 +1,579 @@
+//! record and replay the ntt frames exchanged with a peer
+//!
+//! A `Tap` follows the bytes sent and received on a connection, cuts them
+//! into ntt frames (handshake, control headers and light connection data)
+//! and writes every frame to a `Recorder` with the time it was completed.
+//! `Capture` wraps a stream with a `Tap`.
+//!
+//! A capture is a text file, one frame per line:
+//!
+//! ```text
+//! # ntt capture started at 1539870000
+//! 0.000012 > handshake 00000000000000000000000000000000
+//! 0.020443 < handshake-response 00000000
+//! 0.020501 > control 0 1024
+//! 0.020530 > data 1024 53000000000000000a
+//! ```
+//!
+//! `>` being the frames we sent and `<` the frames we received. A capture
+//! can be read back with `read`, pretty printed with a `Decoder`, or
+//! played back with `Replay`, to turn a session with a peer into a test.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File};
+use std::io::{self, Read, Write, BufRead};
+use std::path::{Path};
+use std::str::{FromStr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, cmp};
+
+use raw_cbor::de::{RawCbor};
+use wallet_crypto::util::{hex};
+
+use ntt::{self, LightweightConnectionId, protocol::{NodeId}};
+use packet::{self, Handshake, MsgType, GetHeaders, GetBlocks, BlockHeaderResponse, BlockResponse};
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Sent     => write!(f, ">"),
+            Direction::Received => write!(f, "<"),
+        }
+    }
+}
+
+/// an ntt frame, as it is on the wire
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Frame {
+    /// the initial handshake of the connecting side
+    Handshake(Vec<u8>),
+    /// the answer of the accepting side to the initial handshake
+    HandshakeResponse(Vec<u8>),
+    /// a control header (see `ntt::protocol::ControlHeader`) and its light
+    /// connection id
+    Control(u32, LightweightConnectionId),
+    /// data sent on a light connection
+    Data(LightweightConnectionId, Vec<u8>),
+}
+impl Frame {
+    /// the bytes of the frame on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Frame::Handshake(bytes) | Frame::HandshakeResponse(bytes) => buf.extend_from_slice(bytes),
+            Frame::Control(code, cid) => {
+                append_u32(*code, &mut buf);
+                append_u32(*cid, &mut buf);
+            },
+            Frame::Data(cid, bytes) => {
+                append_u32(*cid, &mut buf);
+                ntt::protocol::append_with_length(bytes, &mut buf);
+            },
+        }
+        buf
+    }
+}
+
+fn append_u32(v: u32, buf: &mut Vec<u8>) {
+    buf.push((v >> 24) as u8);
+    buf.push((v >> 16) as u8);
+    buf.push((v >> 8) as u8);
+    buf.push(v as u8);
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+/// a frame of a capture
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Record {
+    /// the time since the beginning of the capture
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06} {} ", self.elapsed.as_secs(), self.elapsed.subsec_micros(), self.direction)?;
+        match self.frame {
+            Frame::Handshake(ref bytes) => write!(f, "handshake {}", hex::encode(bytes)),
+            Frame::HandshakeResponse(ref bytes) => write!(f, "handshake-response {}", hex::encode(bytes)),
+            Frame::Control(code, cid) => write!(f, "control {} {}", code, cid),
+            Frame::Data(cid, ref bytes) => write!(f, "data {} {}", cid, hex::encode(bytes)),
+        }
+    }
+}
+impl FromStr for Record {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words : Vec<&str> = s.split_whitespace().collect();
+        if words.len() < 3 {
+            return Err(format!("invalid capture record `{}'", s));
+        }
+        let elapsed = {
+            let mut parts = words[0].splitn(2, '.');
+            let secs = parts.next().and_then(|secs| secs.parse::<u64>().ok());
+            let micros = parts.next().and_then(|micros| micros.parse::<u32>().ok());
+            match (secs, micros) {
+                (Some(secs), Some(micros)) => Duration::new(secs, micros * 1000),
+                _ => return Err(format!("invalid capture time `{}'", words[0])),
+            }
+        };
+        let direction = match words[1] {
+            ">" => Direction::Sent,
+            "<" => Direction::Received,
+            dir => return Err(format!("invalid capture direction `{}'", dir)),
+        };
+        let number = |word: &str| word.parse::<u32>().map_err(|_| format!("invalid number `{}'", word));
+        let bytes = |word: &str| hex::decode(word).map_err(|_| format!("invalid hexadecimal `{}'", word));
+        let frame = match (words[2], &words[3..]) {
+            ("handshake", [dat]) => Frame::Handshake(bytes(dat)?),
+            ("handshake-response", [dat]) => Frame::HandshakeResponse(bytes(dat)?),
+            ("control", [code, cid]) => Frame::Control(number(code)?, number(cid)?),
+            ("data", [cid]) => Frame::Data(number(cid)?, Vec::new()),
+            ("data", [cid, dat]) => Frame::Data(number(cid)?, bytes(dat)?),
+            _ => return Err(format!("invalid capture record `{}'", s)),
+        };
+        Ok(Record { elapsed: elapsed, direction: direction, frame: frame })
+    }
+}
+
+/// read all the records of a capture
+pub fn read<R: BufRead>(reader: R) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let record = line.parse().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// write the records of a capture
+pub struct Recorder {
+    writer: Box<Write + Send>,
+    started: Instant,
+}
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let mut writer : Box<Write + Send> = Box::new(writer);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if let Err(err) = writeln!(writer, "# ntt capture started at {}", now) {
+            warn!("cannot write capture: {}", err);
+        }
+        Recorder { writer: writer, started: Instant::now() }
+    }
+
+    /// record in the given file, replacing it if it exists
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Recorder::new(File::create(path)?))
+    }
+
+    pub fn record(&mut self, direction: Direction, frame: Frame) {
+        let record = Record { elapsed: self.started.elapsed(), direction: direction, frame: frame };
+        // the capture is only a debugging help, failing to write it must
+        // not break the connection
+        if let Err(err) = writeln!(self.writer, "{}", record).and_then(|_| self.writer.flush()) {
+            warn!("cannot write capture: {}", err);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Handshake,
+    HandshakeResponse,
+    Frames,
+}
+
+// cut the bytes going in one direction into frames
+struct Parser {
+    stage: Stage,
+    buf: Vec<u8>,
+}
+impl Parser {
+    fn new(stage: Stage) -> Self { Parser { stage: stage, buf: Vec::new() } }
+
+    fn push(&mut self, bytes: &[u8]) -> Vec<Frame> {
+        self.buf.extend_from_slice(bytes);
+        let mut frames = Vec::new();
+        while let Some((len, frame)) = self.next_frame() {
+            self.buf.drain(..len);
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn next_frame(&mut self) -> Option<(usize, Frame)> {
+        match self.stage {
+            Stage::Handshake => {
+                // version, handshake length, endpoint id, endpoint length and endpoint
+                if self.buf.len() < 16 { return None; }
+                let len = 16 + read_u32(&self.buf[12..16]) as usize;
+                if self.buf.len() < len { return None; }
+                self.stage = Stage::Frames;
+                Some((len, Frame::Handshake(self.buf[..len].to_vec())))
+            },
+            Stage::HandshakeResponse => {
+                // the unsupported version response is followed by our version
+                if self.buf.len() < 4 { return None; }
+                let len = if read_u32(&self.buf[..4]) == 0xffffffff { 8 } else { 4 };
+                if self.buf.len() < len { return None; }
+                self.stage = Stage::Frames;
+                Some((len, Frame::HandshakeResponse(self.buf[..len].to_vec())))
+            },
+            Stage::Frames => {
+                if self.buf.len() < 8 { return None; }
+                let hdr = read_u32(&self.buf[..4]);
+                let value = read_u32(&self.buf[4..8]);
+                if hdr < ntt::LIGHT_ID_MIN {
+                    return Some((8, Frame::Control(hdr, value)));
+                }
+                let len = 8 + value as usize;
+                if self.buf.len() < len { return None; }
+                Some((len, Frame::Data(hdr, self.buf[8..len].to_vec())))
+            },
+        }
+    }
+}
+
+/// record the frames of a connection from the bytes sent and received
+pub struct Tap {
+    recorder: Recorder,
+    sent: Parser,
+    received: Parser,
+}
+impl Tap {
+    /// tap a connection we initiated
+    pub fn client(recorder: Recorder) -> Self {
+        Tap { recorder: recorder, sent: Parser::new(Stage::Handshake), received: Parser::new(Stage::HandshakeResponse) }
+    }
+
+    /// tap a connection we accepted
+    pub fn server(recorder: Recorder) -> Self {
+        Tap { recorder: recorder, sent: Parser::new(Stage::HandshakeResponse), received: Parser::new(Stage::Handshake) }
+    }
+
+    pub fn sent(&mut self, bytes: &[u8]) {
+        for frame in self.sent.push(bytes) {
+            self.recorder.record(Direction::Sent, frame);
+        }
+    }
+
+    pub fn received(&mut self, bytes: &[u8]) {
+        for frame in self.received.push(bytes) {
+            self.recorder.record(Direction::Received, frame);
+        }
+    }
+}
+
+/// a stream recording the frames going through it
+pub struct Capture<T> {
+    stream: T,
+    tap: Tap,
+}
+impl<T> Capture<T> {
+    pub fn new(stream: T, tap: Tap) -> Self { Capture { stream: stream, tap: tap } }
+
+    pub fn get_backend(&self) -> &T { &self.stream }
+}
+impl<T: Read> Read for Capture<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sz = self.stream.read(buf)?;
+        self.tap.received(&buf[..sz]);
+        Ok(sz)
+    }
+}
+impl<T: Write> Write for Capture<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sz = self.stream.write(buf)?;
+        self.tap.sent(&buf[..sz]);
+        Ok(sz)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+}
+
+/// a transport playing back a capture
+///
+/// The received frames are read back in order, and the bytes written must
+/// be the ones of the sent frames, in order, or the write fails with
+/// `InvalidData`. The ordering between the sent and the received frames
+/// is not enforced. Once all the received frames are read, reading
+/// returns the end of the stream.
+///
+/// The connection must be replayed with the same drg seed than the one
+/// captured, for the node ids to match.
+pub struct Replay {
+    received: VecDeque<u8>,
+    sent: VecDeque<u8>,
+}
+impl Replay {
+    pub fn new(records: &[Record]) -> Self {
+        let mut received = VecDeque::new();
+        let mut sent = VecDeque::new();
+        for record in records.iter() {
+            match record.direction {
+                Direction::Received => received.extend(record.frame.to_bytes()),
+                Direction::Sent     => sent.extend(record.frame.to_bytes()),
+            }
+        }
+        Replay { received: received, sent: sent }
+    }
+
+    /// tells if all the captured frames have been played back
+    pub fn is_finished(&self) -> bool {
+        self.received.is_empty() && self.sent.is_empty()
+    }
+}
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sz = cmp::min(buf.len(), self.received.len());
+        for (dst, src) in buf.iter_mut().zip(self.received.drain(..sz)) {
+            *dst = src;
+        }
+        Ok(sz)
+    }
+}
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let expected = cmp::min(buf.len(), self.sent.len());
+        if expected < buf.len() || self.sent.iter().take(expected).ne(buf.iter()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "replay: the data sent differ from the capture"));
+        }
+        self.sent.drain(..expected);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Conversation {
+    // created, expecting the node id (or the handshake)
+    Opened,
+    // the node id has been sent, expecting the message code
+    Syn(NodeId),
+    // the message code has been sent, the messages are requests
+    Request(u8),
+    // acknowledging a conversation of the other side, the messages are
+    // responses
+    Ack(NodeId),
+}
+
+/// describe the frames of a capture, decoding the messages of the node
+/// to node protocol
+pub struct Decoder {
+    conversations: BTreeMap<(Direction, LightweightConnectionId), Conversation>,
+    codes: BTreeMap<NodeId, u8>,
+}
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder { conversations: BTreeMap::new(), codes: BTreeMap::new() }
+    }
+
+    /// the description of the given frame
+    pub fn describe(&mut self, direction: Direction, frame: &Frame) -> String {
+        match frame {
+            Frame::Handshake(bytes) => format!("ntt handshake {}", hex::encode(bytes)),
+            Frame::HandshakeResponse(bytes) if bytes.len() < 4 => {
+                format!("ntt handshake invalid response {}", hex::encode(bytes))
+            },
+            Frame::HandshakeResponse(bytes) => {
+                match read_u32(bytes) {
+                    0x00000000 => String::from("ntt handshake accepted"),
+                    0x00000001 => String::from("ntt handshake invalid request"),
+                    0x00000002 => String::from("ntt handshake crossed request"),
+                    0xffffffff => String::from("ntt handshake unsupported version"),
+                    v          => format!("ntt handshake unknown response {}", v),
+                }
+            },
+            Frame::Control(code, cid) => {
+                if *code == 0 {
+                    self.conversations.insert((direction, *cid), Conversation::Opened);
+                }
+                match ntt::protocol::control_header_from_u32(*code) {
+                    Ok(ch) => format!("LightId({}) {:?}", cid, ch),
+                    Err(()) => format!("LightId({}) unknown control {}", cid, code),
+                }
+            },
+            Frame::Data(cid, bytes) => {
+                format!("LightId({}) {}", cid, self.describe_data(direction, *cid, bytes))
+            },
+        }
+    }
+
+    fn describe_data(&mut self, direction: Direction, cid: LightweightConnectionId, bytes: &[u8]) -> String {
+        let conversation = match self.conversations.get(&(direction, cid)) {
+            None => return format!("data on unknown light connection: {}", hex::encode(bytes)),
+            Some(conversation) => *conversation,
+        };
+        match conversation {
+            Conversation::Opened => {
+                match NodeId::from_slice(bytes) {
+                    Some(nodeid) if nodeid.is_syn() => {
+                        self.conversations.insert((direction, cid), Conversation::Syn(nodeid));
+                        format!("node id SYN {}", nodeid)
+                    },
+                    Some(nodeid) => {
+                        self.conversations.insert((direction, cid), Conversation::Ack(nodeid));
+                        format!("node id ACK {}", nodeid)
+                    },
+                    None => match RawCbor::from(bytes).deserialize() {
+                        Ok(handshake) => {
+                            let handshake : Handshake = handshake;
+                            format!("handshake\n{}", handshake)
+                        },
+                        Err(_) => format!("unexpected data: {}", hex::encode(bytes)),
+                    },
+                }
+            },
+            Conversation::Syn(nodeid) => {
+                match packet::decode_msg_code(bytes) {
+                    None => format!("invalid message code: {}", hex::encode(bytes)),
+                    Some(code) => {
+                        self.codes.insert(nodeid, code);
+                        self.conversations.insert((direction, cid), Conversation::Request(code));
+                        format!("message code 0x{:x} ({})", code, message_name(code))
+                    },
+                }
+            },
+            Conversation::Request(code) => describe_request(code, bytes),
+            Conversation::Ack(nodeid) => {
+                match self.codes.get(&nodeid.ack_to_syn()) {
+                    None => format!("response: {}", hex::encode(bytes)),
+                    Some(code) => describe_response(*code, bytes),
+                }
+            },
+        }
+    }
+}
+
+fn message_name(code: u8) -> &'static str {
+    if code == MsgType::MsgSubscribe.to_u8() { "Subscribe" }
+    else if code == MsgType::MsgGetHeaders.to_u8() { "GetHeaders" }
+    else if code == MsgType::MsgHeaders.to_u8() { "Headers" }
+    else if code == MsgType::MsgGetBlocks.to_u8() { "GetBlocks" }
+    else { "unknown" }
+}
+
+fn describe_headers(response: BlockHeaderResponse) -> String {
+    match response {
+        BlockHeaderResponse::Ok(headers) => {
+            let mut s = format!("{} headers", headers.len());
+            for header in headers.iter() {
+                s.push_str(&format!("\n  {} {}", header.get_blockdate(), header.compute_hash()));
+            }
+            s
+        },
+        BlockHeaderResponse::Err(err) => format!("headers error: {}", err),
+    }
+}
+
+fn describe_request(code: u8, bytes: &[u8]) -> String {
+    let mut raw = RawCbor::from(bytes);
+    let description = if code == MsgType::MsgGetHeaders.to_u8() {
+        raw.deserialize().ok().map(|req: GetHeaders| {
+            let to = req.to.map(|to| format!("{}", to)).unwrap_or(String::from("tip"));
+            let from : Vec<String> = req.from.iter().map(|from| format!("{}", from)).collect();
+            format!("get headers to {} from [{}]", to, from.join(", "))
+        })
+    } else if code == MsgType::MsgGetBlocks.to_u8() {
+        raw.deserialize().ok().map(|req: GetBlocks| format!("get blocks from {} to {}", req.from, req.to))
+    } else if code == MsgType::MsgHeaders.to_u8() {
+        raw.deserialize().ok().map(describe_headers)
+    } else if code == MsgType::MsgSubscribe.to_u8() {
+        raw.unsigned_integer().ok().map(|v| match v {
+            43 => String::from("subscribe keep-alive"),
+            42 => String::from("subscribe"),
+            v  => format!("subscribe {}", v),
+        })
+    } else {
+        None
+    };
+    description.unwrap_or(format!("request: {}", hex::encode(bytes)))
+}
+
+fn describe_response(code: u8, bytes: &[u8]) -> String {
+    let mut raw = RawCbor::from(bytes);
+    let description = if code == MsgType::MsgGetHeaders.to_u8() {
+        raw.deserialize().ok().map(describe_headers)
+    } else if code == MsgType::MsgGetBlocks.to_u8() {
+        raw.deserialize().ok().map(|response| match response {
+            BlockResponse::Ok(block) => {
+                let header = block.get_header();
+                format!("block {} {}", header.get_blockdate(), header.compute_hash())
+            },
+            BlockResponse::NoBlock(err) => format!("no block: {}", err),
+        })
+    } else {
+        None
+    };
+    description.unwrap_or(format!("response: {}", hex::encode(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::{env, fs, process};
+    use command::{Command, GetBlockHeader, GetBlock};
+    use mock::{Chain, MockPeer};
+    use protocol::Connection;
+    use wallet_crypto::config::{ProtocolMagic};
+
+    fn session<T: Read+Write>(stream: T, chain: &Chain) -> Vec<usize> {
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        connection.handshake(&Handshake::default()).unwrap();
+        let headers = GetBlockHeader::range(&[chain.hash(2)], chain.hash(8)).execute(&mut connection).unwrap();
+        let blocks = GetBlock::from(&chain.hash(3), &chain.hash(5)).execute(&mut connection).unwrap();
+        vec![headers.decode().unwrap().len(), blocks.len()]
+    }
+
+    #[test]
+    fn record_format() {
+        let records = vec![
+            Record { elapsed: Duration::new(0, 12000), direction: Direction::Sent, frame: Frame::Handshake(vec![0;16]) },
+            Record { elapsed: Duration::new(1, 5000), direction: Direction::Received, frame: Frame::HandshakeResponse(vec![0;4]) },
+            Record { elapsed: Duration::new(1, 6000), direction: Direction::Sent, frame: Frame::Control(0, 1024) },
+            Record { elapsed: Duration::new(2, 0), direction: Direction::Received, frame: Frame::Data(1024, vec![]) },
+            Record { elapsed: Duration::new(2, 1000), direction: Direction::Received, frame: Frame::Data(1025, vec![0x82, 0x00, 0x80]) },
+        ];
+        let text : Vec<String> = records.iter().map(|record| format!("{}", record)).collect();
+        assert_eq!(text[0], "0.000012 > handshake 00000000000000000000000000000000");
+        assert_eq!(text[2], "1.000006 > control 0 1024");
+        let read_back = read(io::Cursor::new(text.join("\n"))).unwrap();
+        assert_eq!(read_back, records);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 12);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let path = env::temp_dir().join(format!("protocol-capture-{}", process::id()));
+
+        let stream = TcpStream::connect(peer.local_addr()).unwrap();
+        let recorder = Recorder::create(&path).unwrap();
+        let results = session(Capture::new(stream, Tap::client(recorder)), &chain);
+        assert_eq!(results, vec![6, 3]);
+
+        let records = read(io::BufReader::new(File::open(&path).unwrap())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(records.iter().any(|record| record.frame == Frame::HandshakeResponse(vec![0;4])));
+
+        let mut decoder = Decoder::new();
+        let descriptions : Vec<String> = records.iter().map(|record| decoder.describe(record.direction, &record.frame)).collect();
+        assert!(descriptions.iter().any(|d| d.ends_with("message code 0x6 (GetBlocks)")));
+        assert!(descriptions.iter().any(|d| d.ends_with(&format!("block 0.3 {}", chain.hash(4)))));
+
+        // the peer is not needed to play the session again
+        let replay = Replay::new(&records);
+        assert_eq!(session(replay, &chain), results);
+    }
+}
@@ -5,8 +5,18 @@ extern crate log;
 #[macro_use]
 extern crate raw_cbor;
 
+extern crate futures;
+extern crate bytes;
+extern crate tokio_io;
+extern crate tokio_codec;
+extern crate tokio_core;
+
 pub mod ntt;
 pub mod packet;
+pub mod server;
+pub mod mux;
+pub mod mock;
+pub mod capture;
 
 mod protocol;
 
@@ -0,0 +1,695 @@
+//! a scripted fake peer, to test the clients of the protocol offline
+//!
+//! A `MockPeer` listens on a local TCP port, answers the ntt and node
+//! handshakes like any other peer (see `server`) and serves the headers
+//! and the blocks of a synthetic `Chain`. The chain can be replaced while
+//! the peer is running (to simulate a fork) and the answers to the next
+//! requests can be scripted with `Action`s (to simulate the failures).
+//! The peers subscribed to the peer (see `command::Subscription`) are
+//! announced the tip of the chain whenever it changes.
+//!
+//! ```no_run
+//! use protocol::mock::{Chain, MockPeer, Action};
+//! use protocol::packet::Handshake;
+//!
+//! // 3 epochs of 10 slots
+//! let chain = Chain::generate(Handshake::default().protocol_magic, 10, 33);
+//! let peer = MockPeer::start(chain).unwrap();
+//! peer.script(Action::Refuse(String::from("busy")));
+//! println!("mock peer listening on {}", peer.local_addr());
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration};
+
+use blockchain::{self, genesis, normal, BlockHeader, BlockDate, HeaderHash, RawBlock, SlotId, ChainDifficulty};
+use raw_cbor::{self, de::RawCbor};
+use wallet_crypto::config::{self, ProtocolMagic};
+use wallet_crypto::address::{ExtendedAddr};
+use wallet_crypto::coin::{Coin};
+use wallet_crypto::hdwallet::{XPrv, Seed, SEED_SIZE};
+use wallet_crypto::tx::{Tx, TxAux, TxIn, TxOut, TxInWitness};
+
+use packet::{self, Handshake, MsgType, GetHeaders, GetBlocks, BlockHeaderResponse, BlockResponse};
+use server::{Handler, Listener, Reply, Server};
+use protocol::{Error, Result, WaitReadable};
+
+// the fixtures the synthetic blocks are derived from: only the date, the
+// chain difficulty and the previous header of these headers are changed,
+// the signatures and the proofs are not valid.
+const MAIN_HEADER_FIXTURE : [u8;408] =
+        [ 0x82, 0x01, 0x85, 0x00, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91, 0xf8, 0x8b
+        , 0x26, 0xa9, 0x83, 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8, 0x10, 0x69
+        , 0x6b, 0xee, 0x57, 0x5d, 0x1d, 0x22, 0x84, 0x83, 0x01, 0x58, 0x20, 0x96, 0xd3, 0x8c, 0x5a, 0xaf
+        , 0xb8, 0x39, 0x45, 0x05, 0x11, 0xe1, 0xba, 0xe3, 0xb4, 0xec, 0xde, 0x21, 0x58, 0x88, 0xde, 0xe3
+        , 0x40, 0x35, 0x26, 0xe2, 0x37, 0x3d, 0x01, 0x6f, 0xdf, 0xdd, 0x1e, 0x58, 0x20, 0x83, 0xac, 0x5d
+        , 0x0d, 0x6a, 0xc0, 0xc0, 0x2a, 0xbf, 0x8c, 0x5a, 0xd7, 0x66, 0xd0, 0x13, 0x58, 0x73, 0xca, 0x4a
+        , 0xc5, 0x3d, 0xd5, 0x82, 0x18, 0x7c, 0x9a, 0xa1, 0x5a, 0xa1, 0x49, 0xc0, 0xda, 0x82, 0x03, 0x58
+        , 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91, 0xf8, 0x8b, 0x26, 0xa9, 0x83, 0x44, 0x53
+        , 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8, 0x10, 0x69, 0x6b, 0xee, 0x57, 0x5d, 0x1d
+        , 0x22, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91, 0xf8, 0x8b, 0x26, 0xa9, 0x83
+        , 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8, 0x10, 0x69, 0x6b, 0xee, 0x57
+        , 0x5d, 0x1d, 0x22, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91, 0xf8, 0x8b, 0x26
+        , 0xa9, 0x83, 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8, 0x10, 0x69, 0x6b
+        , 0xee, 0x57, 0x5d, 0x1d, 0x22, 0x84, 0x82, 0x01, 0x18, 0x2a, 0x58, 0x40, 0x1c, 0x0c, 0x3a, 0xe1
+        , 0x82, 0x5e, 0x90, 0xb6, 0xdd, 0xda, 0x3f, 0x40, 0xa1, 0x22, 0xc0, 0x07, 0xe1, 0x00, 0x8e, 0x83
+        , 0xb2, 0xe1, 0x02, 0xc1, 0x42, 0xba, 0xef, 0xb7, 0x21, 0xd7, 0x2c, 0x1a, 0x5d, 0x36, 0x61, 0xde
+        , 0xb9, 0x06, 0x4f, 0x2d, 0x0e, 0x03, 0xfe, 0x85, 0xd6, 0x80, 0x70, 0xb2, 0xfe, 0x33, 0xb4, 0x91
+        , 0x60, 0x59, 0x65, 0x8e, 0x28, 0xac, 0x7f, 0x7f, 0x91, 0xca, 0x4b, 0x12, 0x81, 0x18, 0x2a, 0x82
+        , 0x00, 0x58, 0x40, 0xa9, 0x05, 0x22, 0x87, 0x4c, 0xcc, 0xf9, 0xa6, 0x7e, 0x20, 0x90, 0x31, 0xfd
+        , 0x9d, 0xfe, 0x37, 0xa8, 0x2f, 0xd9, 0x43, 0xde, 0xe6, 0x33, 0x00, 0xaa, 0x82, 0x3c, 0xb9, 0x8e
+        , 0x0f, 0x70, 0x4e, 0x91, 0x3f, 0x6e, 0x02, 0xb2, 0xaa, 0x0a, 0x33, 0x69, 0x3e, 0x05, 0x2c, 0x15
+        , 0xf4, 0x3a, 0xee, 0x24, 0x21, 0x64, 0xd2, 0x81, 0x2a, 0x57, 0x2b, 0x27, 0x74, 0xc1, 0xb5, 0xad
+        , 0xa8, 0x18, 0x01, 0x84, 0x83, 0x00, 0x01, 0x00, 0x82, 0x6a, 0x63, 0x61, 0x72, 0x64, 0x61, 0x6e
+        , 0x6f, 0x2d, 0x73, 0x6c, 0x00, 0xa0, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91
+        , 0xf8, 0x8b, 0x26, 0xa9, 0x83, 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8
+        , 0x10, 0x69, 0x6b, 0xee, 0x57, 0x5d, 0x1d, 0x22];
+const GENESIS_HEADER_FIXTURE : [u8;78] =
+        [ 0x82, 0x00, 0x85, 0x00, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91, 0xf8, 0x8b
+        , 0x26, 0xa9, 0x83, 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8, 0x10, 0x69
+        , 0x6b, 0xee, 0x57, 0x5d, 0x1d, 0x22, 0x58, 0x20, 0xc4, 0xe0, 0xfc, 0x3a, 0x4f, 0xfb, 0x31, 0x91
+        , 0xf8, 0x8b, 0x26, 0xa9, 0x83, 0x44, 0x53, 0xcb, 0xac, 0x0e, 0x6b, 0x9c, 0x8d, 0x8f, 0x7a, 0xe8
+        , 0x10, 0x69, 0x6b, 0xee, 0x57, 0x5d, 0x1d, 0x22, 0x82, 0x01, 0x81, 0x00, 0x81, 0xa0];
+// an empty body: no transactions, no certificates, no delegation, no update
+const MAIN_BODY_FIXTURE : [u8;15] =
+        [ 0x84, 0x9f, 0xff, 0x82, 0x03, 0xd9, 0x01, 0x02, 0x80, 0x9f, 0xff, 0x82, 0x80, 0x9f, 0xff];
+const BLOCK_EXTRA_FIXTURE : [u8;2] = [ 0x81, 0xa0 ];
+
+fn fixture_main_header() -> normal::BlockHeader {
+    match RawCbor::from(&MAIN_HEADER_FIXTURE[..]).deserialize().unwrap() {
+        BlockHeader::MainBlockHeader(hdr) => hdr,
+        BlockHeader::GenesisBlockHeader(_) => unreachable!(),
+    }
+}
+
+fn fixture_genesis_header() -> genesis::BlockHeader {
+    match RawCbor::from(&GENESIS_HEADER_FIXTURE[..]).deserialize().unwrap() {
+        BlockHeader::GenesisBlockHeader(hdr) => hdr,
+        BlockHeader::MainBlockHeader(_) => unreachable!(),
+    }
+}
+
+fn fixture_extra() -> raw_cbor::Value {
+    RawCbor::from(&BLOCK_EXTRA_FIXTURE[..]).deserialize().unwrap()
+}
+
+/// the key of the fixture wallet `n`, owning `fixture_address(n)`
+pub fn fixture_key(n: u8) -> XPrv {
+    XPrv::generate_from_seed(&Seed::from_bytes([n;SEED_SIZE]))
+}
+
+/// the address of the fixture wallet `n`
+pub fn fixture_address(n: u8) -> ExtendedAddr {
+    ExtendedAddr::new_simple(fixture_key(n).public())
+}
+
+/// a transaction spending the given inputs (along the fixture wallet
+/// owning each of them) to the given outputs (the fixture wallet and the
+/// value in lovelace). The transactions are not checked against the
+/// chain: the inputs may be made up.
+pub fn fixture_transaction(protocol_magic: ProtocolMagic, inputs: &[(TxIn, u8)], outputs: &[(u8, u64)]) -> TxAux {
+    let tx = Tx::new_with(
+        inputs.iter().map(|(txin, _)| txin.clone()).collect(),
+        outputs.iter().map(|(n, value)| TxOut::new(fixture_address(*n), Coin::new(*value).unwrap())).collect(),
+    );
+    let cfg = config::Config::new(protocol_magic);
+    let witnesses = inputs.iter().map(|(_, n)| TxInWitness::new(&cfg, &fixture_key(*n), &tx)).collect();
+    TxAux::new(tx, witnesses)
+}
+
+/// a synthetic chain of blocks
+///
+/// Every epoch starts with a genesis block followed by a main block per
+/// slot, unless slots are skipped with `skip_slots`. The first block of
+/// the chain is the genesis block of the epoch 0.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    protocol_magic: ProtocolMagic,
+    epoch_slots: u32,
+    blocks: Vec<RawBlock>,
+    headers: Vec<BlockHeader>,
+    hashes: BTreeMap<HeaderHash, usize>,
+    next_date: BlockDate,
+    difficulty: u64,
+}
+impl Chain {
+    /// an empty chain, of `epoch_slots` slots per epoch
+    pub fn new(protocol_magic: ProtocolMagic, epoch_slots: u32) -> Self {
+        assert!(epoch_slots > 0);
+        Chain {
+            protocol_magic: protocol_magic,
+            epoch_slots: epoch_slots,
+            blocks: Vec::new(),
+            headers: Vec::new(),
+            hashes: BTreeMap::new(),
+            next_date: BlockDate::Genesis(0),
+            difficulty: 0,
+        }
+    }
+
+    /// a chain of `len` blocks, of `epoch_slots` slots per epoch
+    pub fn generate(protocol_magic: ProtocolMagic, epoch_slots: u32, len: usize) -> Self {
+        let mut chain = Chain::new(protocol_magic, epoch_slots);
+        chain.extend(len);
+        chain
+    }
+
+    /// a copy of the first `len` blocks of this chain
+    ///
+    /// Skip a slot (`skip_slots`) before extending the copy for its blocks
+    /// to differ from the blocks of this chain.
+    pub fn fork(&self, len: usize) -> Self {
+        assert!(len <= self.len());
+        let mut chain = Chain::new(self.protocol_magic, self.epoch_slots);
+        for (block, header) in self.blocks.iter().zip(self.headers.iter()).take(len) {
+            chain.push(block.clone(), header.clone());
+        }
+        chain
+    }
+
+    /// append `len` blocks
+    pub fn extend(&mut self, len: usize) {
+        for _ in 0..len {
+            self.append(Vec::new());
+        }
+    }
+
+    /// append a main block holding the given transactions (see
+    /// `fixture_transaction`), after the genesis block of the epoch if the
+    /// next block starts an epoch
+    pub fn push_transactions(&mut self, txs: Vec<TxAux>) {
+        if self.next_date.is_genesis() {
+            self.append(Vec::new());
+        }
+        self.append(txs);
+    }
+
+    fn append(&mut self, txs: Vec<TxAux>) {
+        let block = self.forge(txs);
+        let header = block.get_header();
+        self.push(RawBlock::from_dat(cbor!(&block).unwrap()), header);
+    }
+
+    /// leave the next `len` slots empty, the genesis blocks are never skipped
+    pub fn skip_slots(&mut self, len: u32) {
+        for _ in 0..len {
+            if self.next_date.is_genesis() { break; }
+            self.next_date = self.date_after(&self.next_date);
+        }
+    }
+
+    pub fn len(&self) -> usize { self.blocks.len() }
+
+    pub fn is_empty(&self) -> bool { self.blocks.is_empty() }
+
+    pub fn get_protocol_magic(&self) -> ProtocolMagic { self.protocol_magic }
+
+    /// the header of the most recent block
+    pub fn tip(&self) -> Option<&BlockHeader> { self.headers.last() }
+
+    /// the header of the block at the given height, 0 being the first block
+    pub fn header(&self, height: usize) -> &BlockHeader { &self.headers[height] }
+
+    /// the hash of the block at the given height, 0 being the first block
+    pub fn hash(&self, height: usize) -> HeaderHash { self.headers[height].compute_hash() }
+
+    /// the block at the given height, 0 being the first block
+    pub fn block(&self, height: usize) -> &RawBlock { &self.blocks[height] }
+
+    /// the height of the block of the given hash
+    pub fn height(&self, hash: &HeaderHash) -> Option<usize> { self.hashes.get(hash).cloned() }
+
+    /// the headers answering the given request, the most recent first
+    ///
+    /// from the requested block (or the tip) back to the most recent of
+    /// the given checkpoints (excluded), or only the requested header if
+    /// there is no checkpoint.
+    pub fn get_headers(&self, req: &GetHeaders) -> ::std::result::Result<Vec<BlockHeader>, String> {
+        let to = match req.to {
+            None => match self.len() { 0 => return Err(String::from("no tip available")), len => len - 1 },
+            Some(ref to) => self.height(to).ok_or(format!("unknown block {}", to))?,
+        };
+        if req.from.is_empty() {
+            return Ok(vec![self.headers[to].clone()]);
+        }
+        let from = (0..to + 1).rev()
+            .find(|height| req.from.contains(&self.hash(*height)))
+            .ok_or(format!("no checkpoint found before {}", self.hash(to)))?;
+        Ok(self.headers[from + 1..to + 1].iter().rev().cloned().collect())
+    }
+
+    /// the blocks answering the given request, the oldest first
+    pub fn get_blocks(&self, req: &GetBlocks) -> ::std::result::Result<Vec<RawBlock>, String> {
+        let from = self.height(&req.from).ok_or(format!("unknown block {}", req.from))?;
+        let to = self.height(&req.to).ok_or(format!("unknown block {}", req.to))?;
+        if from > to {
+            return Err(format!("block {} is after block {}", req.from, req.to));
+        }
+        Ok(self.blocks[from..to + 1].to_vec())
+    }
+
+    fn push(&mut self, block: RawBlock, header: BlockHeader) {
+        self.next_date = self.date_after(&header.get_blockdate());
+        if ! header.is_genesis_block() { self.difficulty += 1; }
+        self.hashes.insert(header.compute_hash(), self.blocks.len());
+        self.blocks.push(block);
+        self.headers.push(header);
+    }
+
+    fn date_after(&self, date: &BlockDate) -> BlockDate {
+        match date {
+            BlockDate::Genesis(epoch) => BlockDate::Normal(SlotId { epoch: *epoch, slotid: 0 }),
+            BlockDate::Normal(ref slot) if slot.slotid + 1 >= self.epoch_slots => BlockDate::Genesis(slot.epoch + 1),
+            BlockDate::Normal(ref slot) => BlockDate::Normal(slot.next()),
+        }
+    }
+
+    // create the block following the tip, a main block holds the given
+    // transactions
+    fn forge(&self, txs: Vec<TxAux>) -> blockchain::Block {
+        let previous_header = match self.tip() {
+            None => HeaderHash::from_bytes([0;32]),
+            Some(tip) => tip.compute_hash(),
+        };
+        match self.next_date {
+            BlockDate::Genesis(epoch) => {
+                let mut header = fixture_genesis_header();
+                header.protocol_magic = self.protocol_magic;
+                header.previous_header = previous_header;
+                header.consensus.epoch = epoch;
+                header.consensus.chain_difficulty = ChainDifficulty::from(self.difficulty);
+                blockchain::Block::GenesisBlock(genesis::Block {
+                    header: header,
+                    body: genesis::Body { slot_leaders: Vec::new() },
+                    extra: fixture_extra(),
+                })
+            },
+            BlockDate::Normal(ref slot) => {
+                let mut header = fixture_main_header();
+                header.protocol_magic = self.protocol_magic;
+                header.previous_header = previous_header;
+                header.consensus.slot_id = slot.clone();
+                header.consensus.chain_difficulty = ChainDifficulty::from(self.difficulty + 1);
+                let mut body : normal::Body = RawCbor::from(&MAIN_BODY_FIXTURE[..]).deserialize().unwrap();
+                body.tx = normal::TxPayload::new(txs);
+                blockchain::Block::MainBlock(normal::Block::new(header, body, fixture_extra()))
+            },
+        }
+    }
+}
+
+/// what the peer does with the next request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// answer from the chain
+    Serve,
+    /// answer with an error response holding the given message
+    Refuse(String),
+    /// close the connection without answering
+    Disconnect,
+}
+
+struct State {
+    chain: Chain,
+    script: VecDeque<Action>,
+    subscriptions: usize,
+}
+impl State {
+    fn next_action(&mut self) -> Action {
+        self.script.pop_front().unwrap_or(Action::Serve)
+    }
+}
+
+// how long a connection waits for the messages of the peer before
+// checking whether the tip changed
+const ANNOUNCE_POLL : Duration = Duration::from_millis(10);
+
+/// a fake peer serving a synthetic chain on a local port
+///
+/// The peer stops accepting connections when the test process exits.
+#[derive(Clone)]
+pub struct MockPeer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+impl MockPeer {
+    /// listen on a free local port and serve the given chain, the
+    /// protocol magic of the handshake is the one of the chain
+    pub fn start(chain: Chain) -> Result<Self> {
+        let mut handshake = Handshake::default();
+        handshake.protocol_magic = chain.get_protocol_magic();
+        handshake.in_handlers.insert(MsgType::MsgSubscribe.to_u8() as packet::MessageCode, packet::HandlerSpec::new(MsgType::MsgHeaders.to_u8() as u16));
+        let state = Arc::new(Mutex::new(State { chain: chain, script: VecDeque::new(), subscriptions: 0 }));
+
+        let mut listener = Listener::bind("127.0.0.1:0", handshake)?;
+        listener.register(MsgType::MsgGetHeaders.to_u8(), HeadersHandler(state.clone()));
+        listener.register(MsgType::MsgGetBlocks.to_u8(), BlocksHandler(state.clone()));
+        // the subscription request and the keep-alives are not answered,
+        // the announcements are sent by `serve`
+        listener.register(MsgType::MsgSubscribe.to_u8(), |_: &mut Reply, _: &[u8]| Ok(()));
+        let addr = listener.local_addr()?;
+        let accepting = state.clone();
+        thread::spawn(move || loop {
+            match listener.accept() {
+                Err(err) => warn!("mock peer: cannot accept connection: {:?}", err),
+                Ok((addr, mut server)) => {
+                    let state = accepting.clone();
+                    thread::spawn(move || {
+                        let mut announced = None;
+                        let result = serve(&mut server, &state, &mut announced);
+                        if announced.is_some() {
+                            state.lock().unwrap().subscriptions -= 1;
+                        }
+                        if let Err(err) = result {
+                            debug!("mock peer: connection from {} closed: {:?}", addr, err)
+                        }
+                    });
+                },
+            }
+        });
+        Ok(MockPeer { addr: addr, state: state })
+    }
+
+    /// the address to connect to
+    pub fn local_addr(&self) -> SocketAddr { self.addr }
+
+    /// a copy of the chain currently served
+    pub fn get_chain(&self) -> Chain {
+        self.state.lock().unwrap().chain.clone()
+    }
+
+    /// serve another chain from now on, i.e. switch to a fork, its tip is
+    /// announced to the subscriptions
+    pub fn set_chain(&self, chain: Chain) {
+        self.state.lock().unwrap().chain = chain
+    }
+
+    /// the number of connections subscribed to the peer
+    pub fn subscriptions(&self) -> usize {
+        self.state.lock().unwrap().subscriptions
+    }
+
+    /// queue the action to perform on the next request (of any connection),
+    /// the requests are served once the queued actions are consumed.
+    pub fn script(&self, action: Action) {
+        self.state.lock().unwrap().script.push_back(action)
+    }
+}
+
+// serve the connection until the peer closes it, announcing the tip of the
+// chain to its subscription whenever it changes. `announced` holds the last
+// tip known to the subscription (`None` for an empty chain), if subscribed.
+fn serve(server: &mut Server<TcpStream>, state: &Mutex<State>, announced: &mut Option<Option<HeaderHash>>) -> Result<()> {
+    loop {
+        if server.get_backend().wait_readable(ANNOUNCE_POLL)? {
+            if ! server.process_message()? { return Ok(()) }
+        }
+
+        let mut state = state.lock().unwrap();
+        if ! server.has_conversation(MsgType::MsgSubscribe.to_u8()) {
+            if announced.take().is_some() { state.subscriptions -= 1 }
+            continue;
+        }
+        let tip = state.chain.tip().map(|hdr| hdr.compute_hash());
+        match announced.clone() {
+            // the subscription starts from the current tip
+            None => state.subscriptions += 1,
+            Some(ref known) if *known == tip => continue,
+            Some(_) => {
+                let response = BlockHeaderResponse::Ok(state.chain.tip().cloned().into_iter().collect());
+                server.announce(&[&[MsgType::MsgHeaders.to_u8()], &cbor!(&response)?])?;
+            },
+        }
+        *announced = Some(tip);
+    }
+}
+
+struct HeadersHandler(Arc<Mutex<State>>);
+impl Handler for HeadersHandler {
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> Result<()> {
+        let response = {
+            let mut state = self.0.lock().unwrap();
+            match state.next_action() {
+                Action::Disconnect => return Err(Error::ConnectionClosed),
+                Action::Refuse(err) => BlockHeaderResponse::Err(err),
+                Action::Serve => {
+                    let headers = RawCbor::from(msg).deserialize()
+                        .map_err(|err| format!("invalid request: {:?}", err))
+                        .and_then(|req: GetHeaders| state.chain.get_headers(&req));
+                    match headers {
+                        Ok(headers) => BlockHeaderResponse::Ok(headers),
+                        Err(err) => BlockHeaderResponse::Err(err),
+                    }
+                },
+            }
+        };
+        reply.send_bytes(&cbor!(&response)?)?;
+        reply.close();
+        Ok(())
+    }
+}
+
+struct BlocksHandler(Arc<Mutex<State>>);
+impl Handler for BlocksHandler {
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> Result<()> {
+        let blocks = {
+            let mut state = self.0.lock().unwrap();
+            match state.next_action() {
+                Action::Disconnect => return Err(Error::ConnectionClosed),
+                Action::Refuse(err) => Err(err),
+                Action::Serve => {
+                    RawCbor::from(msg).deserialize()
+                        .map_err(|err| format!("invalid request: {:?}", err))
+                        .and_then(|req: GetBlocks| state.chain.get_blocks(&req))
+                },
+            }
+        };
+        match blocks {
+            Ok(blocks) => {
+                for block in blocks.iter() {
+                    reply.send_bytes(&packet::raw_block_response(block))?;
+                }
+            },
+            Err(err) => {
+                reply.send_bytes(&cbor!(&BlockResponse::NoBlock(err))?)?;
+            },
+        }
+        reply.close();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use command::{Command, GetBlockHeader, GetBlock, Subscription, SUBSCRIPTION_KEEP_ALIVE};
+    use protocol::Connection;
+    use ntt;
+    use wallet_crypto::tx::{TxId};
+
+    fn connect(peer: &MockPeer) -> Connection<TcpStream> {
+        let stream = TcpStream::connect(peer.local_addr()).unwrap();
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        let mut handshake = Handshake::default();
+        handshake.protocol_magic = peer.get_chain().get_protocol_magic();
+        connection.handshake(&handshake).unwrap();
+        connection
+    }
+
+    fn hashes(headers: &[BlockHeader]) -> Vec<HeaderHash> {
+        headers.iter().map(|hdr| hdr.compute_hash()).collect()
+    }
+
+    #[test]
+    fn synthetic_chain() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        assert_eq!(chain.header(0).get_blockdate(), BlockDate::Genesis(0));
+        assert_eq!(chain.header(11).get_blockdate(), BlockDate::Genesis(1));
+        assert_eq!(chain.header(24).get_blockdate(), BlockDate::Normal(SlotId { epoch: 2, slotid: 1 }));
+        for height in 1..chain.len() {
+            assert_eq!(chain.header(height).get_previous_header(), chain.hash(height - 1));
+            let block = chain.block(height).decode().unwrap();
+            assert_eq!(block.get_header().compute_hash(), chain.hash(height));
+        }
+
+        let mut fork = chain.fork(15);
+        fork.skip_slots(1);
+        fork.extend(5);
+        assert_eq!(fork.hash(14), chain.hash(14));
+        assert_eq!(fork.header(15).get_blockdate(), BlockDate::Normal(SlotId { epoch: 1, slotid: 4 }));
+        assert!(chain.height(&fork.hash(15)).is_none());
+    }
+
+    #[test]
+    fn blocks_with_transactions() {
+        let mut chain = Chain::generate(ProtocolMagic::default(), 10, 11);
+        let tx = fixture_transaction(chain.get_protocol_magic(), &[(TxIn::new(TxId::new(&[1]), 0), 1)], &[(2, 100), (1, 50)]);
+        // the next block is the genesis block of the epoch 1
+        chain.push_transactions(vec![tx.clone()]);
+        assert_eq!(chain.len(), 13);
+        assert_eq!(chain.header(12).get_blockdate(), BlockDate::Normal(SlotId { epoch: 1, slotid: 0 }));
+        match chain.block(12).decode().unwrap() {
+            blockchain::Block::MainBlock(mblk) => {
+                let txs : Vec<_> = mblk.body.tx.iter().map(|txaux| txaux.tx.clone()).collect();
+                assert_eq!(txs, vec![tx.tx.clone()]);
+                assert_eq!(txs[0].outputs[0].address, fixture_address(2));
+            },
+            blockchain::Block::GenesisBlock(_) => panic!("expected a main block"),
+        }
+    }
+
+    #[test]
+    fn serve_headers_and_blocks() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut connection = connect(&peer);
+
+        let tip = GetBlockHeader::tip().execute(&mut connection).unwrap().decode().unwrap();
+        assert_eq!(hashes(&tip), vec![chain.hash(24)]);
+
+        let headers = GetBlockHeader::range(&[chain.hash(3)], chain.hash(20)).execute(&mut connection).unwrap();
+        let expected : Vec<_> = (4..21).rev().map(|height| chain.hash(height)).collect();
+        assert_eq!(hashes(&headers.decode().unwrap()), expected);
+
+        let blocks = GetBlock::from(&chain.hash(9), &chain.hash(13)).execute(&mut connection).unwrap();
+        let got : Vec<_> = blocks.iter().map(|blk| blk.decode().unwrap().get_header().compute_hash()).collect();
+        let expected : Vec<_> = (9..14).map(|height| chain.hash(height)).collect();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn serve_fork() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut connection = connect(&peer);
+
+        let mut fork = chain.fork(15);
+        fork.skip_slots(2);
+        fork.extend(12);
+        peer.set_chain(fork.clone());
+
+        let tip = GetBlockHeader::tip().execute(&mut connection).unwrap().decode().unwrap();
+        assert_eq!(hashes(&tip), vec![fork.hash(26)]);
+
+        // the blocks of the abandoned branch are not known anymore
+        assert!(GetBlockHeader::range(&[chain.hash(20)], fork.hash(26)).execute(&mut connection).is_err());
+        let headers = GetBlockHeader::range(&[chain.hash(20), chain.hash(14)], fork.hash(26)).execute(&mut connection).unwrap();
+        assert_eq!(headers.decode().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn scripted_failures() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut connection = connect(&peer);
+
+        peer.script(Action::Refuse(String::from("busy")));
+        match GetBlockHeader::tip().execute(&mut connection) {
+            Err(Error::PeerError(ref err)) if err == "busy" => {},
+            r => panic!("expected the peer error, got {:?}", r),
+        }
+        assert!(GetBlockHeader::tip().execute(&mut connection).is_ok());
+
+        peer.script(Action::Refuse(String::from("busy")));
+        match GetBlock::only(&chain.hash(3)).execute(&mut connection) {
+            Err(Error::PeerError(ref err)) if err == "busy" => {},
+            r => panic!("expected the peer error, got {:?}", r),
+        }
+        assert!(GetBlock::only(&chain.hash(3)).execute(&mut connection).is_ok());
+
+        peer.script(Action::Disconnect);
+        let err = GetBlockHeader::tip().execute(&mut connection).unwrap_err();
+        assert!(err.is_retryable(), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn handshake_validation() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 5);
+        let peer = MockPeer::start(chain).unwrap();
+
+        let stream = TcpStream::connect(peer.local_addr()).unwrap();
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        let mut handshake = Handshake::default();
+        handshake.protocol_magic = ProtocolMagic::new(1);
+        match connection.handshake(&handshake) {
+            Err(Error::ProtocolMagicMismatch(ours, theirs)) => {
+                assert_eq!(ours, ProtocolMagic::new(1));
+                assert_eq!(theirs, ProtocolMagic::default());
+            },
+            r => panic!("expected a protocol magic mismatch, got {:?}", r),
+        }
+
+        let stream = TcpStream::connect(peer.local_addr()).unwrap();
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        let mut handshake = Handshake::default();
+        handshake.version = blockchain::Version::new(1, 0, 0);
+        match connection.handshake(&handshake) {
+            Err(Error::ProtocolVersionMismatch(ours, theirs)) => {
+                assert_eq!(ours, blockchain::Version::new(1, 0, 0));
+                assert_eq!(theirs, blockchain::Version::default());
+            },
+            r => panic!("expected a protocol version mismatch, got {:?}", r),
+        }
+
+        // only the revision differs
+        let stream = TcpStream::connect(peer.local_addr()).unwrap();
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        let mut handshake = Handshake::default();
+        handshake.version = blockchain::Version::new(0, 1, 7);
+        assert!(connection.handshake(&handshake).is_ok());
+    }
+
+    fn wait_subscriptions(peer: &MockPeer, n: usize) {
+        while peer.subscriptions() != n {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn announce_the_new_tips() {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let peer = MockPeer::start(chain.clone()).unwrap();
+        let mut connection = connect(&peer);
+        let mut subscription = Subscription::start(&mut connection, Duration::from_millis(50)).unwrap();
+        wait_subscriptions(&peer, 1);
+
+        let mut extended = chain.clone();
+        extended.extend(2);
+        peer.set_chain(extended.clone());
+        assert_eq!(hashes(&subscription.next_headers(&mut connection).unwrap()), vec![extended.hash(26)]);
+
+        // the requests are served while subscribed
+        let tip = GetBlockHeader::tip().execute(&mut connection).unwrap().decode().unwrap();
+        assert_eq!(hashes(&tip), vec![extended.hash(26)]);
+
+        let mut fork = chain.fork(20);
+        fork.skip_slots(1);
+        fork.extend(3);
+        peer.set_chain(fork.clone());
+        let mut announced = Vec::new();
+        subscription.for_each(&mut connection, |hdr| {
+            announced.push(hdr.compute_hash());
+            false
+        }).unwrap();
+        assert_eq!(announced, vec![fork.hash(22)]);
+
+        subscription.stop(&mut connection).unwrap();
+        wait_subscriptions(&peer, 0);
+        let tip = GetBlockHeader::tip().execute(&mut connection).unwrap().decode().unwrap();
+        assert_eq!(hashes(&tip), vec![fork.hash(22)]);
+
+        // a new subscription starts from the current tip, the subscription
+        // ends with its connection
+        let mut connection = connect(&peer);
+        let mut subscription = Subscription::start(&mut connection, SUBSCRIPTION_KEEP_ALIVE).unwrap();
+        wait_subscriptions(&peer, 1);
+        peer.set_chain(chain.clone());
+        assert_eq!(hashes(&subscription.next_headers(&mut connection).unwrap()), vec![chain.hash(24)]);
+        drop(connection);
+        wait_subscriptions(&peer, 0);
+    }
+}
@@ -0,0 +1,704 @@
+//! asynchronous (tokio) implementation of the node-to-node protocol
+//!
+//! Unlike the blocking `Connection`, this `Connection` multiplexes as many
+//! light connections as needed over a single TCP stream: every request
+//! opens its own `LightConnection`, so headers, blocks and subscriptions
+//! can be in flight at the same time.
+//!
+//! The stream is driven by a task spawned on the given tokio `Handle`,
+//! the `Connection` and the `LightConnection`s only talk to this task
+//! through channels.
+//!
+//! ```no_run
+//! extern crate tokio_core;
+//! extern crate futures;
+//! extern crate protocol;
+//!
+//! use futures::Future;
+//! use protocol::{packet, mux};
+//!
+//! # fn main() {
+//! let mut core = tokio_core::reactor::Core::new().unwrap();
+//! let addr = "127.0.0.1:3000".parse().unwrap();
+//! let connection = core.run(mux::Connection::connect(&core.handle(), 0, &addr, &packet::Handshake::default())).unwrap();
+//! let headers = connection.get_block_headers(&[], None).join(connection.get_block_headers(&[], None));
+//! let (tip1, tip2) = core.run(headers).unwrap();
+//! # }
+//! ```
+
+use std::collections::{BTreeMap};
+use std::net::{SocketAddr};
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use std::time::{Duration};
+use std::{io, mem};
+
+use bytes::{BytesMut, BufMut};
+use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use tokio_codec::{Decoder, Encoder, Framed};
+use tokio_core::net::{TcpStream};
+use tokio_core::reactor::{Handle, Interval};
+use tokio_io::{AsyncRead, AsyncWrite, io::{write_all, read_exact}};
+
+use blockchain;
+use raw_cbor::{de::RawCbor};
+use wallet_crypto::cbor::hs::util::decode_sum_type;
+
+use ntt::{self, LIGHT_ID_MIN, protocol::{ControlHeader, NodeId}};
+use packet::{self, Handshake};
+use protocol::{LightId, ServerLightConnection, Error, Result, check_peer_handshake, check_peer_supports};
+
+/// a frame of the ntt protocol
+#[derive(Debug)]
+pub enum Frame {
+    Control(ControlHeader, LightId),
+    Data(LightId, Vec<u8>),
+}
+
+/// encode and decode the ntt frames, once the ntt handshake is done
+pub struct NttCodec;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+impl Decoder for NttCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if src.len() < 8 { return Ok(None) }
+        let hdr = read_u32(&src[0..4]);
+        let v = read_u32(&src[4..8]);
+        if hdr < LIGHT_ID_MIN {
+            let ch = match ntt::protocol::control_header_from_u32(hdr) {
+                Err(()) => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown ntt control header")),
+                Ok(ch) => ch,
+            };
+            src.split_to(8);
+            Ok(Some(Frame::Control(ch, LightId(v))))
+        } else {
+            let len = v as usize;
+            if src.len() < 8 + len {
+                src.reserve(8 + len - src.len());
+                return Ok(None)
+            }
+            src.split_to(8);
+            let dat = src.split_to(len);
+            Ok(Some(Frame::Data(LightId(hdr), dat.to_vec())))
+        }
+    }
+}
+impl Encoder for NttCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        match frame {
+            Frame::Control(ch, id) => {
+                dst.reserve(8);
+                dst.put_u32_be(ntt::protocol::control_header_to_u32(ch));
+                dst.put_u32_be(id.0);
+            },
+            Frame::Data(id, dat) => {
+                dst.reserve(8 + dat.len());
+                dst.put_u32_be(id.0);
+                dst.put_u32_be(dat.len() as u32);
+                dst.put_slice(&dat);
+            },
+        }
+        Ok(())
+    }
+}
+
+// what the multiplexer sends to the light connections
+enum Event {
+    Data(Vec<u8>),
+    End,
+}
+
+// what the connection and the light connections ask to the multiplexer
+enum Request {
+    Open(LightId, UnboundedSender<Event>),
+    Send(LightId, Vec<u8>),
+    Close(LightId),
+    Listen(UnboundedSender<(LightId, UnboundedReceiver<Event>)>),
+}
+
+/// a light connection of a `Connection`
+///
+/// the messages received on the light connection are read as a `Stream`,
+/// which ends when the peer closes its side of the light connection.
+/// The light connection is closed when dropped.
+pub struct LightConnection {
+    id: LightId,
+    requests: UnboundedSender<Request>,
+    received: UnboundedReceiver<Event>,
+    eos: bool,
+}
+impl LightConnection {
+    pub fn get_id(&self) -> LightId { self.id }
+
+    pub fn send_bytes(&self, bytes: &[u8]) -> Result<()> {
+        self.requests.unbounded_send(Request::Send(self.id, bytes.to_vec()))
+            .map_err(|_| Error::ConnectionClosed)
+    }
+}
+impl Stream for LightConnection {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Error> {
+        if self.eos { return Ok(Async::Ready(None)) }
+        match self.received.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(Event::Data(dat)))) => Ok(Async::Ready(Some(dat))),
+            Ok(Async::Ready(Some(Event::End))) => {
+                self.eos = true;
+                Ok(Async::Ready(None))
+            },
+            // the multiplexer is gone without ending the light connection
+            Ok(Async::Ready(None)) | Err(()) => Err(Error::ConnectionClosed),
+        }
+    }
+}
+impl Drop for LightConnection {
+    fn drop(&mut self) {
+        let _ = self.requests.unbounded_send(Request::Close(self.id));
+    }
+}
+
+// one of our light connections, as known by the multiplexer
+struct Light {
+    node_id: NodeId,
+    sender: UnboundedSender<Event>,
+}
+
+// the task driving the ntt stream
+struct Multiplexer<T> {
+    transport: Framed<T, NttCodec>,
+    // the frames waiting to be accepted by the transport
+    pending: Vec<Frame>,
+    requests: UnboundedReceiver<Request>,
+    requests_closed: bool,
+    drg: u64,
+    next_light_id: Arc<AtomicUsize>,
+    client_cons: BTreeMap<LightId, Light>,
+    server_cons: BTreeMap<LightId, ServerLightConnection>,
+    map_to_client: BTreeMap<NodeId, LightId>,
+    // where to deliver the conversations initiated by the peer, see
+    // `Conversations`. The multiplexer holds no sender of its own requests
+    // so they end once the `Connection` and its light connections are gone.
+    listener: Option<UnboundedSender<(LightId, UnboundedReceiver<Event>)>>,
+}
+impl<T: AsyncRead+AsyncWrite> Multiplexer<T> {
+    fn emit(&mut self, frame: Frame) {
+        self.pending.push(frame)
+    }
+
+    fn flush(&mut self) -> Poll<(), Error> {
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        let mut frames = pending.into_iter();
+        while let Some(frame) = frames.next() {
+            if let AsyncSink::NotReady(frame) = self.transport.start_send(frame)? {
+                self.pending.push(frame);
+                self.pending.extend(frames);
+                break;
+            }
+        }
+        Ok(self.transport.poll_complete()?)
+    }
+
+    fn get_free_light_id(&mut self) -> LightId {
+        LightId::new(self.next_light_id.fetch_add(1, Ordering::SeqCst) as u32)
+    }
+
+    fn process_request(&mut self, request: Request) {
+        match request {
+            Request::Open(id, sender) => {
+                let node_id = NodeId::make_syn(self.drg);
+                self.drg += 1;
+                self.emit(Frame::Control(ControlHeader::CreatedNewConnection, id));
+                self.emit(Frame::Data(id, node_id.as_ref().to_vec()));
+                self.client_cons.insert(id, Light { node_id: node_id, sender: sender });
+            },
+            Request::Send(id, dat) => {
+                self.emit(Frame::Data(id, dat));
+            },
+            Request::Close(id) => self.close(id),
+            Request::Listen(conversations) => {
+                self.listener = Some(conversations);
+            },
+        }
+    }
+
+    // close our light connection, forgetting the peer's light connection
+    // routed to it
+    fn close(&mut self, id: LightId) {
+        if self.client_cons.remove(&id).is_none() { return; }
+        self.emit(Frame::Control(ControlHeader::CloseConnection, id));
+        let nodeids : Vec<NodeId> = self.map_to_client.iter().filter(|&(_, lightid)| *lightid == id).map(|(nodeid, _)| *nodeid).collect();
+        for nodeid in nodeids {
+            self.map_to_client.remove(&nodeid);
+            self.server_cons.retain(|_, con| *con != ServerLightConnection::Established(nodeid));
+        }
+    }
+
+    // same as the blocking `Connection::process_messages`, except that the
+    // received data are sent to the light connections' channels
+    fn process_frame(&mut self, frame: Frame) -> Result<()> {
+        match frame {
+            Frame::Control(ControlHeader::CloseConnection, id) => {
+                if let Some(ServerLightConnection::Established(nodeid)) = self.server_cons.remove(&id) {
+                    if let Some(lightid) = self.map_to_client.remove(&nodeid) {
+                        if let Some(con) = self.client_cons.get(&lightid) {
+                            let _ = con.sender.unbounded_send(Event::End);
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Frame::Control(ControlHeader::CreatedNewConnection, id) => {
+                if self.server_cons.contains_key(&id) {
+                    error!("light id created twice, {}", id);
+                    return Err(Error::ServerCreatedLightIdTwice(id));
+                }
+                self.server_cons.insert(id, ServerLightConnection::Establishing);
+                Ok(())
+            },
+            Frame::Control(ch, id) => {
+                error!("LightId({}) Unsupported control `{:?}`", id, ch);
+                Err(Error::UnsupportedControl(ch))
+            },
+            Frame::Data(id, dat) => {
+                match self.server_cons.get(&id).cloned() {
+                    Some(ServerLightConnection::Established(nodeid)) => {
+                        let con = self.map_to_client.get(&nodeid).and_then(|lightid| self.client_cons.get(lightid));
+                        match con {
+                            // the light connection may just have been closed on our side
+                            None => debug!("LightId({}) dropping data of a closed light connection", id),
+                            Some(con) => { let _ = con.sender.unbounded_send(Event::Data(dat)); },
+                        }
+                        Ok(())
+                    },
+                    Some(ServerLightConnection::Establishing) => {
+                        let nodeid = match NodeId::from_slice(&dat[..]) {
+                            None => return Err(Error::InvalidNodeId(id)),
+                            Some(nodeid) => nodeid,
+                        };
+                        self.server_cons.insert(id, ServerLightConnection::Established(nodeid));
+                        if nodeid.is_syn() {
+                            self.accept_conversation(nodeid);
+                        } else {
+                            let client = self.client_cons.iter().find(|&(_, con)| con.node_id.match_ack(&nodeid)).map(|(z, _)| *z);
+                            if let Some(lightid) = client {
+                                self.map_to_client.insert(nodeid, lightid);
+                            }
+                        }
+                        Ok(())
+                    },
+                    None => {
+                        warn!("LightId({}) does not exists but received data", id);
+                        Ok(())
+                    },
+                }
+            },
+        }
+    }
+
+    // the peer initiates a conversation, acknowledge it and deliver it to the
+    // listener. Without listener the conversation's data are dropped.
+    fn accept_conversation(&mut self, nodeid: NodeId) {
+        let conversations = match self.listener {
+            None => {
+                debug!("no listener, ignoring conversation of node {}", nodeid);
+                return;
+            },
+            Some(ref listener) => listener.clone(),
+        };
+        let id = self.get_free_light_id();
+        self.emit(Frame::Control(ControlHeader::CreatedNewConnection, id));
+        self.emit(Frame::Data(id, nodeid.syn_to_ack().as_ref().to_vec()));
+        let (sender, received) = mpsc::unbounded();
+        self.client_cons.insert(id, Light { node_id: nodeid, sender: sender });
+        self.map_to_client.insert(nodeid, id);
+        if conversations.unbounded_send((id, received)).is_err() {
+            self.listener = None;
+            self.close(id);
+        }
+    }
+}
+impl<T: AsyncRead+AsyncWrite> Future for Multiplexer<T> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while ! self.requests_closed {
+            match self.requests.poll() {
+                Ok(Async::Ready(Some(request))) => self.process_request(request),
+                Ok(Async::Ready(None)) | Err(()) => self.requests_closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+        loop {
+            match self.transport.poll()? {
+                Async::Ready(Some(frame)) => self.process_frame(frame)?,
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+        if self.requests_closed {
+            // the conversations accepted but never picked up by a listener
+            let ids : Vec<LightId> = self.client_cons.keys().cloned().collect();
+            for id in ids { self.close(id) }
+        }
+        let flushed = self.flush()?;
+        // nobody can use the connection anymore
+        if self.requests_closed && self.client_cons.is_empty() && flushed.is_ready() && self.pending.is_empty() {
+            return Ok(Async::Ready(()))
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn next_frame<T>(transport: Framed<T, NttCodec>) -> Box<Future<Item=(Frame, Framed<T, NttCodec>), Error=Error>>
+    where T: AsyncRead+AsyncWrite+'static
+{
+    Box::new(transport.into_future().map_err(|(err, _)| Error::from(err)).and_then(|(frame, transport)| {
+        match frame {
+            None => Err(Error::ConnectionClosed),
+            Some(frame) => Ok((frame, transport)),
+        }
+    }))
+}
+
+fn next_data_on<T>(transport: Framed<T, NttCodec>, id: LightId) -> Box<Future<Item=(Vec<u8>, Framed<T, NttCodec>), Error=Error>>
+    where T: AsyncRead+AsyncWrite+'static
+{
+    Box::new(next_frame(transport).and_then(move |(frame, transport)| {
+        match frame {
+            Frame::Data(cid, dat) => if cid == id { Ok((dat, transport)) } else { Err(Error::UnexpectedFrame(Frame::Data(cid, dat))) },
+            frame => Err(Error::UnexpectedFrame(frame)),
+        }
+    }))
+}
+
+/// the delay between two keep-alives of a subscription
+pub const SUBSCRIPTION_KEEP_ALIVE : Duration = Duration::from_secs(20);
+
+/// an asynchronous connection to a peer
+#[derive(Clone)]
+pub struct Connection {
+    requests: UnboundedSender<Request>,
+    next_light_id: Arc<AtomicUsize>,
+    peer_handshake: Handshake,
+}
+impl Connection {
+    /// connect to the given address and perform the handshakes
+    pub fn connect(handle: &Handle, drg_seed: u64, addr: &SocketAddr, hs: &Handshake) -> Box<Future<Item=Self, Error=Error>> {
+        let handle = handle.clone();
+        let hs = hs.clone();
+        Box::new(TcpStream::connect(addr, &handle).map_err(Error::from).and_then(move |stream| {
+            Connection::handshake(&handle, drg_seed, stream, &hs)
+        }))
+    }
+
+    /// perform the ntt handshake and the node handshake on the given stream
+    ///
+    /// once done, the task multiplexing the light connections over the
+    /// stream is spawned on the given handle.
+    pub fn handshake<T>(handle: &Handle, drg_seed: u64, stream: T, hs: &Handshake) -> Box<Future<Item=Self, Error=Error>>
+        where T: AsyncRead+AsyncWrite+'static
+    {
+        let handle = handle.clone();
+        let mut buf = vec![];
+        ntt::protocol::handshake(&mut buf);
+        let hs_dat = packet::send_handshake(hs);
+        let hs = hs.clone();
+
+        let id = LightId::new(LIGHT_ID_MIN);
+        let nodeid = NodeId::make_syn(drg_seed);
+
+        let ntt_handshake = write_all(stream, buf)
+            .and_then(|(stream, _)| read_exact(stream, [0u8;4]))
+            .map_err(Error::from)
+            .and_then(|(stream, response)| {
+                match read_u32(&response) {
+                    0x00000000 => Ok(stream),
+                    0x00000001 => Err(Error::NttError(ntt::Error::InvalidRequest)),
+                    0x00000002 => Err(Error::NttError(ntt::Error::CrossedRequest)),
+                    0xffffffff => Err(Error::NttError(ntt::Error::UnsupportedVersion)),
+                    v          => Err(Error::NttError(ntt::Error::UnknownErrorCode(v))),
+                }
+            });
+
+        let node_handshake = ntt_handshake.and_then(move |stream| {
+            let transport = Framed::new(stream, NttCodec);
+            transport.send(Frame::Control(ControlHeader::CreatedNewConnection, id))
+                .and_then(move |transport| transport.send(Frame::Data(id, hs_dat)))
+                .and_then(move |transport| transport.send(Frame::Data(id, nodeid.as_ref().to_vec())))
+                .map_err(Error::from)
+        }).and_then(|transport| {
+            next_frame(transport).and_then(|(frame, transport)| {
+                match frame {
+                    Frame::Control(ControlHeader::CreatedNewConnection, sid) => Ok((sid, transport)),
+                    frame => Err(Error::UnexpectedFrame(frame)),
+                }
+            })
+        }).and_then(|(sid, transport)| {
+            next_data_on(transport, sid).and_then(move |(dat, transport)| {
+                let server_handshake : Handshake = RawCbor::from(&dat).deserialize()?;
+                debug!("peer handshake:\n{}", server_handshake);
+                check_peer_handshake(&hs, &server_handshake)?;
+                Ok((sid, server_handshake, transport))
+            })
+        }).and_then(|(sid, server_handshake, transport)| {
+            next_data_on(transport, sid).and_then(move |(dat, transport)| {
+                match NodeId::from_slice(&dat) {
+                    None => Err(Error::InvalidNodeId(sid)),
+                    Some(server_nodeid) => Ok((sid, server_handshake, server_nodeid, transport)),
+                }
+            })
+        });
+
+        Box::new(node_handshake.map(move |(sid, server_handshake, server_nodeid, transport)| {
+            let (requests, receiver) = mpsc::unbounded();
+            let next_light_id = Arc::new(AtomicUsize::new(id.next().0 as usize));
+            let mut server_cons = BTreeMap::new();
+            server_cons.insert(sid, ServerLightConnection::Established(server_nodeid));
+            let multiplexer = Multiplexer {
+                transport: transport,
+                pending: Vec::new(),
+                requests: receiver,
+                requests_closed: false,
+                drg: drg_seed + 1,
+                next_light_id: next_light_id.clone(),
+                client_cons: BTreeMap::new(),
+                server_cons: server_cons,
+                map_to_client: BTreeMap::new(),
+                listener: None,
+            };
+            handle.spawn(multiplexer.map_err(|err| error!("connection failed: {:?}", err)));
+            Connection { requests: requests, next_light_id: next_light_id, peer_handshake: server_handshake }
+        }))
+    }
+
+    /// the handshake the peer sent us
+    pub fn get_peer_handshake(&self) -> &Handshake {
+        &self.peer_handshake
+    }
+
+    /// open a new light connection
+    pub fn open(&self) -> Result<LightConnection> {
+        let id = LightId::new(self.next_light_id.fetch_add(1, Ordering::SeqCst) as u32);
+        let (sender, received) = mpsc::unbounded();
+        self.requests.unbounded_send(Request::Open(id, sender)).map_err(|_| Error::ConnectionClosed)?;
+        Ok(LightConnection { id: id, requests: self.requests.clone(), received: received, eos: false })
+    }
+
+    // open a light connection and send the given message on it
+    fn request(&self, msg: (u8, Vec<u8>)) -> Result<LightConnection> {
+        check_peer_supports(&self.peer_handshake, msg.0 as packet::MessageCode)?;
+        let light = self.open()?;
+        light.send_bytes(&[msg.0])?;
+        light.send_bytes(&msg.1)?;
+        Ok(light)
+    }
+
+    /// get the conversations initiated by the peer, replacing the
+    /// previous listener if any
+    pub fn conversations(&self) -> Result<Conversations> {
+        let (sender, accepted) = mpsc::unbounded();
+        self.requests.unbounded_send(Request::Listen(sender)).map_err(|_| Error::ConnectionClosed)?;
+        Ok(Conversations { requests: self.requests.clone(), accepted: accepted })
+    }
+
+    /// get the block headers from `to` (or the tip) back to the most recent
+    /// of the given checkpoints
+    pub fn get_block_headers(&self, from: &[blockchain::HeaderHash], to: Option<blockchain::HeaderHash>) -> Box<Future<Item=blockchain::RawBlockHeaderMultiple, Error=Error>> {
+        let light = match self.request(packet::send_msg_getheaders(from, &to)) {
+            Err(err) => return Box::new(future::err(err)),
+            Ok(light) => light,
+        };
+        Box::new(light.into_future().map_err(|(err, _)| err).and_then(|(msg, _)| {
+            match msg {
+                None => Err(Error::ConnectionClosed),
+                Some(msg) => strip_msg_response(&msg).map(blockchain::RawBlockHeaderMultiple::from_dat),
+            }
+        }))
+    }
+
+    /// get the blocks between the given hashes, both included
+    pub fn get_blocks(&self, from: &blockchain::HeaderHash, to: &blockchain::HeaderHash) -> Box<Future<Item=Vec<blockchain::RawBlock>, Error=Error>> {
+        let light = match self.request(packet::send_msg_getblocks(from, to)) {
+            Err(err) => return Box::new(future::err(err)),
+            Ok(light) => light,
+        };
+        Box::new(light.collect().and_then(|msgs| {
+            msgs.iter().map(|msg| strip_msg_response(msg).map(blockchain::RawBlock::from_dat)).collect::<Result<Vec<_>>>()
+        }))
+    }
+
+    /// subscribe to the new block headers announced by the peer
+    ///
+    /// a keep-alive is sent every `keep_alive`.
+    pub fn subscribe(&self, handle: &Handle, keep_alive: Duration) -> Result<Subscription> {
+        let conversations = self.conversations()?;
+        let light = self.request(packet::send_msg_subscribe(true))?;
+        Ok(Subscription {
+            light: light,
+            conversations: conversations,
+            keep_alive: Interval::new(keep_alive, handle)?,
+            pending: Vec::new(),
+        })
+    }
+}
+
+/// the stream of the conversations initiated by the peer, see
+/// `Connection::conversations`
+pub struct Conversations {
+    requests: UnboundedSender<Request>,
+    accepted: UnboundedReceiver<(LightId, UnboundedReceiver<Event>)>,
+}
+impl Stream for Conversations {
+    type Item = LightConnection;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<LightConnection>, ()> {
+        match self.accepted.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some((id, received))) => {
+                Ok(Async::Ready(Some(LightConnection { id: id, requests: self.requests.clone(), received: received, eos: false })))
+            },
+        }
+    }
+}
+
+// here we unwrap the CBOR of Array(2, [uint(0), something]) to something
+fn strip_msg_response(msg: &[u8]) -> Result<Vec<u8>> {
+    match decode_sum_type(msg) {
+        Some((0, dat)) => Ok(dat.to_vec()),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+/// the stream of the headers announced by the peer, the most recent
+/// header first, see `Connection::subscribe`
+pub struct Subscription {
+    light: LightConnection,
+    conversations: Conversations,
+    keep_alive: Interval,
+    // the conversations of the peer and the messages received so far
+    pending: Vec<(LightConnection, Vec<Vec<u8>>)>,
+}
+impl Stream for Subscription {
+    type Item = Vec<blockchain::BlockHeader>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        while let Async::Ready(Some(())) = self.keep_alive.poll()? {
+            trace!("subscription {}: sending keep-alive", self.light.get_id());
+            self.light.send_bytes(&packet::send_msg_subscribe(true).1)?;
+        }
+        // the peer does not send anything on the subscription itself but closing it
+        if let Async::Ready(None) = self.light.poll()? {
+            return Ok(Async::Ready(None))
+        }
+        loop {
+            match self.conversations.poll() {
+                Ok(Async::Ready(Some(light))) => self.pending.push((light, Vec::new())),
+                Ok(Async::Ready(None)) | Err(()) => return Err(Error::ConnectionClosed),
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            let msg = match self.pending[i].0.poll()? {
+                Async::NotReady => { i += 1; continue },
+                Async::Ready(None) => { self.pending.remove(i); continue },
+                Async::Ready(Some(msg)) => msg,
+            };
+            self.pending[i].1.push(msg);
+            let msgs = &self.pending[i].1;
+            if msgs.len() == 1 {
+                let code = packet::decode_msg_code(&msgs[0]);
+                if code != Some(packet::MsgType::MsgHeaders.to_u8()) {
+                    debug!("subscription: ignoring conversation of message code {:?}", code);
+                    self.pending.remove(i);
+                }
+                continue;
+            }
+            let response = RawCbor::from(&msgs[1]).deserialize();
+            self.pending.remove(i);
+            match response {
+                Ok(packet::BlockHeaderResponse::Ok(headers)) => return Ok(Async::Ready(Some(headers))),
+                Ok(packet::BlockHeaderResponse::Err(err)) => debug!("subscription: peer announced no headers: {}", err),
+                Err(err) => return Err(Error::from(err)),
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::{Listener, Reply};
+    use std::{thread, sync::mpsc as std_mpsc, time::Instant};
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn concurrent_get_headers() {
+        let mut listener = Listener::bind("127.0.0.1:0", Handshake::default()).unwrap();
+        listener.register(0x4, |reply: &mut Reply, _msg: &[u8]| {
+            // an empty list of headers
+            reply.send_bytes(&[0x82, 0x00, 0x80])?;
+            reply.close();
+            Ok(())
+        });
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || { let _ = listener.serve(); });
+
+        let mut core = Core::new().unwrap();
+        let connection = core.run(Connection::connect(&core.handle(), 0, &addr, &Handshake::default())).unwrap();
+        let requests = future::join_all((0..8).map(|_| connection.get_block_headers(&[], None)));
+        let responses = core.run(requests).unwrap();
+        assert_eq!(responses.len(), 8);
+        for headers in responses {
+            assert!(headers.decode().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn task_ends_once_dropped() {
+        let mut listener = Listener::bind("127.0.0.1:0", Handshake::default()).unwrap();
+        listener.register(0x4, |reply: &mut Reply, _msg: &[u8]| {
+            reply.send_bytes(&[0x82, 0x00, 0x80])?;
+            reply.close();
+            Ok(())
+        });
+        let addr = listener.local_addr().unwrap();
+        let (closed, server_closed) = std_mpsc::channel();
+        thread::spawn(move || {
+            let (_, mut server) = listener.accept().unwrap();
+            // ends when the multiplexer drops the stream
+            let _ = server.run();
+            closed.send(()).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let connection = core.run(Connection::connect(&core.handle(), 0, &addr, &Handshake::default())).unwrap();
+        let conversations = connection.conversations().unwrap();
+        core.run(connection.get_block_headers(&[], None)).unwrap();
+        drop(conversations);
+        drop(connection);
+
+        let start = Instant::now();
+        while server_closed.try_recv().is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5), "the multiplexer is still running");
+            core.turn(Some(Duration::from_millis(10)));
+        }
+    }
+}
@@ -5,6 +5,10 @@ pub type LightweightConnectionId = u32;
 
 pub const LIGHT_ID_MIN : u32 = 1024;
 
+// the longest endpoint address we accept from a peer during the handshake
+const MAX_ENDPOINT_LENGTH : u32 = 1024;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct EndPoint(Vec<u8>);
 impl AsRef<[u8]> for EndPoint {
     fn as_ref(&self) -> &[u8] { &self.0 }
@@ -14,6 +18,10 @@ impl EndPoint {
     pub fn unaddressable() -> Self {
         EndPoint(vec![])
     }
+
+    pub fn is_unaddressable(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Debug)]
@@ -62,6 +70,54 @@ impl<W: Sized+Write+Read> Connection<W> {
         }
     }
 
+    /// answer the handshake of a peer connecting to us
+    ///
+    /// the peer's protocol version is checked against ours and answered with
+    /// `UnsupportedVersion` on mismatch. `crossed` is given the endpoint
+    /// the peer connects from and tells if we are connecting to this very
+    /// endpoint at the same time, in which case the request is answered
+    /// with `CrossedRequest`. On success, the peer's endpoint is returned
+    /// along with the connection.
+    pub fn accept<F>(stream: W, crossed: F) -> Result<(Self, EndPoint)>
+        where F: FnOnce(&EndPoint) -> bool
+    {
+        trace!("receiving initial handshake");
+        // the accepting side only acknowledges node ids, it never creates any
+        let mut conn = Connection { stream: stream, drg: 0, debug: false };
+        let version = conn.recv_u32()?;
+        let handshake_length = conn.recv_u32()?;
+        if version != protocol::PROTOCOL_VERSION {
+            // the remaining of the handshake is of unknown format, skip it
+            conn.recv_len(handshake_length)?;
+            conn.reply_handshake(protocol::HandshakeResponse::UnsupportedVersion)?;
+            return Err(Error::UnsupportedVersion);
+        }
+
+        // our own initiator does not fill the handshake length (see
+        // `protocol::handshake`) so it is only checked when not 0.
+        let _endpoint_id = conn.recv_u32()?;
+        let endpoint_length = conn.recv_u32()?;
+        if endpoint_length > MAX_ENDPOINT_LENGTH
+            || (handshake_length != 0 && handshake_length != 8 + endpoint_length) {
+            conn.reply_handshake(protocol::HandshakeResponse::InvalidRequest)?;
+            return Err(Error::InvalidRequest);
+        }
+        let endpoint = EndPoint(conn.recv_len(endpoint_length)?);
+
+        if crossed(&endpoint) {
+            conn.reply_handshake(protocol::HandshakeResponse::CrossedRequest)?;
+            return Err(Error::CrossedRequest);
+        }
+        conn.reply_handshake(protocol::HandshakeResponse::Accepted)?;
+        Ok((conn, endpoint))
+    }
+
+    fn reply_handshake(&mut self, response: protocol::HandshakeResponse) -> Result<()> {
+        let mut buf = vec![];
+        protocol::handshake_response(response, &mut buf);
+        self.emit("handshake response", &buf)
+    }
+
     pub fn get_nonce(&mut self) -> protocol::Nonce {
         let v = self.drg;
         self.drg += 1;
@@ -157,7 +213,7 @@ impl<W: Sized+Write+Read> Connection<W> {
 pub mod protocol {
     use std::{fmt};
     use wallet_crypto::util::{hex};
-    const PROTOCOL_VERSION : u32 = 0x00000000;
+    pub const PROTOCOL_VERSION : u32 = 0x00000000;
 
     #[derive(Debug)]
     pub enum ControlHeader {
@@ -216,8 +272,8 @@ pub mod protocol {
 
     impl NodeId {
         pub fn from_slice(slice: &[u8]) -> Option<Self> {
-            if slice[0] != NODEID_SYN && slice[0] != NODEID_ACK { return None }
             if slice.len() != 9 { return None }
+            if slice[0] != NODEID_SYN && slice[0] != NODEID_ACK { return None }
             let mut buf = [0u8;9];
             buf.clone_from_slice(slice);
             Some(NodeId(buf))
@@ -234,6 +290,10 @@ pub mod protocol {
             if self.0[0] == NODEID_ACK { NodeControlHeader::Syn } else { NodeControlHeader::Ack }
         }
 
+        pub fn is_syn(&self) -> bool {
+            self.0[0] == NODEID_SYN
+        }
+
         // check if a SYN nodeid match a specific ACK nodeid
         pub fn match_ack(&self, ack_nodeid: &NodeId) -> bool {
             assert!(self.0[0] == NODEID_SYN);
@@ -243,16 +303,16 @@ pub mod protocol {
         // Given a ACK nodeid, get the equivalent SYN nodeid
         pub fn ack_to_syn(&self) -> Self {
             assert!(self.0[0] == NODEID_ACK);
-            let nodeid = self.clone();
-            nodeid.0[0] == NODEID_SYN;
+            let mut nodeid = self.clone();
+            nodeid.0[0] = NODEID_SYN;
             nodeid
         }
 
         // Given a SYN nodeid, get the equivalent ACK nodeid
         pub fn syn_to_ack(&self) -> Self {
             assert!(self.0[0] == NODEID_SYN);
-            let nodeid = self.clone();
-            nodeid.0[0] == NODEID_ACK;
+            let mut nodeid = self.clone();
+            nodeid.0[0] = NODEID_ACK;
             nodeid
         }
     }
@@ -267,6 +327,28 @@ pub mod protocol {
         //append_u32(0, buf); // ignored but should be handshake length
     }
 
+    /// the answer of the server to the initial handshake of a client
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum HandshakeResponse {
+        Accepted,
+        InvalidRequest,
+        CrossedRequest,
+        UnsupportedVersion,
+    }
+
+    pub fn handshake_response(response: HandshakeResponse, buf: &mut Vec<u8>) {
+        match response {
+            HandshakeResponse::Accepted       => append_u32(0x00000000, buf),
+            HandshakeResponse::InvalidRequest => append_u32(0x00000001, buf),
+            HandshakeResponse::CrossedRequest => append_u32(0x00000002, buf),
+            HandshakeResponse::UnsupportedVersion => {
+                // followed by the highest version we support
+                append_u32(0xffffffff, buf);
+                append_u32(PROTOCOL_VERSION, buf);
+            },
+        }
+    }
+
     /// encode an int32
     /*
     fn append_i32(v: i32, buf: &mut Vec<u8>) {
@@ -6,12 +6,13 @@ use blockchain::{HeaderHash};
 
 use raw_cbor::{self, se, de::{self, RawCbor}};
 
-type MessageCode = u32;
+pub type MessageCode = u32;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct HandlerSpec(u16);
 impl HandlerSpec {
     pub fn new(c: u16) -> Self { HandlerSpec(c) }
+    pub fn get(&self) -> u16 { self.0 }
 }
 impl fmt::Display for HandlerSpec {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -48,6 +49,15 @@ impl de::Deserialize for HandlerSpec {
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct HandlerSpecs(BTreeMap<MessageCode, HandlerSpec>);
 impl HandlerSpecs {
+    pub fn new() -> Self { HandlerSpecs(BTreeMap::new()) }
+
+    pub fn insert(&mut self, code: MessageCode, spec: HandlerSpec) { self.0.insert(code, spec); }
+
+    /// tell if there is a handler for the messages of the given code
+    pub fn supports(&self, code: MessageCode) -> bool { self.0.contains_key(&code) }
+
+    pub fn iter(&self) -> ::std::collections::btree_map::Iter<MessageCode, HandlerSpec> { self.0.iter() }
+
     pub fn default_ins() -> Self {
         let mut bm = BTreeMap::new();
         bm.insert(0x04,  HandlerSpec::new(0x05));
@@ -175,6 +185,7 @@ type Message = (u8, Vec<u8>);
 pub enum MsgType {
     MsgSubscribe,
     MsgGetHeaders,
+    MsgHeaders,
     MsgGetBlocks,
 }
 
@@ -183,11 +194,23 @@ impl MsgType {
         match self {
             MsgType::MsgSubscribe => 0xe,
             MsgType::MsgGetHeaders => 0x4,
+            MsgType::MsgHeaders => 0x5,
             MsgType::MsgGetBlocks => 0x6,
         }
     }
 }
 
+/// decode the message code sent first on a conversation
+///
+/// the code is a CBOR unsigned integer, so a single byte for the codes
+/// below 24 but two bytes for the others.
+pub fn decode_msg_code(bytes: &[u8]) -> Option<u8> {
+    match RawCbor::from(bytes).unsigned_integer() {
+        Ok(code) if code <= 0xff => Some(code as u8),
+        _ => None,
+    }
+}
+
 pub fn send_msg_subscribe(keep_alive: bool) -> Message {
     let value = if keep_alive { 43 } else { 42 };
     let dat = se::Serializer::new().write_unsigned_integer(value).unwrap().finalize();
@@ -216,6 +239,48 @@ pub fn send_msg_getblocks(from: &HeaderHash, to: &HeaderHash) -> Message {
     (0x6, dat)
 }
 
+/// the content of a `MsgGetHeaders` as received by the responder
+#[derive(Debug, Clone)]
+pub struct GetHeaders {
+    pub from: Vec<HeaderHash>,
+    pub to: Option<HeaderHash>,
+}
+impl de::Deserialize for GetHeaders {
+    fn deserialize<'a>(raw: &mut RawCbor<'a>) -> raw_cbor::Result<Self> {
+        let len = raw.array()?;
+        if len != raw_cbor::Len::Len(2) {
+            return Err(raw_cbor::Error::CustomError(format!("Invalid GetHeaders: recieved array of {:?} elements", len)));
+        }
+        let from = raw.deserialize()?;
+        let to = match raw.array()? {
+            raw_cbor::Len::Len(0) => None,
+            raw_cbor::Len::Len(1) => Some(raw.deserialize()?),
+            len => {
+                return Err(raw_cbor::Error::CustomError(format!("Invalid GetHeaders: recieved `to' of {:?} elements", len)));
+            }
+        };
+        Ok(GetHeaders { from: from, to: to })
+    }
+}
+
+/// the content of a `MsgGetBlocks` as received by the responder
+#[derive(Debug, Clone)]
+pub struct GetBlocks {
+    pub from: HeaderHash,
+    pub to: HeaderHash,
+}
+impl de::Deserialize for GetBlocks {
+    fn deserialize<'a>(raw: &mut RawCbor<'a>) -> raw_cbor::Result<Self> {
+        let len = raw.array()?;
+        if len != raw_cbor::Len::Len(2) {
+            return Err(raw_cbor::Error::CustomError(format!("Invalid GetBlocks: recieved array of {:?} elements", len)));
+        }
+        let from = raw.deserialize()?;
+        let to = raw.deserialize()?;
+        Ok(GetBlocks { from: from, to: to })
+    }
+}
+
 #[derive(Debug)]
 pub enum BlockHeaderResponse {
     Ok(Vec<blockchain::BlockHeader>),
@@ -236,6 +301,19 @@ impl fmt::Display for BlockHeaderResponse {
         write!(f, "")
     }
 }
+impl se::Serialize for BlockHeaderResponse {
+    fn serialize(&self, serializer: se::Serializer) -> raw_cbor::Result<se::Serializer> {
+        let serializer = serializer.write_array(raw_cbor::Len::Len(2))?;
+        match self {
+            &BlockHeaderResponse::Ok(ref headers) => {
+                se::serialize_indefinite_array(headers.iter(), serializer.write_unsigned_integer(0)?)
+            },
+            &BlockHeaderResponse::Err(ref err) => {
+                serializer.write_unsigned_integer(1)?.write_text(err)
+            },
+        }
+    }
+}
 impl de::Deserialize for BlockHeaderResponse {
     fn deserialize<'a>(raw: &mut RawCbor<'a>) -> raw_cbor::Result<Self> {
         let len = raw.array()?;
@@ -259,7 +337,33 @@ impl de::Deserialize for BlockHeaderResponse {
 
 #[derive(Debug)]
 pub enum BlockResponse {
-    Ok(blockchain::Block)
+    Ok(blockchain::Block),
+    NoBlock(String),
+}
+impl se::Serialize for BlockResponse {
+    fn serialize(&self, serializer: se::Serializer) -> raw_cbor::Result<se::Serializer> {
+        let serializer = serializer.write_array(raw_cbor::Len::Len(2))?;
+        match self {
+            &BlockResponse::Ok(ref block) => {
+                serializer.write_unsigned_integer(0)?.serialize(block)
+            },
+            &BlockResponse::NoBlock(ref err) => {
+                serializer.write_unsigned_integer(1)?.write_text(err)
+            },
+        }
+    }
+}
+
+/// encode a `BlockResponse::Ok` of the given block, as it is stored
+///
+/// this avoids decoding and re-encoding the block when it is served
+/// from the storage.
+pub fn raw_block_response(block: &blockchain::RawBlock) -> Vec<u8> {
+    let mut dat = se::Serializer::new().write_array(raw_cbor::Len::Len(2)).unwrap()
+        .write_unsigned_integer(0).unwrap()
+        .finalize();
+    dat.extend_from_slice(block.as_ref());
+    dat
 }
 impl de::Deserialize for BlockResponse {
     fn deserialize<'a>(raw: &mut RawCbor<'a>) -> raw_cbor::Result<Self> {
@@ -272,6 +376,9 @@ impl de::Deserialize for BlockResponse {
             0 => {
                 Ok(BlockResponse::Ok(raw.deserialize()?))
             },
+            1 => {
+                Ok(BlockResponse::NoBlock(raw.text()?))
+            },
             _ => {
                 return Err(raw_cbor::Error::CustomError(format!("Invalid BlockHeaderResponse: recieved sumtype of {}", sum_type)));
             }
@@ -336,6 +443,13 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_get_block_headers_response() {
+        let b : BlockHeaderResponse = RawCbor::from(GET_BLOCK_HEADER_BYTES).deserialize().unwrap();
+        let vec = cbor!(&b).unwrap();
+        assert_eq!(GET_BLOCK_HEADER_BYTES, vec.as_slice());
+    }
+
     const HANDSHAKE_BYTES : &'static [u8] = &[
         0x84, 0x1a, 0x2d, 0x96, 0x4a, 0x09, 0x83, 0x00
       , 0x01, 0x00, 0xb3, 0x04, 0x82, 0x00, 0xd8, 0x18, 0x41, 0x05, 0x05, 0x82, 0x00, 0xd8, 0x18, 0x41
@@ -1,12 +1,17 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::{Read, Write};
+use std::net::{TcpStream};
+use std::time::{Duration};
 use std::{io, fmt, result};
 
+use blockchain;
 use packet;
 use packet::{Handshake};
 use ntt;
+use mux;
 
 use raw_cbor::{self, de::{RawCbor}};
+use wallet_crypto::config::{ProtocolMagic};
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,6 +22,37 @@ pub enum Error {
     UnsupportedControl(ntt::protocol::ControlHeader),
     NodeIdNotFound(ntt::protocol::NodeId),
     ClientIdNotFoundFromNodeId(ntt::protocol::NodeId, LightId),
+    ClientCreatedLightIdTwice(LightId),
+    UnexpectedCommand(ntt::protocol::Command),
+    InvalidNodeId(LightId),
+    NodeIdMismatch(ntt::protocol::NodeId, ntt::protocol::NodeId),
+    LightIdNotFound(LightId),
+    InvalidMessageCode(LightId),
+    UnexpectedFrame(mux::Frame),
+    UnexpectedResponse,
+    /// the peer answered the request with an error message
+    PeerError(String),
+    /// the peer is on another network (expected, received)
+    ProtocolMagicMismatch(ProtocolMagic, ProtocolMagic),
+    /// the peer talks an incompatible version of the protocol (expected,
+    /// received), see `blockchain::Version::is_compatible`
+    ProtocolVersionMismatch(blockchain::Version, blockchain::Version),
+    /// the peer does not handle the messages of the given code
+    UnsupportedMessage(packet::MessageCode),
+    ConnectionClosed,
+}
+impl Error {
+    /// tell if the error is a network failure (as opposed to a protocol
+    /// violation or an error reported by the peer), the request may then
+    /// succeed on a new connection
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::IOError(_) => true,
+            Error::NttError(ntt::Error::IOError(_)) => true,
+            Error::ConnectionClosed => true,
+            _ => false,
+        }
+    }
 }
 impl From<raw_cbor::Error> for Error {
     fn from(e: raw_cbor::Error) -> Self { Error::ByteEncodingError(e) }
@@ -30,6 +66,25 @@ impl From<ntt::Error> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// check the handshake received from the peer against ours: both must be
+/// of the same network (protocol magic) and talk compatible versions of
+/// the protocol
+pub fn check_peer_handshake(ours: &Handshake, peer: &Handshake) -> Result<()> {
+    if ours.protocol_magic != peer.protocol_magic {
+        return Err(Error::ProtocolMagicMismatch(ours.protocol_magic, peer.protocol_magic));
+    }
+    if ! ours.version.is_compatible(&peer.version) {
+        return Err(Error::ProtocolVersionMismatch(ours.version, peer.version));
+    }
+    Ok(())
+}
+
+/// check the peer handles the messages of the given code, as announced
+/// in its handshake (`Handshake::in_handlers`)
+pub fn check_peer_supports(peer: &Handshake, code: packet::MessageCode) -> Result<()> {
+    if peer.in_handlers.supports(code) { Ok(()) } else { Err(Error::UnsupportedMessage(code)) }
+}
+
 /// Light ID create by the server or by the client
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub struct LightId(pub u32);
@@ -117,6 +172,29 @@ pub enum ServerLightConnection {
     Established(ntt::protocol::NodeId),
 }
 
+/// a transport able to wait for inbound data, so the idle connections
+/// send their keep-alives on time (see `command::Subscription`)
+pub trait WaitReadable {
+    /// wait up to `timeout` for inbound data, without consuming it.
+    /// Returns `false` if nothing arrived in time.
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool>;
+}
+impl WaitReadable for TcpStream {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let previous = self.read_timeout()?;
+        // a zero timeout is refused, it would mean no timeout
+        self.set_read_timeout(Some(::std::cmp::max(timeout, Duration::from_millis(1))))?;
+        let result = match self.peek(&mut [0u8]) {
+            // the end of the stream is readable too, the next read fails
+            Ok(_) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(err) => Err(err),
+        };
+        self.set_read_timeout(previous)?;
+        result
+    }
+}
+
 pub struct Connection<T> {
     ntt: ntt::Connection<T>,
     // this is a line of active connections open by the server/client
@@ -125,6 +203,11 @@ pub struct Connection<T> {
     client_cons: BTreeMap<LightId, LightConnection>,
     // this is for the server to map from its own nodeid to the client lightid
     map_to_client: BTreeMap<ntt::protocol::NodeId, LightId>,
+    // the client light connections acknowledging the conversations
+    // initiated by the server, not yet picked up with `pop_inbound`
+    inbound: VecDeque<LightId>,
+    // the handshake of the server, once connected
+    peer_handshake: Option<Handshake>,
     // potentialy the server close its connection before we have time
     // to process it on the client, so keep the buffer alive here
     //server_dones: BTreeMap<LightId, LightConnection>,
@@ -139,6 +222,13 @@ impl<T: Write+Read> Connection<T> {
         self.ntt.get_backend()
     }
 
+    /// wait up to `timeout` for a message of the peer, see `WaitReadable`
+    pub fn wait_readable(&self, timeout: Duration) -> Result<bool>
+        where T: WaitReadable
+    {
+        Ok(self.ntt.get_backend().wait_readable(timeout)?)
+    }
+
     // search for the next free LIGHT ID in the client connection map
     fn find_next_connection_id(&self) -> LightId {
         let mut x = LightId(ntt::LIGHT_ID_MIN);
@@ -160,6 +250,8 @@ impl<T: Write+Read> Connection<T> {
             server_cons: BTreeMap::new(),
             client_cons: BTreeMap::new(),
             map_to_client: BTreeMap::new(),
+            inbound: VecDeque::new(),
+            peer_handshake: None,
             //server_dones: BTreeMap::new(),
             next_light_id: LightId::new(0x401)
         }
@@ -182,33 +274,36 @@ impl<T: Write+Read> Connection<T> {
          */
         let siv = match self.ntt.recv()? {
             Command::Control(ControlHeader::CreatedNewConnection, cid) => { LightId::new(cid) },
-            _ => { unimplemented!() }
+            cmd => { return Err(Error::UnexpectedCommand(cmd)) }
         };
 
         fn data_recv_on<T: Read+Write>(con: &mut Connection<T>, expected_id: LightId) -> Result<Vec<u8>> {
             match con.ntt.recv()? {
-                ntt::protocol::Command::Data(cid, len) => {
-                    if cid == expected_id.0 {
-                        let bytes = con.ntt.recv_len(len)?;
-                        Ok(bytes)
-                    } else {
-                        unimplemented!()
-                    }
+                ntt::protocol::Command::Data(cid, len) if cid == expected_id.0 => {
+                    let bytes = con.ntt.recv_len(len)?;
+                    Ok(bytes)
                 }
-                _ => { unimplemented!() }
+                cmd => { Err(Error::UnexpectedCommand(cmd)) }
             }
         };
 
         let server_bytes_hs = data_recv_on(self, siv)?;
-        let _server_handshake : Handshake = RawCbor::from(&server_bytes_hs).deserialize()?;
+        let server_handshake : Handshake = RawCbor::from(&server_bytes_hs).deserialize()?;
+        debug!("peer handshake:\n{}", server_handshake);
+        check_peer_handshake(hs, &server_handshake)?;
+        self.peer_handshake = Some(server_handshake);
 
         let server_bytes_nodeid = data_recv_on(self, siv)?;
         let server_nodeid = match ntt::protocol::NodeId::from_slice(&server_bytes_nodeid[..]) {
-            None   => unimplemented!(),
+            None   => return Err(Error::InvalidNodeId(siv)),
             Some(nodeid) => nodeid,
         };
 
-        // TODO compare server_nodeid and client_id
+        // the server acknowledges the node id of our handshake connection
+        let client_nodeid = self.client_cons[&lcid].node_id;
+        if ! client_nodeid.match_ack(&server_nodeid) {
+            return Err(Error::NodeIdMismatch(client_nodeid, server_nodeid));
+        }
 
         let _scon = LightConnection::new_expecting_nodeid(siv, server_nodeid);
         self.server_cons.insert(siv, ServerLightConnection::Established(server_nodeid));
@@ -216,6 +311,20 @@ impl<T: Write+Read> Connection<T> {
         Ok(())
     }
 
+    /// the handshake the server sent us, once connected
+    pub fn get_peer_handshake(&self) -> Option<&Handshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// check the server handles the messages of the given code (see
+    /// `check_peer_supports`), any message is allowed before the handshake
+    pub fn check_peer_supports(&self, code: packet::MessageCode) -> Result<()> {
+        match self.peer_handshake {
+            None => Ok(()),
+            Some(ref hs) => check_peer_supports(hs, code),
+        }
+    }
+
     pub fn new_light_connection(&mut self, id: LightId) -> Result<()> {
         self.ntt.create_light(id.0)?;
 
@@ -225,9 +334,19 @@ impl<T: Write+Read> Connection<T> {
         Ok(())
     }
 
-    pub fn close_light_connection(&mut self, id: LightId) {
+    pub fn close_light_connection(&mut self, id: LightId) -> Result<()> {
         self.client_cons.remove(&id);
-        self.ntt.close_light(id.0).unwrap();
+        self.ntt.close_light(id.0)?;
+        Ok(())
+    }
+
+    /// get the next conversation initiated by the server, if any
+    ///
+    /// the returned light connection receives the messages of the server
+    /// (starting with the message code), use `wait_msg` to read them and
+    /// `close_light_connection` once done.
+    pub fn pop_inbound(&mut self) -> Option<LightId> {
+        self.inbound.pop_front()
     }
 
     pub fn has_bytes_to_read_or_finish(&self, id: LightId) -> bool {
@@ -243,10 +362,10 @@ impl<T: Write+Read> Connection<T> {
         }
 
         match self.client_cons.get_mut(&id) {
-            None => panic!("oops"),
+            None => Err(Error::LightIdNotFound(id)),
             Some(ref mut con) => {
                 match con.pop_received() {
-                    None => panic!("oops 2"),
+                    None => Err(Error::ConnectionClosed),
                     Some(yy) => Ok(yy),
                 }
             },
@@ -262,10 +381,11 @@ impl<T: Write+Read> Connection<T> {
             }
 
             match self.client_cons.get_mut(&id) {
-                None => panic!("oops"),
+                None => return Err(Error::LightIdNotFound(id)),
                 Some(ref mut con) => {
                     match con.pop_received() {
-                        None => { if con.eos { return Ok(r) } else { panic!("oops 2") } },
+                        // the data is checked first, the end of stream is then the only case left
+                        None => return Ok(r),
                         Some(yy) => r.push(yy),
                     }
                 },
@@ -287,7 +407,7 @@ impl<T: Write+Read> Connection<T> {
     // TODO return some kind of opaque token
     pub fn send_bytes_ack(&mut self, id: LightId, bytes: &[u8]) -> Result<ntt::protocol::NodeId> {
         match self.client_cons.get(&id) {
-            None => panic!("send bytes ack ERROR. connection doesn't exist"),
+            None => Err(Error::LightIdNotFound(id)),
             Some(con) => {
                 self.ntt.light_send_data(id.0, bytes)?;
                 Ok(con.node_id)
@@ -367,7 +487,7 @@ impl<T: Write+Read> Connection<T> {
                                 match self.client_cons.get_mut(client_id) {
                                     None => Err(Error::ClientIdNotFoundFromNodeId(nodeid, *client_id)),
                                     Some(con) => {
-                                        let bytes = self.ntt.recv_len(len).unwrap();
+                                        let bytes = self.ntt.recv_len(len)?;
                                         con.add_to_receive(&bytes);
                                         Ok(())
                                     }
@@ -380,9 +500,9 @@ impl<T: Write+Read> Connection<T> {
                     // if matching, then we remove the establishing server connection and
                     // add a established connection and setup the routing to the client
                     Some(ServerLightConnection::Establishing) => {
-                        let bytes = self.ntt.recv_len(len).unwrap();
+                        let bytes = self.ntt.recv_len(len)?;
                         let nodeid = match ntt::protocol::NodeId::from_slice(&bytes[..]) {
-                            None         => panic!("ERROR: expecting nodeid but receive stuff"),
+                            None         => return Err(Error::InvalidNodeId(id)),
                             Some(nodeid) => nodeid,
                         };
 
@@ -390,6 +510,18 @@ impl<T: Write+Read> Connection<T> {
                         self.server_cons.remove(&id);
                         self.server_cons.insert(id, ServerLightConnection::Established(nodeid.clone()));
 
+                        if nodeid.is_syn() {
+                            // the server initiates a conversation: acknowledge it on
+                            // a light connection of ours which receives the data
+                            let lcid = self.get_free_light_id();
+                            self.ntt.create_light(lcid.0)?;
+                            self.send_nodeid(lcid, &nodeid.syn_to_ack())?;
+                            self.client_cons.insert(lcid, LightConnection::new_expecting_nodeid(lcid, nodeid));
+                            self.map_to_client.insert(nodeid, lcid);
+                            self.inbound.push_back(lcid);
+                            return Ok(());
+                        }
+
                         match self.client_cons.iter().find(|&(_,v)| v.node_id.match_ack(&nodeid)) {
                             None        => { Ok(()) },
                             Some((z,_)) => {
@@ -410,24 +542,30 @@ impl<T: Write+Read> Connection<T> {
 
 pub mod command {
     use std::io::{Read, Write};
-    use super::{LightId, Connection};
+    use std::time::{Duration, Instant};
+    use super::{LightId, Connection, Error, Result, WaitReadable};
     use wallet_crypto::cbor::hs::util::decode_sum_type;
+    use raw_cbor::de::{RawCbor};
     use blockchain;
     use packet;
 
+    fn new_light_connection<W: Read+Write>(connection: &mut Connection<W>) -> Result<LightId> {
+        let id = connection.get_free_light_id();
+        trace!("creating light connection: {}", id);
+
+        connection.new_light_connection(id)?;
+        Ok(id)
+    }
+
     pub trait Command<W: Read+Write> {
         type Output;
-        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<(), &'static str>;
-        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output, &'static str>;
-
-        fn initial(&self, connection: &mut Connection<W>) -> Result<LightId, &'static str> {
-            let id = connection.get_free_light_id();
-            trace!("creating light connection: {}", id);
+        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<()>;
+        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output>;
 
-            connection.new_light_connection(id).unwrap();
-            Ok(id)
+        fn initial(&self, connection: &mut Connection<W>) -> Result<LightId> {
+            new_light_connection(connection)
         }
-        fn execute(&self, connection: &mut Connection<W>) -> Result<Self::Output, &'static str> {
+        fn execute(&self, connection: &mut Connection<W>) -> Result<Self::Output> {
             let id = Command::initial(self, connection)?;
 
             Command::command(self, connection, id)?;
@@ -437,9 +575,8 @@ pub mod command {
 
             Ok(ret)
         }
-        fn terminate(&self, connection: &mut Connection<W>, id: LightId) -> Result<(), &'static str> {
-            connection.close_light_connection(id);
-            Ok(())
+        fn terminate(&self, connection: &mut Connection<W>, id: LightId) -> Result<()> {
+            connection.close_light_connection(id)
         }
     }
 
@@ -461,27 +598,21 @@ pub mod command {
 
     impl<W> Command<W> for GetBlockHeader where W: Read+Write {
         type Output = blockchain::RawBlockHeaderMultiple;
-        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<(), &'static str> {
+        fn initial(&self, connection: &mut Connection<W>) -> Result<LightId> {
+            connection.check_peer_supports(packet::MsgType::MsgGetHeaders.to_u8() as packet::MessageCode)?;
+            new_light_connection(connection)
+        }
+        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<()> {
             let (get_header_id, get_header_dat) = packet::send_msg_getheaders(&self.from[..], &self.to);
-            connection.send_bytes(id, &[get_header_id]).unwrap();
-            connection.send_bytes(id, &get_header_dat[..]).unwrap();
+            connection.send_bytes(id, &[get_header_id])?;
+            connection.send_bytes(id, &get_header_dat[..])?;
             Ok(())
         }
-        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output, &'static str> {
+        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output> {
             // require the initial header
-            let dat = connection.wait_msg(id).unwrap();
-            match decode_sum_type(&dat) {
-                None => Err("message block decoder failed with something unexpected"),
-                Some((sumval, dat)) => {
-                    if sumval == 0 {
-                        let mut v = Vec::new();
-                        v.extend_from_slice(dat);
-                        Ok(blockchain::RawBlockHeaderMultiple::from_dat(v))
-                    } else {
-                        Err("message block decoder failed with something unexpected")
-                    }
-                },
-            }
+            let dat = connection.wait_msg(id)?;
+            let dat = strip_msg_response(&dat)?;
+            Ok(blockchain::RawBlockHeaderMultiple::from_dat(dat))
         }
     }
 
@@ -495,41 +626,152 @@ pub mod command {
         pub fn from(from: &blockchain::HeaderHash, to: &blockchain::HeaderHash) -> Self { GetBlock { from: from.clone(), to: to.clone() } }
     }
 
-    fn strip_msg_response(msg: &[u8]) -> Result<blockchain::RawBlock, &'static str> {
+    fn strip_msg_response(msg: &[u8]) -> Result<Vec<u8>> {
         // here we unwrap the CBOR of Array(2, [uint(0), something]) to something
+        // or Array(2, [uint(1), error message]) to the error of the peer
         match decode_sum_type(msg) {
-            None => Err("message block decoder failed with something unexpected"),
-            Some((sumval, dat)) => {
-                if sumval == 0 {
-                    let mut v = Vec::new();
-                    v.extend_from_slice(dat);
-                    Ok(blockchain::RawBlock::from_dat(v))
-                } else {
-                    Err("message block decoder failed with something unexpected")
-                }
-            },
+            Some((0, dat)) => Ok(dat.to_vec()),
+            Some((1, dat)) => Err(Error::PeerError(RawCbor::from(dat).text()?)),
+            _ => Err(Error::UnexpectedResponse),
         }
     }
 
     impl<W> Command<W> for GetBlock where W: Read+Write {
         type Output = Vec<blockchain::RawBlock>;
-        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<(), &'static str> {
+        fn initial(&self, connection: &mut Connection<W>) -> Result<LightId> {
+            connection.check_peer_supports(packet::MsgType::MsgGetBlocks.to_u8() as packet::MessageCode)?;
+            new_light_connection(connection)
+        }
+        fn command(&self, connection: &mut Connection<W>, id: LightId) -> Result<()> {
             // require the initial header
             let (get_header_id, get_header_dat) = packet::send_msg_getblocks(&self.from, &self.to);
-            connection.send_bytes(id, &[get_header_id]).unwrap();
-            connection.send_bytes(id, &get_header_dat[..]).unwrap();
+            connection.send_bytes(id, &[get_header_id])?;
+            connection.send_bytes(id, &get_header_dat[..])?;
             Ok(())
         }
 
-        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output, &'static str> {
-            let msg_response = connection.wait_msg_eos(id).unwrap();
+        fn result(&self, connection: &mut Connection<W>, id: LightId) -> Result<Self::Output> {
+            let msg_response = connection.wait_msg_eos(id)?;
             let mut msgs = Vec::new();
             for response in msg_response.iter() {
                 let msg = strip_msg_response(&response[..])?;
-                msgs.push(msg)
+                msgs.push(blockchain::RawBlock::from_dat(msg))
             }
             Ok(msgs)
         }
     }
 
+    /// the delay between two keep-alives of a subscription
+    pub const SUBSCRIPTION_KEEP_ALIVE : Duration = Duration::from_secs(20);
+
+    /// a subscription to the new block headers announced by the peer
+    ///
+    /// The subscription holds a light connection open on which keep-alives
+    /// are sent, while the peer announces the headers of the new blocks in
+    /// conversations of its own (`MsgHeaders`).
+    ///
+    /// A keep-alive is sent every `keep_alive`, the messages of the peer
+    /// being waited for no longer than the next keep-alive is due (see
+    /// `WaitReadable`), so an idle subscription is kept alive too.
+    #[derive(Debug)]
+    pub struct Subscription {
+        id: LightId,
+        keep_alive: Duration,
+        last_keep_alive: Instant,
+    }
+    impl Subscription {
+        /// open the subscription, sending a keep-alive every `keep_alive`
+        pub fn start<W: Read+Write>(connection: &mut Connection<W>, keep_alive: Duration) -> Result<Self> {
+            connection.check_peer_supports(packet::MsgType::MsgSubscribe.to_u8() as packet::MessageCode)?;
+            let id = new_light_connection(connection)?;
+
+            let (subscribe_id, subscribe_dat) = packet::send_msg_subscribe(true);
+            connection.send_bytes(id, &[subscribe_id])?;
+            connection.send_bytes(id, &subscribe_dat[..])?;
+            Ok(Subscription { id: id, keep_alive: keep_alive, last_keep_alive: Instant::now() })
+        }
+
+        fn keep_alive<W: Read+Write>(&mut self, connection: &mut Connection<W>) -> Result<()> {
+            if self.last_keep_alive.elapsed() >= self.keep_alive {
+                trace!("subscription {}: sending keep-alive", self.id);
+                let (_, dat) = packet::send_msg_subscribe(true);
+                connection.send_bytes(self.id, &dat[..])?;
+                self.last_keep_alive = Instant::now();
+            }
+            Ok(())
+        }
+
+        /// wait for the next headers announced by the peer, the most
+        /// recent header first
+        pub fn next_headers<W: Read+Write+WaitReadable>(&mut self, connection: &mut Connection<W>) -> Result<Vec<blockchain::BlockHeader>> {
+            loop {
+                self.keep_alive(connection)?;
+                if connection.client_cons.get(&self.id).map(|con| con.is_eos()).unwrap_or(true) {
+                    return Err(Error::ConnectionClosed);
+                }
+                let id = match connection.pop_inbound() {
+                    None => {
+                        let next_keep_alive = self.keep_alive.checked_sub(self.last_keep_alive.elapsed())
+                                                  .unwrap_or(Duration::from_secs(0));
+                        if connection.wait_readable(next_keep_alive)? {
+                            connection.process_messages()?;
+                        }
+                        continue;
+                    },
+                    Some(id) => id,
+                };
+
+                let code = packet::decode_msg_code(&connection.wait_msg(id)?);
+                if code != Some(packet::MsgType::MsgHeaders.to_u8()) {
+                    debug!("subscription: ignoring conversation of message code {:?}", code);
+                    connection.close_light_connection(id)?;
+                    continue;
+                }
+                let dat = connection.wait_msg(id)?;
+                connection.close_light_connection(id)?;
+                match RawCbor::from(&dat).deserialize()? {
+                    packet::BlockHeaderResponse::Ok(headers) => return Ok(headers),
+                    packet::BlockHeaderResponse::Err(err) => {
+                        debug!("subscription: peer announced no headers: {}", err);
+                    },
+                }
+            }
+        }
+
+        /// iterate over the headers announced by the peer
+        pub fn iter<'a, W: Read+Write+WaitReadable>(&'a mut self, connection: &'a mut Connection<W>) -> Headers<'a, W> {
+            Headers { subscription: self, connection: connection }
+        }
+
+        /// call `f` on every header announced by the peer, until it
+        /// returns `false`
+        pub fn for_each<W, F>(&mut self, connection: &mut Connection<W>, mut f: F) -> Result<()>
+            where W: Read+Write+WaitReadable
+                , F: FnMut(&blockchain::BlockHeader) -> bool
+        {
+            loop {
+                for header in self.next_headers(connection)?.iter().rev() {
+                    if ! f(header) { return Ok(()) }
+                }
+            }
+        }
+
+        /// close the subscription
+        pub fn stop<W: Read+Write>(self, connection: &mut Connection<W>) -> Result<()> {
+            connection.close_light_connection(self.id)
+        }
+    }
+
+    /// iterator over the headers announced to a subscription, see
+    /// `Subscription::iter`
+    pub struct Headers<'a, W: 'a> {
+        subscription: &'a mut Subscription,
+        connection: &'a mut Connection<W>,
+    }
+    impl<'a, W: Read+Write+WaitReadable> Iterator for Headers<'a, W> {
+        type Item = Result<Vec<blockchain::BlockHeader>>;
+        fn next(&mut self) -> Option<Self::Item> {
+            Some(self.subscription.next_headers(self.connection))
+        }
+    }
 }
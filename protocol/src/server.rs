@@ -0,0 +1,432 @@
+//! server side of the node-to-node protocol
+//!
+//! A `Listener` accepts inbound TCP connections and answers the ntt
+//! handshake and the node handshake. Then every light connection opened
+//! by the peer (a conversation) is acknowledged and dispatched to the
+//! `Handler` registered for the message code the peer sends first on it.
+//! The server opens conversations of its own with `Server::announce`,
+//! e.g. to announce the new headers to a subscription.
+//!
+//! ```no_run
+//! use protocol::{packet, server::{Listener, Reply}};
+//!
+//! let mut listener = Listener::bind("127.0.0.1:3000", packet::Handshake::default()).unwrap();
+//! listener.register(0x4, |reply: &mut Reply, _msg: &[u8]| {
+//!     reply.close();
+//!     Ok(())
+//! });
+//! listener.serve().unwrap();
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::{io, thread};
+
+use raw_cbor::de::{RawCbor};
+
+use ntt::{self, protocol::{ControlHeader, Command, NodeId}};
+use packet::{self, Handshake};
+use protocol::{LightId, Error, Result, check_peer_handshake};
+
+/// handle the messages of the conversations opened by the peer
+pub trait Handler {
+    /// handle one message of a conversation, `reply` sends data back
+    /// to the peer on this conversation.
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> Result<()>;
+}
+impl<F> Handler for F where F: Fn(&mut Reply, &[u8]) -> Result<()> {
+    fn handle(&self, reply: &mut Reply, msg: &[u8]) -> Result<()> {
+        self(reply, msg)
+    }
+}
+
+/// the registered handlers, indexed by message code
+pub type Handlers = BTreeMap<u8, Arc<Handler + Send + Sync>>;
+
+// what the handlers reply through, allows `Reply` not to depend on the
+// type of the underlying stream.
+trait Sink {
+    fn send(&mut self, id: LightId, bytes: &[u8]) -> Result<()>;
+}
+impl<T: Read+Write> Sink for ntt::Connection<T> {
+    fn send(&mut self, id: LightId, bytes: &[u8]) -> Result<()> {
+        self.light_send_data(id.0, bytes)?;
+        Ok(())
+    }
+}
+
+/// the replying side of a conversation
+pub struct Reply<'a> {
+    sink: &'a mut Sink,
+    id: LightId,
+    closed: bool,
+}
+impl<'a> Reply<'a> {
+    /// send a message to the peer
+    pub fn send_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        assert!(!self.closed);
+        self.sink.send(self.id, bytes)
+    }
+
+    /// end the conversation once the handler returns, the peer will not
+    /// receive anything else on it and its remaining messages are dropped.
+    pub fn close(&mut self) {
+        self.closed = true
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Conversation {
+    // the peer created the light connection, its node id is expected
+    Establishing,
+    // the node id has been acknowledged on our light connection, the
+    // message code is expected
+    Established(LightId),
+    // the messages are dispatched to the handler of the message code
+    Dispatching(LightId, u8),
+    // the conversation has been closed on our side
+    Closed,
+}
+
+/// a connection accepted from a peer
+pub struct Server<T> {
+    ntt: ntt::Connection<T>,
+    endpoint: ntt::EndPoint,
+    peer_handshake: Handshake,
+    handlers: Handlers,
+    conversations: BTreeMap<LightId, Conversation>,
+    // the node ids of the conversations we opened, until the peer
+    // acknowledges them
+    announcements: BTreeSet<NodeId>,
+    next_light_id: LightId,
+}
+
+// receive the next data of the given light connection, any other command
+// is unexpected at this stage.
+fn recv_data_on<T: Read+Write>(ntt: &mut ntt::Connection<T>, id: LightId) -> Result<Vec<u8>> {
+    match ntt.recv()? {
+        Command::Data(cid, len) if cid == id.0 => Ok(ntt.recv_len(len)?),
+        cmd => Err(Error::UnexpectedCommand(cmd)),
+    }
+}
+
+// parse the node id the peer sends when opening a light connection
+fn recv_syn_nodeid(id: LightId, bytes: &[u8]) -> Result<NodeId> {
+    match NodeId::from_slice(bytes) {
+        Some(ref nodeid) if nodeid.is_syn() => Ok(*nodeid),
+        _ => Err(Error::InvalidNodeId(id)),
+    }
+}
+
+impl<T: Read+Write> Server<T> {
+    /// answer the ntt handshake and the node handshake of a peer
+    ///
+    /// The peer opens a first light connection to send its `Handshake`
+    /// followed by its node id. We answer on a light connection of our own
+    /// with the given `Handshake` and the acknowledgement of the node id.
+    pub fn accept(stream: T, handshake: &Handshake, handlers: Handlers) -> Result<Self> {
+        // our outbound connections are unaddressable, the peer cannot be
+        // connecting to us while we are connecting to it.
+        let (mut ntt, endpoint) = ntt::Connection::accept(stream, |_| false)?;
+
+        let peer_id = match ntt.recv()? {
+            Command::Control(ControlHeader::CreatedNewConnection, cid) => LightId(cid),
+            cmd => return Err(Error::UnexpectedCommand(cmd)),
+        };
+        let bytes = recv_data_on(&mut ntt, peer_id)?;
+        let peer_handshake : Handshake = RawCbor::from(&bytes).deserialize()?;
+        let bytes = recv_data_on(&mut ntt, peer_id)?;
+        let nodeid = recv_syn_nodeid(peer_id, &bytes)?;
+        debug!("peer handshake:\n{}", peer_handshake);
+
+        let mut server = Server {
+            ntt: ntt,
+            endpoint: endpoint,
+            peer_handshake: peer_handshake,
+            handlers: handlers,
+            conversations: BTreeMap::new(),
+            announcements: BTreeSet::new(),
+            next_light_id: LightId::new(ntt::LIGHT_ID_MIN),
+        };
+        let id = server.get_free_light_id();
+        server.ntt.create_light(id.0)?;
+        server.ntt.light_send_data(id.0, &packet::send_handshake(handshake))?;
+        server.ntt.light_send_data(id.0, nodeid.syn_to_ack().as_ref())?;
+        // the peer is answered first for it to see the mismatch too
+        check_peer_handshake(handshake, &server.peer_handshake)?;
+        Ok(server)
+    }
+
+    pub fn get_backend(&self) -> &T {
+        self.ntt.get_backend()
+    }
+
+    /// the endpoint the peer announced during the ntt handshake
+    pub fn get_endpoint(&self) -> &ntt::EndPoint {
+        &self.endpoint
+    }
+
+    /// the handshake the peer sent us
+    pub fn get_peer_handshake(&self) -> &Handshake {
+        &self.peer_handshake
+    }
+
+    fn get_free_light_id(&mut self) -> LightId {
+        let id = self.next_light_id;
+        self.next_light_id = id.next();
+        id
+    }
+
+    /// whether the peer holds a conversation dispatched to the handler of
+    /// the given message code, e.g. a subscription
+    pub fn has_conversation(&self, code: u8) -> bool {
+        self.conversations.values().any(|conv| match *conv {
+            Conversation::Dispatching(_, c) => c == code,
+            _ => false,
+        })
+    }
+
+    /// open a conversation of our own, send it the given messages (the
+    /// first one being the message code) and close it
+    pub fn announce(&mut self, messages: &[&[u8]]) -> Result<()> {
+        let id = self.get_free_light_id();
+        let nodeid = NodeId::make_syn(self.ntt.get_nonce());
+        self.ntt.create_light(id.0)?;
+        self.ntt.light_send_data(id.0, nodeid.as_ref())?;
+        for msg in messages {
+            self.ntt.light_send_data(id.0, msg)?;
+        }
+        self.ntt.close_light(id.0)?;
+        self.announcements.insert(nodeid);
+        Ok(())
+    }
+
+    /// process the messages of the peer until it closes the connection
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            match self.process_message() {
+                Ok(true)  => {},
+                Ok(false) => return Ok(()),
+                Err(Error::NttError(ntt::Error::IOError(ref err)))
+                    if err.kind() == io::ErrorKind::UnexpectedEof || err.kind() == io::ErrorKind::ConnectionReset => {
+                    debug!("peer disconnected");
+                    return Ok(())
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// process one message from the peer
+    ///
+    /// returns `false` if the peer closed the connection.
+    pub fn process_message(&mut self) -> Result<bool> {
+        match self.ntt.recv()? {
+            Command::Control(ControlHeader::CreatedNewConnection, cid) => {
+                let id = LightId(cid);
+                if self.conversations.contains_key(&id) {
+                    error!("light id created twice, {}", id);
+                    return Err(Error::ClientCreatedLightIdTwice(id));
+                }
+                self.conversations.insert(id, Conversation::Establishing);
+            },
+            Command::Control(ControlHeader::CloseConnection, cid) => {
+                match self.conversations.remove(&LightId(cid)) {
+                    Some(Conversation::Established(reply_id)) |
+                    Some(Conversation::Dispatching(reply_id, _)) => {
+                        self.ntt.close_light(reply_id.0)?;
+                    },
+                    _ => {},
+                }
+            },
+            Command::Control(ControlHeader::CloseSocket, _) |
+            Command::Control(ControlHeader::CloseEndPoint, _) => {
+                return Ok(false);
+            },
+            Command::Control(ch, cid) => {
+                error!("LightId({}) Unsupported control `{:?}`", cid, ch);
+                return Err(Error::UnsupportedControl(ch));
+            },
+            Command::Data(cid, len) => {
+                let id = LightId(cid);
+                let bytes = self.ntt.recv_len(len)?;
+                match self.conversations.get(&id).cloned() {
+                    None => {
+                        warn!("LightId({}) does not exists but received data", id);
+                    },
+                    Some(Conversation::Establishing) => {
+                        // the acknowledgement of one of our announcements,
+                        // nothing else is expected on this light connection
+                        if let Some(nodeid) = NodeId::from_slice(&bytes) {
+                            if ! nodeid.is_syn() && self.announcements.remove(&nodeid.ack_to_syn()) {
+                                self.conversations.insert(id, Conversation::Closed);
+                                return Ok(true);
+                            }
+                        }
+                        let nodeid = recv_syn_nodeid(id, &bytes)?;
+                        let reply_id = self.get_free_light_id();
+                        self.ntt.create_light(reply_id.0)?;
+                        self.ntt.light_send_data(reply_id.0, nodeid.syn_to_ack().as_ref())?;
+                        self.conversations.insert(id, Conversation::Established(reply_id));
+                    },
+                    Some(Conversation::Established(reply_id)) => {
+                        let code = match packet::decode_msg_code(&bytes) {
+                            None => return Err(Error::InvalidMessageCode(id)),
+                            Some(code) => code,
+                        };
+                        if self.handlers.contains_key(&code) {
+                            self.conversations.insert(id, Conversation::Dispatching(reply_id, code));
+                        } else {
+                            warn!("LightId({}) no handler for message code 0x{:x}", id, code);
+                            self.ntt.close_light(reply_id.0)?;
+                            self.conversations.insert(id, Conversation::Closed);
+                        }
+                    },
+                    Some(Conversation::Dispatching(reply_id, code)) => {
+                        let handler = self.handlers[&code].clone();
+                        let closed = {
+                            let mut reply = Reply { sink: &mut self.ntt, id: reply_id, closed: false };
+                            handler.handle(&mut reply, &bytes)?;
+                            reply.closed
+                        };
+                        if closed {
+                            self.ntt.close_light(reply_id.0)?;
+                            self.conversations.insert(id, Conversation::Closed);
+                        }
+                    },
+                    Some(Conversation::Closed) => {
+                        debug!("LightId({}) dropping message on closed conversation", id);
+                    },
+                }
+            },
+        }
+        Ok(true)
+    }
+}
+
+/// accept inbound connections and serve them with the registered handlers
+pub struct Listener {
+    listener: TcpListener,
+    handshake: Handshake,
+    handlers: Handlers,
+}
+impl Listener {
+    pub fn bind<A: ToSocketAddrs>(addr: A, handshake: Handshake) -> Result<Self> {
+        Ok(Listener {
+            listener: TcpListener::bind(addr)?,
+            handshake: handshake,
+            handlers: BTreeMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// register the handler of the conversations starting with the given
+    /// message code, replacing the previous one if any.
+    pub fn register<H>(&mut self, code: u8, handler: H)
+        where H: Handler + Send + Sync + 'static
+    {
+        self.handlers.insert(code, Arc::new(handler));
+    }
+
+    /// accept the next inbound connection and perform the handshakes
+    pub fn accept(&self) -> Result<(SocketAddr, Server<TcpStream>)> {
+        let (stream, addr) = self.listener.accept()?;
+        let server = Server::accept(stream, &self.handshake, self.handlers.clone())?;
+        Ok((addr, server))
+    }
+
+    /// serve the inbound connections, one thread per connection
+    ///
+    /// only returns if the listening socket fails.
+    pub fn serve(self) -> Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept()?;
+            let handshake = self.handshake.clone();
+            let handlers = self.handlers.clone();
+            thread::spawn(move || {
+                info!("accepted connection from {}", addr);
+                let result = Server::accept(stream, &handshake, handlers)
+                    .and_then(|mut server| server.run());
+                match result {
+                    Ok(()) => info!("connection from {} closed", addr),
+                    Err(err) => error!("connection from {} failed: {:?}", addr, err),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Command, GetBlockHeader, Subscription};
+    use protocol::Connection;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration};
+
+    #[test]
+    fn serve_get_headers() {
+        let mut listener = Listener::bind("127.0.0.1:0", Handshake::default()).unwrap();
+        listener.register(0x4, |reply: &mut Reply, _msg: &[u8]| {
+            // an empty list of headers
+            reply.send_bytes(&[0x82, 0x00, 0x80])?;
+            reply.close();
+            Ok(())
+        });
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (_, mut server) = listener.accept().unwrap();
+            server.run().unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let ntt = ntt::Connection::handshake(0, stream).unwrap();
+        let mut connection = Connection::new(ntt);
+        connection.handshake(&Handshake::default()).unwrap();
+        let headers = GetBlockHeader::tip().execute(&mut connection).unwrap();
+        assert!(headers.decode().unwrap().is_empty());
+
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn subscription_keep_alives_while_idle() {
+        let messages = Arc::new(AtomicUsize::new(0));
+        let mut handshake = Handshake::default();
+        handshake.in_handlers.insert(packet::MsgType::MsgSubscribe.to_u8() as packet::MessageCode, packet::HandlerSpec::new(packet::MsgType::MsgHeaders.to_u8() as u16));
+        let mut listener = Listener::bind("127.0.0.1:0", handshake).unwrap();
+        let received = messages.clone();
+        listener.register(packet::MsgType::MsgSubscribe.to_u8(), move |reply: &mut Reply, _msg: &[u8]| {
+            // the subscription request then two keep-alives, no header
+            // is ever announced
+            if received.fetch_add(1, Ordering::SeqCst) == 2 {
+                reply.close();
+            }
+            Ok(())
+        });
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (_, mut server) = listener.accept().unwrap();
+            server.run().unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut connection = Connection::new(ntt::Connection::handshake(0, stream).unwrap());
+        connection.handshake(&Handshake::default()).unwrap();
+        let mut subscription = Subscription::start(&mut connection, Duration::from_millis(50)).unwrap();
+        match subscription.next_headers(&mut connection) {
+            Err(Error::ConnectionClosed) => {},
+            r => panic!("expected the subscription to be closed, got {:?}", r),
+        }
+        assert_eq!(messages.load(Ordering::SeqCst), 3);
+
+        drop(connection);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+// an address index file is associated to a pack and is:
+//
+// MAGIC (8 Bytes)
+// ADDRESSES BLOOM SIZE (4 bytes BE)
+// INPUTS BLOOM SIZE (4 bytes BE)
+// ADDRESSES BLOOM FILTER (ADDRESSES_BLOOM_SIZE bytes)
+// INPUTS BLOOM FILTER (INPUTS_BLOOM_SIZE bytes)
+//
+// the addresses bloom filter records all the addresses found in the outputs
+// of the transactions of the pack, the inputs bloom filter records all the
+// outputs spent by the transactions of the pack (TxId followed by the index
+// of the output, 4 bytes BE).
+//
+// bloom filters allow false positive: a pack may not contain the address
+// even if the bloom filter says so. However if the bloom filter says the
+// address is not present, the address is certainly not in the pack.
+
+use std::iter::repeat;
+use std::io;
+use std::io::{Write};
+
+use wallet_crypto::tx::{TxIn};
+use wallet_crypto::address::{ExtendedAddr};
+use wallet_crypto::util::{hex};
+use blockchain::{Block};
+
+use super::{Storage, Result};
+use types::{HASH_SIZE, PackHash, StorageFileType};
+use pack::{PackReader, default_bloom_size, write_size, read_size};
+use bloom;
+
+const MAGIC : &[u8] = b"ADAADDR1";
+const MAGIC_SIZE : usize = 8;
+const SIZE_SIZE : usize = 4;
+const HEADER_SIZE : usize = MAGIC_SIZE + SIZE_SIZE + SIZE_SIZE;
+
+/// the lookup structure of an address index
+pub struct AddressLookup {
+    addresses: Vec<u8>,
+    inputs: Vec<u8>,
+}
+impl AddressLookup {
+    /// check if the given address may be in an output of the pack
+    pub fn search_address(&self, address: &ExtendedAddr) -> bool {
+        bloom::is_set(&self.addresses[..], &address.to_bytes())
+    }
+
+    /// check if the given output may be spent by a transaction of the pack
+    pub fn search_input(&self, txin: &TxIn) -> bool {
+        bloom::is_set(&self.inputs[..], &input_key(txin))
+    }
+}
+
+fn input_key(txin: &TxIn) -> [u8;HASH_SIZE + SIZE_SIZE] {
+    let mut buf = [0u8;HASH_SIZE + SIZE_SIZE];
+    buf[0..HASH_SIZE].clone_from_slice(txin.id.bytes());
+    write_size(&mut buf[HASH_SIZE..], txin.index);
+    buf
+}
+
+/// build the address index of the given pack
+///
+/// the pack is read block by block and every output address and every
+/// input found is recorded in a new index file, replacing the previous
+/// one if any.
+pub fn create(storage: &Storage, packhash: &PackHash) -> Result<AddressLookup> {
+    let mut addresses = Vec::new();
+    let mut inputs = Vec::new();
+    let mut reader = PackReader::init(storage, packhash)?;
+    while let Some(rblk) = reader.get_next()? {
+        if let Block::MainBlock(mblk) = rblk.decode()? {
+            for txaux in mblk.body.tx.iter() {
+                for txin in txaux.tx.inputs.iter() {
+                    inputs.push(input_key(txin));
+                }
+                for txout in txaux.tx.outputs.iter() {
+                    addresses.push(txout.address.to_bytes());
+                }
+            }
+        }
+    }
+
+    let addresses_bloom_size = default_bloom_size(addresses.len());
+    let inputs_bloom_size = default_bloom_size(inputs.len());
+
+    let mut lookup = AddressLookup {
+        addresses: repeat(0).take(addresses_bloom_size as usize).collect(),
+        inputs: repeat(0).take(inputs_bloom_size as usize).collect(),
+    };
+    for address in addresses.iter() {
+        bloom::set(&mut lookup.addresses[..], address);
+    }
+    for input in inputs.iter() {
+        bloom::set(&mut lookup.inputs[..], input);
+    }
+
+    let mut output = storage.get_backend().create(StorageFileType::AddrIndex)?;
+    let mut hdr_buf = [0u8;HEADER_SIZE];
+    hdr_buf[0..MAGIC_SIZE].clone_from_slice(&MAGIC[..]);
+    write_size(&mut hdr_buf[8..12], addresses_bloom_size);
+    write_size(&mut hdr_buf[12..16], inputs_bloom_size);
+    output.write_all(&hdr_buf)?;
+    output.write_all(&lookup.addresses[..])?;
+    output.write_all(&lookup.inputs[..])?;
+    output.commit(&hex::encode(packhash))?;
+    Ok(lookup)
+}
+
+/// check if the address index of the given pack has been created
+pub fn exist(storage: &Storage, packhash: &PackHash) -> bool {
+    storage.get_backend().exist(StorageFileType::AddrIndex, &hex::encode(packhash))
+}
+
+/// read the address index of the given pack
+pub fn read(storage: &Storage, packhash: &PackHash) -> io::Result<AddressLookup> {
+    let content = storage.get_backend().read(StorageFileType::AddrIndex, &hex::encode(packhash))?;
+    if content.len() < HEADER_SIZE || &content[0..MAGIC_SIZE] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid address index magic"));
+    }
+    let addresses_end = HEADER_SIZE + read_size(&content[8..12]) as usize;
+    let inputs_end = addresses_end + read_size(&content[12..16]) as usize;
+    if content.len() < inputs_end {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated address index"));
+    }
+    Ok(AddressLookup {
+        addresses: Vec::from(&content[HEADER_SIZE..addresses_end]),
+        inputs: Vec::from(&content[addresses_end..inputs_end]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{MemoryBackend};
+    use pack::{PackWriter};
+    use types::{header_to_blockhash};
+    use protocol::mock::{Chain, fixture_address, fixture_transaction};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::tx::{TxId};
+
+    #[test]
+    fn search_the_addresses_and_inputs() {
+        let mut chain = Chain::generate(ProtocolMagic::default(), 10, 3);
+        let pm = chain.get_protocol_magic();
+        let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 1)], &[(2, 100), (1, 50)]);
+        let tx2 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 2)], &[(3, 90)]);
+        chain.push_transactions(vec![tx1.clone()]);
+        chain.push_transactions(vec![tx2.clone()]);
+        chain.extend(2);
+
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let mut writer = PackWriter::init(&storage);
+        for height in 0..chain.len() {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        assert!(exist(&storage, &packhash));
+
+        let lookup = read(&storage, &packhash).unwrap();
+        for n in 1..4 {
+            assert!(lookup.search_address(&fixture_address(n)));
+        }
+        assert!(!lookup.search_address(&fixture_address(4)));
+        assert!(lookup.search_input(&TxIn::new(TxId::new(&[1]), 0)));
+        assert!(lookup.search_input(&TxIn::new(tx1.tx.id(), 0)));
+        assert!(!lookup.search_input(&TxIn::new(tx1.tx.id(), 1)));
+        assert!(!lookup.search_input(&TxIn::new(tx2.tx.id(), 0)));
+
+        let name = hex::encode(&packhash);
+        let content = storage.get_backend().read(StorageFileType::AddrIndex, &name).unwrap();
+        storage.get_backend().write(StorageFileType::AddrIndex, &name, &content[..content.len() - 1]).unwrap();
+        assert_eq!(read(&storage, &packhash).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+        storage.get_backend().write(StorageFileType::AddrIndex, &name, b"ADAADDR0").unwrap();
+        assert_eq!(read(&storage, &packhash).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,484 @@
+// an archive is a portable copy of a chain, or of a range of a chain:
+//
+// MAGIC (8 bytes)
+// PROTOCOL MAGIC (4 bytes BE)
+// 0-PADDING (4 bytes)
+// GENESIS: hash of the first block of the network (32 bytes)
+// GENESIS PREVIOUS: previous hash of the first block of the network (32 bytes)
+// RECORDS, a record being:
+//   TYPE (4 bytes BE)
+//   for a record BLOCK (TYPE 1):
+//     SIZE (4 bytes BE)
+//     CRC32 of the BLOCK (4 bytes BE)
+//     BLOCK, compressed as in the packs (SIZE bytes)
+//   for a record EPOCH (TYPE 2):
+//     EPOCH (4 bytes BE)
+//     NUMBER OF ENTRIES (4 bytes BE)
+//     REFPACK of the epoch (#ENTRIES * 32 bytes)
+//   for a record END (TYPE 0xffffffff), the last record:
+//     NUMBER OF BLOCKS (4 bytes BE)
+//     HASH OF THE LAST BLOCK (32 bytes)
+//
+// the blocks are in the order of the chain. A record EPOCH follows the
+// blocks of a complete epoch (from its genesis block to its last block),
+// they form the epoch pack. The blocks of the epochs which are not
+// complete (the first and the last epochs of the range) have no record
+// EPOCH, they are imported in packs which are not epoch packs, tagged
+// `PARTIAL_EPOCH_<n>_<packhash>` (see `tag::get_partial_epoch_tag`).
+
+use std::io;
+use std::io::{Read, Write};
+use wallet_crypto::config::{ProtocolMagic};
+use wallet_crypto::crc32::crc32;
+use blockchain::{RawBlock, HeaderHash, BlockDate, EpochId};
+
+use super::{Storage, block, block_read, tag, epoch};
+use refpack::{self, RefPack};
+use pack::{PackWriter, write_size, read_size};
+use compression;
+use types::*;
+
+const MAGIC : &[u8] = b"ADAARCH1";
+const HEADER_SIZE : usize = 8 + 8 + HASH_SIZE + HASH_SIZE;
+
+const RECORD_BLOCK : u32 = 1;
+const RECORD_EPOCH : u32 = 2;
+const RECORD_END : u32 = 0xffffffff;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    StorageError(super::Error),
+    BlockError(block::Error),
+    CborBlockError(::raw_cbor::Error),
+    RefPackError(refpack::Error),
+    InvalidMagic,
+    InvalidRecord(u32),
+    /// the archive is not of the expected network
+    NetworkMismatch(Header),
+    /// the block (counting from 0) does not match its CRC32
+    ChecksumMismatch(u32),
+    BlockNotFound(BlockHash),
+    /// the first block of the archive does not follow a known block
+    UnknownParent(HeaderHash),
+    /// the block does not follow the previous block of the archive
+    ChainBroken(BlockDate, HeaderHash, HeaderHash),
+    /// the block is not after the previous block of the archive
+    DateRewind(BlockDate, BlockDate),
+    /// the refpack of the epoch does not match its blocks
+    RefPackMismatch(EpochId),
+    /// the archive ends before the expected number of blocks
+    Truncated(u32, u32),
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Error::IoError(e) }
+}
+impl From<super::Error> for Error {
+    fn from(e: super::Error) -> Self { Error::StorageError(e) }
+}
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Self { Error::BlockError(e) }
+}
+impl From<refpack::Error> for Error {
+    fn from(e: refpack::Error) -> Self { Error::RefPackError(e) }
+}
+impl From<::raw_cbor::Error> for Error {
+    fn from(e: ::raw_cbor::Error) -> Self { Error::CborBlockError(e) }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// the network the blocks of the archive belong to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub protocol_magic: ProtocolMagic,
+    pub genesis: HeaderHash,
+    pub genesis_prev: HeaderHash,
+}
+
+fn hash_from_slice(buf: &[u8]) -> HeaderHash {
+    let mut bytes = [0u8;HASH_SIZE];
+    bytes.clone_from_slice(&buf[0..HASH_SIZE]);
+    HeaderHash::from_bytes(bytes)
+}
+
+fn write_u32<W: Write>(writer: &mut W, v: u32) -> io::Result<()> {
+    let mut buf = [0u8;4];
+    write_size(&mut buf, v);
+    writer.write_all(&buf)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8;4];
+    reader.read_exact(&mut buf)?;
+    Ok(read_size(&buf))
+}
+
+// the epoch being written
+struct EpochState {
+    epochid: EpochId,
+    refpack: RefPack,
+    next_date: BlockDate,
+    // the first block of the epoch is its genesis block
+    from_genesis: bool,
+}
+
+pub struct ArchiveWriter<W> {
+    writer: W,
+    epoch: Option<EpochState>,
+    nb_blocks: u32,
+    last: Option<HeaderHash>,
+}
+impl<W: Write> ArchiveWriter<W> {
+    pub fn init(mut writer: W, header: &Header) -> Result<Self> {
+        let mut buf = [0u8;HEADER_SIZE];
+        buf[0..8].clone_from_slice(MAGIC);
+        write_size(&mut buf[8..12], header.protocol_magic.into());
+        buf[16..48].clone_from_slice(header.genesis.as_ref());
+        buf[48..80].clone_from_slice(header.genesis_prev.as_ref());
+        writer.write_all(&buf)?;
+        Ok(ArchiveWriter { writer: writer, epoch: None, nb_blocks: 0, last: None })
+    }
+
+    /// append the next block of the chain
+    pub fn append(&mut self, rblk: &RawBlock) -> Result<()> {
+        let hdr = rblk.decode()?.get_header();
+        let date = hdr.get_blockdate();
+        let hash = hdr.compute_hash();
+
+        // a block of the next epoch completes the current one
+        if self.epoch.as_ref().map(|e| e.epochid != date.get_epochid()).unwrap_or(false) {
+            self.close_epoch(true)?;
+        }
+        let epoch = self.epoch.get_or_insert_with(|| EpochState {
+            epochid: date.get_epochid(),
+            refpack: RefPack::new(),
+            next_date: BlockDate::Genesis(date.get_epochid()),
+            from_genesis: date.is_genesis(),
+        });
+        if date < epoch.next_date {
+            return Err(Error::DateRewind(epoch.next_date.clone(), date));
+        }
+        while epoch.next_date != date {
+            epoch.refpack.push_back_missing();
+            epoch.next_date = epoch.next_date.next();
+        }
+        epoch.refpack.push_back(header_to_blockhash(&hash));
+        epoch.next_date = date.next();
+
+        let block = compression::compress_conditional(rblk.as_ref());
+        write_u32(&mut self.writer, RECORD_BLOCK)?;
+        write_u32(&mut self.writer, block.len() as u32)?;
+        write_u32(&mut self.writer, crc32(&block))?;
+        self.writer.write_all(&block)?;
+        self.nb_blocks += 1;
+        self.last = Some(hash);
+        Ok(())
+    }
+
+    fn close_epoch(&mut self, complete: bool) -> Result<()> {
+        let epoch = match self.epoch.take() { None => return Ok(()), Some(epoch) => epoch };
+        if ! (complete && epoch.from_genesis) { return Ok(()); }
+        write_u32(&mut self.writer, RECORD_EPOCH)?;
+        write_u32(&mut self.writer, epoch.epochid)?;
+        write_u32(&mut self.writer, epoch.refpack.iter().count() as u32)?;
+        epoch.refpack.write(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// write the end of the archive, `complete` telling if the last
+    /// block appended is the last block of its epoch
+    pub fn finalize(mut self, complete: bool) -> Result<W> {
+        self.close_epoch(complete)?;
+        write_u32(&mut self.writer, RECORD_END)?;
+        write_u32(&mut self.writer, self.nb_blocks)?;
+        let last = self.last.as_ref().map(|h| header_to_blockhash(h)).unwrap_or([0u8;HASH_SIZE]);
+        self.writer.write_all(&last)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub enum Record {
+    /// a block, as stored (compressed)
+    Block(Vec<u8>),
+    Epoch(EpochId, RefPack),
+    End(u32, BlockHash),
+}
+
+pub struct ArchiveReader<R> {
+    reader: R,
+    header: Header,
+    nb_blocks: u32,
+}
+impl<R: Read> ArchiveReader<R> {
+    pub fn init(mut reader: R) -> Result<Self> {
+        let mut buf = [0u8;HEADER_SIZE];
+        reader.read_exact(&mut buf)?;
+        if &buf[0..8] != MAGIC { return Err(Error::InvalidMagic); }
+        let header = Header {
+            protocol_magic: ProtocolMagic::new(read_size(&buf[8..12])),
+            genesis: hash_from_slice(&buf[16..48]),
+            genesis_prev: hash_from_slice(&buf[48..80]),
+        };
+        Ok(ArchiveReader { reader: reader, header: header, nb_blocks: 0 })
+    }
+
+    pub fn get_header(&self) -> &Header { &self.header }
+
+    /// read the next record, checking the blocks against their CRC32
+    pub fn next_record(&mut self) -> Result<Record> {
+        match read_u32(&mut self.reader)? {
+            RECORD_BLOCK => {
+                let size = read_u32(&mut self.reader)?;
+                let crc = read_u32(&mut self.reader)?;
+                let mut block = Vec::new();
+                (&mut self.reader).take(size as u64).read_to_end(&mut block)?;
+                if block.len() != size as usize {
+                    return Err(Error::IoError(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block")));
+                }
+                if crc32(&block) != crc { return Err(Error::ChecksumMismatch(self.nb_blocks)); }
+                self.nb_blocks += 1;
+                Ok(Record::Block(block))
+            },
+            RECORD_EPOCH => {
+                let epochid = read_u32(&mut self.reader)?;
+                let nb_entries = read_u32(&mut self.reader)?;
+                let mut buf = Vec::new();
+                (&mut self.reader).take(nb_entries as u64 * HASH_SIZE as u64).read_to_end(&mut buf)?;
+                let refpack = RefPack::read(&mut &buf[..])?;
+                Ok(Record::Epoch(epochid, refpack))
+            },
+            RECORD_END => {
+                let nb_blocks = read_u32(&mut self.reader)?;
+                let mut last = [0u8;HASH_SIZE];
+                self.reader.read_exact(&mut last)?;
+                Ok(Record::End(nb_blocks, last))
+            },
+            ty => Err(Error::InvalidRecord(ty)),
+        }
+    }
+}
+
+/// export the blocks from `from` to `to` (included) in an archive,
+/// returning the number of blocks exported
+pub fn export<W: Write>(storage: &Storage, header: &Header, from: &BlockHash, to: &BlockHash, writer: W) -> Result<u32> {
+    let range = storage.range(*from, *to)?;
+    let mut archive = ArchiveWriter::init(writer, header)?;
+    for hash in range.iter() {
+        let rblk = block_read(storage, hash).ok_or(Error::BlockNotFound(*hash))?;
+        archive.append(&rblk)?;
+    }
+    let nb_blocks = archive.nb_blocks;
+
+    // the last epoch is complete if `to` is the last block of the stored epoch
+    let complete = match storage.get_header(to) {
+        None => false,
+        Some(hdr) => match epoch::epoch_read(storage, hdr.date.get_epochid()) {
+            Err(_) => false,
+            Ok((_, refpack)) => refpack.iter().rev().find(|h| **h != [0u8;HASH_SIZE]) == Some(to),
+        },
+    };
+    archive.finalize(complete)?;
+    Ok(nb_blocks)
+}
+
+/// the result of an import
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub nb_blocks: u32,
+    /// the epoch packs created
+    pub epochs: Vec<(EpochId, PackHash)>,
+    /// the packs created for the blocks of the incomplete epochs
+    pub packs: Vec<(EpochId, PackHash)>,
+    /// the last block of the archive
+    pub last: Option<HeaderHash>,
+}
+
+// the blocks of the epoch being imported
+struct Pending {
+    epochid: EpochId,
+    writer: PackWriter,
+    hashes: Vec<BlockHash>,
+}
+
+/// import the blocks of the archive in the storage
+///
+/// the archive must be of the given network and its blocks must follow
+/// a block of the storage (or the genesis). The epoch packs are created
+/// along their refpack and `EPOCH_n` tag, the packs of the incomplete
+/// epochs are tagged too for the garbage collection to keep them. The
+/// `HEAD` tag is moved to the last block if its chain is more difficult.
+pub fn import<R: Read>(storage: &mut Storage, network: &Header, reader: R) -> Result<Imported> {
+    let mut archive = ArchiveReader::init(reader)?;
+    if archive.get_header() != network {
+        return Err(Error::NetworkMismatch(archive.get_header().clone()));
+    }
+
+    let mut imported = Imported::default();
+    let mut pending : Option<Pending> = None;
+    let mut last_date : Option<BlockDate> = None;
+    loop {
+        match archive.next_record()? {
+            Record::Block(block) => {
+                let rblk = RawBlock::from_dat(compression::try_decompress_conditional(&block)?);
+                let hdr = rblk.decode()?.get_header();
+                let hash = hdr.compute_hash();
+                let date = hdr.get_blockdate();
+                let previous = hdr.get_previous_header();
+
+                match imported.last {
+                    None => {
+                        if previous != network.genesis_prev && storage.get_header(&header_to_blockhash(&previous)).is_none() {
+                            return Err(Error::UnknownParent(previous));
+                        }
+                    },
+                    Some(ref last) => {
+                        if *last != previous { return Err(Error::ChainBroken(date, last.clone(), previous)); }
+                    },
+                }
+                if let Some(last_date) = last_date {
+                    if date <= last_date { return Err(Error::DateRewind(last_date, date)); }
+                }
+
+                if pending.as_ref().map(|p| p.epochid != date.get_epochid()).unwrap_or(false) {
+                    let p = pending.take().unwrap();
+                    imported.packs.push(finalize_partial_epoch(storage, p)?);
+                }
+                let p = pending.get_or_insert_with(|| Pending {
+                    epochid: date.get_epochid(),
+                    writer: PackWriter::init(storage),
+                    hashes: Vec::new(),
+                });
+                let blockhash = header_to_blockhash(&hash);
+                // the blocks of the archive are compressed just like the
+                // blocks of a pack with the default compression
+                p.writer.append_raw(&blockhash, &block);
+                p.hashes.push(blockhash);
+
+                imported.nb_blocks += 1;
+                imported.last = Some(hash);
+                last_date = Some(date);
+            },
+            Record::Epoch(epochid, refpack) => {
+                let p = match pending.take() {
+                    Some(ref p) if p.epochid != epochid => return Err(Error::RefPackMismatch(epochid)),
+                    None => return Err(Error::RefPackMismatch(epochid)),
+                    Some(p) => p,
+                };
+                let hashes : Vec<BlockHash> = refpack.iter().filter(|h| **h != [0u8;HASH_SIZE]).cloned().collect();
+                if hashes != p.hashes { return Err(Error::RefPackMismatch(epochid)); }
+
+                let packhash = finalize_pack(storage, p.writer)?;
+                epoch::epoch_create_with_refpack(storage, &packhash, &refpack, epochid);
+                tag::write(storage, &tag::get_epoch_tag(epochid), &packhash[..]);
+                imported.epochs.push((epochid, packhash));
+            },
+            Record::End(nb_blocks, last) => {
+                let last_hash = imported.last.as_ref().map(|h| header_to_blockhash(h)).unwrap_or([0u8;HASH_SIZE]);
+                if nb_blocks != imported.nb_blocks || last != last_hash {
+                    return Err(Error::Truncated(nb_blocks, imported.nb_blocks));
+                }
+                if let Some(p) = pending.take() {
+                    imported.packs.push(finalize_partial_epoch(storage, p)?);
+                }
+                break;
+            },
+        }
+    }
+
+    if let Some(ref last) = imported.last {
+        let difficulty = |hash: &HeaderHash| storage.get_header(&header_to_blockhash(hash)).map(|hdr| u64::from(hdr.difficulty));
+        let move_head = match tag::read_hash(storage, &tag::HEAD) {
+            None => true,
+            Some(head) => difficulty(&head) < difficulty(last),
+        };
+        if move_head { tag::write_hash(storage, &tag::HEAD, last); }
+    }
+    Ok(imported)
+}
+
+fn finalize_pack(storage: &mut Storage, mut writer: PackWriter) -> Result<PackHash> {
+    let (packhash, index) = writer.finalize();
+    storage.add_pack(&packhash, &index)?;
+    Ok(packhash)
+}
+
+fn finalize_partial_epoch(storage: &mut Storage, pending: Pending) -> Result<(EpochId, PackHash)> {
+    let packhash = finalize_pack(storage, pending.writer)?;
+    tag::write(storage, &tag::get_partial_epoch_tag(pending.epochid, &packhash), &packhash[..]);
+    Ok((pending.epochid, packhash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::mock::{Chain};
+    use backend::{MemoryBackend};
+    use super::super::{blob, gc};
+
+    fn storage() -> Storage { Storage::memory(MemoryBackend::new()).unwrap() }
+
+    fn hash_at(chain: &Chain, height: usize) -> BlockHash { header_to_blockhash(&chain.hash(height)) }
+
+    #[test]
+    fn export_import_gc() {
+        // epochs of 11 blocks, the epoch 3 is incomplete
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 40);
+        let header = Header {
+            protocol_magic: chain.get_protocol_magic(),
+            genesis: chain.hash(0),
+            genesis_prev: chain.header(0).get_previous_header(),
+        };
+
+        let mut exported = storage();
+        for epochid in 0..3 {
+            let mut writer = PackWriter::init(&exported);
+            for height in (epochid * 11)..(epochid * 11 + 11) {
+                writer.append(&hash_at(&chain, height), chain.block(height).as_ref());
+            }
+            let packhash = finalize_pack(&mut exported, writer).unwrap();
+            epoch::epoch_create(&exported, &packhash, epochid as EpochId);
+            tag::write(&exported, &tag::get_epoch_tag(epochid as EpochId), &packhash[..]);
+        }
+        for height in 33..40 {
+            blob::write(&exported, &hash_at(&chain, height), chain.block(height).as_ref()).unwrap();
+        }
+        tag::write_hash(&exported, &tag::HEAD, &chain.hash(39));
+
+        // the archive starts in the middle of the epoch 0
+        let mut buf = Vec::new();
+        assert_eq!(export(&exported, &header, &hash_at(&chain, 5), &hash_at(&chain, 39), &mut buf).unwrap(), 35);
+
+        let mut imported = storage();
+        for height in 0..5 {
+            blob::write(&imported, &hash_at(&chain, height), chain.block(height).as_ref()).unwrap();
+        }
+        tag::write_hash(&imported, &tag::HEAD, &chain.hash(4));
+        let result = import(&mut imported, &header, &buf[..]).unwrap();
+        assert_eq!(result.nb_blocks, 35);
+        assert_eq!(result.epochs.iter().map(|&(epochid, _)| epochid).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(result.packs.iter().map(|&(epochid, _)| epochid).collect::<Vec<_>>(), vec![0, 3]);
+        for &(epochid, ref packhash) in result.packs.iter() {
+            assert_eq!(tag::read(&imported, &tag::get_partial_epoch_tag(epochid, packhash)), Some(packhash.to_vec()));
+        }
+        assert_eq!(tag::read_hash(&imported, &tag::HEAD), Some(chain.hash(39)));
+
+        // every imported pack is kept, by its tag even off the HEAD chain
+        tag::write_hash(&imported, &tag::HEAD, &chain.hash(4));
+        let report = gc::gc(&mut imported, &gc::Params::default()).unwrap();
+        assert!(report.unreferenced_packs.is_empty());
+        assert_eq!(report.reachable_packs.len(), 4);
+        for height in 0..40 {
+            assert!(block_read(&imported, &hash_at(&chain, height)).is_some());
+        }
+
+        // an archive of a range ending at the end of a complete epoch
+        let mut buf = Vec::new();
+        export(&exported, &header, &hash_at(&chain, 0), &hash_at(&chain, 21), &mut buf).unwrap();
+        let mut reimported = storage();
+        let result = import(&mut reimported, &header, &buf[..]).unwrap();
+        assert_eq!(result.epochs.iter().map(|&(epochid, _)| epochid).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(result.packs.is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+//! where the files of the storage are kept
+//!
+//! The storage is made of objects (blobs, packs, indexes, tags, refpacks
+//! and epochs) identified by their type and their name: the hexadecimal
+//! hash for the blobs, the packs and their indexes, the name of the tags
+//! and of the refpacks, `<epoch>/pack` and `<epoch>/refpack` for the epochs.
+//!
+//! `FsBackend` keeps the objects in the directories of the
+//! `StorageConfig`, `MemoryBackend` keeps them in memory (for the tests
+//! or to embed a storage in another program).
+
+use std::collections::{BTreeMap};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::{Deref};
+use std::path::{PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration};
+use memmap::{Mmap};
+
+use config::{StorageConfig};
+use lock::{self, Access, StorageLock};
+use tmpfile::{TmpFile};
+use types::{StorageFileType};
+
+/// the content of an object of the storage
+pub enum Content {
+    /// a file mapped in memory
+    Mapped(Mmap),
+    Memory(Arc<Vec<u8>>),
+}
+impl Deref for Content {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            &Content::Mapped(ref mmap) => &mmap[..],
+            &Content::Memory(ref bytes) => &bytes[..],
+        }
+    }
+}
+impl AsRef<[u8]> for Content {
+    fn as_ref(&self) -> &[u8] { self.deref() }
+}
+
+/// an object being written, only visible once committed under its name
+pub trait Output: Write + Send {
+    fn commit(self: Box<Self>, name: &str) -> io::Result<()>;
+}
+
+pub trait StorageBackend: Send + Sync {
+    /// read the given object
+    fn read(&self, filetype: StorageFileType, name: &str) -> io::Result<Content>;
+
+    /// create a new object of the given type, see `Output`
+    fn create(&self, filetype: StorageFileType) -> io::Result<Box<Output>>;
+
+    fn exist(&self, filetype: StorageFileType, name: &str) -> bool;
+
+    /// the size of the given object, in bytes
+    fn size(&self, filetype: StorageFileType, name: &str) -> io::Result<u64>;
+
+    fn remove(&self, filetype: StorageFileType, name: &str) -> io::Result<()>;
+
+    /// the names of the objects of the given type
+    fn list(&self, filetype: StorageFileType) -> io::Result<Vec<String>>;
+
+    /// the objects created but never committed for longer than `age`
+    /// (left over by an interrupted write), along their size. They are
+    /// named by the backend, only to be given to `remove_stale`.
+    fn list_stale(&self, age: Duration) -> io::Result<Vec<(StorageFileType, String, u64)>>;
+
+    /// remove an object listed by `list_stale`
+    fn remove_stale(&self, filetype: StorageFileType, name: &str) -> io::Result<()>;
+
+    /// lock the storage against the other processes sharing it
+    fn lock(&self, access: Access, timeout: Option<Duration>) -> lock::Result<StorageLock>;
+
+    /// write the given object at once, replacing the previous one if any
+    fn write(&self, filetype: StorageFileType, name: &str, content: &[u8]) -> io::Result<()> {
+        let mut output = self.create(filetype)?;
+        output.write_all(content)?;
+        output.commit(name)
+    }
+}
+
+/// the objects of the storage in the directories of the `StorageConfig`
+pub struct FsBackend {
+    config: StorageConfig,
+}
+impl FsBackend {
+    pub fn new(config: &StorageConfig) -> Self {
+        FsBackend { config: config.clone() }
+    }
+
+    fn path(&self, filetype: StorageFileType, name: &str) -> PathBuf {
+        self.config.get_filetype_dir(filetype).join(name)
+    }
+}
+
+struct FsOutput {
+    tmpfile: TmpFile,
+    dir: PathBuf,
+}
+impl Write for FsOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.tmpfile.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.tmpfile.flush() }
+}
+impl Output for FsOutput {
+    fn commit(self: Box<Self>, name: &str) -> io::Result<()> {
+        let path = self.dir.join(name);
+        // the epochs are in their own directories
+        if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+        self.tmpfile.render_permanent(&path)
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn read(&self, filetype: StorageFileType, name: &str) -> io::Result<Content> {
+        let mut file = fs::File::open(self.path(filetype, name))?;
+        match filetype {
+            // the packs and their indexes are never modified once written,
+            // they are read in place
+            StorageFileType::Pack | StorageFileType::Index | StorageFileType::TxIndex
+                | StorageFileType::AddrIndex | StorageFileType::HeaderIndex if file.metadata()?.len() > 0 => {
+                Ok(Content::Mapped(unsafe { Mmap::map(&file)? }))
+            },
+            _ => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)?;
+                Ok(Content::Memory(Arc::new(content)))
+            },
+        }
+    }
+
+    fn create(&self, filetype: StorageFileType) -> io::Result<Box<Output>> {
+        let dir = self.config.get_filetype_dir(filetype);
+        let tmpfile = TmpFile::create(dir.clone())?;
+        Ok(Box::new(FsOutput { tmpfile: tmpfile, dir: dir }))
+    }
+
+    fn exist(&self, filetype: StorageFileType, name: &str) -> bool {
+        self.path(filetype, name).exists()
+    }
+
+    fn size(&self, filetype: StorageFileType, name: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(filetype, name))?.len())
+    }
+
+    fn remove(&self, filetype: StorageFileType, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(filetype, name))
+    }
+
+    fn list(&self, filetype: StorageFileType) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut dirs = vec![(self.config.get_filetype_dir(filetype), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = match entry.file_name().into_string() { Ok(name) => name, Err(_) => continue };
+                if name.starts_with(".tmp.") { continue; }
+                if entry.file_type()?.is_dir() {
+                    dirs.push((entry.path(), format!("{}{}/", prefix, name)));
+                } else {
+                    names.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // the temporary files of the outputs never committed, in the directory
+    // of every filetype (and of every epoch)
+    fn list_stale(&self, age: Duration) -> io::Result<Vec<(StorageFileType, String, u64)>> {
+        let mut dirs = Vec::new();
+        for filetype in [ StorageFileType::Pack, StorageFileType::Index, StorageFileType::Blob,
+                          StorageFileType::Tag, StorageFileType::RefPack, StorageFileType::Epoch,
+                          StorageFileType::TxIndex, StorageFileType::AddrIndex, StorageFileType::HeaderIndex ].iter() {
+            dirs.push((*filetype, self.config.get_filetype_dir(*filetype), String::new()));
+        }
+        let epoch_dir = self.config.get_filetype_dir(StorageFileType::Epoch);
+        if epoch_dir.is_dir() {
+            for entry in fs::read_dir(&epoch_dir)? {
+                let entry = entry?;
+                let name = match entry.file_name().into_string() { Ok(name) => name, Err(_) => continue };
+                if entry.file_type()?.is_dir() { dirs.push((StorageFileType::Epoch, entry.path(), format!("{}/", name))); }
+            }
+        }
+
+        let mut stale = Vec::new();
+        for (filetype, dir, prefix) in dirs {
+            if ! dir.is_dir() { continue; }
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = match entry.file_name().into_string() { Ok(name) => name, Err(_) => continue };
+                if ! name.starts_with(".tmp.") { continue; }
+                let metadata = entry.metadata()?;
+                let expired = metadata.modified()?.elapsed().map(|elapsed| elapsed >= age).unwrap_or(false);
+                if metadata.is_file() && expired {
+                    stale.push((filetype, format!("{}{}", prefix, name), metadata.len()));
+                }
+            }
+        }
+        Ok(stale)
+    }
+
+    fn remove_stale(&self, filetype: StorageFileType, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(filetype, name))
+    }
+
+    fn lock(&self, access: Access, timeout: Option<Duration>) -> lock::Result<StorageLock> {
+        StorageLock::acquire(self.config.get_path(), access, timeout)
+    }
+}
+
+type Objects = BTreeMap<(StorageFileType, String), Arc<Vec<u8>>>;
+
+/// the objects of the storage in memory
+///
+/// The clones of a `MemoryBackend` share the same objects.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    objects: Arc<RwLock<Objects>>,
+}
+impl MemoryBackend {
+    pub fn new() -> Self { MemoryBackend::default() }
+}
+
+struct MemoryOutput {
+    objects: Arc<RwLock<Objects>>,
+    filetype: StorageFileType,
+    content: Vec<u8>,
+}
+impl Write for MemoryOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.content.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+impl Output for MemoryOutput {
+    fn commit(self: Box<Self>, name: &str) -> io::Result<()> {
+        let output = *self;
+        output.objects.write().unwrap().insert((output.filetype, name.to_owned()), Arc::new(output.content));
+        Ok(())
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read(&self, filetype: StorageFileType, name: &str) -> io::Result<Content> {
+        match self.objects.read().unwrap().get(&(filetype, name.to_owned())) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} {} not found", filetype, name))),
+            Some(content) => Ok(Content::Memory(content.clone())),
+        }
+    }
+
+    fn create(&self, filetype: StorageFileType) -> io::Result<Box<Output>> {
+        Ok(Box::new(MemoryOutput { objects: self.objects.clone(), filetype: filetype, content: Vec::new() }))
+    }
+
+    fn exist(&self, filetype: StorageFileType, name: &str) -> bool {
+        self.objects.read().unwrap().contains_key(&(filetype, name.to_owned()))
+    }
+
+    fn size(&self, filetype: StorageFileType, name: &str) -> io::Result<u64> {
+        match self.objects.read().unwrap().get(&(filetype, name.to_owned())) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} {} not found", filetype, name))),
+            Some(content) => Ok(content.len() as u64),
+        }
+    }
+
+    fn remove(&self, filetype: StorageFileType, name: &str) -> io::Result<()> {
+        match self.objects.write().unwrap().remove(&(filetype, name.to_owned())) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} {} not found", filetype, name))),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn list(&self, filetype: StorageFileType) -> io::Result<Vec<String>> {
+        Ok(self.objects.read().unwrap().keys().filter(|k| k.0 == filetype).map(|k| k.1.clone()).collect())
+    }
+
+    // the objects are only visible once committed, nothing is left over
+    fn list_stale(&self, _age: Duration) -> io::Result<Vec<(StorageFileType, String, u64)>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_stale(&self, filetype: StorageFileType, name: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} {} not found", filetype, name)))
+    }
+
+    fn lock(&self, access: Access, _timeout: Option<Duration>) -> lock::Result<StorageLock> {
+        Ok(StorageLock::unshared(access))
+    }
+}
@@ -1,37 +1,44 @@
 //! objects to iterate through the blocks depending on the backend used
 //!
 
-use super::super::{Storage, StorageConfig, block_location, block_read_location};
+use super::super::{Storage, block_location, block_read_location};
 use super::super::tag;
 use super::super::epoch::epoch_read_pack;
-use super::super::pack::{PackReader};
+use super::super::pack::{MappedPack, Offset};
+use super::super::headerindex::{CompactHeader};
+use types::{BlockHash, header_to_blockhash};
 use blockchain::{HeaderHash, Block, RawBlock, EpochId};
 
-use std::{iter, fs};
+use std::{iter};
 
 use super::error::{Error, Result};
 
 pub struct Iter<'a> {
-    storage: &'a StorageConfig,
+    storage: &'a Storage,
     from:    EpochId,
-    current: PackReader<fs::File>,
+    current: MappedPack,
+    pos:     Offset,
 }
 
 impl<'a> Iter<'a> {
     /// create a block iterator, going forward, moving from epoch to epoch
     /// starting from the given epoch.
-    pub fn new(storage: &'a StorageConfig, from: EpochId) -> Result<Self> {
+    pub fn new(storage: &'a Storage, from: EpochId) -> Result<Self> {
         let current = {
             let epochref = epoch_read_pack(storage, from)?;
-            PackReader::init(&storage, &epochref)
+            MappedPack::open(storage, &epochref)?
         };
-        Ok(Iter { storage, from, current })
+        let pos = current.first_block_offset();
+        Ok(Iter { storage, from, current, pos })
     }
 
     /// get the next raw block, don't attempt to decode the raw block
     pub fn next_raw(&mut self, retry: bool) -> Result<Option<RawBlock>> {
-        match self.current.get_next() {
-            Some(expr) => Ok(Some(expr)),
+        match self.current.entry_at(self.pos)? {
+            Some((block, next)) => {
+                self.pos = next;
+                Ok(Some(self.current.decompress(block)?))
+            },
             None => {
                 if ! retry { return Ok(None); }
                 let next_epoch = self.from + 1;
@@ -46,8 +53,9 @@ impl<'a> Iter<'a> {
                         },
                         Ok(c) => c
                     };
-                    PackReader::init(self.storage, &epochref)
+                    MappedPack::open(self.storage, &epochref)?
                 };
+                self.pos = self.current.first_block_offset();
                 self.from = next_epoch;
                 self.next_raw(false)
             },
@@ -117,3 +125,27 @@ impl<'a> iter::Iterator for ReverseIter<'a> {
         }
     }
 }
+
+/// reverse iterator over the headers of the block chain
+///
+/// unlike `ReverseIter`, the blocks are not read if their headers are
+/// known (see `Storage::get_header`).
+pub struct ReverseHeaderIter<'a> {
+    storage: &'a Storage,
+    current_block: Option<BlockHash>
+}
+impl<'a> ReverseHeaderIter<'a> {
+    pub fn from(storage: &'a Storage, bh: &BlockHash) -> Self {
+        ReverseHeaderIter { storage: storage, current_block: Some(*bh) }
+    }
+}
+impl<'a> iter::Iterator for ReverseHeaderIter<'a> {
+    type Item = CompactHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hash = self.current_block.take()?;
+        let hdr = self.storage.get_header(&hash)?;
+        self.current_block = Some(header_to_blockhash(&hdr.previous_header));
+        Some(hdr)
+    }
+}
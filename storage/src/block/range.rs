@@ -3,17 +3,20 @@ use super::super::{Storage};
 use types::{BlockHash};
 
 use super::error::{Error, Result};
-use super::iter::{ReverseIter};
+use super::iter::{ReverseHeaderIter};
 
 pub struct Range(refpack::RefPack);
 impl Range {
     pub fn new(storage: &Storage, from: BlockHash, to: BlockHash) -> Result<Self> {
-        let ri = ReverseIter::from(storage, &to[..])?;
+        if storage.get_header(&to).is_none() {
+            return Err(Error::HashNotFound(to));
+        }
+        let ri = ReverseHeaderIter::from(storage, &to);
         let mut rp = refpack::RefPack::new();
         let mut finished = false;
 
-        for block in ri {
-            let hash = block.get_header().compute_hash().into_bytes();
+        for hdr in ri {
+            let hash = hdr.hash.into_bytes();
             rp.push_front(hash);
             if hash == from { finished = true; break; }
         }
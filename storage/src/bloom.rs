@@ -39,4 +39,12 @@ pub fn set(bitmap: &mut [u8], content: &[u8]) {
 pub fn is_set(bitmap: &[u8], content: &[u8]) -> bool {
     let (v1,v2,v3) = addr3(bitmap.len() * 8, content);
     bitmap::get_bit(bitmap, v1) && bitmap::get_bit(bitmap, v2) && bitmap::get_bit(bitmap, v3)
+}
+
+// the probability for a content not in the filter to be found: the 3
+// bits of its address are set
+pub fn false_positive_rate(bitmap: &[u8]) -> f64 {
+    if bitmap.is_empty() { return 1.0; }
+    let nb_set : u32 = bitmap.iter().map(|b| b.count_ones()).sum();
+    (nb_set as f64 / (bitmap.len() * 8) as f64).powi(3)
 }
\ No newline at end of file
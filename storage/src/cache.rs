@@ -0,0 +1,80 @@
+//! bounded cache, evicting the least recently used entries
+
+use std::collections::{HashMap, BTreeMap};
+use std::hash::Hash;
+
+pub struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    // the keys ordered by the time of their last use
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru { capacity: capacity, tick: 0, entries: HashMap::new(), order: BTreeMap::new() }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            None => None,
+            Some(&mut (ref value, ref mut last_used)) => {
+                self.order.remove(last_used);
+                *last_used = tick;
+                self.order.insert(tick, key.clone());
+                Some(value.clone())
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 { return; }
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let oldest = *self.order.keys().next().unwrap();
+            let key = self.order.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_the_least_recently_used() {
+        let mut lru = Lru::new(3);
+        lru.insert(1, "one");
+        lru.insert(2, "two");
+        lru.insert(3, "three");
+        assert_eq!(lru.get(&1), Some("one"));
+        // 2 is the least recently used
+        lru.insert(4, "four");
+        assert_eq!(lru.get(&2), None);
+        // replacing a value makes it the most recently used
+        lru.insert(3, "THREE");
+        lru.insert(5, "five");
+        assert_eq!(lru.get(&1), None);
+        assert_eq!(lru.get(&3), Some("THREE"));
+        assert_eq!(lru.get(&4), Some("four"));
+        assert_eq!(lru.get(&5), Some("five"));
+        assert_eq!(lru.entries.len(), 3);
+        assert_eq!(lru.order.len(), 3);
+
+        let mut empty = Lru::new(0);
+        empty.insert(1, "one");
+        assert_eq!(empty.get(&1), None);
+    }
+}
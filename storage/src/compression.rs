@@ -1,23 +1,199 @@
-use flate2::Compression;
+use flate2;
 use flate2::write::DeflateEncoder;
 use flate2::write::DeflateDecoder;
-use std::io::{Error, Write};
+use std::io::{Error, ErrorKind, Write};
+use std::{fmt, str};
+#[cfg(feature = "zstd")]
+use std::io::{Read};
+#[cfg(feature = "zstd")]
+use zstd;
+
+/// the default compression level of zstd
+pub const ZSTD_DEFAULT_LEVEL : i32 = 19;
+
+/// the default size of a trained zstd dictionary, see `train_dictionary`
+pub const DICTIONARY_DEFAULT_SIZE : usize = 112640;
+
+/// the compression of the blocks of a pack file, recorded in the pack
+/// header (see `pack::PackHeader`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Deflate,
+    /// zstd at the given level, with an optional dictionary (see
+    /// `train_dictionary`) needed to decompress the blocks
+    Zstd { level: i32, dictionary: Option<Vec<u8>> },
+}
+impl Default for Compression {
+    fn default() -> Self {
+        if super::USE_COMPRESSION { Compression::Deflate } else { Compression::None }
+    }
+}
+impl Compression {
+    pub fn zstd(level: i32) -> Self { Compression::Zstd { level: level, dictionary: None } }
+
+    /// the zstd compression at the given level using the given dictionary
+    pub fn zstd_with_dictionary(level: i32, dictionary: Vec<u8>) -> Self {
+        Compression::Zstd { level: level, dictionary: Some(dictionary) }
+    }
+
+    /// the identifier of the compression method in the pack header
+    pub fn get_method(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Zstd { .. } => 2,
+        }
+    }
+
+    pub fn get_level(&self) -> i32 {
+        match self {
+            Compression::Zstd { level, .. } => *level,
+            _ => 0,
+        }
+    }
+
+    pub fn get_dictionary(&self) -> Option<&[u8]> {
+        match self {
+            Compression::Zstd { dictionary: Some(ref dict), .. } => Some(&dict[..]),
+            _ => None,
+        }
+    }
+
+    /// the compression from the fields of the pack header
+    pub fn from_header(method: u8, level: i32, dictionary: Option<Vec<u8>>) -> Result<Self, Error> {
+        match method {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            2 => Ok(Compression::Zstd { level: level, dictionary: dictionary }),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unknown compression method {}", method))),
+        }
+    }
+
+    /// tell if this build can compress and decompress with this method
+    /// (zstd needs the `zstd` feature)
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Compression::Zstd { .. } => cfg!(feature = "zstd"),
+            _ => true,
+        }
+    }
+
+    pub fn compress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(Vec::from(input)),
+            Compression::Deflate => {
+                let mut e = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                e.write_all(input)?;
+                e.finish()
+            },
+            Compression::Zstd { level, dictionary } => zstd_compress(*level, dictionary, input),
+        }
+    }
+
+    pub fn decompress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(Vec::from(input)),
+            Compression::Deflate => {
+                let mut deflater = DeflateDecoder::new(Vec::new());
+                deflater.write_all(input)?;
+                deflater.finish()
+            },
+            Compression::Zstd { dictionary, .. } => zstd_decompress(dictionary, input),
+        }
+    }
+}
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Deflate => write!(f, "deflate"),
+            Compression::Zstd { level, dictionary: None } => write!(f, "zstd:{}", level),
+            Compression::Zstd { level, dictionary: Some(ref dict) } => write!(f, "zstd:{} (dictionary of {} bytes)", level, dict.len()),
+        }
+    }
+}
+/// parse `none`, `deflate`, `zstd` or `zstd:LEVEL` (without dictionary)
+impl str::FromStr for Compression {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::zstd(ZSTD_DEFAULT_LEVEL)),
+            _ => {
+                if ! s.starts_with("zstd:") { return Err("expected none, deflate, zstd or zstd:LEVEL"); }
+                match s[5..].parse::<i32>() {
+                    Ok(level) if level >= 1 && level <= 22 => Ok(Compression::zstd(level)),
+                    _ => Err("invalid zstd level, expected 1 to 22"),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(level: i32, dictionary: &Option<Vec<u8>>, input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut e = match dictionary {
+        None => zstd::stream::Encoder::new(Vec::new(), level)?,
+        Some(ref dict) => zstd::stream::Encoder::with_dictionary(Vec::new(), level, dict)?,
+    };
+    e.write_all(input)?;
+    e.finish()
+}
+#[cfg(feature = "zstd")]
+fn zstd_decompress(dictionary: &Option<Vec<u8>>, input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    match dictionary {
+        None => zstd::stream::Decoder::new(input)?.read_to_end(&mut output)?,
+        Some(ref dict) => zstd::stream::Decoder::with_dictionary(input, dict)?.read_to_end(&mut output)?,
+    };
+    Ok(output)
+}
+
+/// train a zstd dictionary of (at most) the given size on the given
+/// blocks, uncompressed. A few epochs worth of blocks make a good sample.
+#[cfg(feature = "zstd")]
+pub fn train_dictionary(samples: &[Vec<u8>], size: usize) -> Result<Vec<u8>, Error> {
+    zstd::dict::from_samples(samples, size)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> Error {
+    Error::new(ErrorKind::Other, "zstd compression is not supported by this build (see the `zstd` feature)")
+}
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: i32, _: &Option<Vec<u8>>, _: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(zstd_unsupported())
+}
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &Option<Vec<u8>>, _: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(zstd_unsupported())
+}
+#[cfg(not(feature = "zstd"))]
+pub fn train_dictionary(_: &[Vec<u8>], _: usize) -> Result<Vec<u8>, Error> {
+    Err(zstd_unsupported())
+}
 
 pub fn decompress_conditional(input: &[u8]) -> Vec<u8> {
+    try_decompress_conditional(input).unwrap()
+}
+
+pub fn try_decompress_conditional(input: &[u8]) -> Result<Vec<u8>, Error> {
     if super::USE_COMPRESSION {
         let mut writer = Vec::new();
         let mut deflater = DeflateDecoder::new(writer);
-        deflater.write_all(&input[..]).unwrap();
-        writer = deflater.finish().unwrap();
-        writer
+        deflater.write_all(&input[..])?;
+        writer = deflater.finish()?;
+        Ok(writer)
     } else {
-        Vec::from(input)
+        Ok(Vec::from(input))
     }
 }
 
 pub fn compress_conditional(input: &[u8]) -> Vec<u8> {
     if super::USE_COMPRESSION {
-        let mut e = DeflateEncoder::new(Vec::new(), Compression::best());
+        let mut e = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
         e.write_all(input).unwrap();
         e.finish().unwrap()
     } else {
@@ -27,7 +203,7 @@ pub fn compress_conditional(input: &[u8]) -> Vec<u8> {
 
 pub fn compress_write<T: Write>(writer: &mut T, input: &[u8]) -> Result<(), Error> {
     if super::USE_COMPRESSION {
-        let mut e = DeflateEncoder::new(Vec::new(), Compression::best());
+        let mut e = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
         e.write_all(input)?;
         let compressed_block = e.finish()?;
         writer.write_all(&compressed_block[..])
@@ -27,6 +27,9 @@ impl StorageConfig {
             StorageFileType::Blob => p.push("blob/"),
             StorageFileType::Tag => p.push("tag/"),
             StorageFileType::Epoch => p.push("epoch/"),
+            StorageFileType::TxIndex => p.push("txindex/"),
+            StorageFileType::AddrIndex => p.push("addrindex/"),
+            StorageFileType::HeaderIndex => p.push("headerindex/"),
         }
         p
     }
@@ -35,6 +38,11 @@ impl StorageConfig {
         p.push("config.yml");
         p
     }
+    pub fn get_peers_file(&self) -> PathBuf {
+        let mut p = self.get_path();
+        p.push("peers.yml");
+        p
+    }
     pub fn get_pack_filepath(&self, packhash: &PackHash) -> PathBuf {
         let mut p = self.get_filetype_dir(StorageFileType::Pack);
         p.push(hex::encode(packhash));
@@ -45,6 +53,21 @@ impl StorageConfig {
         p.push(hex::encode(packhash));
         p
     }
+    pub fn get_txindex_filepath(&self, packhash: &PackHash) -> PathBuf {
+        let mut p = self.get_filetype_dir(StorageFileType::TxIndex);
+        p.push(hex::encode(packhash));
+        p
+    }
+    pub fn get_addrindex_filepath(&self, packhash: &PackHash) -> PathBuf {
+        let mut p = self.get_filetype_dir(StorageFileType::AddrIndex);
+        p.push(hex::encode(packhash));
+        p
+    }
+    pub fn get_headerindex_filepath(&self, packhash: &PackHash) -> PathBuf {
+        let mut p = self.get_filetype_dir(StorageFileType::HeaderIndex);
+        p.push(hex::encode(packhash));
+        p
+    }
     pub fn get_blob_filepath(&self, blockhash: &BlockHash) -> PathBuf {
         let mut p = self.get_filetype_dir(StorageFileType::Blob);
         p.push(hex::encode(blockhash));
@@ -1,35 +1,111 @@
-use std::fs;
 use std::io;
-use std::io::{Read};
 use wallet_crypto::util::{hex};
 
 use blockchain;
 
-use super::{StorageConfig, PackHash, TmpFile, RefPack, pack::PackReader, header_to_blockhash};
+use super::{Storage, PackHash, RefPack, pack::{PackReader, read_size, write_size, read_offset, write_offset}, header_to_blockhash};
+use refpack;
+use types::{StorageFileType};
 
-pub fn epoch_create_with_refpack(config: &StorageConfig, packref: &PackHash, refpack: &RefPack, epochid: blockchain::EpochId) {
-    let dir = config.get_epoch_dir(epochid);
-    fs::create_dir_all(dir).unwrap();
+// the name of the pack pointer of the given epoch
+fn pack_name(epochid: blockchain::EpochId) -> String {
+    format!("{}/pack", epochid)
+}
+
+// the name of the refpack of the given epoch
+fn refpack_name(epochid: blockchain::EpochId) -> String {
+    format!("{}/refpack", epochid)
+}
 
-    let pack_filepath = config.get_epoch_pack_filepath(epochid);
-    super::atomic_write_simple(&pack_filepath, hex::encode(packref).as_bytes()).unwrap();
+// the name of the height record of the given epoch
+fn height_name(epochid: blockchain::EpochId) -> String {
+    format!("{}/height", epochid)
+}
 
-    let mut tmpfile = TmpFile::create(config.get_epoch_dir(epochid)).unwrap();
-    refpack.write(&mut tmpfile).unwrap();
-    tmpfile.render_permanent(&config.get_epoch_refpack_filepath(epochid)).unwrap();
+/// the heights of the blocks of an epoch, the height of a block being its
+/// chain difficulty (i.e. the number of blocks since the start of the chain,
+/// not counting the genesis blocks of the epochs which share the height
+/// of the block before them).
+///
+/// It is recorded along the refpack of the epoch (`<epoch>/height`, 8
+/// bytes BE first height then 4 bytes BE count), see
+/// `Storage::block_at_height`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpochHeight {
+    /// the height of the first block of the epoch after its genesis block
+    pub first: u64,
+    /// the number of blocks of the epoch, without its genesis block
+    pub count: u32,
+}
+impl EpochHeight {
+    pub fn contains(&self, height: u64) -> bool {
+        height >= self.first && height < self.first + self.count as u64
+    }
 }
 
-pub fn epoch_create(config: &StorageConfig, packref: &PackHash, epochid: blockchain::EpochId) {
+/// the heights of the blocks of the epoch, from its refpack and the headers
+/// of the first blocks (see `Storage::get_header`)
+pub fn epoch_compute_height(storage: &Storage, refpack: &RefPack) -> Option<EpochHeight> {
+    let mut blocks = refpack.iter().skip(1).filter(|bh| ! refpack::is_missing(bh));
+    let count = blocks.clone().count() as u32;
+    let first = match blocks.next() {
+        Some(bh) => storage.get_header(bh)?.difficulty.into(),
+        // the epoch is empty, but for its genesis block
+        None => {
+            let difficulty : u64 = storage.get_header(&refpack.get_hash(0)?)?.difficulty.into();
+            difficulty + 1
+        },
+    };
+    Some(EpochHeight { first: first, count: count })
+}
+
+pub fn epoch_write_height(storage: &Storage, epochid: blockchain::EpochId, height: &EpochHeight) -> io::Result<()> {
+    let mut buf = [0u8;12];
+    write_offset(&mut buf[0..8], height.first);
+    write_size(&mut buf[8..12], height.count);
+    storage.get_backend().write(StorageFileType::Epoch, &height_name(epochid), &buf)
+}
+
+pub fn epoch_read_height(storage: &Storage, epochid: blockchain::EpochId) -> io::Result<EpochHeight> {
+    let content = storage.get_backend().read(StorageFileType::Epoch, &height_name(epochid))?;
+    if content.len() != 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid epoch height"));
+    }
+    Ok(EpochHeight { first: read_offset(&content[0..8]), count: read_size(&content[8..12]) })
+}
+
+pub fn epoch_create_with_refpack(storage: &Storage, packref: &PackHash, refpack: &RefPack, epochid: blockchain::EpochId) {
+    let backend = storage.get_backend();
+    backend.write(StorageFileType::Epoch, &pack_name(epochid), hex::encode(packref).as_bytes()).unwrap();
+
+    let mut output = backend.create(StorageFileType::Epoch).unwrap();
+    refpack.write(&mut output).unwrap();
+    output.commit(&refpack_name(epochid)).unwrap();
+
+    match epoch_compute_height(storage, refpack) {
+        None => warn!("cannot find the height of the blocks of epoch {}", epochid),
+        Some(height) => epoch_write_height(storage, epochid, &height).unwrap(),
+    }
+}
+
+pub fn epoch_create(storage: &Storage, packref: &PackHash, epochid: blockchain::EpochId) {
     // read the pack and append the block hash as we find them in the refpack.
     let mut rp = RefPack::new();
-    let mut reader = PackReader::init(config, packref);
+    let mut reader = PackReader::init(storage, packref).unwrap();
 
     let mut current_slotid = blockchain::BlockDate::Genesis(epochid);
-    while let Some(rblk) = reader.get_next() {
+    let mut height = None;
+    while let Some(rblk) = reader.get_next().unwrap() {
         let blk = rblk.decode().unwrap();
         let hdr = blk.get_header();
         let hash = hdr.compute_hash();
         let blockdate = hdr.get_blockdate();
+        let difficulty : u64 = hdr.get_chain_difficulty().into();
+        height = match height {
+            None if blockdate.is_genesis() => Some(EpochHeight { first: difficulty + 1, count: 0 }),
+            None => Some(EpochHeight { first: difficulty, count: 1 }),
+            Some(height) => Some(EpochHeight { first: height.first, count: height.count + 1 }),
+        };
 
         while current_slotid != blockdate {
             rp.push_back_missing();
@@ -42,40 +118,37 @@ pub fn epoch_create(config: &StorageConfig, packref: &PackHash, epochid: blockch
     let got = reader.finalize();
     assert!(&got == packref);
 
-    // create the directory if not exist
-    let dir = config.get_epoch_dir(epochid);
-    fs::create_dir_all(dir).unwrap();
+    let backend = storage.get_backend();
 
     // write the refpack
-    let mut tmpfile = TmpFile::create(config.get_epoch_dir(epochid)).unwrap();
-    rp.write(&mut tmpfile).unwrap();
-    tmpfile.render_permanent(&config.get_epoch_refpack_filepath(epochid)).unwrap();
+    let mut output = backend.create(StorageFileType::Epoch).unwrap();
+    rp.write(&mut output).unwrap();
+    output.commit(&refpack_name(epochid)).unwrap();
+
+    if let Some(height) = height {
+        epoch_write_height(storage, epochid, &height).unwrap();
+    }
 
     // write the pack pointer
-    let pack_filepath = config.get_epoch_pack_filepath(epochid);
-    super::atomic_write_simple(&pack_filepath, hex::encode(packref).as_bytes()).unwrap();
+    backend.write(StorageFileType::Epoch, &pack_name(epochid), hex::encode(packref).as_bytes()).unwrap();
 }
 
-pub fn epoch_read_pack(config: &StorageConfig, epochid: blockchain::EpochId) -> io::Result<PackHash> {
-    let mut content = Vec::new();
-
-    let pack_filepath = config.get_epoch_pack_filepath(epochid);
-    let mut file = fs::File::open(&pack_filepath)?;
-    let _read = file.read_to_end(&mut content).unwrap();
+pub fn epoch_read_pack(storage: &Storage, epochid: blockchain::EpochId) -> io::Result<PackHash> {
+    let content = storage.get_backend().read(StorageFileType::Epoch, &pack_name(epochid))?;
 
-    let p = String::from_utf8(content.clone()).ok().and_then(|r| hex::decode(&r).ok()).unwrap();
+    let p = String::from_utf8(Vec::from(&content[..])).ok().and_then(|r| hex::decode(&r).ok()).unwrap();
     let mut ph = [0u8; super::HASH_SIZE];
     ph.clone_from_slice(&p[..]);
 
     Ok(ph)
 }
 
-pub fn epoch_read(config: &StorageConfig, epochid: blockchain::EpochId) -> io::Result<(PackHash, RefPack)> {
-    match epoch_read_pack(config, epochid) {
+pub fn epoch_read(storage: &Storage, epochid: blockchain::EpochId) -> io::Result<(PackHash, RefPack)> {
+    match epoch_read_pack(storage, epochid) {
         Err(e) => Err(e),
         Ok(ph) => {
-            let mut file = fs::File::open(config.get_epoch_refpack_filepath(epochid))?;
-            let rp = RefPack::read(&mut file).unwrap();
+            let content = storage.get_backend().read(StorageFileType::Epoch, &refpack_name(epochid))?;
+            let rp = RefPack::read(&mut &content[..]).unwrap();
 
             Ok((ph, rp))
         }
@@ -0,0 +1,409 @@
+//! garbage collection of the storage
+//!
+//! The storage accumulates files no longer needed: the packs replaced by
+//! the epoch packs (or created by `pack_blobs` then superseded), the
+//! loose blobs already stored in a pack, the temporary files of the
+//! interrupted writes, the epochs partially downloaded from hermes and
+//! the indexes of the packs which have been removed.
+//!
+//! The reachable packs are the packs referenced by a tag (`EPOCH_n`...)
+//! or by an epoch (`epoch/<n>/pack`), the packs storing the blocks of the
+//! refpacks, and the packs storing any block of the chains of the block
+//! tags (`HEAD`...), down to the genesis. Every other pack is unreferenced, it is removed or,
+//! with `Params::merge`, its blocks not stored anywhere else are merged
+//! into a new pack (tagged `MERGED_<packhash>`) before it is removed.
+//!
+//! The garbage collection expects the caller to hold a
+//! `lock::Access::Exclusive` lock on the storage, unless it is a dry run.
+//! Such a lock excludes the readers: hermes has to be stopped while the
+//! storages it serves are collected (see `lock`).
+
+use std::collections::{BTreeSet};
+use std::io;
+use std::time::{Duration};
+use wallet_crypto::util::{hex};
+use blockchain::{RawBlock, EpochId};
+
+use super::{Storage, Result, blob, tag, epoch, refpack, pack, list_hashes};
+use headerindex::{CompactHeader};
+use types::*;
+
+/// the temporary files older than this are considered left over by an
+/// interrupted write
+pub const TMPFILE_AGE : Duration = Duration::from_secs(3600);
+
+const PARTIAL_EPOCH_PREFIX : &str = ".partial.epoch.";
+const MERGED_TAG_PREFIX : &str = "MERGED_";
+
+const INDEX_TYPES : [StorageFileType;4] =
+    [ StorageFileType::Index, StorageFileType::TxIndex, StorageFileType::AddrIndex, StorageFileType::HeaderIndex ];
+
+pub struct Params {
+    /// only compute the report, nothing is removed
+    pub dry_run: bool,
+    /// keep the blocks of the unreferenced packs which are not stored
+    /// anywhere else by merging them in a new pack
+    pub merge: bool,
+    /// see `TMPFILE_AGE`
+    pub tmpfile_age: Duration,
+}
+impl Default for Params {
+    fn default() -> Self {
+        Params { dry_run: false, merge: false, tmpfile_age: TMPFILE_AGE }
+    }
+}
+
+/// the result of the garbage collection, the sizes are in bytes
+#[derive(Debug, Default)]
+pub struct Report {
+    /// the packs kept
+    pub reachable_packs: BTreeSet<PackHash>,
+    /// the packs removed, along the size of the pack and its indexes
+    pub unreferenced_packs: Vec<(PackHash, u64)>,
+    /// the number of blocks only stored in the unreferenced packs
+    pub orphaned_blocks: u32,
+    /// the loose blobs removed as stored in a reachable pack
+    pub redundant_blobs: Vec<(BlockHash, u64)>,
+    /// the indexes removed as their pack does not exist
+    pub orphaned_indexes: Vec<(StorageFileType, PackHash, u64)>,
+    /// the temporary files removed (see `backend::StorageBackend::list_stale`)
+    pub stale_tmpfiles: Vec<(StorageFileType, String, u64)>,
+    /// the partial epoch downloads (and their etag) removed, named as in
+    /// the packs
+    pub partial_epochs: Vec<(String, u64)>,
+    /// the pack the orphaned blocks have been merged into, if any
+    pub merged: Option<PackHash>,
+}
+impl Report {
+    /// the space reclaimed by the garbage collection (not counting the
+    /// merged pack)
+    pub fn reclaimable(&self) -> u64 {
+        self.unreferenced_packs.iter().map(|&(_, sz)| sz).sum::<u64>()
+            + self.redundant_blobs.iter().map(|&(_, sz)| sz).sum::<u64>()
+            + self.orphaned_indexes.iter().map(|&(_, _, sz)| sz).sum::<u64>()
+            + self.stale_tmpfiles.iter().map(|&(_, _, sz)| sz).sum::<u64>()
+            + self.partial_epochs.iter().map(|&(_, sz)| sz).sum::<u64>()
+    }
+}
+
+/// collect the garbage of the storage, see module documentation
+pub fn gc(storage: &mut Storage, params: &Params) -> Result<Report> {
+    let mut report = collect(storage, params)?;
+    if params.dry_run { return Ok(report); }
+
+    if params.merge && report.orphaned_blocks > 0 {
+        report.merged = Some(merge(storage, &report)?);
+    }
+    for &(ref packhash, _) in report.unreferenced_packs.iter() {
+        storage.packs.remove(packhash);
+        storage.headers.remove(packhash);
+        remove(storage, StorageFileType::Pack, &hex::encode(packhash))?;
+        for filetype in INDEX_TYPES.iter() {
+            remove(storage, *filetype, &hex::encode(packhash))?;
+        }
+    }
+    for &(ref hash, _) in report.redundant_blobs.iter() {
+        remove(storage, StorageFileType::Blob, &hex::encode(hash))?;
+    }
+    for &(filetype, ref packhash, _) in report.orphaned_indexes.iter() {
+        remove(storage, filetype, &hex::encode(packhash))?;
+    }
+    for &(ref name, _) in report.partial_epochs.iter() {
+        remove(storage, StorageFileType::Pack, name)?;
+    }
+    for &(filetype, ref name, _) in report.stale_tmpfiles.iter() {
+        ignore_not_found(storage.get_backend().remove_stale(filetype, name))?;
+    }
+    Ok(report)
+}
+
+/// compute the report of the garbage collection, without modifying the
+/// storage
+pub fn collect(storage: &Storage, params: &Params) -> Result<Report> {
+    let backend = storage.get_backend();
+    let mut report = Report::default();
+    let packs : BTreeSet<PackHash> = list_hashes(backend, StorageFileType::Pack)?.into_iter().collect();
+
+    // the packs referenced by the tags and the epochs, the other tags are
+    // block hashes whose chain is followed once the roots are known
+    let mut block_tags = Vec::new();
+    for name in backend.list(StorageFileType::Tag)? {
+        let content = match tag::read(storage, &name) { None => continue, Some(content) => content };
+        if content.len() != HASH_SIZE { continue; }
+        let mut hash = [0u8;HASH_SIZE];
+        hash.clone_from_slice(&content[..]);
+        if packs.contains(&hash) {
+            report.reachable_packs.insert(hash);
+        } else {
+            block_tags.push(hash);
+        }
+    }
+    for name in backend.list(StorageFileType::Epoch)? {
+        // the epochs are listed as `<epoch>/pack` and `<epoch>/refpack`
+        if ! name.ends_with("/pack") { continue; }
+        let epochid = match name.split('/').next().unwrap().parse() { Err(_) => continue, Ok(epochid) => epochid };
+        if let Ok(packhash) = epoch::epoch_read_pack(storage, epochid) {
+            if packs.contains(&packhash) { report.reachable_packs.insert(packhash); }
+        }
+    }
+
+    // the packs storing the blocks of the refpacks
+    for name in backend.list(StorageFileType::RefPack)? {
+        let rp = refpack::read_refpack(storage, &name)?;
+        for hash in rp.iter() {
+            if let Some(packhash) = find_pack(storage, hash) {
+                report.reachable_packs.insert(packhash);
+            }
+        }
+    }
+
+    // the chains of the block tags are followed down to the genesis, a
+    // pack may hold blocks of the chain without being referenced (e.g. the
+    // incomplete epoch of an imported archive)
+    for hash in block_tags {
+        mark_chain(storage, &mut report.reachable_packs, hash);
+    }
+
+    let mut orphaned = BTreeSet::new();
+    let reachable = &report.reachable_packs;
+    for packhash in packs.difference(reachable) {
+        let mut size = object_size(storage, StorageFileType::Pack, packhash);
+        for filetype in INDEX_TYPES.iter() {
+            size += object_size(storage, *filetype, packhash);
+        }
+        report.unreferenced_packs.push((*packhash, size));
+
+        for_each_block(storage, packhash, |hash, _| {
+            if ! is_kept(storage, reachable, &hash) { orphaned.insert(hash); }
+            Ok(())
+        })?;
+    }
+    report.orphaned_blocks = orphaned.len() as u32;
+
+    for hash in list_hashes(backend, StorageFileType::Blob)? {
+        if in_packs(storage, &report.reachable_packs, &hash) {
+            report.redundant_blobs.push((hash, object_size(storage, StorageFileType::Blob, &hash)));
+        }
+    }
+
+    for filetype in INDEX_TYPES.iter() {
+        for packhash in list_hashes(backend, *filetype)? {
+            if packs.contains(&packhash) { continue; }
+            report.orphaned_indexes.push((*filetype, packhash, object_size(storage, *filetype, &packhash)));
+        }
+    }
+
+    report.stale_tmpfiles = backend.list_stale(params.tmpfile_age)?;
+    for name in backend.list(StorageFileType::Pack)? {
+        if ! name.starts_with(PARTIAL_EPOCH_PREFIX) { continue; }
+        let size = backend.size(StorageFileType::Pack, &name).unwrap_or(0);
+        report.partial_epochs.push((name, size));
+    }
+
+    Ok(report)
+}
+
+// write the orphaned blocks of the unreferenced packs in a new pack,
+// indexed and tagged so it is reachable
+fn merge(storage: &mut Storage, report: &Report) -> Result<PackHash> {
+    let mut writer = pack::PackWriter::init(storage);
+    let mut merged = BTreeSet::new();
+    for &(ref packhash, _) in report.unreferenced_packs.iter() {
+        for_each_block(storage, packhash, |hash, block| {
+            if ! is_kept(storage, &report.reachable_packs, &hash) && merged.insert(hash) {
+                writer.append(&hash, block.as_ref());
+            }
+            Ok(())
+        })?;
+    }
+    let (packhash, index) = writer.finalize();
+    storage.add_pack(&packhash, &index)?;
+    tag::write(storage, &format!("{}{}", MERGED_TAG_PREFIX, hex::encode(&packhash)), &packhash[..]);
+    Ok(packhash)
+}
+
+// mark the packs storing the blocks of the chain ending at `from`. The
+// epochs whose refpack holds the block are skipped at once: their blocks
+// are all stored in the epoch pack.
+fn mark_chain(storage: &Storage, reachable: &mut BTreeSet<PackHash>, from: BlockHash) {
+    let mut hash = from;
+    let mut checked_epoch = None;
+    loop {
+        if let Some(packhash) = find_pack(storage, &hash) {
+            reachable.insert(packhash);
+        }
+        let hdr = match storage.get_header(&hash) { None => break, Some(hdr) => hdr };
+        let epochid = hdr.date.get_epochid();
+        if checked_epoch != Some(epochid) {
+            checked_epoch = Some(epochid);
+            if let Some(first) = epoch_start(storage, reachable, epochid, &hash) {
+                hash = header_to_blockhash(&first.previous_header);
+                continue;
+            }
+        }
+        hash = header_to_blockhash(&hdr.previous_header);
+    }
+}
+
+// the first block of the epoch if its refpack holds the given block, the
+// epoch pack is then marked
+fn epoch_start(storage: &Storage, reachable: &mut BTreeSet<PackHash>, epochid: EpochId, hash: &BlockHash) -> Option<CompactHeader> {
+    let (packhash, rp) = epoch::epoch_read(storage, epochid).ok()?;
+    if ! rp.iter().any(|h| h == hash) { return None; }
+    let first = rp.iter().find(|h| ! refpack::is_missing(h))?;
+    let hdr = storage.get_header(first)?;
+    if storage.packs.contains_key(&packhash) { reachable.insert(packhash); }
+    Some(hdr)
+}
+
+// call `f` with the hash and the (decompressed) block of every block of
+// the pack, in the order of the pack
+fn for_each_block<F>(storage: &Storage, packhash: &PackHash, mut f: F) -> Result<()>
+    where F: FnMut(BlockHash, &RawBlock) -> Result<()>
+{
+    let pack = pack::MappedPack::open(storage, packhash)?;
+    let mut blocks = pack.iter();
+    while let Some(entry) = blocks.next_raw() {
+        let (_, rblk) = entry?;
+        let hash = rblk.decode()?.get_header().compute_hash();
+        f(header_to_blockhash(&hash), &rblk)?;
+    }
+    Ok(())
+}
+
+// the block is stored in one of the given packs
+fn in_packs(storage: &Storage, packs: &BTreeSet<PackHash>, hash: &BlockHash) -> bool {
+    packs.iter().any(|packhash| {
+        storage.packs.get(packhash).map(|&(ref index, _)| index.search(hash).is_some()).unwrap_or(false)
+    })
+}
+
+// the block is stored in one of the given packs or as a loose blob
+fn is_kept(storage: &Storage, packs: &BTreeSet<PackHash>, hash: &BlockHash) -> bool {
+    in_packs(storage, packs, hash) || blob::exist(storage, hash)
+}
+
+fn find_pack(storage: &Storage, hash: &BlockHash) -> Option<PackHash> {
+    storage.packs.iter().find(|&(_, &(ref index, _))| index.search(hash).is_some()).map(|(packhash, _)| *packhash)
+}
+
+fn object_size(storage: &Storage, filetype: StorageFileType, hash: &[u8;HASH_SIZE]) -> u64 {
+    storage.get_backend().size(filetype, &hex::encode(hash)).unwrap_or(0)
+}
+
+fn remove(storage: &Storage, filetype: StorageFileType, name: &str) -> io::Result<()> {
+    ignore_not_found(storage.get_backend().remove(filetype, name))
+}
+
+fn ignore_not_found(r: io::Result<()>) -> io::Result<()> {
+    match r {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::ops::{Range};
+    use protocol::mock::{Chain};
+    use wallet_crypto::config::{ProtocolMagic};
+    use super::super::{StorageConfig, block_read};
+
+    fn storage(name: &str) -> Storage {
+        let dir = env::temp_dir().join(format!("storage-gc-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Storage::init(&StorageConfig::new(&dir)).unwrap()
+    }
+
+    // write the blocks of the chain at the given heights in a new pack
+    fn write_pack(storage: &mut Storage, chain: &Chain, heights: Range<usize>) -> PackHash {
+        let mut writer = pack::PackWriter::init(storage);
+        for height in heights {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        packhash
+    }
+
+    fn hash_at(chain: &Chain, height: usize) -> BlockHash { header_to_blockhash(&chain.hash(height)) }
+
+    fn pack_exists(storage: &Storage, packhash: &PackHash) -> bool {
+        storage.get_backend().exist(StorageFileType::Pack, &hex::encode(packhash))
+    }
+
+    #[test]
+    fn keep_the_packs_of_the_head_chain() {
+        // epochs of 11 blocks, the epoch 3 is incomplete
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 40);
+        let mut fork = chain.fork(30);
+        fork.skip_slots(1);
+        fork.extend(3);
+
+        let mut storage = storage("head-chain");
+        let epoch0 = write_pack(&mut storage, &chain, 0..11);
+        epoch::epoch_create(&storage, &epoch0, 0);
+        let epoch1 = write_pack(&mut storage, &chain, 11..22);
+        tag::write(&storage, &"EPOCH_1", &epoch1[..]);
+        // packs of the HEAD chain only reachable through its blocks, like
+        // the incomplete epoch of an imported archive
+        let untagged = write_pack(&mut storage, &chain, 22..33);
+        let imported = write_pack(&mut storage, &chain, 33..36);
+        for height in 36..40 {
+            blob::write(&storage, &hash_at(&chain, height), chain.block(height).as_ref()).unwrap();
+        }
+        blob::write(&storage, &hash_at(&chain, 25), chain.block(25).as_ref()).unwrap();
+        tag::write_hash(&storage, &tag::HEAD, &chain.hash(39));
+
+        let duplicate = write_pack(&mut storage, &chain, 0..5);
+        let forked = write_pack(&mut storage, &fork, 28..33);
+
+        storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.4", b"partial").unwrap();
+        storage.get_backend().write(StorageFileType::Pack, ".partial.epoch.4.etag", b"etag").unwrap();
+        fs::write(storage.config.get_filetype_dir(StorageFileType::Blob).join(".tmp.interrupted"), b"tmp").unwrap();
+        fs::write(storage.config.get_filetype_dir(StorageFileType::Epoch).join("0").join(".tmp.interrupted"), b"epoch").unwrap();
+
+        let params = Params { dry_run: true, merge: true, tmpfile_age: Duration::from_secs(0) };
+        let report = gc(&mut storage, &params).unwrap();
+        assert_eq!(report.reachable_packs, [epoch0, epoch1, untagged, imported].iter().cloned().collect());
+        let mut unreferenced : Vec<PackHash> = report.unreferenced_packs.iter().map(|&(packhash, _)| packhash).collect();
+        unreferenced.sort();
+        let mut expected = vec![duplicate, forked];
+        expected.sort();
+        assert_eq!(unreferenced, expected);
+        // the fork blocks 30..33, its first blocks are the chain's
+        assert_eq!(report.orphaned_blocks, 3);
+        assert_eq!(report.redundant_blobs.iter().map(|&(hash, _)| hash).collect::<Vec<_>>(), vec![hash_at(&chain, 25)]);
+        assert_eq!(report.partial_epochs.len(), 2);
+        let mut stale_tmpfiles = report.stale_tmpfiles.clone();
+        stale_tmpfiles.sort();
+        assert_eq!(stale_tmpfiles, vec![(StorageFileType::Blob, String::from(".tmp.interrupted"), 3),
+                                        (StorageFileType::Epoch, String::from("0/.tmp.interrupted"), 5)]);
+        assert!(report.merged.is_none());
+        assert!(pack_exists(&storage, &forked));
+        assert!(storage.get_backend().exist(StorageFileType::Pack, ".partial.epoch.4"));
+
+        let params = Params { dry_run: false, ..params };
+        let report = gc(&mut storage, &params).unwrap();
+        let merged = report.merged.unwrap();
+        assert!(! pack_exists(&storage, &duplicate));
+        assert!(! pack_exists(&storage, &forked));
+        assert!(! storage.get_backend().exist(StorageFileType::Pack, ".partial.epoch.4"));
+        assert!(! blob::exist(&storage, &hash_at(&chain, 25)));
+        for height in 0..40 {
+            assert!(block_read(&storage, &hash_at(&chain, height)).is_some());
+        }
+        for height in 30..33 {
+            assert!(block_read(&storage, &hash_at(&fork, height)).is_some());
+        }
+
+        // nothing left to collect, the merged pack is tagged
+        let report = collect(&storage, &params).unwrap();
+        assert!(report.reachable_packs.contains(&merged));
+        assert!(report.unreferenced_packs.is_empty());
+        assert_eq!(report.reclaimable(), 0);
+        fs::remove_dir_all(storage.config.get_path()).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+// a header index file is associated to a pack and is:
+//
+// MAGIC (8 Bytes)
+// NUMBER OF ENTRIES (4 bytes BE)
+// 0-PADDING (4 bytes)
+// FANOUT (256*4 bytes)
+// ENTRIES ordered lexigraphically by block hash (#ENTRIES * 88 bytes), an entry being:
+//     BLOCK HASH (32 bytes)
+//     PREVIOUS BLOCK HASH (32 bytes)
+//     EPOCH (4 bytes BE)
+//     SLOT (4 bytes BE, 0xffffffff for the genesis block of the epoch)
+//     CHAIN DIFFICULTY (8 bytes BE)
+//     OFFSET of the BLOCK in the pack (8 bytes BE)
+//
+// it allows to follow the chain (and to know the date and the difficulty
+// of the blocks) without reading nor decoding the blocks.
+
+use std::io;
+use std::io::{Write};
+
+use blockchain::{BlockHeader, BlockDate, HeaderHash, ChainDifficulty, SlotId};
+use wallet_crypto::util::{hex};
+
+use super::{Storage, Result};
+use backend::{Content};
+use types::{HASH_SIZE, BlockHash, PackHash, StorageFileType};
+use pack::{MappedPack, Offset, write_size, read_size, write_offset, read_offset, write_fanout, fanout_search};
+
+const MAGIC : &[u8] = b"ADAHDR01";
+const MAGIC_SIZE : usize = 8;
+const OFF_SIZE : usize = 8;
+const SIZE_SIZE : usize = 4;
+
+const FANOUT_ELEMENTS : usize = 256;
+const FANOUT_OFFSET : usize = MAGIC_SIZE + 8;
+const HEADER_SIZE : usize = FANOUT_OFFSET + FANOUT_ELEMENTS * SIZE_SIZE;
+
+const ENTRY_SIZE : usize = HASH_SIZE + HASH_SIZE + SIZE_SIZE + SIZE_SIZE + OFF_SIZE + OFF_SIZE;
+
+const GENESIS_SLOT : u32 = 0xffffffff;
+
+/// the part of a block header needed to follow the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactHeader {
+    pub hash: HeaderHash,
+    pub previous_header: HeaderHash,
+    pub date: BlockDate,
+    pub difficulty: ChainDifficulty,
+}
+impl<'a> From<&'a BlockHeader> for CompactHeader {
+    fn from(hdr: &'a BlockHeader) -> Self {
+        CompactHeader {
+            hash: hdr.compute_hash(),
+            previous_header: hdr.get_previous_header(),
+            date: hdr.get_blockdate(),
+            difficulty: hdr.get_chain_difficulty(),
+        }
+    }
+}
+
+fn hash_from_slice(buf: &[u8]) -> HeaderHash {
+    let mut bytes = [0u8;HASH_SIZE];
+    bytes.clone_from_slice(&buf[0..HASH_SIZE]);
+    HeaderHash::from_bytes(bytes)
+}
+
+/// build the header index of the given pack
+///
+/// the pack is read block by block and the header of every block is
+/// recorded in a new index file, replacing the previous one if any.
+pub fn create(storage: &Storage, packhash: &PackHash) -> Result<usize> {
+    let mut entries = Vec::new();
+    let pack = MappedPack::open(storage, packhash)?;
+    let mut blocks = pack.iter();
+    while let Some(rblk) = blocks.next_raw() {
+        let (offset, rblk) = rblk?;
+        let hdr = rblk.decode()?.get_header();
+        entries.push((CompactHeader::from(&hdr), offset));
+    }
+    entries.sort_by(|a, b| a.0.hash.as_ref().cmp(b.0.hash.as_ref()));
+
+    let mut output = storage.get_backend().create(StorageFileType::HeaderIndex)?;
+    let mut hdr_buf = [0u8;HEADER_SIZE];
+    hdr_buf[0..MAGIC_SIZE].clone_from_slice(&MAGIC[..]);
+    write_size(&mut hdr_buf[8..12], entries.len() as u32);
+    write_size(&mut hdr_buf[12..16], 0);
+
+    write_fanout(&mut hdr_buf[FANOUT_OFFSET..HEADER_SIZE], entries.iter().map(|&(ref hdr, _)| hdr.hash.as_ref()[0]));
+    output.write_all(&hdr_buf)?;
+
+    for &(ref hdr, offset) in entries.iter() {
+        let (epoch, slot) = match hdr.date {
+            BlockDate::Genesis(epoch) => (epoch, GENESIS_SLOT),
+            BlockDate::Normal(ref slotid) => (slotid.epoch, slotid.slotid),
+        };
+        let mut buf = [0u8;ENTRY_SIZE];
+        buf[0..32].clone_from_slice(hdr.hash.as_ref());
+        buf[32..64].clone_from_slice(hdr.previous_header.as_ref());
+        write_size(&mut buf[64..68], epoch);
+        write_size(&mut buf[68..72], slot);
+        write_offset(&mut buf[72..80], hdr.difficulty.into());
+        write_offset(&mut buf[80..88], offset);
+        output.write_all(&buf)?;
+    }
+    output.commit(&hex::encode(packhash))?;
+    Ok(entries.len())
+}
+
+/// check if the header index of the given pack has been created
+pub fn exist(storage: &Storage, packhash: &PackHash) -> bool {
+    storage.get_backend().exist(StorageFileType::HeaderIndex, &hex::encode(packhash))
+}
+
+/// a header index file mapped in memory
+pub struct HeaderIndex {
+    mmap: Content,
+    total: u32,
+}
+impl HeaderIndex {
+    pub fn open(storage: &Storage, packhash: &PackHash) -> io::Result<Self> {
+        // the index files are never modified once written (see `create`)
+        let mmap = storage.get_backend().read(StorageFileType::HeaderIndex, &hex::encode(packhash))?;
+        if mmap.len() < HEADER_SIZE || &mmap[0..MAGIC_SIZE] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header index magic"));
+        }
+        let total = read_size(&mmap[8..12]);
+        if mmap.len() < HEADER_SIZE + total as usize * ENTRY_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated header index"));
+        }
+        Ok(HeaderIndex { mmap: mmap, total: total })
+    }
+
+    /// the number of headers in the index
+    pub fn len(&self) -> u32 { self.total }
+
+    fn entry(&self, index: u32) -> &[u8] {
+        let ofs = HEADER_SIZE + index as usize * ENTRY_SIZE;
+        &self.mmap[ofs..ofs + ENTRY_SIZE]
+    }
+
+    /// look for the header of the given block, returning it along the
+    /// offset of the block in the pack
+    pub fn search(&self, hash: &BlockHash) -> Option<(CompactHeader, Offset)> {
+        let index = fanout_search(&self.mmap[FANOUT_OFFSET..HEADER_SIZE], self.total, hash[0], |index| self.entry(index)[0..32].cmp(&hash[..]))?;
+        let buf = self.entry(index);
+        let epoch = read_size(&buf[64..68]);
+        let date = match read_size(&buf[68..72]) {
+            GENESIS_SLOT => BlockDate::Genesis(epoch),
+            slot => BlockDate::Normal(SlotId { epoch: epoch, slotid: slot }),
+        };
+        let hdr = CompactHeader {
+            hash: hash_from_slice(&buf[0..32]),
+            previous_header: hash_from_slice(&buf[32..64]),
+            date: date,
+            difficulty: ChainDifficulty::from(read_offset(&buf[72..80])),
+        };
+        Some((hdr, read_offset(&buf[80..88])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{MemoryBackend};
+    use pack::{PackWriter};
+    use protocol::mock::{Chain};
+    use types::{header_to_blockhash};
+    use wallet_crypto::config::{ProtocolMagic};
+
+    #[test]
+    fn search_the_headers_of_a_pack() {
+        // epochs of 11 slots: the blocks 0 and 11 are genesis blocks
+        let chain = Chain::generate(ProtocolMagic::default(), 10, 25);
+        let storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let mut writer = PackWriter::init(&storage);
+        for height in 0..20 {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        assert!(! exist(&storage, &packhash));
+        assert_eq!(create(&storage, &packhash).unwrap(), 20);
+        assert!(exist(&storage, &packhash));
+
+        let headers = HeaderIndex::open(&storage, &packhash).unwrap();
+        assert_eq!(headers.len(), 20);
+        for (hash, offset) in index.hashes.iter().zip(index.offsets.iter()) {
+            let (hdr, found) = headers.search(hash).unwrap();
+            let height = chain.height(&hdr.hash).unwrap();
+            assert_eq!((&hdr, found), (&CompactHeader::from(chain.header(height)), *offset));
+        }
+        let genesis = headers.search(&header_to_blockhash(&chain.hash(11))).unwrap().0;
+        assert_eq!(genesis.date, BlockDate::Genesis(1));
+        assert_eq!(genesis.previous_header, chain.hash(10));
+        let first = headers.search(&header_to_blockhash(&chain.hash(12))).unwrap().0;
+        assert_eq!(first.date, BlockDate::Normal(SlotId { epoch: 1, slotid: 0 }));
+        for height in 20..25 {
+            assert!(headers.search(&header_to_blockhash(&chain.hash(height))).is_none());
+        }
+
+        // a corrupted fanout must not make the search read past the entries
+        let content = storage.get_backend().read(StorageFileType::HeaderIndex, &hex::encode(&packhash)).unwrap();
+        let mut corrupted = Vec::from(&content[..]);
+        for byte in corrupted[FANOUT_OFFSET..HEADER_SIZE].iter_mut() { *byte = 0xff }
+        storage.get_backend().write(StorageFileType::HeaderIndex, &hex::encode(&packhash), &corrupted).unwrap();
+        let headers = HeaderIndex::open(&storage, &packhash).unwrap();
+        for hash in index.hashes.iter() {
+            headers.search(hash);
+        }
+
+        storage.get_backend().write(StorageFileType::HeaderIndex, &hex::encode(&packhash), &content[..content.len() - 1]).unwrap();
+        assert_eq!(HeaderIndex::open(&storage, &packhash).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
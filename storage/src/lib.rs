@@ -6,6 +6,13 @@ extern crate wallet_crypto;
 extern crate blockchain;
 extern crate rand;
 extern crate flate2;
+extern crate memmap;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(test)]
+extern crate protocol;
 
 pub mod block;
 pub mod types;
@@ -17,22 +24,43 @@ pub mod refpack;
 pub mod tmpfile;
 pub mod lock;
 pub mod append;
-mod compression;
+pub mod txindex;
+pub mod addrindex;
+pub mod headerindex;
+pub mod gc;
+pub mod archive;
+pub mod stats;
+pub mod backend;
+pub mod compression;
+mod cache;
 mod bitmap;
 mod bloom;
 use std::{fs, io, result};
+use std::path::{PathBuf};
 
 pub use config::StorageConfig;
 
+use std::cmp::{Ordering};
 use std::collections::BTreeMap;
+use std::sync::{Mutex};
+use std::time::{Duration};
 use refpack::{RefPack};
 use blockchain::{HeaderHash, BlockDate, RawBlock};
+use headerindex::{CompactHeader, HeaderIndex};
+use cache::{Lru};
+use backend::{StorageBackend, FsBackend, MemoryBackend};
+use wallet_crypto::tx::{TxId, TxAux};
+use wallet_crypto::util::{hex};
 
 use types::*;
-use tmpfile::*;
 
 const USE_COMPRESSION : bool = true;
 
+/// number of raw blocks kept in memory by the storage
+pub const BLOCK_CACHE_SIZE : usize = 256;
+/// number of headers kept in memory by the storage (see `Storage::get_header`)
+pub const HEADER_CACHE_SIZE : usize = 0x10000;
+
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
@@ -63,35 +91,117 @@ impl From<raw_cbor::Error> for Error {
 pub type Result<T> = result::Result<T, Error>;
 
 pub struct Storage {
+    /// the directories of the storage, used by the `FsBackend` (and the
+    /// tools working on the files directly: gc, verification)
     pub config: StorageConfig,
-    lookups: BTreeMap<PackHash, pack::Lookup>,
+    backend: Box<StorageBackend>,
+    // the indexed packs, mapped in memory
+    packs: BTreeMap<PackHash, (pack::MappedIndex, pack::MappedPack)>,
+    // the header indexes of the packs, mapped in memory
+    headers: BTreeMap<PackHash, HeaderIndex>,
+    block_cache: Mutex<Lru<BlockHash, RawBlock>>,
+    header_cache: Mutex<Lru<BlockHash, CompactHeader>>,
 }
 
 impl Storage {
+    /// open the storage in the directories of the given configuration
     pub fn init(cfg: &StorageConfig) -> Result<Self> {
-        let mut lookups = BTreeMap::new();
-
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::Blob))?;
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::Index))?;
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::Pack))?;
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::Tag))?;
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::Epoch))?;
         fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::RefPack))?;
+        fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::TxIndex))?;
+        fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::AddrIndex))?;
+        fs::create_dir_all(cfg.get_filetype_dir(StorageFileType::HeaderIndex))?;
 
-        let packhashes = cfg.list_indexes();
-        for p in packhashes.iter() {
-            match pack::read_index_fanout(&cfg, p) {
-                Err(_)     => {},
-                Ok(lookup) => {
-                    lookups.insert(*p, lookup);
+        Storage::with_backend(cfg, Box::new(FsBackend::new(cfg)))
+    }
+
+    /// open a storage kept in memory (see `backend::MemoryBackend`), the
+    /// configuration of such storage has no directory.
+    pub fn memory(backend: MemoryBackend) -> Result<Self> {
+        Storage::with_backend(&StorageConfig::new(&PathBuf::new()), Box::new(backend))
+    }
+
+    /// open the storage whose objects are kept by the given backend
+    pub fn with_backend(cfg: &StorageConfig, backend: Box<StorageBackend>) -> Result<Self> {
+        let mut storage = Storage {
+            config: cfg.clone(),
+            backend: backend,
+            packs: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            block_cache: Mutex::new(Lru::new(BLOCK_CACHE_SIZE)),
+            header_cache: Mutex::new(Lru::new(HEADER_CACHE_SIZE)),
+        };
+
+        for p in list_hashes(&*storage.backend, StorageFileType::Index)? {
+            match storage.map_pack(&p) {
+                Err(err)   => warn!("cannot open pack {}: {:?}", hex::encode(&p), err),
+                Ok(mapped) => {
+                    storage.packs.insert(p, mapped);
+                }
+            }
+            if headerindex::exist(&storage, &p) {
+                match HeaderIndex::open(&storage, &p) {
+                    Err(err)  => warn!("cannot open header index {}: {:?}", hex::encode(&p), err),
+                    Ok(index) => { storage.headers.insert(p, index); },
                 }
             }
         }
-
-        let storage = Storage { config: cfg.clone(), lookups: lookups };
         Ok(storage)
     }
 
+    /// the backend keeping the objects of the storage
+    pub fn get_backend(&self) -> &StorageBackend { &*self.backend }
+
+    fn map_pack(&self, packhash: &PackHash) -> io::Result<(pack::MappedIndex, pack::MappedPack)> {
+        Ok((pack::MappedIndex::open(self, packhash)?, pack::MappedPack::open(self, packhash)?))
+    }
+
+    /// index the pack newly written (see `pack::PackWriter::finalize`) and
+    /// map it in memory, for its blocks, transactions and headers to be
+    /// found by the lookups of this storage
+    pub fn add_pack(&mut self, packhash: &PackHash, index: &pack::Index) -> Result<()> {
+        pack::create_index(self, packhash, index)?;
+        txindex::create(self, packhash)?;
+        addrindex::create(self, packhash)?;
+        headerindex::create(self, packhash)?;
+
+        let mapped = self.map_pack(packhash)?;
+        self.packs.insert(*packhash, mapped);
+        let headers = HeaderIndex::open(self, packhash)?;
+        self.headers.insert(*packhash, headers);
+        Ok(())
+    }
+
+    /// lock the storage against the other processes (see `lock::StorageLock`)
+    ///
+    /// the operations writing in the storage (sync, pack) are expected to
+    /// hold a `lock::Access::Write` lock.
+    pub fn lock(&self, access: lock::Access, timeout: Option<Duration>) -> lock::Result<lock::StorageLock> {
+        self.backend.lock(access, timeout)
+    }
+
+    /// get the header of the given block, without reading the block if
+    /// possible: from the cache or the header indexes of the packs (see
+    /// `headerindex::create`).
+    pub fn get_header(&self, hash: &BlockHash) -> Option<CompactHeader> {
+        if let Some(hdr) = self.header_cache.lock().unwrap().get(hash) {
+            return Some(hdr);
+        }
+        let hdr = match self.headers.values().filter_map(|index| index.search(hash)).next() {
+            Some((hdr, _)) => hdr,
+            None => {
+                let blk = block_read(self, hash)?.decode().ok()?;
+                CompactHeader::from(&blk.get_header())
+            },
+        };
+        self.header_cache.lock().unwrap().insert(*hash, hdr.clone());
+        Some(hdr)
+    }
+
     /// create a reverse iterator over the stored blocks
     ///
     /// it will iterate from the tag `HEAD` until there is no more
@@ -100,66 +210,149 @@ impl Storage {
         block::ReverseIter::new(self).map_err(|err| Error::BlockError(err))
     }
 
+    /// create a reverse iterator over the headers of the stored blocks,
+    /// from the given block
+    pub fn reverse_header_iter<'a>(&'a self, from: &BlockHash) -> block::ReverseHeaderIter<'a> {
+        block::ReverseHeaderIter::from(self, from)
+    }
+
     /// create a block iterator starting from the given EpochId
     pub fn iterate_from_epoch<'a>(&'a self, from: blockchain::EpochId) -> Result<block::Iter<'a>> {
-        Ok(block::Iter::new(&self.config, from)?)
+        Ok(block::Iter::new(self, from)?)
     }
 
     /// construct a range between the given hash
     pub fn range(&self, from: BlockHash, to: BlockHash) -> Result<block::Range> {
         block::Range::new(self, from, to).map_err(|err| Error::BlockError(err))
     }
+
+    /// the hash of the block of the given date, in the chain of `HEAD`
+    ///
+    /// the block is found in the refpack of the epoch if the epoch has been
+    /// packed (see `epoch::epoch_create`), otherwise the chain is walked
+    /// back from `HEAD`.
+    pub fn block_at_date(&self, date: &BlockDate) -> Option<BlockHash> {
+        if let Ok((_, refpack)) = epoch::epoch_read(self, date.get_epochid()) {
+            let index = match date {
+                BlockDate::Genesis(_) => 0,
+                BlockDate::Normal(slotid) => slotid.slotid as usize + 1,
+            };
+            return refpack.get_hash(index);
+        }
+        self.find_from_head(|hdr| hdr.date.cmp(date))
+    }
+
+    /// the hash of the block at the given height (its chain difficulty, see
+    /// `epoch::EpochHeight`), in the chain of `HEAD`
+    ///
+    /// the epoch of the block is searched with the heights recorded along
+    /// the refpacks of the epochs, the chain is walked back from `HEAD` for
+    /// the blocks of the epochs not packed yet.
+    pub fn block_at_height(&self, height: u64) -> Option<BlockHash> {
+        let head = tag::read_hash(self, &tag::HEAD)?;
+        let tip = self.get_header(&head.into_bytes())?;
+        let tip_height : u64 = tip.difficulty.into();
+        if height == 0 || height > tip_height { return None; }
+
+        // the packed epochs come first, binary search them
+        let (mut low, mut high) = (0, tip.date.get_epochid() + 1);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let epoch_height = match epoch::epoch_read_height(self, middle) {
+                Ok(epoch_height) => Some(epoch_height),
+                // the epochs packed before the heights were recorded
+                Err(_) => epoch::epoch_read(self, middle).ok()
+                            .and_then(|(_, refpack)| epoch::epoch_compute_height(self, &refpack)),
+            };
+            match epoch_height {
+                None => high = middle,
+                Some(ref epoch_height) if height < epoch_height.first => high = middle,
+                Some(ref epoch_height) if ! epoch_height.contains(height) => low = middle + 1,
+                Some(epoch_height) => {
+                    let (_, refpack) = epoch::epoch_read(self, middle).ok()?;
+                    return refpack.iter().skip(1).filter(|bh| ! refpack::is_missing(bh))
+                                  .nth((height - epoch_height.first) as usize).cloned();
+                },
+            }
+        }
+        self.find_from_head(|hdr| {
+            let difficulty : u64 = hdr.difficulty.into();
+            // the genesis block shares the height of the block before it
+            if hdr.date.is_genesis() && difficulty == height { Ordering::Greater } else { difficulty.cmp(&height) }
+        })
+    }
+
+    // walk back the chain from `HEAD` until the block compares equal (found)
+    // or less (not found) to the one looked for
+    fn find_from_head<F>(&self, compare: F) -> Option<BlockHash>
+        where F: Fn(&CompactHeader) -> Ordering
+    {
+        let head = tag::read_hash(self, &tag::HEAD)?;
+        for hdr in self.reverse_header_iter(&head.into_bytes()) {
+            match compare(&hdr) {
+                Ordering::Greater => {},
+                Ordering::Equal => return Some(hdr.hash.into_bytes()),
+                Ordering::Less => return None,
+            }
+        }
+        None
+    }
 }
 
-fn tmpfile_create_type(storage: &Storage, filetype: StorageFileType) -> TmpFile {
-    TmpFile::create(storage.config.get_filetype_dir(filetype)).unwrap()
+// the hashes naming the objects of the given type
+fn list_hashes(backend: &StorageBackend, filetype: StorageFileType) -> io::Result<Vec<[u8;HASH_SIZE]>> {
+    let mut hashes = Vec::new();
+    for name in backend.list(filetype)? {
+        if name.len() != 2 * HASH_SIZE { continue; }
+        if let Ok(v) = hex::decode(&name) {
+            let mut hash = [0;HASH_SIZE];
+            hash.clone_from_slice(&v[..]);
+            hashes.push(hash);
+        }
+    }
+    Ok(hashes)
 }
 
 pub mod blob {
-    use std::fs;
-    use std::io::{Read};
-    use super::{Result, Error};
+    use super::{Result};
     use compression;
     use blockchain::RawBlock;
+    use types::{StorageFileType};
+    use wallet_crypto::util::{hex};
 
     pub fn write(storage: &super::Storage, hash: &super::BlockHash, block: &[u8]) -> Result<()> {
-        let path = storage.config.get_blob_filepath(&hash);
-        let mut tmp_file = super::tmpfile_create_type(storage, super::StorageFileType::Blob);
-        compression::compress_write(&mut tmp_file, block)?;
-        tmp_file.render_permanent(&path).map_err(|e| Error::IoError(e))
+        let mut output = storage.backend.create(StorageFileType::Blob)?;
+        compression::compress_write(&mut output, block)?;
+        Ok(output.commit(&hex::encode(hash))?)
     }
 
     pub fn read_raw(storage: &super::Storage, hash: &super::BlockHash) -> Result<Vec<u8>> {
-        let mut content = Vec::new();
-        let path = storage.config.get_blob_filepath(&hash);
-
-        let mut file = fs::File::open(path)?;
-        file.read_to_end(&mut content)?;
-        Ok(content)
+        let content = storage.backend.read(StorageFileType::Blob, &hex::encode(hash))?;
+        Ok(Vec::from(&content[..]))
     }
 
     pub fn read(storage: &super::Storage, hash: &super::BlockHash) -> Result<RawBlock> {
-        let mut content = Vec::new();
-        let path = storage.config.get_blob_filepath(&hash);
-
-        let mut file = fs::File::open(path)?;
-        file.read_to_end(&mut content)?;
-
-        Ok(RawBlock::from_dat(compression::decompress_conditional(content.as_ref())))
+        let content = storage.backend.read(StorageFileType::Blob, &hex::encode(hash))?;
+        Ok(RawBlock::from_dat(compression::decompress_conditional(&content[..])))
     }
 
     pub fn exist(storage: &super::Storage, hash: &super::BlockHash) -> bool {
-        let p = storage.config.get_blob_filepath(hash);
-        p.as_path().exists()
+        storage.backend.exist(StorageFileType::Blob, &hex::encode(hash))
     }
 
     pub fn remove(storage: &super::Storage, hash: &super::BlockHash) {
-        let p = storage.config.get_blob_filepath(hash);
-        match fs::remove_file(p) {
+        match storage.backend.remove(StorageFileType::Blob, &hex::encode(hash)) {
             Ok(()) => {},
             Err(_) => {},
         }
     }
+
+    /// the hashes of the loose blocks, up to the given number
+    pub fn list(storage: &super::Storage, limits: Option<u32>) -> Result<Vec<super::BlockHash>> {
+        let mut blobs = super::list_hashes(&*storage.backend, StorageFileType::Blob)?;
+        if let Some(l) = limits { blobs.truncate(l as usize); }
+        Ok(blobs)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -169,20 +362,9 @@ pub enum BlockLocation {
 }
 
 pub fn block_location(storage: &Storage, hash: &BlockHash) -> Option<BlockLocation> {
-    for (packref, lookup) in storage.lookups.iter() {
-        let (start, nb) = lookup.fanout.get_indexer_by_hash(hash);
-        match nb {
-            pack::FanoutNb(0) => {},
-            _                 => {
-                if lookup.bloom.search(hash) {
-                    let idx_filepath = storage.config.get_index_filepath(packref);
-                    let mut idx_file = fs::File::open(idx_filepath).unwrap();
-                    match pack::search_index(&mut idx_file, &lookup.params, hash, start, nb) {
-                        None       => {},
-                        Some(iloc) => return Some(BlockLocation::Packed(packref.clone(), iloc)),
-                    }
-                }
-            }
+    for (packref, &(ref index, _)) in storage.packs.iter() {
+        if let Some(iloc) = index.search(hash) {
+            return Some(BlockLocation::Packed(packref.clone(), iloc));
         }
     }
     if blob::exist(storage, hash) {
@@ -192,41 +374,75 @@ pub fn block_location(storage: &Storage, hash: &BlockHash) -> Option<BlockLocati
 }
 
 pub fn block_read_location(storage: &Storage, loc: &BlockLocation, hash: &BlockHash) -> Option<RawBlock> {
-    match loc {
+    if let Some(rblk) = storage.block_cache.lock().unwrap().get(hash) {
+        return Some(rblk);
+    }
+    let rblk = match loc {
         &BlockLocation::Loose                 => blob::read(storage, hash).ok(),
         &BlockLocation::Packed(ref packref, ref iofs) => {
-            match storage.lookups.get(packref) {
+            match storage.packs.get(packref) {
                 None         => { unreachable!(); },
-                Some(lookup) => {
-                    let idx_filepath = storage.config.get_index_filepath(packref);
-                    let mut idx_file = fs::File::open(idx_filepath).unwrap();
-                    let pack_offset = pack::resolve_index_offset(&mut idx_file, lookup, *iofs);
-                    let pack_filepath = storage.config.get_pack_filepath(packref);
-                    let mut pack_file = fs::File::open(pack_filepath).unwrap();
-                    pack::read_block_at(&mut pack_file, pack_offset).ok()
+                Some(&(ref index, ref pack)) => {
+                    let pack_offset = index.resolve_offset(*iofs);
+                    pack.read_block_at(pack_offset).ok()
                 }
             }
         }
-    }
+    }?;
+    storage.block_cache.lock().unwrap().insert(*hash, rblk.clone());
+    Some(rblk)
 }
 
 pub fn block_read(storage: &Storage, hash: &BlockHash) -> Option<RawBlock> {
+    if let Some(rblk) = storage.block_cache.lock().unwrap().get(hash) {
+        return Some(rblk);
+    }
     match block_location(storage, hash) {
         None      => None,
         Some(loc) => block_read_location(storage, &loc, hash),
     }
 }
 
+/// look for the location of the given transaction in the indexed packs
+///
+/// only the packs whose transaction index has been created are looked
+/// into (see `txindex::create`), loose blocks are not indexed.
+pub fn tx_location(storage: &Storage, txid: &TxId) -> Option<txindex::TxLocation> {
+    for packref in storage.packs.keys() {
+        if ! txindex::exist(storage, packref) { continue; }
+        match txindex::search(storage, packref, txid) {
+            Err(err) => warn!("error while searching the transaction index {}: {:?}", hex::encode(packref), err),
+            Ok(None) => {},
+            Ok(Some(loc)) => return Some(loc),
+        }
+    }
+    None
+}
+
+/// read the transaction at the given location
+pub fn tx_read_location(storage: &Storage, loc: &txindex::TxLocation) -> Option<TxAux> {
+    let rblk = match storage.packs.get(&loc.pack) {
+        Some(&(_, ref pack)) => pack.read_block_at(loc.offset).ok()?,
+        None => pack::MappedPack::open(storage, &loc.pack).ok()?.read_block_at(loc.offset).ok()?,
+    };
+    match rblk.decode().ok()? {
+        blockchain::Block::GenesisBlock(_) => None,
+        blockchain::Block::MainBlock(mblk) => mblk.body.tx.iter().nth(loc.position as usize).cloned(),
+    }
+}
+
 /// packing parameters
 ///
 /// optionally set the maximum number of blobs in this pack
 /// optionally set the maximum size in bytes of the pack file.
 ///            note that the limits is best effort, not strict.
+/// the compression of the blocks of the pack, deflate by default.
 pub struct PackParameters {
     pub limit_nb_blobs: Option<u32>,
     pub limit_size: Option<u64>,
     pub delete_blobs_after_pack: bool,
     pub range: Option<(BlockHash, BlockHash)>,
+    pub compression: compression::Compression,
 }
 impl Default for PackParameters {
     fn default() -> Self {
@@ -235,22 +451,23 @@ impl Default for PackParameters {
             limit_size: None,
             delete_blobs_after_pack: true,
             range: None,
+            compression: compression::Compression::default(),
         }
     }
 }
 
 pub fn pack_blobs(storage: &mut Storage, params: &PackParameters) -> PackHash {
-    let mut writer = pack::PackWriter::init(&storage.config);
+    let mut writer = pack::PackWriter::with_compression(storage, params.compression.clone()).unwrap();
     let mut blob_packed = Vec::new();
 
     let block_hashes : Vec<BlockHash> = if let Some((from, to)) = params.range {
         storage.range(from, to).unwrap().iter().cloned().collect()
     } else {
-        storage.config.list_blob(params.limit_nb_blobs)
+        blob::list(storage, params.limit_nb_blobs).unwrap()
     };
     for bh in block_hashes {
-        let blob = blob::read_raw(storage, &bh).unwrap();
-        writer.append(&bh, &blob[..]);
+        let blob = blob::read(storage, &bh).unwrap();
+        writer.append(&bh, blob.as_ref());
         blob_packed.push(bh);
         match params.limit_size {
             None => {},
@@ -264,17 +481,14 @@ pub fn pack_blobs(storage: &mut Storage, params: &PackParameters) -> PackHash {
 
     let (packhash, index) = writer.finalize();
 
-    let (lookup, tmpfile) = pack::create_index(storage, &index);
-    tmpfile.render_permanent(&storage.config.get_index_filepath(&packhash)).unwrap();
+    // index and append to the mapped packs
+    storage.add_pack(&packhash, &index).unwrap();
 
     if params.delete_blobs_after_pack {
         for bh in blob_packed.iter() {
             blob::remove(storage, bh);
         }
     }
-
-    // append to lookups
-    storage.lookups.insert(packhash, lookup);
     packhash
 }
 
@@ -286,11 +500,11 @@ pub fn refpack_epoch_pack<S: AsRef<str>>(storage: &Storage, tag: &S) -> Result<(
     let packhash_vec = tag::read(storage, tag).expect("EPOCH not found");
     let mut packhash = [0;HASH_SIZE];
     packhash[..].clone_from_slice(packhash_vec.as_slice());
-    let mut pack = pack::PackReader::init(&storage.config, &packhash);
+    let mut pack = pack::PackReader::init(storage, &packhash)?;
 
     let mut current_state = None;
 
-    while let Some(raw_block) = pack.get_next() {
+    while let Some(raw_block) = pack.get_next()? {
         let block = raw_block.decode()?;
         let hdr = block.get_header();
         let hash = hdr.compute_hash();
@@ -335,7 +549,7 @@ pub fn refpack_epoch_pack<S: AsRef<str>>(storage: &Storage, tag: &S) -> Result<(
         }
     }
 
-    refpack::write_refpack(&storage.config, tag, &rp).map_err(From::from)
+    refpack::write_refpack(storage, tag, &rp).map_err(From::from)
 }
 
 pub fn integrity_check(storage: &Storage, genesis_hash: HeaderHash, count: u32) {
@@ -350,11 +564,11 @@ fn epoch_integrity_check(storage: &Storage, epochid: u32, last_known_hash: Heade
     let packhash_vec = tag::read(storage, &format!("EPOCH_{}", epochid)).expect("EPOCH not found");
     let mut packhash = [0;HASH_SIZE];
     packhash[..].clone_from_slice(packhash_vec.as_slice());
-    let mut pack = pack::PackReader::init(&storage.config, &packhash);
+    let mut pack = pack::PackReader::init(storage, &packhash)?;
 
     let mut current_state = None;
 
-    while let Some(raw_block) = pack.get_next() {
+    while let Some(raw_block) = pack.get_next()? {
         let block = raw_block.decode()?;
         let hdr = block.get_header();
         let hash = hdr.compute_hash();
@@ -406,3 +620,57 @@ fn epoch_integrity_check(storage: &Storage, epochid: u32, last_known_hash: Heade
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{MemoryBackend};
+    use blockchain::{SlotId};
+    use protocol::mock::{Chain};
+    use wallet_crypto::config::{ProtocolMagic};
+
+    fn hash_at(chain: &Chain, height: usize) -> BlockHash { header_to_blockhash(&chain.hash(height)) }
+
+    #[test]
+    fn blocks_at_date_and_height() {
+        // epochs of 11 slots, the slots 3 and 4 of the epoch 1 are empty,
+        // the epochs 0 and 1 are packed and the epoch 2 is not
+        let mut chain = Chain::new(ProtocolMagic::default(), 10);
+        chain.extend(15);
+        chain.skip_slots(2);
+        chain.extend(10);
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        for (epochid, heights) in vec![(0, 0..11), (1, 11..20)] {
+            let mut writer = pack::PackWriter::init(&storage);
+            for height in heights {
+                writer.append(&hash_at(&chain, height), chain.block(height).as_ref());
+            }
+            let (packhash, index) = writer.finalize();
+            storage.add_pack(&packhash, &index).unwrap();
+            epoch::epoch_create(&storage, &packhash, epochid);
+        }
+        for height in 20..25 {
+            blob::write(&storage, &hash_at(&chain, height), chain.block(height).as_ref()).unwrap();
+        }
+        assert_eq!(storage.block_at_height(1), None);
+        tag::write_hash(&storage, &tag::HEAD, &chain.hash(24));
+
+        let date = |epoch, slotid| BlockDate::Normal(SlotId { epoch: epoch, slotid: slotid });
+        assert_eq!(storage.block_at_date(&BlockDate::Genesis(1)), Some(hash_at(&chain, 11)));
+        assert_eq!(storage.block_at_date(&date(1, 2)), Some(hash_at(&chain, 14)));
+        assert_eq!(storage.block_at_date(&date(1, 3)), None);
+        assert_eq!(storage.block_at_date(&date(1, 5)), Some(hash_at(&chain, 15)));
+        assert_eq!(storage.block_at_date(&BlockDate::Genesis(2)), Some(hash_at(&chain, 20)));
+        assert_eq!(storage.block_at_date(&date(2, 3)), Some(hash_at(&chain, 24)));
+        assert_eq!(storage.block_at_date(&date(2, 4)), None);
+        assert_eq!(storage.block_at_date(&date(3, 0)), None);
+
+        // the genesis blocks have the height of the block before them
+        for height in (0..25).filter(|height| ! chain.header(*height).is_genesis_block()) {
+            let difficulty : u64 = chain.header(height).get_chain_difficulty().into();
+            assert_eq!(storage.block_at_height(difficulty), Some(hash_at(&chain, height)), "height {}", difficulty);
+        }
+        assert_eq!(storage.block_at_height(0), None);
+        assert_eq!(storage.block_at_height(23), None);
+    }
+}
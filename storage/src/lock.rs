@@ -1,4 +1,27 @@
-use std::{fmt, result, num, io::{self, Write}, process::{self}, fs::{self, OpenOptions}, path::{Path, PathBuf}};
+//! locking of the files of the storage, between processes
+//!
+//! * `Lock` locks a single file with a `<filename>.LOCK` file holding the
+//!   process ID of the owner;
+//! * `StorageLock` locks a whole storage directory with advisory locks
+//!   (`flock`), released by the system if the process dies:
+//!   * `Access::Read` is shared by all the readers (e.g. hermes serving
+//!     the blocks);
+//!   * `Access::Write` is exclusive between the writers (sync, pack), but
+//!     compatible with the readers as the files are written atomically;
+//!   * `Access::Exclusive` excludes the readers and the writers, to remove
+//!     files of the storage.
+//!
+//! The readers hold their lock for as long as they use the storage: hermes
+//! keeps a `Read` lock on the storage of every network it serves, so an
+//! `Exclusive` lock (e.g. `blockchain gc`) cannot be taken while hermes
+//! runs and times out with `Error::Busy`.
+
+use std::{fmt, result, num, thread, io::{self, Write, Seek, SeekFrom}, process::{self}, fs::{self, OpenOptions}, path::{Path, PathBuf}};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use libc;
 
 /// the extension that will be added to the file, this will allow us to
 /// lock a specific file.
@@ -15,7 +38,9 @@ pub enum Error {
     /// ID, this will be the error.
     ParseError(num::ParseIntError),
     /// tell the file was already locked and by whom (which process ID)
-    AlreadyLocked(PathBuf, u32)
+    AlreadyLocked(PathBuf, u32),
+    /// tell the storage is in use by other processes (readers)
+    Busy(PathBuf),
 }
 impl Error {
     /// convenient function to check if the error is because the file
@@ -23,6 +48,7 @@ impl Error {
     pub fn already_locked(&self) -> bool {
         match self {
             Error::AlreadyLocked(_, _) => true,
+            Error::Busy(_) => true,
             _ => false
         }
     }
@@ -38,12 +64,28 @@ impl fmt::Display for Error {
         match self {
             Error::IoError(err) => write!(f, "IoError: {:?}", err),
             Error::ParseError(err) => write!(f, "{}", err),
-            Error::AlreadyLocked(path, id) => write!(f, "file {:?} already locked by {}", path, id)
+            Error::AlreadyLocked(path, id) => write!(f, "file {:?} already locked by {}", path, id),
+            Error::Busy(path) => write!(f, "file {:?} locked by other processes", path),
         }
     }
 }
 
-type Result<T> = result::Result<T, Error>;
+pub type Result<T> = result::Result<T, Error>;
+
+/// the default time to wait for a lock, see `StorageLock::acquire`
+pub const DEFAULT_TIMEOUT : Duration = Duration::from_secs(10);
+
+// interval between 2 attempts to acquire a lock
+const RETRY_INTERVAL : Duration = Duration::from_millis(50);
+
+/// tell if the process of the given ID is still running
+#[cfg(unix)]
+pub fn process_alive(id: u32) -> bool {
+    let r = unsafe { libc::kill(id as libc::pid_t, 0) };
+    r == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+#[cfg(not(unix))]
+pub fn process_alive(_id: u32) -> bool { true }
 
 /// Object which lifetime is bound to a file in the filesystem
 ///
@@ -67,35 +109,56 @@ impl Lock {
     ///
     /// this function will try to create a file `<path> '.LOCK'`.
     ///
-    /// If the file already exists it will fail to create the lock.
+    /// If the file already exists it will fail to create the lock, unless
+    /// the process which created it is not running anymore (stale lock).
     ///
     /// This is a non blocking function in the sense that: if a lock
     /// already exists, the function fail straight away and do not wait
     /// for the other process to release the `Lock`.
     ///
     pub fn lock(path: PathBuf) -> Result<Self> {
-        let lock = Lock { id: process::id(), path };
-        lock.acquire()?;
-        Ok(lock)
+        // the `Lock` is built once the file is acquired only: dropping it
+        // removes the lock file.
+        let id = process::id();
+        Self::acquire(&path.with_extension(EXTENSION), id)?;
+        Ok(Lock { id, path })
     }
 
     fn lock_path(&self) -> PathBuf {
         self.path.with_extension(EXTENSION)
     }
 
-    fn acquire(&self) -> Result<()> {
-        let mut file = OpenOptions::new()
+    fn acquire(lock_path: &Path, id: u32) -> Result<()> {
+        match Self::create(lock_path, id) {
+            Err(Error::AlreadyLocked(path, owner)) => {
+                if process_alive(owner) {
+                    return Err(Error::AlreadyLocked(path, owner));
+                }
+                warn!("removing stale lock {:?} of process {}", path, owner);
+                fs::remove_file(&path)?;
+                Self::create(lock_path, id)
+            },
+            r => r,
+        }
+    }
+
+    fn create(lock_path: &Path, id: u32) -> Result<()> {
+        let mut file = match OpenOptions::new()
             .write(true)
             .create_new(true)
             .truncate(false)
-            .open(self.lock_path())
-            .or(Self::fail_with_lock(self.lock_path()))?;
-        write!(file, "{}", self.id)?;
+            .open(lock_path) {
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Self::fail_with_lock(lock_path.to_path_buf())
+            },
+            r => r?,
+        };
+        write!(file, "{}", id)?;
         Ok(())
     }
 
     fn fail_with_lock<A: Sized>(path: PathBuf) -> Result<A> {
-        let id : u32 = fs::read_to_string(&path)?.parse()?;
+        let id : u32 = fs::read_to_string(&path)?.trim().parse()?;
         Err(Error::AlreadyLocked(path, id))
     }
 
@@ -121,3 +184,149 @@ impl fmt::Display for Lock {
 impl AsRef<Path> for Lock {
     fn as_ref(&self) -> &Path { self.path.as_ref() }
 }
+
+/// the access to the storage a `StorageLock` gives, see module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Exclusive,
+}
+
+const READERS_LOCK : &'static str = "readers.lock";
+const WRITER_LOCK : &'static str = "writer.lock";
+
+/// advisory lock of a whole storage directory, see module documentation
+///
+/// The lock is released when it drops out of scope. Being bound to the
+/// opened files, a process cannot take a `Write` or `Exclusive` lock on a
+/// storage it holds another `StorageLock` of the same kind.
+#[derive(Debug)]
+pub struct StorageLock {
+    access: Access,
+    // the locked files, only held to be released on drop
+    _files: Vec<fs::File>,
+}
+
+impl StorageLock {
+    /// lock the storage at the given path, waiting for the other processes
+    /// to release the lock up to the given timeout (forever if `None`).
+    pub fn acquire<P: AsRef<Path>>(path: P, access: Access, timeout: Option<Duration>) -> Result<Self> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut files = Vec::new();
+        if access != Access::Read {
+            let mut file = lock_file(path.as_ref().join(WRITER_LOCK), true, deadline)?;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", process::id())?;
+            files.push(file);
+        }
+        if access != Access::Write {
+            files.push(lock_file(path.as_ref().join(READERS_LOCK), access == Access::Exclusive, deadline)?);
+        }
+        Ok(StorageLock { access: access, _files: files })
+    }
+
+    /// lock the storage at the given path, failing straight away if the
+    /// storage is locked by other processes.
+    pub fn try_acquire<P: AsRef<Path>>(path: P, access: Access) -> Result<Self> {
+        StorageLock::acquire(path, access, Some(Duration::from_secs(0)))
+    }
+
+    /// a lock holding no file, for the storages which are not shared with
+    /// other processes (see `backend::MemoryBackend`)
+    pub fn unshared(access: Access) -> Self {
+        StorageLock { access: access, _files: Vec::new() }
+    }
+
+    pub fn get_access(&self) -> Access { self.access }
+}
+
+// open and lock the given file, retrying until the deadline
+fn lock_file(path: PathBuf, exclusive: bool, deadline: Option<Instant>) -> Result<fs::File> {
+    let file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+    loop {
+        if try_flock(&file, exclusive, deadline.is_some())? {
+            return Ok(file);
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                return match fs::read_to_string(&path).ok().and_then(|id| id.trim().parse().ok()) {
+                    Some(id) => Err(Error::AlreadyLocked(path, id)),
+                    None => Err(Error::Busy(path)),
+                };
+            },
+            _ => thread::sleep(RETRY_INTERVAL),
+        }
+    }
+}
+
+// try to lock the file, return `false` if the file is locked by another
+// process and `nonblocking` is set.
+#[cfg(unix)]
+fn try_flock(file: &fs::File, exclusive: bool, nonblocking: bool) -> Result<bool> {
+    let mut operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    if nonblocking { operation |= libc::LOCK_NB; }
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+        _ => Err(Error::IoError(err)),
+    }
+}
+#[cfg(not(unix))]
+fn try_flock(_file: &fs::File, _exclusive: bool, _nonblocking: bool) -> Result<bool> { Ok(true) }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("storage-lock-{}-{}", name, process::id()));
+        fs::DirBuilder::new().recursive(true).create(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writers_exclude_each_other() {
+        let dir = lock_dir("writers");
+        let writer = StorageLock::try_acquire(&dir, Access::Write).unwrap();
+        match StorageLock::try_acquire(&dir, Access::Write) {
+            Err(Error::AlreadyLocked(_, id)) => assert_eq!(id, process::id()),
+            r => panic!("expected the storage to be locked, got {:?}", r),
+        }
+        // the readers are not blocked by the writer
+        let reader = StorageLock::try_acquire(&dir, Access::Read).unwrap();
+        assert!(StorageLock::try_acquire(&dir, Access::Exclusive).unwrap_err().already_locked());
+        drop(writer);
+        // the writer lock is free, but the storage is still read
+        match StorageLock::try_acquire(&dir, Access::Exclusive) {
+            Err(Error::Busy(_)) => {},
+            r => panic!("expected the storage to be busy, got {:?}", r),
+        }
+        drop(reader);
+        assert_eq!(StorageLock::try_acquire(&dir, Access::Exclusive).unwrap().get_access(), Access::Exclusive);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wait_for_the_lock_up_to_the_timeout() {
+        let dir = lock_dir("timeout");
+        let writer = StorageLock::try_acquire(&dir, Access::Write).unwrap();
+        let start = Instant::now();
+        assert!(StorageLock::acquire(&dir, Access::Write, Some(Duration::from_millis(200))).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // released while waiting
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(writer);
+        });
+        assert!(StorageLock::acquire(&dir, Access::Write, Some(Duration::from_secs(5))).is_ok());
+        releaser.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,27 @@
 
-// a pack file is:
+// a pack file (version 3) is:
+//
+// PACK MAGIC (8 Bytes)
+// COMPRESSION METHOD (1 byte: 0 none, 1 deflate, 2 zstd)
+// COMPRESSION LEVEL (1 byte, signed)
+// 0-PADDING (2 bytes)
+// DICTIONARY SIZE (4 bytes BE, 0 if no dictionary)
+// DICTIONARY (DICTIONARY SIZE bytes)
+// 0-PADDING to the next 4 bytes boundary
+// for each block (compressed with the compression method):
+//   SIZE (4 bytes BE)
+//   CRC32 of the BLOCK (4 bytes BE)
+//   BLOCK (SIZE bytes)
+//   0-PADDING to the next 4 bytes boundary
+// FOOTER MARKER (4 bytes, 0xffffffff)
+// NUMBER OF BLOCKS (4 bytes BE)
+// PACK HASH (32 bytes)
+//
+// a pack file of version 2 has no compression header: its blocks are
+// compressed with deflate. A pack file of version 1 has no magic, no CRC32
+// and no footer.
+//
+// an index file is:
 //
 // MAGIC (8 Bytes)
 // BLOOM SIZE (4 bytes BE)
@@ -9,8 +31,6 @@
 // BLOCK HASHES present in this pack ordered lexigraphically (#ENTRIES * 32 bytes)
 // OFFSET of BLOCK in the same order as BLOCK_HASHES (#ENTRIES * 8 bytes)
 
-use super::{TmpFile};
-
 use std::iter::repeat;
 use std::io::SeekFrom;
 use std::io;
@@ -22,12 +42,24 @@ use types::HASH_SIZE;
 use bloom;
 use types::BlockHash;
 use compression;
+use compression::{Compression};
 use blockchain;
+use wallet_crypto::crc32::crc32;
+use memmap::{Mmap};
+use wallet_crypto::util::{hex};
+use backend::{Content, Output};
+use types::{StorageFileType};
 
 const MAGIC : &[u8] = b"ADAPACK1";
 const MAGIC_SIZE : usize = 8;
 const OFF_SIZE : usize = 8;
 const SIZE_SIZE : usize = 4;
+const CRC_SIZE : usize = 4;
+
+const PACK_MAGIC_V2 : &[u8] = b"ADAPACK2";
+const PACK_MAGIC_V3 : &[u8] = b"ADAPACK3";
+const COMPRESSION_HEADER_SIZE : usize = 8;
+const FOOTER_MARKER : Size = 0xffffffff;
 
 const FANOUT_ELEMENTS : usize = 256;
 const FANOUT_SIZE : usize = FANOUT_ELEMENTS*SIZE_SIZE;
@@ -47,8 +79,8 @@ fn offset_offsets(bloom_size: u32, number_hashes: u32) -> u64 {
     offset_hashes(bloom_size) + HASH_SIZE as u64 * number_hashes as u64
 }
 
-type Offset = u64;
-type Size = u32;
+pub type Offset = u64;
+pub type Size = u32;
 pub type IndexOffset = u32;
 
 // The parameters associated with the index file.
@@ -95,23 +127,29 @@ impl Bloom {
     }
 
     pub fn len(&self) -> usize { self.0.len() }
+
+    /// the estimated probability for a block not in the pack to be found
+    /// by `search` (the index is then searched for nothing)
+    pub fn false_positive_rate(&self) -> f64 {
+        bloom::false_positive_rate(&self.0[..])
+    }
 }
 
 
-fn write_size(buf: &mut [u8], sz: Size) {
+pub fn write_size(buf: &mut [u8], sz: Size) {
     buf[0] = (sz >> 24) as u8;
     buf[1] = (sz >> 16) as u8;
     buf[2] = (sz >> 8) as u8;
     buf[3] = sz as u8;
 }
-fn read_size(buf: &[u8]) -> Size {
+pub fn read_size(buf: &[u8]) -> Size {
     ((buf[0] as Size) << 24)
         | ((buf[1] as Size) << 16)
         | ((buf[2] as Size) << 8)
         | (buf[3] as Size)
 }
 
-fn write_offset(buf: &mut [u8], sz: Offset) {
+pub fn write_offset(buf: &mut [u8], sz: Offset) {
     buf[0] = (sz >> 56) as u8;
     buf[1] = (sz >> 48) as u8;
     buf[2] = (sz >> 40) as u8;
@@ -122,7 +160,7 @@ fn write_offset(buf: &mut [u8], sz: Offset) {
     buf[7] = sz as u8;
 }
 
-fn read_offset(buf: &[u8]) -> Offset {
+pub fn read_offset(buf: &[u8]) -> Offset {
     ((buf[0] as u64) << 56)
         | ((buf[1] as u64) << 48)
         | ((buf[2] as u64) << 40)
@@ -145,6 +183,43 @@ fn file_read_hash(mut file: &fs::File) -> super::BlockHash {
     buf
 }
 
+/// write the fanout of the keys of an index, given their first byte: the
+/// number of keys up to each first byte (`FANOUT_ELEMENTS` sizes)
+pub fn write_fanout<I: IntoIterator<Item=u8>>(buf: &mut [u8], first_bytes: I) {
+    let mut fanout = [0u32;FANOUT_ELEMENTS];
+    for byte in first_bytes {
+        fanout[byte as usize] += 1;
+    }
+    let mut fanout_sum = 0;
+    for i in 0..FANOUT_ELEMENTS {
+        fanout_sum += fanout[i];
+        write_size(&mut buf[i * SIZE_SIZE..(i + 1) * SIZE_SIZE], fanout_sum);
+    }
+}
+
+/// search a key in the sorted entries of an index with its fanout (see
+/// `write_fanout`): `compare(i)` compares the key of the entry `i` to the
+/// key looked for, whose first byte is given.
+///
+/// The fanout read from a file is not trusted: the search stays within
+/// the `total` entries of the index.
+pub fn fanout_search<F>(fanout: &[u8], total: u32, first_byte: u8, compare: F) -> Option<u32>
+    where F: Fn(u32) -> ::std::cmp::Ordering
+{
+    let fanout_at = |i: usize| ::std::cmp::min(read_size(&fanout[i * SIZE_SIZE..]), total);
+    let mut start = if first_byte == 0 { 0 } else { fanout_at(first_byte as usize - 1) };
+    let mut end = fanout_at(first_byte as usize);
+    while start < end {
+        let middle = start + (end - start) / 2;
+        match compare(middle) {
+            ::std::cmp::Ordering::Equal   => return Some(middle),
+            ::std::cmp::Ordering::Less    => start = middle + 1,
+            ::std::cmp::Ordering::Greater => end = middle,
+        }
+    }
+    None
+}
+
 // the default size (in bytes) of the bloom filter related to the number of
 // expected entries in the files.
 pub fn default_bloom_size(entries: usize) -> u32 {
@@ -159,8 +234,9 @@ pub fn default_bloom_size(entries: usize) -> u32 {
     }
 }
 
-pub fn create_index(storage: &super::Storage, index: &Index) -> (Lookup, super::TmpFile) {
-    let mut tmpfile = super::tmpfile_create_type(storage, super::StorageFileType::Index);
+/// write the index of the given pack, see `PackWriter::finalize`
+pub fn create_index(storage: &super::Storage, packhash: &super::PackHash, index: &Index) -> io::Result<Lookup> {
+    let mut output = storage.get_backend().create(StorageFileType::Index)?;
     let mut hdr_buf = [0u8;HEADER_SIZE];
 
     let entries = index.hashes.len();
@@ -194,14 +270,14 @@ pub fn create_index(storage: &super::Storage, index: &Index) -> (Lookup, super::
         }
         Fanout(fanout_incr)
     };
-    tmpfile.write_all(&hdr_buf).unwrap();
+    output.write_all(&hdr_buf)?;
 
     let mut bloom : Vec<u8> = repeat(0).take(bloom_size as usize).collect();
     for hash in index.hashes.iter() {
         bloom::set(&mut bloom[..], hash);
     }
 
-    tmpfile.write_all(&bloom[..]).unwrap();
+    output.write_all(&bloom[..])?;
 
     let mut sorted = Vec::with_capacity(entries);
     for i in 0..entries {
@@ -210,15 +286,16 @@ pub fn create_index(storage: &super::Storage, index: &Index) -> (Lookup, super::
     sorted.sort_by(|a, b| a.0.cmp(&b.0));
 
     for &(hash,_) in sorted.iter() {
-        tmpfile.write_all(&hash[..]).unwrap();
+        output.write_all(&hash[..])?;
     }
 
     for &(_, ofs) in sorted.iter() {
         let mut buf = [0u8;OFF_SIZE];
         write_offset(&mut buf, ofs);
-        tmpfile.write_all(&buf[..]).unwrap();
+        output.write_all(&buf[..])?;
     }
-    (Lookup { params: params, fanout: fanout, bloom: Bloom(bloom) }, tmpfile)
+    output.commit(&hex::encode(packhash))?;
+    Ok(Lookup { params: params, fanout: fanout, bloom: Bloom(bloom) })
 }
 
 pub fn open_index(storage_config: &super::StorageConfig, pack: &super::PackHash) -> fs::File {
@@ -349,30 +426,204 @@ pub fn read_block_raw_next<R: Read>(mut file: R) -> io::Result<blockchain::RawBl
     Ok(blockchain::RawBlock::from_dat(v))
 }
 
-pub fn read_block_at(mut file: &fs::File, ofs: Offset) -> io::Result<blockchain::RawBlock> {
-    file.seek(SeekFrom::Start(ofs))?;
-    let v = read_block_raw_next(file)?;
-    Ok(blockchain::RawBlock::from_dat(compression::decompress_conditional(v.as_ref())))
+/// the version of the format of a pack file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// blocks without checksum nor footer
+    V1,
+    /// `PACK_MAGIC_V2`, blocks with their CRC32 and a footer
+    V2,
+    /// `PACK_MAGIC_V3`, the compression of the blocks then the blocks and
+    /// the footer just like V2
+    V3,
+}
+impl Version {
+    // the blocks have a CRC32 and the pack has a footer
+    fn is_checked(self) -> bool { self != Version::V1 }
+
+    // size of the entry of a block in the pack file
+    fn entry_size(self, block_size: usize) -> Offset {
+        let header = if self.is_checked() { SIZE_SIZE + CRC_SIZE } else { SIZE_SIZE };
+        header as Offset + align4(block_size as Offset)
+    }
+}
+
+/// the header of a pack file: its version and the compression of its
+/// blocks (recorded in the pack file from version 3, deflate before)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackHeader {
+    pub version: Version,
+    pub compression: Compression,
+}
+impl PackHeader {
+    pub fn new(compression: Compression) -> Self {
+        PackHeader { version: Version::V3, compression: compression }
+    }
+
+    /// offset of the first block in the pack file
+    pub fn first_block_offset(&self) -> Offset {
+        match self.version {
+            Version::V1 => 0,
+            Version::V2 => MAGIC_SIZE as Offset,
+            Version::V3 => {
+                let dict_size = self.compression.get_dictionary().map(|d| d.len()).unwrap_or(0);
+                align4((MAGIC_SIZE + COMPRESSION_HEADER_SIZE + dict_size) as Offset)
+            },
+        }
+    }
+
+    // the bytes of the header of a pack file of version 3
+    fn to_bytes(&self) -> Vec<u8> {
+        let dict = self.compression.get_dictionary().unwrap_or(&[]);
+        let mut v = Vec::with_capacity(self.first_block_offset() as usize);
+        v.extend_from_slice(PACK_MAGIC_V3);
+        v.push(self.compression.get_method());
+        v.push(self.compression.get_level() as i8 as u8);
+        v.extend_from_slice(&[0u8;2]);
+        let mut sz_buf = [0u8;SIZE_SIZE];
+        write_size(&mut sz_buf, dict.len() as Size);
+        v.extend_from_slice(&sz_buf[..]);
+        v.extend_from_slice(dict);
+        while v.len() % 4 != 0 { v.push(0) }
+        v
+    }
+
+    /// decompress a block of the pack
+    pub fn decompress(&self, block: &[u8]) -> io::Result<blockchain::RawBlock> {
+        Ok(blockchain::RawBlock::from_dat(self.compression.decompress(block)?))
+    }
+}
+
+/// the footer of a pack file (version 2 and 3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Footer {
+    pub nb_blobs: u32,
+    pub packhash: super::PackHash,
+}
+
+// an entry of a pack file: the block as stored (possibly compressed) with
+// its checksum if any, or the footer
+enum Entry {
+    Block(Vec<u8>, Option<u32>),
+    Footer(Footer),
+}
+
+// read the beginning of a pack file to find its version and compression.
+// The bytes read from a pack file of version 1 are blocks' bytes and are
+// returned.
+fn read_header<R: Read>(mut file: R) -> io::Result<(PackHeader, Vec<u8>)> {
+    let mut buf = [0u8;MAGIC_SIZE];
+    let mut len = 0;
+    while len < MAGIC_SIZE {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    if &buf[..len] == PACK_MAGIC_V3 {
+        let mut hdr_buf = [0u8;COMPRESSION_HEADER_SIZE];
+        file.read_exact(&mut hdr_buf)?;
+        let dict_size = read_size(&hdr_buf[4..8]);
+        let dictionary = if dict_size == 0 {
+            None
+        } else {
+            let mut dict = Vec::new();
+            (&mut file).take(dict_size as u64).read_to_end(&mut dict)?;
+            if dict.len() != dict_size as usize {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated pack header"));
+            }
+            Some(dict)
+        };
+        let compression = Compression::from_header(hdr_buf[0], hdr_buf[1] as i8 as i32, dictionary)?;
+        let header = PackHeader { version: Version::V3, compression: compression };
+        let to_align = header.first_block_offset() as usize - (MAGIC_SIZE + COMPRESSION_HEADER_SIZE + dict_size as usize);
+        let mut align = [0u8;4];
+        file.read_exact(&mut align[0..to_align])?;
+        Ok((header, Vec::new()))
+    } else if &buf[..len] == PACK_MAGIC_V2 {
+        Ok((PackHeader { version: Version::V2, compression: Compression::default() }, Vec::new()))
+    } else {
+        Ok((PackHeader { version: Version::V1, compression: Compression::default() }, Vec::from(&buf[..len])))
+    }
+}
+
+fn read_entry<R: Read>(version: Version, mut file: R) -> io::Result<Entry> {
+    let mut sz_buf = [0u8;SIZE_SIZE];
+    file.read_exact(&mut sz_buf)?;
+    let sz = read_size(&sz_buf);
+    if version.is_checked() && sz == FOOTER_MARKER {
+        let mut buf = [0u8;SIZE_SIZE+HASH_SIZE];
+        file.read_exact(&mut buf)?;
+        let mut packhash = [0u8;HASH_SIZE];
+        packhash.clone_from_slice(&buf[SIZE_SIZE..]);
+        return Ok(Entry::Footer(Footer { nb_blobs: read_size(&buf[0..SIZE_SIZE]), packhash: packhash }));
+    }
+    let crc = if version.is_checked() {
+        let mut crc_buf = [0u8;CRC_SIZE];
+        file.read_exact(&mut crc_buf)?;
+        Some(read_size(&crc_buf))
+    } else {
+        None
+    };
+    // do not trust the size before having read the block: a corrupted
+    // size would make us allocate up to 4GB.
+    let mut v = Vec::new();
+    (&mut file).take(sz as u64).read_to_end(&mut v)?;
+    if v.len() != sz as usize {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated block"));
+    }
+    if (v.len() % 4) != 0 {
+        let to_align = 4 - (v.len() % 4);
+        let mut align = [0u8;4];
+        file.read_exact(&mut align[0..to_align])?;
+    }
+    Ok(Entry::Block(v, crc))
+}
+
+fn check_crc(block: &[u8], crc: Option<u32>) -> bool {
+    crc.map(|crc| crc32(block) == crc).unwrap_or(true)
 }
 
 // A Writer for a specific pack that accumulate some numbers for reportings,
 // index, blobs_hashes for index creation (in finalize)
 pub struct PackWriter {
-    tmpfile: TmpFile,
+    output: Option<Box<Output>>, // committed in finalize
+    compression: Compression,
     index: Index,
     pub nb_blobs: u32,
     pub pos: Offset, // offset in bytes of the current position (double as the current size of the pack)
     hash_context: blake2b::Blake2b, // hash of all the content of blocks without length or padding
-    storage_config: super::StorageConfig,
 }
 
 impl PackWriter {
-    pub fn init(cfg: &super::StorageConfig) -> Self {
-        let tmpfile = TmpFile::create(cfg.get_filetype_dir(super::StorageFileType::Pack)).unwrap();
+    /// a pack with the default compression (deflate)
+    pub fn init(storage: &super::Storage) -> Self {
+        PackWriter::with_compression(storage, Compression::default()).unwrap()
+    }
+
+    /// a pack whose blocks are compressed with the given compression,
+    /// fails if this build does not support it
+    pub fn with_compression(storage: &super::Storage, compression: Compression) -> io::Result<Self> {
+        if ! compression.is_supported() {
+            return Err(io::Error::new(ErrorKind::Other, format!("unsupported compression {}", compression)));
+        }
+        let header = PackHeader::new(compression);
+        let mut output = storage.get_backend().create(StorageFileType::Pack)?;
+        output.write_all(&header.to_bytes())?;
         let idx = Index::new();
         let ctxt = blake2b::Blake2b::new(32);
-        PackWriter
-            { tmpfile: tmpfile, index: idx, pos: 0, nb_blobs: 0, storage_config: cfg.clone(), hash_context: ctxt }
+        Ok(PackWriter
+            { output: Some(output), pos: header.first_block_offset(), compression: header.compression, index: idx, nb_blobs: 0, hash_context: ctxt })
+    }
+
+    pub fn get_compression(&self) -> &Compression {
+        &self.compression
+    }
+
+    fn output(&mut self) -> &mut Box<Output> {
+        self.output.as_mut().expect("pack already finalized")
     }
 
     pub fn get_current_size(&self) -> u64 {
@@ -383,35 +634,44 @@ impl PackWriter {
         self.nb_blobs
     }
 
+    /// append the block as is: it must already be compressed with the
+    /// compression of the pack (see `get_compression`)
     pub fn append_raw(&mut self, blockhash: &super::BlockHash, block: &[u8]) {
         let len = block.len() as Size;
         let mut sz_buf = [0u8;SIZE_SIZE];
         write_size(&mut sz_buf, len);
-        self.tmpfile.write_all(&sz_buf[..]).unwrap();
-        self.tmpfile.write_all(block).unwrap();
+        self.output().write_all(&sz_buf[..]).unwrap();
+        write_size(&mut sz_buf, crc32(block));
+        self.output().write_all(&sz_buf[..]).unwrap();
+        self.output().write_all(block).unwrap();
         self.hash_context.input(block);
 
         let pad = [0u8;SIZE_SIZE-1];
-        let pad_bytes = if (len % 4 as u32) != 0 {
-                            let pad_sz = 4 - len % 4;
-                            self.tmpfile.write_all(&pad[0..pad_sz as usize]).unwrap();
-                            pad_sz
-                        } else { 0 };
+        if (len % 4 as u32) != 0 {
+            let pad_sz = 4 - len % 4;
+            self.output().write_all(&pad[0..pad_sz as usize]).unwrap();
+        }
         self.index.append(blockhash, self.pos);
-        self.pos += 4 + len as u64 + pad_bytes as u64;
+        self.pos += Version::V3.entry_size(block.len());
         self.nb_blobs += 1;
     }
 
     pub fn append(&mut self, blockhash: &super::BlockHash, block: &[u8]) {
-        let compressed_block = compression::compress_conditional(block);
+        let compressed_block = self.compression.compress(block).unwrap();
         self.append_raw(blockhash, &compressed_block[..])
     }
 
     pub fn finalize(&mut self) -> (super::PackHash, Index) {
         let mut packhash : super::PackHash = [0u8;HASH_SIZE];
         self.hash_context.result(&mut packhash);
-        let path = self.storage_config.get_pack_filepath(&packhash);
-        self.tmpfile.render_permanent(&path).unwrap();
+
+        let mut footer = [0u8;SIZE_SIZE+SIZE_SIZE+HASH_SIZE];
+        write_size(&mut footer[0..4], FOOTER_MARKER);
+        write_size(&mut footer[4..8], self.nb_blobs);
+        footer[8..].clone_from_slice(&packhash[..]);
+        self.output().write_all(&footer[..]).unwrap();
+
+        self.output.take().unwrap().commit(&hex::encode(&packhash)).unwrap();
         (packhash, self.index.clone())
     }
 }
@@ -423,8 +683,8 @@ pub struct RawBufPackWriter {
 }
 impl RawBufPackWriter {
     #[deprecated]
-    pub fn init(cfg: &super::StorageConfig) -> Self {
-        let writer = PackWriter::init(cfg);
+    pub fn init(storage: &super::Storage) -> Self {
+        let writer = PackWriter::init(storage);
         RawBufPackWriter {
             writer: writer,
             buffer: Vec::new(),
@@ -479,10 +739,14 @@ impl RawBufPackWriter {
     }
 }
 
-// A Reader
+// A Reader, for the pack files of version 1, 2 and 3
 pub struct PackReader<R> {
     reader: R,
+    header: PackHeader,
+    prefix: io::Cursor<Vec<u8>>, // bytes read to find the version, still to be read as blocks
     pub pos: Offset,
+    nb_blobs: u32,
+    footer: Option<Footer>,
     hash_context: blake2b::Blake2b, // hash of all the content of blocks without length or padding
 }
 
@@ -494,32 +758,77 @@ fn align4(p: Offset) -> Offset {
     }
 }
 
-impl PackReader<fs::File> {
-    pub fn init(cfg: &super::StorageConfig, packhash: &super::PackHash) -> Self {
-        let file = fs::File::open(cfg.get_pack_filepath(packhash)).unwrap();
-        PackReader::from(file)
+impl PackReader<io::Cursor<Content>> {
+    /// open the given pack of the storage, see `open`
+    pub fn init(storage: &super::Storage, packhash: &super::PackHash) -> io::Result<Self> {
+        let content = storage.get_backend().read(StorageFileType::Pack, &hex::encode(packhash))?;
+        PackReader::open(io::Cursor::new(content))
     }
 }
-impl<R: Read> From<R> for PackReader<R> {
-    fn from(reader: R) -> Self {
+impl<R: Read> PackReader<R> {
+    /// read the header of the pack, failing if it is invalid (truncated
+    /// or with an unknown compression)
+    pub fn open(mut reader: R) -> io::Result<Self> {
+        let (header, prefix) = read_header(&mut reader)?;
         let ctxt = blake2b::Blake2b::new(HASH_SIZE);
-        PackReader { reader, pos: 0,  hash_context: ctxt }
+        Ok(PackReader {
+            reader,
+            pos: header.first_block_offset(),
+            header,
+            prefix: io::Cursor::new(prefix),
+            nb_blobs: 0,
+            footer: None,
+            hash_context: ctxt,
+        })
     }
-}
-impl<R: Read> PackReader<R> {
-    pub fn get_next(&mut self) -> Option<blockchain::RawBlock> {
-        match read_block_raw_next(&mut self.reader) {
+
+    pub fn get_version(&self) -> Version { self.header.version }
+
+    pub fn get_compression(&self) -> &Compression { &self.header.compression }
+
+    /// the footer of the pack, once all the blocks have been read
+    /// (pack files of version 2 and 3)
+    pub fn get_footer(&self) -> Option<&Footer> { self.footer.as_ref() }
+
+    fn next_entry(&mut self) -> io::Result<Entry> {
+        read_entry(self.header.version, (&mut self.prefix).chain(&mut self.reader))
+    }
+
+    /// the next block of the pack, `None` after the last block
+    ///
+    /// A corrupted block (CRC32 mismatch, invalid compression) or a
+    /// truncated pack (of version 2 or 3, without its footer) is an
+    /// error: the pack may come from the network, the caller can discard
+    /// it and download it again.
+    pub fn get_next(&mut self) -> io::Result<Option<blockchain::RawBlock>> {
+        if self.footer.is_some() {
+            return Ok(None);
+        }
+        match self.next_entry() {
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof && ! self.header.version.is_checked() => {
+                // the end of a pack file of version 1
+                Ok(None)
+            },
             Err(err) => {
                 if err.kind() == ErrorKind::UnexpectedEof {
-                    None
+                    Err(io::Error::new(ErrorKind::UnexpectedEof, format!("truncated pack at offset {}", self.pos)))
                 } else {
-                    Err(err).unwrap()
+                    Err(err)
                 }
-            }
-            Ok(block_raw) => {
+            },
+            Ok(Entry::Footer(footer)) => {
+                self.footer = Some(footer);
+                Ok(None)
+            },
+            Ok(Entry::Block(block_raw, crc)) => {
+                if ! check_crc(&block_raw, crc) {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("corrupted block at offset {} of the pack", self.pos)));
+                }
+                let block = self.header.decompress(block_raw.as_ref())?;
                 self.hash_context.input(block_raw.as_ref());
-                self.pos += 4 + align4(block_raw.as_ref().len() as u64);
-                Some(blockchain::RawBlock::from_dat(compression::decompress_conditional(block_raw.as_ref())))
+                self.pos += self.header.version.entry_size(block_raw.len());
+                self.nb_blobs += 1;
+                Ok(Some(block))
             },
         }
     }
@@ -530,3 +839,520 @@ impl<R: Read> PackReader<R> {
         packhash
     }
 }
+impl<R: Read + Seek> PackReader<R> {
+    /// read the block at the given offset of the pack file, checking the
+    /// block against its CRC32 (pack files of version 2 and 3) and
+    /// decompressing it with the compression of the pack (the header read
+    /// by `open` is reused). Not to be mixed with `get_next`.
+    pub fn read_block_at(&mut self, ofs: Offset) -> io::Result<blockchain::RawBlock> {
+        self.reader.seek(SeekFrom::Start(ofs))?;
+        match read_entry(self.header.version, &mut self.reader)? {
+            Entry::Block(v, crc) => {
+                if ! check_crc(&v, crc) {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("corrupted block at offset {}", ofs)));
+                }
+                self.header.decompress(v.as_ref())
+            },
+            Entry::Footer(_) => {
+                Err(io::Error::new(ErrorKind::InvalidData, format!("no block at offset {}", ofs)))
+            },
+        }
+    }
+}
+
+/// a pack file (version 1, 2 or 3) mapped in memory
+///
+/// The blocks are read in place: no system call and no copy of the stored
+/// block (which is still to be decompressed, see `PackReader::read_block_at`). The
+/// mapped pack can be shared between threads.
+pub struct MappedPack {
+    mmap: Content,
+    header: PackHeader,
+}
+impl MappedPack {
+    pub fn open(storage: &super::Storage, packhash: &super::PackHash) -> io::Result<Self> {
+        MappedPack::from_content(storage.get_backend().read(StorageFileType::Pack, &hex::encode(packhash))?)
+    }
+
+    pub fn from_file(file: &fs::File) -> io::Result<Self> {
+        // the pack files are never modified once written (see `PackWriter`)
+        MappedPack::from_content(Content::Mapped(unsafe { Mmap::map(file)? }))
+    }
+
+    pub fn from_content(content: Content) -> io::Result<Self> {
+        let (header, _) = read_header(&content[..])?;
+        Ok(MappedPack { mmap: content, header: header })
+    }
+
+    pub fn get_version(&self) -> Version { self.header.version }
+
+    pub fn get_compression(&self) -> &Compression { &self.header.compression }
+
+    /// offset of the first block in the pack file
+    pub fn first_block_offset(&self) -> Offset { self.header.first_block_offset() }
+
+    /// decompress a block of the pack (see `entry_at` and `iter`)
+    pub fn decompress(&self, block: &[u8]) -> io::Result<blockchain::RawBlock> {
+        self.header.decompress(block)
+    }
+
+    /// the block stored at the given offset (checked against its CRC32) and
+    /// the offset of the next entry, `None` at the end of the pack.
+    pub fn entry_at(&self, ofs: Offset) -> io::Result<Option<(&[u8], Offset)>> {
+        let buf = &self.mmap[..];
+        let start = ofs as usize;
+        let version = self.header.version;
+        if start == buf.len() && version == Version::V1 {
+            return Ok(None);
+        }
+        let truncated = || io::Error::new(ErrorKind::UnexpectedEof, format!("truncated block at offset {}", ofs));
+        if buf.len() < start + SIZE_SIZE {
+            return Err(truncated());
+        }
+        let sz = read_size(&buf[start..]);
+        if version.is_checked() && sz == FOOTER_MARKER {
+            return Ok(None);
+        }
+        let block_start = if version.is_checked() { start + SIZE_SIZE + CRC_SIZE } else { start + SIZE_SIZE };
+        let block_end = block_start + sz as usize;
+        if buf.len() < block_end {
+            return Err(truncated());
+        }
+        let block = &buf[block_start..block_end];
+        if version.is_checked() && crc32(block) != read_size(&buf[start+SIZE_SIZE..]) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("corrupted block at offset {}", ofs)));
+        }
+        Ok(Some((block, ofs + version.entry_size(block.len()))))
+    }
+
+    /// the block stored at the given offset, as stored (i.e. compressed)
+    pub fn block_at(&self, ofs: Offset) -> io::Result<&[u8]> {
+        match self.entry_at(ofs)? {
+            None => Err(io::Error::new(ErrorKind::InvalidData, format!("no block at offset {}", ofs))),
+            Some((block, _)) => Ok(block),
+        }
+    }
+
+    /// read the block at the given offset, see `PackReader::read_block_at`
+    pub fn read_block_at(&self, ofs: Offset) -> io::Result<blockchain::RawBlock> {
+        let block = self.block_at(ofs)?;
+        self.decompress(block)
+    }
+
+    /// iterate over the blocks of the pack, as stored, along their offset
+    pub fn iter(&self) -> MappedBlocks {
+        MappedBlocks { pack: self, pos: Some(self.first_block_offset()) }
+    }
+}
+
+pub struct MappedBlocks<'a> {
+    pack: &'a MappedPack,
+    pos: Option<Offset>,
+}
+impl<'a> MappedBlocks<'a> {
+    /// just like `next` but decompress the block
+    pub fn next_raw(&mut self) -> Option<io::Result<(Offset, blockchain::RawBlock)>> {
+        let pack = self.pack;
+        self.next().map(|r| r.and_then(|(ofs, block)| Ok((ofs, pack.decompress(block)?))))
+    }
+}
+impl<'a> Iterator for MappedBlocks<'a> {
+    type Item = io::Result<(Offset, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos?;
+        match self.pack.entry_at(pos) {
+            Err(err) => { self.pos = None; Some(Err(err)) },
+            Ok(None) => { self.pos = None; None },
+            Ok(Some((block, next))) => { self.pos = Some(next); Some(Ok((pos, block))) },
+        }
+    }
+}
+
+/// an index file mapped in memory
+///
+/// The hashes are searched in place (bloom filter, fanout then binary
+/// search), the mapped index can be shared between threads.
+pub struct MappedIndex {
+    mmap: Content,
+    params: Params,
+    total: u32,
+}
+impl MappedIndex {
+    pub fn open(storage: &super::Storage, packhash: &super::PackHash) -> io::Result<Self> {
+        MappedIndex::from_content(storage.get_backend().read(StorageFileType::Index, &hex::encode(packhash))?)
+    }
+
+    pub fn from_file(file: &fs::File) -> io::Result<Self> {
+        // the index files are never modified once written (see `create_index`)
+        MappedIndex::from_content(Content::Mapped(unsafe { Mmap::map(file)? }))
+    }
+
+    pub fn from_content(mmap: Content) -> io::Result<Self> {
+        if mmap.len() < HEADER_SIZE || &mmap[0..MAGIC_SIZE] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid index file"));
+        }
+        let bloom_size = read_size(&mmap[8..12]);
+        let total = read_size(&mmap[FANOUT_OFFSET + (FANOUT_ELEMENTS - 1) * SIZE_SIZE..]);
+        let size = offset_offsets(bloom_size, total) + OFF_SIZE as u64 * total as u64;
+        if (mmap.len() as u64) < size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated index file"));
+        }
+        Ok(MappedIndex { mmap: mmap, params: Params { bloom_size: bloom_size }, total: total })
+    }
+
+    pub fn get_params(&self) -> &Params { &self.params }
+
+    /// the number of blocks in the pack
+    pub fn get_total(&self) -> u32 { self.total }
+
+    fn bloom(&self) -> &[u8] {
+        &self.mmap[BLOOM_OFFSET..BLOOM_OFFSET + self.params.bloom_size as usize]
+    }
+
+    /// a copy of the bloom filter of the index
+    pub fn get_bloom(&self) -> Bloom { Bloom(Vec::from(self.bloom())) }
+
+    /// the hash of the block at the given index offset (the hashes are
+    /// sorted)
+    pub fn hash_at(&self, index_offset: IndexOffset) -> &[u8] {
+        let ofs = offset_hashes(self.params.bloom_size) as usize + index_offset as usize * HASH_SIZE;
+        &self.mmap[ofs..ofs + HASH_SIZE]
+    }
+
+    /// search the hash in the index, returning its index offset
+    pub fn search(&self, blk: &super::BlockHash) -> Option<IndexOffset> {
+        if ! bloom::is_set(self.bloom(), blk) {
+            return None;
+        }
+        fanout_search(&self.mmap[FANOUT_OFFSET..BLOOM_OFFSET], self.total, blk[0], |i| self.hash_at(i).cmp(&blk[..]))
+    }
+
+    /// the offset in the pack file of the block at the given index offset
+    pub fn resolve_offset(&self, index_offset: IndexOffset) -> Offset {
+        let ofs = offset_offsets(self.params.bloom_size, self.total) as usize + index_offset as usize * OFF_SIZE;
+        read_offset(&self.mmap[ofs..ofs + OFF_SIZE])
+    }
+}
+
+/// the reason a block of a pack file is corrupted
+#[derive(Debug)]
+pub enum Corruption {
+    /// the CRC32 stored along the block does not match the block
+    Checksum { stored: u32, computed: u32 },
+    /// the block cannot be decompressed
+    Uncompressable(io::Error),
+    /// the block cannot be decoded
+    Undecodable(::raw_cbor::Error),
+    /// the pack file ends in the middle of the block (or before the footer)
+    Truncated,
+}
+
+#[derive(Debug)]
+pub struct CorruptedBlock {
+    /// index of the block in the pack
+    pub index: u32,
+    /// offset of the block in the pack file
+    pub offset: Offset,
+    pub corruption: Corruption,
+}
+
+/// the result of the verification of a pack file, see `verify`
+#[derive(Debug)]
+pub struct Verification {
+    pub version: Version,
+    pub compression: Compression,
+    /// number of blocks found in the pack file
+    pub nb_blobs: u32,
+    pub corrupted: Vec<CorruptedBlock>,
+    /// the footer of the pack (version 2 and 3)
+    pub footer: Option<Footer>,
+    /// the hash of the content of the pack, as recomputed
+    pub packhash: super::PackHash,
+}
+impl Verification {
+    /// tell if the pack is valid: no corrupted block, the footer (if any)
+    /// matches the content and the content matches the name of the pack
+    pub fn is_valid(&self, packhash: &super::PackHash) -> bool {
+        let footer_valid = match self.footer {
+            None => self.version == Version::V1,
+            Some(ref footer) => footer.nb_blobs == self.nb_blobs && &footer.packhash == packhash,
+        };
+        self.corrupted.is_empty() && footer_valid && &self.packhash == packhash
+    }
+}
+
+/// read the whole pack, checking every block (CRC32 for the pack files
+/// of version 2 and 3, decompression and decoding) and the footer
+///
+/// Unlike `PackReader` which fails on the first corrupted block, every
+/// corrupted block is reported. The verification stops on a truncated
+/// block as the following entries cannot be found.
+pub fn verify(storage: &super::Storage, packhash: &super::PackHash) -> io::Result<Verification> {
+    let content = storage.get_backend().read(StorageFileType::Pack, &hex::encode(packhash))?;
+    let mut reader = PackReader::open(&content[..])?;
+    let mut corrupted = Vec::new();
+    loop {
+        let offset = reader.pos;
+        let index = reader.nb_blobs;
+        let corruption = match reader.next_entry() {
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+                // the end of a pack file of version 1, or a truncated pack
+                if reader.header.version.is_checked() {
+                    corrupted.push(CorruptedBlock { index, offset, corruption: Corruption::Truncated });
+                }
+                break;
+            },
+            Err(err) => return Err(err),
+            Ok(Entry::Footer(footer)) => { reader.footer = Some(footer); break },
+            Ok(Entry::Block(block_raw, crc)) => {
+                reader.hash_context.input(block_raw.as_ref());
+                reader.pos += reader.header.version.entry_size(block_raw.len());
+                reader.nb_blobs += 1;
+                let computed = crc32(&block_raw);
+                match crc {
+                    Some(stored) if stored != computed => Some(Corruption::Checksum { stored, computed }),
+                    _ => match reader.header.decompress(block_raw.as_ref()) {
+                        Err(err) => Some(Corruption::Uncompressable(err)),
+                        Ok(block) => block.decode().err().map(Corruption::Undecodable),
+                    },
+                }
+            },
+        };
+        if let Some(corruption) = corruption {
+            corrupted.push(CorruptedBlock { index, offset, corruption });
+        }
+    }
+    Ok(Verification {
+        version: reader.header.version,
+        compression: reader.header.compression.clone(),
+        nb_blobs: reader.nb_blobs,
+        corrupted: corrupted,
+        footer: reader.footer.clone(),
+        packhash: reader.finalize(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Storage};
+    use std::sync::{Arc};
+    use backend::{MemoryBackend};
+    use protocol::mock::{Chain};
+    use types::{header_to_blockhash};
+    use wallet_crypto::config::{ProtocolMagic};
+
+    fn blocks(len: usize) -> Vec<(BlockHash, Vec<u8>)> {
+        let chain = Chain::generate(ProtocolMagic::default(), 10, len);
+        (0..len).map(|height| (header_to_blockhash(&chain.hash(height)), Vec::from(chain.block(height).as_ref()))).collect()
+    }
+
+    // write a pack of version 1 or 2 (compressed with the default
+    // compression), as written by the previous versions of `PackWriter`
+    fn write_old_pack(storage: &Storage, version: Version, blocks: &[(BlockHash, Vec<u8>)]) -> (super::super::PackHash, Vec<Offset>) {
+        let mut bytes = if version == Version::V1 { Vec::new() } else { Vec::from(PACK_MAGIC_V2) };
+        let mut offsets = Vec::new();
+        let mut hash_context = blake2b::Blake2b::new(HASH_SIZE);
+        let mut sz_buf = [0u8;SIZE_SIZE];
+        for (_, block) in blocks {
+            let block = Compression::default().compress(block).unwrap();
+            offsets.push(bytes.len() as Offset);
+            write_size(&mut sz_buf, block.len() as Size);
+            bytes.extend_from_slice(&sz_buf);
+            if version.is_checked() {
+                write_size(&mut sz_buf, crc32(&block));
+                bytes.extend_from_slice(&sz_buf);
+            }
+            bytes.extend_from_slice(&block);
+            while bytes.len() % 4 != 0 { bytes.push(0) }
+            hash_context.input(&block);
+        }
+        let mut packhash = [0u8;HASH_SIZE];
+        hash_context.result(&mut packhash);
+        if version.is_checked() {
+            write_size(&mut sz_buf, FOOTER_MARKER);
+            bytes.extend_from_slice(&sz_buf);
+            write_size(&mut sz_buf, blocks.len() as Size);
+            bytes.extend_from_slice(&sz_buf);
+            bytes.extend_from_slice(&packhash);
+        }
+        storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), &bytes).unwrap();
+        (packhash, offsets)
+    }
+
+    fn write_pack(storage: &Storage, compression: Compression, blocks: &[(BlockHash, Vec<u8>)]) -> (super::super::PackHash, Index) {
+        let mut writer = PackWriter::with_compression(storage, compression).unwrap();
+        for (hash, block) in blocks {
+            writer.append(hash, block);
+        }
+        writer.finalize()
+    }
+
+    fn pack_content(storage: &Storage, packhash: &super::super::PackHash) -> Vec<u8> {
+        Vec::from(&storage.get_backend().read(StorageFileType::Pack, &hex::encode(packhash)).unwrap()[..])
+    }
+
+    fn read_all<R: Read>(reader: &mut PackReader<R>) -> Vec<Vec<u8>> {
+        let mut read = Vec::new();
+        while let Some(block) = reader.get_next().unwrap() {
+            read.push(Vec::from(block.as_ref()));
+        }
+        read
+    }
+
+    #[test]
+    fn read_the_packs_of_every_version() {
+        let blocks = blocks(5);
+        let expected : Vec<_> = blocks.iter().map(|(_, block)| block.clone()).collect();
+        for version in vec![Version::V1, Version::V2, Version::V3] {
+            // the hash of a pack does not depend on its version
+            let storage = Storage::memory(MemoryBackend::new()).unwrap();
+            let (packhash, offsets) = match version {
+                Version::V3 => {
+                    let (packhash, index) = write_pack(&storage, Compression::default(), &blocks);
+                    (packhash, index.offsets)
+                },
+                _ => write_old_pack(&storage, version, &blocks),
+            };
+            let mut reader = PackReader::init(&storage, &packhash).unwrap();
+            assert_eq!(reader.get_version(), version);
+            assert_eq!(read_all(&mut reader), expected);
+            assert_eq!(reader.get_footer().map(|footer| (footer.nb_blobs, footer.packhash)),
+                       if version == Version::V1 { None } else { Some((5, packhash)) });
+            assert_eq!(reader.finalize(), packhash);
+
+            let mut reader = PackReader::open(io::Cursor::new(pack_content(&storage, &packhash))).unwrap();
+            for (ofs, block) in offsets.iter().zip(expected.iter()).rev() {
+                assert_eq!(reader.read_block_at(*ofs).unwrap().as_ref(), &block[..]);
+            }
+
+            let verification = verify(&storage, &packhash).unwrap();
+            assert_eq!((verification.version, verification.nb_blobs), (version, 5));
+            assert!(verification.is_valid(&packhash));
+            assert!(! verification.is_valid(&[0u8;HASH_SIZE]));
+        }
+    }
+
+    #[test]
+    fn report_the_corrupted_packs() {
+        let storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let blocks = blocks(3);
+        let (packhash, index) = write_pack(&storage, Compression::default(), &blocks);
+        let content = pack_content(&storage, &packhash);
+        assert_eq!(PackReader::init(&storage, &[0u8;HASH_SIZE]).err().unwrap().kind(), ErrorKind::NotFound);
+
+        // a byte of the second block is flipped
+        let mut corrupted = content.clone();
+        corrupted[index.offsets[1] as usize + SIZE_SIZE + CRC_SIZE] ^= 0xff;
+        let mut reader = PackReader::open(&corrupted[..]).unwrap();
+        assert!(reader.get_next().unwrap().is_some());
+        assert_eq!(reader.get_next().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(PackReader::open(io::Cursor::new(&corrupted[..])).unwrap().read_block_at(index.offsets[1]).unwrap_err().kind(), ErrorKind::InvalidData);
+        storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), &corrupted).unwrap();
+        let verification = verify(&storage, &packhash).unwrap();
+        assert_eq!(verification.nb_blobs, 3);
+        assert_eq!(verification.corrupted.len(), 1);
+        assert_eq!((verification.corrupted[0].index, verification.corrupted[0].offset), (1, index.offsets[1]));
+        match verification.corrupted[0].corruption {
+            Corruption::Checksum { stored, computed } => assert!(stored != computed),
+            ref corruption => panic!("unexpected corruption {:?}", corruption),
+        }
+        assert!(verification.footer.is_some());
+        assert!(! verification.is_valid(&packhash));
+
+        // the pack ends in the middle of the third block
+        let footer_offset = content.len() - (SIZE_SIZE + SIZE_SIZE + HASH_SIZE);
+        for len in vec![index.offsets[2] as usize + 10, footer_offset] {
+            let truncated = &content[..len];
+            let mut reader = PackReader::open(truncated).unwrap();
+            assert!(reader.get_next().unwrap().is_some());
+            assert!(reader.get_next().unwrap().is_some());
+            if len == footer_offset { assert!(reader.get_next().unwrap().is_some()); }
+            assert_eq!(reader.get_next().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+            storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), truncated).unwrap();
+            let verification = verify(&storage, &packhash).unwrap();
+            let nb_blobs = if len == footer_offset { 3 } else { 2 };
+            assert_eq!(verification.nb_blobs, nb_blobs);
+            assert_eq!(verification.corrupted.len(), 1);
+            assert_eq!(verification.corrupted[0].index, nb_blobs);
+            match verification.corrupted[0].corruption {
+                Corruption::Truncated => {},
+                ref corruption => panic!("unexpected corruption {:?}", corruption),
+            }
+            assert!(verification.footer.is_none());
+            assert!(! verification.is_valid(&packhash));
+        }
+    }
+
+    #[test]
+    fn record_the_compression_in_the_header() {
+        let storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let blocks = blocks(3);
+        let (packhash, index) = write_pack(&storage, Compression::None, &blocks);
+        let content = pack_content(&storage, &packhash);
+        assert_eq!(index.offsets[0], (MAGIC_SIZE + COMPRESSION_HEADER_SIZE) as Offset);
+        let mut reader = PackReader::open(&content[..]).unwrap();
+        assert_eq!(reader.get_compression(), &Compression::None);
+        assert_eq!(read_all(&mut reader), blocks.iter().map(|(_, block)| block.clone()).collect::<Vec<_>>());
+        assert_eq!(verify(&storage, &packhash).unwrap().compression, Compression::None);
+
+        // the dictionary is stored in the header, aligned on 4 bytes
+        let header = PackHeader::new(Compression::zstd_with_dictionary(3, vec![1,2,3,4,5]));
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len() as Offset, header.first_block_offset());
+        assert_eq!(header.first_block_offset(), 24);
+        assert_eq!(read_header(&bytes[..]).unwrap().0, header);
+        assert_eq!(read_header(&bytes[..20]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(PackWriter::with_compression(&storage, Compression::zstd(3)).is_ok(), cfg!(feature = "zstd"));
+
+        // an unknown compression method
+        let mut unknown = content.clone();
+        unknown[MAGIC_SIZE] = 9;
+        assert_eq!(PackReader::open(&unknown[..]).err().unwrap().kind(), ErrorKind::InvalidData);
+        storage.get_backend().write(StorageFileType::Pack, &hex::encode(&packhash), &unknown).unwrap();
+        assert_eq!(verify(&storage, &packhash).err().unwrap().kind(), ErrorKind::InvalidData);
+        assert!(MappedPack::open(&storage, &packhash).is_err());
+    }
+
+    #[test]
+    fn search_the_mapped_index() {
+        let storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let blocks = blocks(30);
+        let (packhash, index) = write_pack(&storage, Compression::default(), &blocks[..20]);
+        create_index(&storage, &packhash, &index).unwrap();
+
+        let pack = MappedPack::open(&storage, &packhash).unwrap();
+        let mut iter = pack.iter();
+        for (ofs, (_, block)) in index.offsets.iter().zip(blocks.iter()) {
+            let (read_ofs, read) = iter.next_raw().unwrap().unwrap();
+            assert_eq!((read_ofs, read.as_ref()), (*ofs, &block[..]));
+            assert_eq!(pack.read_block_at(*ofs).unwrap().as_ref(), &block[..]);
+        }
+        assert!(iter.next().is_none());
+
+        let mapped = MappedIndex::open(&storage, &packhash).unwrap();
+        assert_eq!(mapped.get_total(), 20);
+        for (hash, ofs) in index.hashes.iter().zip(index.offsets.iter()) {
+            let index_offset = mapped.search(hash).unwrap();
+            assert_eq!(mapped.hash_at(index_offset), &hash[..]);
+            assert_eq!(mapped.resolve_offset(index_offset), *ofs);
+        }
+        for (hash, _) in &blocks[20..] {
+            assert!(mapped.search(hash).is_none());
+        }
+
+        // truncated or corrupted files
+        let content = storage.get_backend().read(StorageFileType::Index, &hex::encode(&packhash)).unwrap();
+        assert!(MappedIndex::from_content(Content::Memory(Arc::new(Vec::from(&content[..content.len() - 1])))).is_err());
+        let mut invalid = Vec::from(&content[..]);
+        invalid[0] = b'X';
+        assert!(MappedIndex::from_content(Content::Memory(Arc::new(invalid))).is_err());
+        let content = storage.get_backend().read(StorageFileType::Pack, &hex::encode(&packhash)).unwrap();
+        let truncated = MappedPack::from_content(Content::Memory(Arc::new(Vec::from(&content[..index.offsets[1] as usize + 10])))).unwrap();
+        assert!(truncated.read_block_at(index.offsets[0]).is_ok());
+        assert_eq!(truncated.entry_at(index.offsets[1]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(truncated.iter().collect::<Vec<_>>().len(), 2);
+    }
+}
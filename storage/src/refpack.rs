@@ -2,8 +2,9 @@
 
 use types::{BlockHash, HASH_SIZE};
 use std::collections::vec_deque::{VecDeque};
-use std::{io, fs, result, fmt};
-use config::{StorageConfig};
+use std::{io, result, fmt};
+use types::{StorageFileType};
+use super::{Storage};
 
 pub use std::collections::vec_deque::{Iter};
 
@@ -38,6 +39,11 @@ impl RefPack {
     pub fn push_front_missing(&mut self) { self.0.push_front([0u8; HASH_SIZE]) }
     pub fn iter<'a>(&'a self) -> Iter<'a, BlockHash> { self.0.iter() }
 
+    /// the hash at the given index, `None` if missing (see `push_back_missing`)
+    pub fn get_hash(&self, index: usize) -> Option<BlockHash> {
+        self.0.get(index).filter(|bh| ! is_missing(bh)).cloned()
+    }
+
     pub fn read<R: io::Read>(reader: &mut R) -> Result<Self> {
         let mut rf = Self::new();
         let mut bh = [0;HASH_SIZE];
@@ -53,13 +59,16 @@ impl RefPack {
     }
 }
 
-pub fn read_refpack<P: AsRef<str>>(storage_config: &StorageConfig, name: P) -> Result<RefPack> {
-    let mut file = fs::File::open(storage_config.get_refpack_filepath(name))?;
-    RefPack::read(&mut file)
+/// tell if the hash marks a missing block of the refpack
+pub fn is_missing(bh: &BlockHash) -> bool { bh == &[0u8; HASH_SIZE] }
+
+pub fn read_refpack<P: AsRef<str>>(storage: &Storage, name: P) -> Result<RefPack> {
+    let content = storage.get_backend().read(StorageFileType::RefPack, name.as_ref())?;
+    RefPack::read(&mut &content[..])
 }
 
-pub fn write_refpack<P: AsRef<str>>(storage_config: &StorageConfig, name: P, rf: &RefPack) -> Result<()> {
-    let path = storage_config.get_refpack_filepath(name);
-    let mut file = fs::File::create(path).unwrap();
-    rf.write(&mut file)
+pub fn write_refpack<P: AsRef<str>>(storage: &Storage, name: P, rf: &RefPack) -> Result<()> {
+    let mut output = storage.get_backend().create(StorageFileType::RefPack)?;
+    rf.write(&mut output)?;
+    Ok(output.commit(name.as_ref())?)
 }
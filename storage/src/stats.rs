@@ -0,0 +1,217 @@
+//! statistics of the storage
+//!
+//! For every epoch pack: its size, the compression and the number of its
+//! blocks, the empty slots of the epoch (against the number of slots of an
+//! epoch of the network, see `net::Config::epoch_slots` in exe-common), the
+//! transactions and the total value of their outputs, and the estimated
+//! false positive rate of the bloom filter of its index.
+//! For the whole storage: the number and the size of the objects of
+//! every type (`types::StorageFileType`).
+//!
+//! Every block of the epoch packs is decoded, expect the collection to
+//! take a while on a fully synced storage. The caller is expected to hold
+//! a `lock::Access::Read` lock on the storage.
+
+use std::io;
+use blockchain::{Block, EpochId};
+use wallet_crypto::util::{hex};
+
+use super::{Storage, Result, epoch, pack};
+use compression::{Compression};
+use types::*;
+
+const FILE_TYPES : [StorageFileType;9] =
+    [ StorageFileType::Pack, StorageFileType::Index, StorageFileType::Blob
+    , StorageFileType::Tag, StorageFileType::RefPack, StorageFileType::Epoch
+    , StorageFileType::TxIndex, StorageFileType::AddrIndex, StorageFileType::HeaderIndex
+    ];
+
+#[derive(Debug, Clone)]
+pub struct EpochStats {
+    pub epochid: EpochId,
+    pub packhash: PackHash,
+    /// the size of the pack file, in bytes
+    pub pack_size: u64,
+    /// the size of the index file of the pack, in bytes
+    pub index_size: u64,
+    pub compression: Compression,
+    pub nb_blocks: u32,
+    /// the number of slots of the epoch, the genesis block included
+    pub nb_slots: u32,
+    /// the number of slots of the epoch without block
+    pub nb_empty_slots: u32,
+    pub nb_txs: u64,
+    /// the sum of the outputs of the transactions, in lovelace (saturated
+    /// at `u64::MAX`)
+    pub total_output: u64,
+    /// the size of the bloom filter of the index, in bytes
+    pub bloom_size: u32,
+    /// see `pack::Bloom::false_positive_rate`
+    pub bloom_false_positive_rate: f64,
+}
+
+/// the number and the total size (in bytes) of the objects of a type
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub filetype: StorageFileType,
+    pub nb_objects: u32,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub epochs: Vec<EpochStats>,
+    pub usage: Vec<Usage>,
+}
+impl Stats {
+    /// the size of all the objects of the storage, in bytes
+    pub fn total_size(&self) -> u64 {
+        self.usage.iter().map(|u| u.size).sum()
+    }
+
+    pub fn get_usage(&self, filetype: StorageFileType) -> Option<&Usage> {
+        self.usage.iter().find(|u| u.filetype == filetype)
+    }
+}
+
+/// collect the statistics of the storage, see module documentation
+///
+/// `epoch_slots` is the number of slots of an epoch, its genesis block
+/// excluded.
+pub fn collect(storage: &Storage, epoch_slots: u32) -> Result<Stats> {
+    let mut stats = Stats { epochs: Vec::new(), usage: Vec::new() };
+    for epochid in list_epochs(storage)? {
+        stats.epochs.push(epoch_stats(storage, epochid, epoch_slots)?);
+    }
+    for filetype in FILE_TYPES.iter() {
+        stats.usage.push(usage(storage, *filetype)?);
+    }
+    Ok(stats)
+}
+
+/// the statistics of the given epoch, which must have been packed, see
+/// `collect`
+pub fn epoch_stats(storage: &Storage, epochid: EpochId, epoch_slots: u32) -> Result<EpochStats> {
+    let packhash = epoch::epoch_read_pack(storage, epochid)?;
+    let pack = pack::MappedPack::open(storage, &packhash)?;
+    let index = pack::MappedIndex::open(storage, &packhash)?;
+    let bloom = index.get_bloom();
+
+    let mut stats = EpochStats {
+        epochid: epochid,
+        packhash: packhash,
+        pack_size: object_size(storage, StorageFileType::Pack, &hex::encode(&packhash)),
+        index_size: object_size(storage, StorageFileType::Index, &hex::encode(&packhash)),
+        compression: pack.get_compression().clone(),
+        nb_blocks: 0,
+        nb_slots: epoch_slots + 1,
+        nb_empty_slots: 0,
+        nb_txs: 0,
+        total_output: 0,
+        bloom_size: index.get_params().bloom_size,
+        bloom_false_positive_rate: bloom.false_positive_rate(),
+    };
+
+    let mut blocks = pack.iter();
+    while let Some(entry) = blocks.next_raw() {
+        let (_, rblk) = entry?;
+        stats.nb_blocks += 1;
+        if let Block::MainBlock(ref mblk) = rblk.decode()? {
+            for txaux in mblk.body.tx.iter() {
+                stats.nb_txs += 1;
+                stats.total_output = txaux.tx.outputs.iter()
+                    .fold(stats.total_output, |total, txout| total.saturating_add(u64::from(txout.value)));
+            }
+        }
+    }
+    stats.nb_empty_slots = stats.nb_slots.saturating_sub(stats.nb_blocks);
+    Ok(stats)
+}
+
+// the epochs with a pack, in order
+fn list_epochs(storage: &Storage) -> io::Result<Vec<EpochId>> {
+    let mut epochs : Vec<EpochId> = storage.get_backend().list(StorageFileType::Epoch)?.iter()
+        .filter(|name| name.ends_with("/pack"))
+        .filter_map(|name| name[..name.len() - 5].parse().ok())
+        .collect();
+    epochs.sort();
+    Ok(epochs)
+}
+
+fn usage(storage: &Storage, filetype: StorageFileType) -> io::Result<Usage> {
+    let names = match storage.get_backend().list(filetype) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        r => r?,
+    };
+    Ok(Usage {
+        filetype: filetype,
+        nb_objects: names.len() as u32,
+        size: names.iter().map(|name| object_size(storage, filetype, name)).sum(),
+    })
+}
+
+fn object_size(storage: &Storage, filetype: StorageFileType, name: &str) -> u64 {
+    storage.get_backend().size(filetype, name).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::mock::{Chain, fixture_transaction};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::tx::{TxId, TxIn};
+    use backend::{MemoryBackend};
+
+    // write the blocks of the chain at the given heights in the pack of
+    // the epoch
+    fn write_epoch(storage: &mut Storage, chain: &Chain, heights: ::std::ops::Range<usize>, epochid: EpochId) -> PackHash {
+        let mut writer = pack::PackWriter::init(storage);
+        for height in heights {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        epoch::epoch_create(storage, &packhash, epochid);
+        packhash
+    }
+
+    #[test]
+    fn stats_of_the_epochs() {
+        // epochs of 11 slots, the epoch 0 has 3 transactions, the slots 4,
+        // 5, 8 and 9 of the epoch 1 are empty, the last two ending it
+        let mut chain = Chain::new(ProtocolMagic::default(), 10);
+        let pm = chain.get_protocol_magic();
+        let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 1)], &[(2, 100), (1, 50)]);
+        let tx2 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[2]), 0), 1)], &[(3, 7)]);
+        let tx3 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 2)], &[(3, 30), (4, 20)]);
+        chain.extend(3);
+        chain.push_transactions(vec![tx1, tx2]);
+        chain.push_transactions(vec![tx3]);
+        chain.extend(11);
+        chain.skip_slots(2);
+        chain.extend(2);
+        chain.skip_slots(2);
+        chain.extend(1);
+        assert!(chain.header(18).is_genesis_block());
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let epoch0 = write_epoch(&mut storage, &chain, 0..11, 0);
+        let epoch1 = write_epoch(&mut storage, &chain, 11..18, 1);
+
+        let stats = collect(&storage, 10).unwrap();
+        assert_eq!(stats.epochs.iter().map(|e| (e.epochid, e.packhash)).collect::<Vec<_>>(), vec![(0, epoch0), (1, epoch1)]);
+        let e = &stats.epochs[0];
+        assert_eq!((e.nb_blocks, e.nb_slots, e.nb_empty_slots), (11, 11, 0));
+        assert_eq!((e.nb_txs, e.total_output), (3, 207));
+        let e = &stats.epochs[1];
+        assert_eq!((e.nb_blocks, e.nb_slots, e.nb_empty_slots), (7, 11, 4));
+        assert_eq!((e.nb_txs, e.total_output), (0, 0));
+        let pack_size = storage.get_backend().read(StorageFileType::Pack, &hex::encode(&epoch1)).unwrap().len() as u64;
+        assert_eq!(e.pack_size, pack_size);
+
+        let packs = stats.get_usage(StorageFileType::Pack).unwrap();
+        assert_eq!(packs.nb_objects, 2);
+        assert_eq!(packs.size, stats.epochs.iter().map(|e| e.pack_size).sum::<u64>());
+        assert_eq!(stats.get_usage(StorageFileType::Blob).unwrap().nb_objects, 0);
+        assert!(stats.total_size() > packs.size);
+    }
+}
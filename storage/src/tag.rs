@@ -1,8 +1,7 @@
-use std::fs;
-use std::io::{Write,Read};
 use wallet_crypto::util::{hex};
 
 use blockchain;
+use types::{StorageFileType, PackHash};
 
 pub const OLDEST_BLOCK : &str = "OLDEST_BLOCK";
 pub const HEAD : &str = "HEAD";
@@ -11,10 +10,15 @@ pub fn get_epoch_tag(epoch: u32) -> String {
     format!("EPOCH_{}", epoch)
 }
 
+/// the tag of a pack holding some of the blocks of an epoch, but not all
+/// (e.g. imported from an archive starting or ending in the middle of the
+/// epoch)
+pub fn get_partial_epoch_tag(epoch: u32, packhash: &PackHash) -> String {
+    format!("PARTIAL_EPOCH_{}_{}", epoch, hex::encode(packhash))
+}
+
 pub fn write<S: AsRef<str>>(storage: &super::Storage, name: &S, content: &[u8]) {
-    let mut tmp_file = super::tmpfile_create_type(storage, super::StorageFileType::Tag);
-    tmp_file.write_all(hex::encode(content).as_bytes()).unwrap();
-    tmp_file.render_permanent(&storage.config.get_tag_filepath(name)).unwrap();
+    storage.get_backend().write(StorageFileType::Tag, name.as_ref(), hex::encode(content).as_bytes()).unwrap();
 }
 
 pub fn write_hash<S: AsRef<str>>(storage: &super::Storage, name: &S, content: &blockchain::HeaderHash) {
@@ -23,10 +27,7 @@ pub fn write_hash<S: AsRef<str>>(storage: &super::Storage, name: &S, content: &b
 
 pub fn read<S: AsRef<str>>(storage: &super::Storage, name: &S) -> Option<Vec<u8>> {
     if ! exist(storage, name) { return None; }
-    let mut content = Vec::new();
-    let path = storage.config.get_tag_filepath(name);
-    let mut file = fs::File::open(path).unwrap();
-    file.read_to_end(&mut content).unwrap();
+    let content = Vec::from(&storage.get_backend().read(StorageFileType::Tag, name.as_ref()).unwrap()[..]);
     String::from_utf8(content.clone()).ok()
         .and_then(|r| hex::decode(&r).ok())
         .or(Some(content))
@@ -37,6 +38,5 @@ pub fn read_hash<S: AsRef<str>>(storage: &super::Storage, name: &S) -> Option<bl
 }
 
 pub fn exist<S: AsRef<str>>(storage: &super::Storage, name: &S) -> bool {
-    let p = storage.config.get_tag_filepath(name);
-    p.as_path().exists()
+    storage.get_backend().exist(StorageFileType::Tag, name.as_ref())
 }
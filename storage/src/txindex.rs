@@ -0,0 +1,193 @@
+// a transaction index file is associated to a pack and is:
+//
+// MAGIC (8 Bytes)
+// NUMBER OF ENTRIES (4 bytes BE)
+// 0-PADDING (4 bytes)
+// FANOUT (256*4 bytes)
+// ENTRIES ordered lexigraphically by TxId (#ENTRIES * 76 bytes), an entry being:
+//     TXID (32 bytes)
+//     BLOCK HASH (32 bytes)
+//     OFFSET of the BLOCK in the pack (8 bytes BE)
+//     POSITION of the TX in the block (4 bytes BE)
+
+use std::io;
+use std::io::{Write};
+
+use wallet_crypto::tx::{TxId};
+use wallet_crypto::util::{hex};
+use blockchain::{Block};
+
+use super::{Storage, Result};
+use types::{HASH_SIZE, BlockHash, PackHash, StorageFileType, header_to_blockhash};
+use pack::{PackReader, Offset, write_size, read_size, write_offset, read_offset, write_fanout, fanout_search};
+
+const MAGIC : &[u8] = b"ADATXID1";
+const MAGIC_SIZE : usize = 8;
+const OFF_SIZE : usize = 8;
+const SIZE_SIZE : usize = 4;
+
+const FANOUT_ELEMENTS : usize = 256;
+const FANOUT_OFFSET : usize = MAGIC_SIZE + 8;
+const HEADER_SIZE : usize = FANOUT_OFFSET + FANOUT_ELEMENTS * SIZE_SIZE;
+
+const ENTRY_SIZE : usize = HASH_SIZE + HASH_SIZE + OFF_SIZE + SIZE_SIZE;
+
+/// where to find a given transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxLocation {
+    /// the pack the transaction is stored in
+    pub pack: PackHash,
+    /// the hash of the block the transaction is part of
+    pub block: BlockHash,
+    /// the offset of the block in the pack
+    pub offset: Offset,
+    /// the position of the transaction in the block's transactions
+    pub position: u32,
+}
+
+struct Entry {
+    txid: TxId,
+    block: BlockHash,
+    offset: Offset,
+    position: u32,
+}
+
+/// build the transaction index of the given pack
+///
+/// the pack is read block by block and every transaction found
+/// is recorded in a new index file, replacing the previous one if any.
+pub fn create(storage: &Storage, packhash: &PackHash) -> Result<usize> {
+    let mut entries = Vec::new();
+    let mut reader = PackReader::init(storage, packhash)?;
+    loop {
+        let offset = reader.pos;
+        let blk = match reader.get_next()? {
+            None => break,
+            Some(rblk) => rblk.decode()?,
+        };
+        if let Block::MainBlock(ref mblk) = blk {
+            let block = header_to_blockhash(&blk.get_header().compute_hash());
+            for (position, txaux) in mblk.body.tx.iter().enumerate() {
+                entries.push(Entry {
+                    txid: txaux.tx.id(),
+                    block: block,
+                    offset: offset,
+                    position: position as u32,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.txid.cmp(&b.txid));
+
+    let mut output = storage.get_backend().create(StorageFileType::TxIndex)?;
+    let mut hdr_buf = [0u8;HEADER_SIZE];
+    hdr_buf[0..MAGIC_SIZE].clone_from_slice(&MAGIC[..]);
+    write_size(&mut hdr_buf[8..12], entries.len() as u32);
+    write_size(&mut hdr_buf[12..16], 0);
+
+    write_fanout(&mut hdr_buf[FANOUT_OFFSET..HEADER_SIZE], entries.iter().map(|entry| entry.txid.bytes()[0]));
+    output.write_all(&hdr_buf)?;
+
+    for entry in entries.iter() {
+        let mut buf = [0u8;ENTRY_SIZE];
+        buf[0..32].clone_from_slice(entry.txid.bytes());
+        buf[32..64].clone_from_slice(&entry.block[..]);
+        write_offset(&mut buf[64..72], entry.offset);
+        write_size(&mut buf[72..76], entry.position);
+        output.write_all(&buf)?;
+    }
+    output.commit(&hex::encode(packhash))?;
+    Ok(entries.len())
+}
+
+/// check if the transaction index of the given pack has been created
+pub fn exist(storage: &Storage, packhash: &PackHash) -> bool {
+    storage.get_backend().exist(StorageFileType::TxIndex, &hex::encode(packhash))
+}
+
+/// look for the given transaction in the transaction index of the given pack
+pub fn search(storage: &Storage, packhash: &PackHash, txid: &TxId) -> io::Result<Option<TxLocation>> {
+    let content = storage.get_backend().read(StorageFileType::TxIndex, &hex::encode(packhash))?;
+    if content.len() < HEADER_SIZE || &content[0..MAGIC_SIZE] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid transaction index magic"));
+    }
+    let total = read_size(&content[8..12]);
+    if content.len() < HEADER_SIZE + total as usize * ENTRY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated transaction index"));
+    }
+
+    let entry = |index: u32| {
+        let ofs = HEADER_SIZE + index as usize * ENTRY_SIZE;
+        &content[ofs..ofs + ENTRY_SIZE]
+    };
+    let found = fanout_search(&content[FANOUT_OFFSET..HEADER_SIZE], total, txid.bytes()[0], |index| entry(index)[0..32].cmp(&txid.bytes()[..]));
+    Ok(found.map(|index| {
+        let buf = entry(index);
+        let mut block = [0u8;HASH_SIZE];
+        block.clone_from_slice(&buf[32..64]);
+        TxLocation {
+            pack: *packhash,
+            block: block,
+            offset: read_offset(&buf[64..72]),
+            position: read_size(&buf[72..76]),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{tx_location, tx_read_location};
+    use backend::{MemoryBackend};
+    use pack::{PackWriter};
+    use protocol::mock::{Chain, fixture_transaction};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::tx::{TxIn};
+
+    #[test]
+    fn locate_the_transactions() {
+        let mut chain = Chain::generate(ProtocolMagic::default(), 10, 3);
+        let pm = chain.get_protocol_magic();
+        let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 1)], &[(2, 100), (1, 50)]);
+        let tx2 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 2)], &[(3, 90)]);
+        let tx3 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 1), 1)], &[(3, 40)]);
+        chain.push_transactions(vec![tx1.clone()]);
+        chain.push_transactions(vec![tx2.clone(), tx3.clone()]);
+        chain.extend(2);
+
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        let mut writer = PackWriter::init(&storage);
+        for height in 0..chain.len() {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        assert!(exist(&storage, &packhash));
+
+        for (tx, height, position) in vec![(&tx1, 3, 0), (&tx2, 4, 0), (&tx3, 4, 1)] {
+            let expected = TxLocation {
+                pack: packhash,
+                block: header_to_blockhash(&chain.hash(height)),
+                offset: index.offsets[height],
+                position: position,
+            };
+            assert_eq!(search(&storage, &packhash, &tx.tx.id()).unwrap(), Some(expected.clone()));
+            assert_eq!(tx_location(&storage, &tx.tx.id()), Some(expected.clone()));
+            assert_eq!(tx_read_location(&storage, &expected).map(|txaux| txaux.tx), Some(tx.tx.clone()));
+        }
+        assert_eq!(search(&storage, &packhash, &TxId::new(&[1])).unwrap(), None);
+        assert_eq!(tx_location(&storage, &TxId::new(&[1])), None);
+
+        // a corrupted fanout must not make the search read past the entries
+        let name = hex::encode(&packhash);
+        let content = storage.get_backend().read(StorageFileType::TxIndex, &name).unwrap();
+        let mut corrupted = Vec::from(&content[..]);
+        for byte in corrupted[FANOUT_OFFSET..HEADER_SIZE].iter_mut() { *byte = 0xff }
+        storage.get_backend().write(StorageFileType::TxIndex, &name, &corrupted).unwrap();
+        for tx in vec![&tx1, &tx2, &tx3] {
+            assert!(search(&storage, &packhash, &tx.tx.id()).is_ok());
+        }
+        storage.get_backend().write(StorageFileType::TxIndex, &name, &content[..content.len() - 1]).unwrap();
+        assert_eq!(search(&storage, &packhash, &tx1.tx.id()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -19,4 +19,7 @@ pub enum StorageFileType {
     Tag,
     RefPack,
     Epoch,
+    TxIndex,
+    AddrIndex,
+    HeaderIndex,
 }
@@ -1,8 +1,10 @@
-use wallet_crypto::{address::{ExtendedAddr}};
+use wallet_crypto::{address::{ExtendedAddr}, tx::{TxIn, TxOut, TxId}, coin::{Coin}};
 use wallet_crypto::util::base58;
 use command::{HasCommand};
 use clap::{ArgMatches, Arg, App};
-use blockchain::{Block};
+use blockchain::{Block, BlockDate, EpochId, HeaderHash};
+use storage::{Storage, epoch, addrindex, pack::{MappedPack}};
+use std::collections::{BTreeMap};
 use raw_cbor::de::RawCbor;
 
 use super::util;
@@ -16,7 +18,7 @@ impl HasCommand for FindAddress {
     const COMMAND : &'static str = "find-addresses";
 
     fn clap_options<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
-        app.about("retrieve addresses in what have been synced from the network")
+        app.about("retrieve addresses, their balance and spent status in what have been synced from the network")
             .arg(util::blockchain_name_arg(1))
             .arg(Arg::with_name("addresses").help("list of addresses to retrieve").multiple(true).required(true).index(2))
     }
@@ -29,8 +31,58 @@ impl HasCommand for FindAddress {
         for address in addresses_bytes {
             addresses.push(RawCbor::from(&address).deserialize().unwrap());
         }
-        let mut iter = storage.iterate_from_epoch(0).unwrap();
-        while let Some(blk) = iter.next_block().unwrap() {
+        let (found, _) = find_addresses(&storage, &addresses);
+
+        for (txin, (txout, blk_hash, date, spent)) in found.iter() {
+            match spent {
+                None => println!("{} {} in block {} at {}: unspent", txin, txout, blk_hash, date),
+                Some(txid) => println!("{} {} in block {} at {}: spent by {}", txin, txout, blk_hash, date, txid),
+            }
+        }
+        for address in addresses.iter() {
+            let balance = found.values()
+                .filter(|v| &v.0.address == address && v.3.is_none())
+                .fold(Coin::zero(), |acc, v| (acc + v.0.value).unwrap());
+            println!("balance of {}: {}", address, balance);
+        }
+    }
+}
+
+/// the outputs paying to one of the looked up addresses, with the block
+/// (and its date) of their transaction and the transaction spending them
+/// if any.
+pub type Found = BTreeMap<TxIn, (TxOut, HeaderHash, BlockDate, Option<TxId>)>;
+
+/// look for the given addresses in the epoch packs of the storage
+///
+/// return the outputs found and the epochs whose pack has been read, the
+/// others being skipped as their address index tells they certainly don't
+/// pay to the addresses nor spend any of the outputs found so far.
+pub fn find_addresses(storage: &Storage, addresses: &[ExtendedAddr]) -> (Found, Vec<EpochId>) {
+    let mut found = Found::new();
+    let mut read = Vec::new();
+    let mut epoch_id = 0;
+    while let Ok(packref) = epoch::epoch_read_pack(storage, epoch_id) {
+        epoch_id += 1;
+
+        // skip the packs that certainly don't contain any of the
+        // addresses or don't spend any of the outputs found so far.
+        if addrindex::exist(storage, &packref) {
+            let lookup = addrindex::read(storage, &packref).unwrap();
+            let has_address = addresses.iter().any(|a| lookup.search_address(a));
+            let has_input = found.iter().any(|(txin, v)| v.3.is_none() && lookup.search_input(txin));
+            if ! has_address && ! has_input {
+                debug!("    skipping epoch {}", epoch_id - 1);
+                continue;
+            }
+        }
+
+        read.push(epoch_id - 1);
+        let pack = MappedPack::open(storage, &packref).unwrap();
+        let mut blocks = pack.iter();
+        while let Some(rblk) = blocks.next_raw() {
+            let (_, rblk) = rblk.unwrap();
+            let blk = rblk.decode().unwrap();
             let hdr = blk.get_header();
             let blk_hash = hdr.compute_hash();
             match blk {
@@ -39,13 +91,20 @@ impl HasCommand for FindAddress {
                 },
                 Block::MainBlock(mblk) => {
                     for txaux in mblk.body.tx.iter() {
-                        for txout in &txaux.tx.outputs {
+                        let txid = txaux.tx.id();
+                        for txin in txaux.tx.inputs.iter() {
+                            if let Some(v) = found.get_mut(txin) {
+                                v.3 = Some(txid);
+                            }
+                        }
+                        for (index, txout) in txaux.tx.outputs.iter().enumerate() {
                             if let Some(_) = addresses.iter().find(|a| *a == &txout.address) {
                                 println!("found address: {} in block {} at {}",
                                     base58::encode(&cbor!(&txout.address).unwrap()),
                                     blk_hash,
                                     hdr.get_blockdate()
                                 );
+                                found.insert(TxIn::new(txid, index as u32), (txout.clone(), blk_hash.clone(), hdr.get_blockdate(), None));
                             }
                         }
                     }
@@ -53,4 +112,62 @@ impl HasCommand for FindAddress {
             }
         }
     }
+    (found, read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::mock::{Chain, fixture_address, fixture_transaction};
+    use storage::{backend::{MemoryBackend}, pack::{PackWriter}, types::{StorageFileType, header_to_blockhash}};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::util::{hex};
+
+    // write the blocks of the epoch (of 11 blocks) in its indexed epoch pack
+    fn write_epoch(storage: &mut Storage, chain: &Chain, epochid: usize) {
+        let mut writer = PackWriter::init(storage);
+        for height in (epochid * 11)..(epochid * 11 + 11) {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        epoch::epoch_create(storage, &packhash, epochid as EpochId);
+    }
+
+    #[test]
+    fn skip_the_epochs_without_the_addresses() {
+        // the epoch 0 pays to the wallet 1, the epoch 2 spends it and the
+        // epochs 1 and 3 have unrelated transactions
+        let mut chain = Chain::new(ProtocolMagic::default(), 10);
+        let pm = chain.get_protocol_magic();
+        let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 2)], &[(1, 100), (2, 50)]);
+        let tx2 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 1)], &[(3, 90)]);
+        chain.extend(3);
+        chain.push_transactions(vec![tx1.clone()]);
+        chain.extend(7);
+        chain.push_transactions(vec![fixture_transaction(pm, &[(TxIn::new(TxId::new(&[2]), 0), 4)], &[(5, 10)])]);
+        chain.extend(9);
+        chain.push_transactions(vec![tx2.clone()]);
+        chain.extend(9);
+        chain.push_transactions(vec![fixture_transaction(pm, &[(TxIn::new(TxId::new(&[3]), 0), 5)], &[(4, 10)])]);
+        chain.extend(9);
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        for epochid in 0..4 {
+            write_epoch(&mut storage, &chain, epochid);
+        }
+
+        let (found, read) = find_addresses(&storage, &[fixture_address(1)]);
+        assert_eq!(read, vec![0, 2]);
+        assert_eq!(found.len(), 1);
+        let (txout, blk_hash, date, spent) = &found[&TxIn::new(tx1.tx.id(), 0)];
+        assert_eq!(txout, &tx1.tx.outputs[0]);
+        assert_eq!((blk_hash, date), (&chain.hash(3), &chain.header(3).get_blockdate()));
+        assert_eq!(spent, &Some(tx2.tx.id()));
+
+        // without its address index, the pack has to be read
+        let packref = epoch::epoch_read_pack(&storage, 1).unwrap();
+        storage.get_backend().remove(StorageFileType::AddrIndex, &hex::encode(&packref)).unwrap();
+        assert_eq!(find_addresses(&storage, &[fixture_address(1)]).1, vec![0, 1, 2]);
+        assert_eq!(find_addresses(&storage, &[fixture_address(4)]).1, vec![1, 3]);
+    }
 }
@@ -1,22 +1,29 @@
-use wallet_crypto::{util::{hex}};
+use wallet_crypto::{util::{hex}, tx::{TxId}};
 use command::{HasCommand};
 use clap::{ArgMatches, Arg, SubCommand, App};
 use storage;
-use storage::{blob, tag, Storage};
+use storage::{blob, tag, lock, gc, archive, compression, stats, Storage};
+use storage::compression::{Compression};
 use storage::types::{PackHash};
 use storage::{pack_blobs, block_location, block_read_location, pack, PackParameters};
 //use storage::tag::{HEAD};
 use blockchain;
 use config::{Config};
-use std::io::{Write, stdout};
+use std::io::{Read, Write, BufReader, BufWriter, stdout};
+use std::time::{Duration, Instant};
+use std::fs::{File};
+use std::path::{Path};
 use raw_cbor::de::RawCbor;
 
-use exe_common::{config::{net}, network::{api::{*}}, sync};
+use exe_common::{config::{net}, network::{api::{*}, responder, native, PeerDb}, sync};
+use protocol::{capture, server::{Listener}};
+use std::sync::{Arc};
 
 use command::pretty::Pretty;
 
 mod util;
 mod find_address;
+mod peers;
 
 use self::util::{*, range::RangeOption};
 
@@ -40,22 +47,37 @@ impl HasCommand for Blockchain {
             )
             .subcommand(SubCommand::with_name("get-block-header")
                 .arg(blockchain_name_arg(1))
+                .arg(capture_arg())
                 .about("get a given block header. (deprecated will be replaced soon).")
             )
             .subcommand(SubCommand::with_name("get-block")
                 .about("get a given block (deprecated will be replaced soon).")
                 .arg(blockchain_name_arg(1))
                 .arg(Arg::with_name("blockid").help("hexadecimal encoded block id").index(2).required(true))
+                .arg(capture_arg())
             )
             .subcommand(SubCommand::with_name("sync")
                 .about("get the next block repeatedly (deprecated will be replaced soon).")
                 .arg(blockchain_name_arg(1))
             )
+            .subcommand(SubCommand::with_name("follow")
+                .about("print the headers of the new blocks as they are announced by the network")
+                .arg(blockchain_name_arg(1))
+                .arg(capture_arg())
+            )
+            .subcommand(SubCommand::with_name("decode-capture")
+                .about("print the frames and the messages of a capture of the exchanges with a peer (see --capture)")
+                .arg(Arg::with_name("file").help("the capture file").index(1).required(true))
+            )
             .subcommand(SubCommand::with_name("cat")
                 .about("show content of a block")
                 .arg(Arg::with_name("noparse").long("raw").help("cat the binary encoded block, no pretty print"))
                 .arg(blockchain_name_arg(1))
-                .arg(Arg::with_name("blockid").help("hexadecimal encoded block id").index(2).required(true))
+                .arg(Arg::with_name("blockid").help("hexadecimal encoded block id").index(2).required_unless_one(&["date", "height"]))
+                .arg(Arg::with_name("date").long("date").takes_value(true).value_name("EPOCH.SLOT").conflicts_with_all(&["blockid", "height"])
+                     .help("the block of the given date (e.g. 42.1234, or 42.GENESIS for the genesis block of the epoch)"))
+                .arg(Arg::with_name("height").long("height").takes_value(true).value_name("HEIGHT").conflicts_with("blockid")
+                     .help("the block of the given height (chain difficulty)"))
             )
             .subcommand(SubCommand::with_name("debug-index")
                 .about("internal debug command")
@@ -72,12 +94,42 @@ impl HasCommand for Blockchain {
                 .arg(blockchain_name_arg(1))
                 .arg(Arg::with_name("packhash").help("pack to re-index").index(2).required(true))
             )
+            .subcommand(SubCommand::with_name("index")
+                .about("create the transaction, address and header indexes of the packs")
+                .arg(Arg::with_name("force").long("force").help("re-create the indexes of packs already indexed"))
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("packhash").help("pack to index, all the packs if not given").index(2).required(false))
+            )
+            .subcommand(SubCommand::with_name("get-tx")
+                .about("retrieve a transaction from the transaction index")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("txid").help("hexadecimal encoded transaction id").index(2).required(true))
+            )
             .subcommand(SubCommand::with_name("pack")
                 .about("internal pack command")
                 .arg(Arg::with_name("preserve-blobs").long("keep").help("keep what is being packed in its original state"))
+                .arg(compression_arg())
+                .arg(dictionary_arg())
                 .arg(blockchain_name_arg(1))
                 .arg(Arg::with_name("range").help("<tag|ref>..<tag|ref>").index(2).required(false))
             )
+            .subcommand(SubCommand::with_name("train-dictionary")
+                .about("train a zstd dictionary on the blocks of the given epochs, to compress the packs with (see pack --dictionary)")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("epoch").help("the epochs whose blocks are the samples").index(2).required(true).multiple(true))
+                .arg(Arg::with_name("output").long("output").short("o").takes_value(true).value_name("FILE").required(true)
+                     .help("the dictionary file to create"))
+                .arg(Arg::with_name("size").long("size").takes_value(true).value_name("BYTES")
+                     .help("the maximum size of the dictionary (112640 by default)"))
+            )
+            .subcommand(SubCommand::with_name("compression-bench")
+                .about("compress and decompress the blocks of an epoch with the different compressions of the packs, reporting the sizes and the times")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("epoch").help("the epoch to compress").index(2).required(true))
+                .arg(Arg::with_name("level").long("level").takes_value(true).value_name("LEVEL")
+                     .help("the zstd compression level (19 by default)"))
+                .arg(dictionary_arg())
+            )
             .subcommand(SubCommand::with_name("epoch-refpack")
                 .about("generate the refpack of a given epoch")
                 .arg(Arg::with_name("epoch").help("The epoch to generate the refpack").index(1).required(true))
@@ -97,6 +149,35 @@ impl HasCommand for Blockchain {
             .subcommand(SubCommand::with_name("integrity-check")
                 .about("check the integrity of the blockchain")
             )
+            .subcommand(SubCommand::with_name("verify-packs")
+                .about("check the blocks of the packs and report the corrupted ones")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("packhash").help("pack to verify, all the packs if not given").index(2).required(false))
+            )
+            .subcommand(SubCommand::with_name("export")
+                .about("export a range of the blockchain in an archive, to import in another blockchain without syncing")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("range").help("<tag|ref>..<tag|ref>, up to HEAD if the end is not given").index(2).required(true))
+                .arg(Arg::with_name("file").help("the archive to create").index(3).required(true))
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("import the blocks of an archive (see the export command)")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("file").help("the archive to import").index(2).required(true))
+            )
+            .subcommand(SubCommand::with_name("gc")
+                .about("remove the unreferenced packs, the packed blobs, the orphaned indexes and the stale temporary files (hermes must not serve the blockchain meanwhile)")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("dry-run").long("dry-run").help("only report what would be removed"))
+                .arg(Arg::with_name("merge").long("merge").help("merge the blocks only stored in the unreferenced packs in a new pack"))
+            )
+            .subcommand(SubCommand::with_name("stats")
+                .about("report the blocks, transactions, empty slots and sizes of the epoch packs and the disk usage of the blockchain")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("epoch").long("epoch").takes_value(true).value_name("EPOCH")
+                     .help("only report the given epoch"))
+                .arg(Arg::with_name("json").long("json").help("print the statistics in JSON, for the monitoring tools"))
+            )
             .subcommand(SubCommand::with_name("is-pack-epoch")
                 .about("internal check to see if a pack is a valid epoch-pack")
                 .arg(blockchain_name_arg(1))
@@ -110,7 +191,13 @@ impl HasCommand for Blockchain {
                 .arg(Arg::with_name("tag-name").help("name of the tag").index(2).required(true))
                 .arg(Arg::with_name("tag-value").help("value to set to the given tag").index(3).required(false))
             )
+            .subcommand(SubCommand::with_name("serve")
+                .about("serve the local blockchain to the peers connecting with the native protocol")
+                .arg(blockchain_name_arg(1))
+                .arg(Arg::with_name("listen").long("listen").help("address to listen on").takes_value(true).default_value("0.0.0.0:3000"))
+            )
             .subcommand(find_address::FindAddress::mk_command())
+            .subcommand(peers::Peers::mk_command())
     }
 
     fn run(_: Self::Config, args: &ArgMatches) -> Self::Output {
@@ -137,8 +224,10 @@ impl HasCommand for Blockchain {
                 let config = resolv_network_by_name(&opts);
                 let netcfg_file = config.get_storage_config().get_config_file();
                 let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
-                let mut net = sync::get_native_peer(config.network, &net_cfg);
-                let mbh = net.get_tip().unwrap();
+                let (mut pool, mut peerdb) = native_peer(&config, &net_cfg, &opts);
+                let result = pool.get_tip();
+                sync::record_outcome(&mut peerdb, &pool, &result, result.as_ref().ok());
+                let mbh = result.unwrap();
                 println!("prv block header: {}", mbh.get_previous_header());
             },
             ("get-block", Some(opts)) => {
@@ -148,15 +237,42 @@ impl HasCommand for Blockchain {
                 let hh = blockchain::HeaderHash::from_slice(&hh_bytes).expect("blockid invalid");
                 let netcfg_file = config.get_storage_config().get_config_file();
                 let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
-                let mut net = sync::get_native_peer(config.network.clone(), &net_cfg);
-                let b = net.get_block(hh.clone()).unwrap();
+                let (mut pool, mut peerdb) = native_peer(&config, &net_cfg, &opts);
+                let result = pool.get_block(hh.clone());
+                sync::record_outcome(&mut peerdb, &pool, &result, None);
+                let b = result.unwrap();
                 let storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "write the block");
                 blob::write(&storage, hh.bytes(), &cbor!(&b).unwrap()).unwrap();
             },
             ("sync", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 sync::net_sync_faster(config.network.clone(), config.get_storage().unwrap())
             },
+            ("follow", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let netcfg_file = config.get_storage_config().get_config_file();
+                let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
+                let (mut pool, mut peerdb) = native_peer(&config, &net_cfg, &opts);
+                let mut last = None;
+                let result = pool.follow(|hdr| {
+                    println!("{} {}", hdr.get_blockdate(), hdr.compute_hash());
+                    last = Some(hdr.clone());
+                    true
+                });
+                sync::record_outcome(&mut peerdb, &pool, &result, last.as_ref());
+                result.unwrap()
+            },
+            ("decode-capture", Some(opts)) => {
+                let path = value_t!(opts.value_of("file"), String).unwrap();
+                let file = File::open(&path).expect("cannot open the capture file");
+                let records = capture::read(BufReader::new(file)).expect("invalid capture file");
+                let mut decoder = capture::Decoder::new();
+                for record in records.iter() {
+                    let time = format!("{}.{:06}", record.elapsed.as_secs(), record.elapsed.subsec_micros());
+                    println!("{} {} {}", time, record.direction, decoder.describe(record.direction, &record.frame));
+                }
+            },
             ("debug-index", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let store_config = config.get_storage_config();
@@ -189,6 +305,7 @@ impl HasCommand for Blockchain {
                 let packrefhex = opts.value_of("packhash")
                             .and_then(|s| Some(s.to_string()))
                             .unwrap();
+                let _lock = lock_storage(&config.get_storage().unwrap(), lock::Access::Write, "unpack");
                 block_unpack(&config, &packref_fromhex(&packrefhex), opts.is_present("preserve-pack"));
             },
             ("re-index", Some(opts)) => {
@@ -196,8 +313,34 @@ impl HasCommand for Blockchain {
                 let packrefhex = opts.value_of("packhash")
                             .and_then(|s| Some(s.to_string()))
                             .unwrap();
+                let _lock = lock_storage(&config.get_storage().unwrap(), lock::Access::Write, "re-index");
                 pack_reindex(&config, &packref_fromhex(&packrefhex))
             },
+            ("stats", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let net_cfg = net::Config::from_file(&config.get_storage_config().get_config_file()).expect("no network config present");
+                let _lock = lock_storage(&storage, lock::Access::Read, "collect the statistics");
+                let result = if opts.is_present("epoch") {
+                    let epochid = value_t!(opts.value_of("epoch"), blockchain::EpochId).unwrap_or_else(|e| e.exit());
+                    stats::epoch_stats(&storage, epochid, net_cfg.epoch_slots).map(|epoch| stats::Stats { epochs: vec![epoch], usage: Vec::new() })
+                } else {
+                    stats::collect(&storage, net_cfg.epoch_slots)
+                };
+                match result {
+                    Ok(stats) => {
+                        if opts.is_present("json") {
+                            println!("{}", stats_json(&stats));
+                        } else {
+                            display_stats(&stats);
+                        }
+                    },
+                    Err(err) => {
+                        println!("Error: cannot collect the statistics: {:?}", err);
+                        ::std::process::exit(1);
+                    },
+                }
+            },
             ("is-pack-epoch", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let packrefhex = opts.value_of("packhash")
@@ -222,11 +365,72 @@ impl HasCommand for Blockchain {
                     }
                 }
             }
+            ("index", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "index");
+                let packs = match opts.value_of("packhash") {
+                    None    => storage.config.list_indexes(),
+                    Some(s) => vec![packref_fromhex(&s.to_string())],
+                };
+                for packref in packs.iter() {
+                    let force = opts.is_present("force");
+                    if force || ! storage::txindex::exist(&storage, packref) {
+                        let nb_txs = storage::txindex::create(&storage, packref).unwrap();
+                        println!("pack {} indexed: {} transactions", hex::encode(packref), nb_txs);
+                    }
+                    if force || ! storage::addrindex::exist(&storage, packref) {
+                        storage::addrindex::create(&storage, packref).unwrap();
+                        println!("pack {} addresses indexed", hex::encode(packref));
+                    }
+                    if force || ! storage::headerindex::exist(&storage, packref) {
+                        let nb_headers = storage::headerindex::create(&storage, packref).unwrap();
+                        println!("pack {} indexed: {} headers", hex::encode(packref), nb_headers);
+                    }
+                }
+            },
+            ("get-tx", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let txid_hex = value_t!(opts.value_of("txid"), String).unwrap();
+                let txid = TxId::from_hex(&txid_hex).expect("txid invalid");
+
+                match storage::tx_location(&storage, &txid) {
+                    None => {
+                        println!("Error: transaction `{}' not found in the transaction index", txid);
+                        ::std::process::exit(1);
+                    },
+                    Some(loc) => {
+                        println!("pack: {}", hex::encode(&loc.pack));
+                        println!("block: {}", hex::encode(&loc.block));
+                        println!("position in block: {}", loc.position);
+                        match storage::tx_read_location(&storage, &loc) {
+                            None        => println!("error while reading"),
+                            Some(txaux) => println!("{}", txaux.to_pretty()),
+                        }
+                    }
+                }
+            },
+            ("serve", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let netcfg_file = config.get_storage_config().get_config_file();
+                let net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
+                let storage = Arc::new(config.get_storage().unwrap());
+                let _lock = lock_storage(&storage, lock::Access::Read, "serve");
+                let listen = value_t!(opts.value_of("listen"), String).unwrap();
+
+                let mut listener = Listener::bind(listen.as_str(), net_cfg.handshake()).expect("cannot listen on the given address");
+                responder::register(&mut listener, storage);
+                info!("serving blockchain `{}' on {}", config.network, listen);
+                listener.serve().unwrap();
+            },
             ("pack", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let mut storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "pack");
                 let mut pack_params = PackParameters::default();
                 pack_params.delete_blobs_after_pack = ! opts.is_present("preserve-blobs");
+                pack_params.compression = compression_option(&opts);
                 if opts.is_present("range") {
                     let range = value_t!(opts.value_of("range"), RangeOption).unwrap();
                     let from = match tag::read(&storage, &range.from) {
@@ -248,6 +452,46 @@ impl HasCommand for Blockchain {
                 let packhash = pack_blobs(&mut storage, &pack_params);
                 println!("pack created: {}", hex::encode(&packhash));
             },
+            ("train-dictionary", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let epochs = values_t!(opts.values_of("epoch"), blockchain::EpochId).unwrap_or_else(|e| e.exit());
+                let size = if opts.is_present("size") {
+                    value_t!(opts.value_of("size"), usize).unwrap_or_else(|e| e.exit())
+                } else {
+                    compression::DICTIONARY_DEFAULT_SIZE
+                };
+                let path = value_t!(opts.value_of("output"), String).unwrap();
+                let mut samples = Vec::new();
+                for epochid in epochs {
+                    samples.extend(epoch_blocks(&storage, epochid).into_iter().map(|blk| Vec::from(blk.as_ref())));
+                }
+                match compression::train_dictionary(&samples, size) {
+                    Ok(dict) => {
+                        File::create(&path).and_then(|mut file| file.write_all(&dict)).expect("cannot write the dictionary");
+                        println!("dictionary of {} bytes trained on {} blocks written to {}", dict.len(), samples.len(), path);
+                    },
+                    Err(err) => {
+                        println!("Error: cannot train the dictionary: {}", err);
+                        ::std::process::exit(1);
+                    },
+                }
+            },
+            ("compression-bench", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let epochid = value_t!(opts.value_of("epoch"), blockchain::EpochId).unwrap_or_else(|e| e.exit());
+                let level = if opts.is_present("level") {
+                    value_t!(opts.value_of("level"), i32).unwrap_or_else(|e| e.exit())
+                } else {
+                    compression::ZSTD_DEFAULT_LEVEL
+                };
+                let mut compressions = vec![Compression::None, Compression::Deflate, Compression::zstd(level)];
+                if let Some(path) = opts.value_of("dictionary") {
+                    compressions.push(Compression::zstd_with_dictionary(level, read_dictionary(path)));
+                }
+                compression_bench(&epoch_blocks(&storage, epochid), &compressions);
+            },
             ("integrity-check", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let storage = config.get_storage().unwrap();
@@ -256,16 +500,89 @@ impl HasCommand for Blockchain {
                 storage::integrity_check(&storage, net_cfg.genesis_prev, 20);
                 println!("integrity check succeed");
             },
+            ("verify-packs", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let packs = match opts.value_of("packhash") {
+                    None    => storage.config.list_indexes(),
+                    Some(s) => vec![packref_fromhex(&s.to_string())],
+                };
+                let mut nb_invalid = 0;
+                for packref in packs.iter() {
+                    if ! pack_verify(&storage, packref) { nb_invalid += 1 }
+                }
+                if nb_invalid > 0 {
+                    println!("{} invalid pack(s) out of {}", nb_invalid, packs.len());
+                    ::std::process::exit(1);
+                }
+                println!("{} pack(s) verified", packs.len());
+            },
+            ("export", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Read, "export");
+                let range = value_t!(opts.value_of("range"), RangeOption).unwrap();
+                let from = blockref_resolve(&storage, &range.from);
+                let to = blockref_resolve(&storage, &range.to.unwrap_or(tag::HEAD.to_string()));
+                let path = value_t!(opts.value_of("file"), String).unwrap();
+                let file = File::create(&path).expect("cannot create the archive");
+                let header = archive_header(&storage.config);
+                match archive::export(&storage, &header, &from, &to, BufWriter::new(file)) {
+                    Ok(nb_blocks) => println!("{} block(s) exported to {}", nb_blocks, path),
+                    Err(err) => {
+                        println!("Error: cannot export: {:?}", err);
+                        ::std::process::exit(1);
+                    },
+                }
+            },
+            ("import", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let mut storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "import");
+                let path = value_t!(opts.value_of("file"), String).unwrap();
+                let file = File::open(&path).expect("cannot open the archive");
+                let header = archive_header(&storage.config);
+                match archive::import(&mut storage, &header, BufReader::new(file)) {
+                    Ok(imported) => {
+                        for &(epochid, ref packhash) in imported.epochs.iter() {
+                            println!("epoch {} imported in pack {}", epochid, hex::encode(packhash));
+                        }
+                        for &(epochid, ref packhash) in imported.packs.iter() {
+                            println!("blocks of epoch {} imported in pack {}", epochid, hex::encode(packhash));
+                        }
+                        println!("{} block(s) imported", imported.nb_blocks);
+                    },
+                    Err(err) => {
+                        println!("Error: cannot import: {:?}", err);
+                        ::std::process::exit(1);
+                    },
+                }
+            },
+            ("gc", Some(opts)) => {
+                let config = resolv_network_by_name(&opts);
+                let mut storage = config.get_storage().unwrap();
+                let params = gc::Params {
+                    dry_run: opts.is_present("dry-run"),
+                    merge: opts.is_present("merge"),
+                    .. gc::Params::default()
+                };
+                let access = if params.dry_run { lock::Access::Read } else { lock::Access::Exclusive };
+                let _lock = lock_storage(&storage, access, "collect the garbage");
+                let report = gc::gc(&mut storage, &params).unwrap();
+                display_gc_report(&report, params.dry_run);
+            },
             ("epoch-refpack", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "create the refpack");
                 let epoch = value_t!(opts.value_of("epoch"), String).unwrap();
                 storage::refpack_epoch_pack(&storage, &epoch).unwrap();
                 println!("refpack successfuly created");
             },
             ("epoch-from-pack", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
-                let storage = config.get_storage_config();
+                let storage = config.get_storage().unwrap();
+                let _lock = lock_storage(&storage, lock::Access::Write, "create the epoch");
                 let epoch = value_t!(opts.value_of("epoch"), u32).unwrap();
                 let packrefhex = opts.value_of("packhash").and_then(|s| Some(s.to_string())).unwrap();
                 storage::epoch::epoch_create(&storage, &packref_fromhex(&packrefhex), epoch);
@@ -283,6 +600,7 @@ impl HasCommand for Blockchain {
                         println!("{}", value);
                     },
                     Some(value) => {
+                        let _lock = lock_storage(&storage, lock::Access::Write, "write the tag");
                         tag::write(&storage, &tag, &hex::decode(value).unwrap());
                     }
                 }
@@ -290,10 +608,24 @@ impl HasCommand for Blockchain {
             ("cat", Some(opts)) => {
                 let config = resolv_network_by_name(&opts);
                 let storage = config.get_storage().unwrap();
-                let hh_hex = value_t!(opts.value_of("blockid"), String).unwrap();
-                let hh_bytes = match tag::read(&storage, &hh_hex) {
-                    None => hex::decode(&hh_hex).unwrap(),
-                    Some(t) => t
+                let hh_bytes = if opts.is_present("date") {
+                    let date = value_t!(opts.value_of("date"), blockchain::BlockDate).unwrap_or_else(|e| e.exit());
+                    match storage.block_at_date(&date) {
+                        None => { println!("Error: no block at date {}", date); ::std::process::exit(1) },
+                        Some(hash) => hash.to_vec(),
+                    }
+                } else if opts.is_present("height") {
+                    let height = value_t!(opts.value_of("height"), u64).unwrap_or_else(|e| e.exit());
+                    match storage.block_at_height(height) {
+                        None => { println!("Error: no block at height {}", height); ::std::process::exit(1) },
+                        Some(hash) => hash.to_vec(),
+                    }
+                } else {
+                    let hh_hex = value_t!(opts.value_of("blockid"), String).unwrap();
+                    match tag::read(&storage, &hh_hex) {
+                        None => hex::decode(&hh_hex).unwrap(),
+                        Some(t) => t
+                    }
                 };
                 let hh = blockchain::HeaderHash::from_slice(&hh_bytes).expect("blockid invalid");
 
@@ -325,6 +657,7 @@ impl HasCommand for Blockchain {
 
             },
             (find_address::FindAddress::COMMAND, Some(opts)) => find_address::FindAddress::run((), opts),
+            (peers::Peers::COMMAND, Some(opts)) => peers::Peers::run((), opts),
             _ => {
                 println!("{}", args.usage());
                 ::std::process::exit(1);
@@ -333,13 +666,33 @@ impl HasCommand for Blockchain {
     }
 }
 
-fn block_unpack(config: &Config, packref: &PackHash, _preserve_pack: bool) {
+// lock the storage with the given access, exiting if the storage is in
+// use by other processes for longer than `lock::DEFAULT_TIMEOUT`
+fn lock_storage(storage: &Storage, access: lock::Access, action: &str) -> lock::StorageLock {
+    storage.lock(access, Some(lock::DEFAULT_TIMEOUT)).unwrap_or_else(|err| {
+        println!("Error: cannot {}: {}", action, err);
+        ::std::process::exit(1);
+    })
+}
+
+// the best native peer of the blockchain and the peer database to record
+// the outcome of the requests in, capturing the exchanges with the peer if
+// the `--capture` option is given
+fn native_peer(config: &Config, net_cfg: &net::Config, opts: &ArgMatches) -> (native::PeerPool, PeerDb) {
     let storage_config = config.get_storage_config();
+    let selected = match opts.value_of("capture") {
+        None       => sync::get_native_peer(&storage_config, net_cfg),
+        Some(path) => sync::get_native_peer_with_capture(&storage_config, net_cfg, Path::new(path)),
+    };
+    selected.expect("no native peer to connect to")
+}
+
+fn block_unpack(config: &Config, packref: &PackHash, _preserve_pack: bool) {
     let storage = config.get_storage().unwrap();
 
-    let mut reader = storage::pack::PackReader::init(&storage_config, packref);
+    let mut reader = open_pack(&storage, packref);
     loop {
-        match reader.get_next() {
+        match reader.get_next().unwrap() {
             None => { break; },
             Some(blk_raw) => {
                 let blk = blk_raw.decode().unwrap();
@@ -355,14 +708,13 @@ fn block_unpack(config: &Config, packref: &PackHash, _preserve_pack: bool) {
 }
 
 fn pack_reindex(config: &Config, packref: &PackHash) {
-    let storage_config = config.get_storage_config();
     let storage = config.get_storage().unwrap();
-    let mut reader = storage::pack::PackReader::init(&storage_config, packref);
+    let mut reader = open_pack(&storage, packref);
     let mut index = storage::pack::Index::new();
     loop {
         let ofs = reader.pos;
         println!("offset {}", ofs);
-        match reader.get_next() {
+        match reader.get_next().unwrap() {
             None    => { break; },
             Some(b) => {
                 let blk = b.decode().unwrap();
@@ -376,15 +728,15 @@ fn pack_reindex(config: &Config, packref: &PackHash) {
         }
     }
 
-    let (_, tmpfile) = storage::pack::create_index(&storage, &index);
-    tmpfile.render_permanent(&storage.config.get_index_filepath(&packref)).unwrap();
+    storage::pack::create_index(&storage, packref, &index).unwrap();
 }
 
 fn pack_debug(config: &Config,
               packref: &PackHash) {
-    let storage_config = config.get_storage_config();
-    let mut reader = storage::pack::PackReader::init(&storage_config, packref);
-    while let Some(blk_raw) = reader.get_next() {
+    let storage = config.get_storage().unwrap();
+    let mut reader = open_pack(&storage, packref);
+    println!("version={:?} compression={}", reader.get_version(), reader.get_compression());
+    while let Some(blk_raw) = reader.get_next().unwrap() {
         let blk = blk_raw.decode().unwrap();
         let hdr = blk.get_header();
         let hash = hdr.compute_hash();
@@ -393,15 +745,192 @@ fn pack_debug(config: &Config,
     }
 }
 
+fn pack_verify(storage: &Storage, packref: &PackHash) -> bool {
+    let verification = match pack::verify(storage, packref) {
+        Ok(verification) => verification,
+        Err(err) => {
+            println!("pack {}: cannot be read: {}", hex::encode(packref), err);
+            return false;
+        },
+    };
+    let valid = verification.is_valid(packref);
+    println!("pack {} ({:?}, {}, {} blocks): {}", hex::encode(packref), verification.version, verification.compression,
+             verification.nb_blobs, if valid { "ok" } else { "INVALID" });
+    for corrupted in verification.corrupted.iter() {
+        println!("  block {} at offset {}: {:?}", corrupted.index, corrupted.offset, corrupted.corruption);
+    }
+    match verification.footer {
+        Some(ref footer) => {
+            if footer.nb_blobs != verification.nb_blobs {
+                println!("  footer: {} blocks expected", footer.nb_blobs);
+            }
+            if &footer.packhash != packref {
+                println!("  footer: unexpected pack hash {}", hex::encode(&footer.packhash));
+            }
+        },
+        None => if verification.version != pack::Version::V1 { println!("  footer: missing") },
+    }
+    if &verification.packhash != packref {
+        println!("  content hash mismatch: {}", hex::encode(&verification.packhash));
+    }
+    valid
+}
+
+fn compression_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("compression").long("compression").takes_value(true).value_name("METHOD")
+        .help("the compression of the blocks of the pack: none, deflate (the default), zstd or zstd:LEVEL (zstd needs the `zstd` feature)")
+}
+
+fn dictionary_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("dictionary").long("dictionary").takes_value(true).value_name("FILE")
+        .help("the zstd dictionary to compress with (see train-dictionary)")
+}
+
+fn read_dictionary(path: &str) -> Vec<u8> {
+    let mut dict = Vec::new();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut dict)).unwrap_or_else(|err| {
+        println!("Error: cannot read the dictionary {}: {}", path, err);
+        ::std::process::exit(1);
+    });
+    dict
+}
+
+// the compression given with `--compression` and `--dictionary`
+fn compression_option(opts: &ArgMatches) -> Compression {
+    let compression = if opts.is_present("compression") {
+        value_t!(opts.value_of("compression"), Compression).unwrap_or_else(|e| e.exit())
+    } else {
+        Compression::default()
+    };
+    let compression = match (compression, opts.value_of("dictionary")) {
+        (compression, None) => compression,
+        (Compression::Zstd { level, .. }, Some(path)) => Compression::zstd_with_dictionary(level, read_dictionary(path)),
+        (_, Some(_)) => {
+            println!("Error: a dictionary can only be used with the zstd compression");
+            ::std::process::exit(1);
+        },
+    };
+    if ! compression.is_supported() {
+        println!("Error: the {} compression is not supported by this build", compression);
+        ::std::process::exit(1);
+    }
+    compression
+}
+
+// open the given pack, exiting on error
+fn open_pack(storage: &Storage, packhash: &PackHash) -> pack::PackReader<::std::io::Cursor<storage::backend::Content>> {
+    pack::PackReader::init(storage, packhash).unwrap_or_else(|err| {
+        println!("Error: cannot read pack {}: {}", hex::encode(packhash), err);
+        ::std::process::exit(1);
+    })
+}
+
+// the (decompressed) blocks of the pack of the given epoch
+fn epoch_blocks(storage: &Storage, epochid: blockchain::EpochId) -> Vec<blockchain::RawBlock> {
+    let packhash = storage::epoch::epoch_read_pack(storage, epochid).unwrap_or_else(|err| {
+        println!("Error: no pack for epoch {}: {}", epochid, err);
+        ::std::process::exit(1);
+    });
+    let mut reader = open_pack(storage, &packhash);
+    let mut blocks = Vec::new();
+    while let Some(blk) = reader.get_next().unwrap() { blocks.push(blk) }
+    blocks
+}
+
+fn compression_bench(blocks: &[blockchain::RawBlock], compressions: &[Compression]) {
+    let seconds = |d: Duration| d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
+    let total : usize = blocks.iter().map(|blk| blk.as_ref().len()).sum();
+    println!("{} blocks, {} bytes", blocks.len(), total);
+    println!("{:<40} {:>12} {:>8} {:>12} {:>12}", "compression", "size", "ratio", "compress(s)", "decompress(s)");
+    for compression in compressions.iter() {
+        if ! compression.is_supported() {
+            println!("{:<40} not supported by this build", compression.to_string());
+            continue;
+        }
+        let start = Instant::now();
+        let compressed : Vec<Vec<u8>> = blocks.iter().map(|blk| compression.compress(blk.as_ref()).unwrap()).collect();
+        let compress_time = seconds(start.elapsed());
+        let start = Instant::now();
+        for block in compressed.iter() { compression.decompress(block).unwrap(); }
+        let decompress_time = seconds(start.elapsed());
+        let size = compressed.iter().map(|block| block.len()).sum::<usize>() + compression.get_dictionary().map(|d| d.len()).unwrap_or(0);
+        println!("{:<40} {:>12} {:>7.2}% {:>12.3} {:>12.3}", compression.to_string(), size,
+                 100.0 * size as f64 / total as f64, compress_time, decompress_time);
+    }
+}
+
+fn display_stats(stats: &stats::Stats) {
+    println!("{:>6} {:>7} {:>7} {:>6} {:>8} {:>20} {:>12} {:>10} {:>9}  {}",
+             "epoch", "blocks", "slots", "empty", "txs", "output", "pack", "index", "bloom fp", "compression");
+    for epoch in stats.epochs.iter() {
+        println!("{:>6} {:>7} {:>7} {:>6} {:>8} {:>20} {:>12} {:>10} {:>8.4}%  {}",
+                 epoch.epochid, epoch.nb_blocks, epoch.nb_slots, epoch.nb_empty_slots, epoch.nb_txs, epoch.total_output,
+                 epoch.pack_size, epoch.index_size, 100.0 * epoch.bloom_false_positive_rate, epoch.compression);
+    }
+    if stats.usage.is_empty() { return; }
+    println!();
+    for usage in stats.usage.iter() {
+        println!("{:<12} {:>8} object(s) {:>14} bytes", format!("{:?}", usage.filetype), usage.nb_objects, usage.size);
+    }
+    println!("{:<12} {:>8}           {:>14} bytes", "total", "", stats.total_size());
+}
+
+fn stats_json(stats: &stats::Stats) -> serde_json::Value {
+    let epochs : Vec<serde_json::Value> = stats.epochs.iter().map(|epoch| json!({
+        "epoch": epoch.epochid,
+        "pack": hex::encode(&epoch.packhash),
+        "pack_size": epoch.pack_size,
+        "index_size": epoch.index_size,
+        "compression": epoch.compression.to_string(),
+        "blocks": epoch.nb_blocks,
+        "slots": epoch.nb_slots,
+        "empty_slots": epoch.nb_empty_slots,
+        "transactions": epoch.nb_txs,
+        "total_output": epoch.total_output,
+        "bloom_size": epoch.bloom_size,
+        "bloom_false_positive_rate": epoch.bloom_false_positive_rate,
+    })).collect();
+    let usage : serde_json::Map<String, serde_json::Value> = stats.usage.iter().map(|usage| {
+        (format!("{:?}", usage.filetype), json!({ "objects": usage.nb_objects, "size": usage.size }))
+    }).collect();
+    json!({ "epochs": epochs, "usage": usage, "total_size": stats.total_size() })
+}
+
+fn display_gc_report(report: &gc::Report, dry_run: bool) {
+    let verb = if dry_run { "would remove" } else { "removed" };
+    println!("{} reachable pack(s)", report.reachable_packs.len());
+    for &(ref packhash, size) in report.unreferenced_packs.iter() {
+        println!("{} unreferenced pack {} ({} bytes)", verb, hex::encode(packhash), size);
+    }
+    if report.orphaned_blocks > 0 {
+        match report.merged {
+            Some(ref packhash) => println!("merged {} block(s) in pack {}", report.orphaned_blocks, hex::encode(packhash)),
+            None => println!("{} block(s) only stored in the unreferenced packs, lost unless merged (--merge)", report.orphaned_blocks),
+        }
+    }
+    println!("{} {} packed blob(s) ({} bytes)", verb, report.redundant_blobs.len(),
+             report.redundant_blobs.iter().map(|&(_, sz)| sz).sum::<u64>());
+    for &(filetype, ref packhash, size) in report.orphaned_indexes.iter() {
+        println!("{} orphaned {:?} {} ({} bytes)", verb, filetype, hex::encode(packhash), size);
+    }
+    for &(filetype, ref name, size) in report.stale_tmpfiles.iter() {
+        println!("{} temporary {:?} {} ({} bytes)", verb, filetype, name, size);
+    }
+    for &(ref name, size) in report.partial_epochs.iter() {
+        println!("{} partial epoch download {} ({} bytes)", verb, name, size);
+    }
+    println!("{} bytes {}", report.reclaimable(), if dry_run { "reclaimable" } else { "reclaimed" });
+}
+
 fn pack_is_epoch(config: &Config,
                  packref: &PackHash,
                  start_previous_header: &blockchain::HeaderHash)
              -> (bool, blockchain::HeaderHash) {
-    let storage_config = config.get_storage_config();
-    let mut reader = storage::pack::PackReader::init(&storage_config, packref);
+    let storage = config.get_storage().unwrap();
+    let mut reader = open_pack(&storage, packref);
     let mut known_prev_header = start_previous_header.clone();
     loop {
-        match reader.get_next() {
+        match reader.get_next().unwrap() {
             None      => { return (true, known_prev_header.clone()); },
             Some(blk_raw) => {
                 let blk : blockchain::Block = RawCbor::from(blk_raw.as_ref()).deserialize().unwrap();
@@ -419,6 +948,27 @@ fn pack_is_epoch(config: &Config,
     }
 }
 
+// the archive header of the blockchain, from its network configuration
+fn archive_header(storage_config: &storage::config::StorageConfig) -> archive::Header {
+    let net_cfg = net::Config::from_file(&storage_config.get_config_file()).expect("no network config present");
+    archive::Header {
+        protocol_magic: net_cfg.protocol_magic,
+        genesis: net_cfg.genesis,
+        genesis_prev: net_cfg.genesis_prev,
+    }
+}
+
+// the block hash of a tag or an hexadecimal block reference
+fn blockref_resolve(storage: &Storage, s: &String) -> storage::types::BlockHash {
+    let bytes = match tag::read(storage, s) {
+        None    => hex::decode(s).unwrap(),
+        Some(t) => t,
+    };
+    let mut blockref = [0u8;32];
+    blockref.clone_from_slice(&bytes[..]);
+    blockref
+}
+
 fn packref_fromhex(s: &String) -> PackHash {
     let mut packref = [0u8;32];
     packref.clone_from_slice(&hex::decode(&s).unwrap()[..]);
@@ -0,0 +1,110 @@
+use command::{HasCommand};
+use clap::{ArgMatches, Arg, SubCommand, App};
+use exe_common::{config::{net}, network::{peerdb, PeerDb}};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::util;
+
+pub struct Peers;
+
+impl HasCommand for Peers {
+    type Output = ();
+    type Config = ();
+
+    const COMMAND : &'static str = "peers";
+
+    fn clap_options<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app.about("manage the peers of the blockchain")
+            .subcommand(SubCommand::with_name("list")
+                .about("list the peers, the addresses they resolve to and their statistics")
+                .arg(util::blockchain_name_arg(1))
+            )
+            .subcommand(SubCommand::with_name("add")
+                .about("add a peer, native (host:port) or http (http://...)")
+                .arg(util::blockchain_name_arg(1))
+                .arg(Arg::with_name("peer-name").help("the name of the new peer").index(2).required(true))
+                .arg(Arg::with_name("address").help("the address of the new peer").index(3).required(true))
+            )
+            .subcommand(SubCommand::with_name("remove")
+                .about("remove a peer and forget its statistics")
+                .arg(util::blockchain_name_arg(1))
+                .arg(Arg::with_name("peer-name").help("the name of the peer to remove").index(2).required(true))
+            )
+    }
+    fn run(_: Self::Config, args: &ArgMatches) -> Self::Output {
+        let opts = match args.subcommand() {
+            (_, Some(opts)) => opts,
+            _ => {
+                println!("{}", args.usage());
+                ::std::process::exit(1);
+            },
+        };
+        let config = util::resolv_network_by_name(&opts);
+        let storage_config = config.get_storage_config();
+        let netcfg_file = storage_config.get_config_file();
+        let mut net_cfg = net::Config::from_file(&netcfg_file).expect("no network config present");
+        let mut peerdb = PeerDb::load(storage_config.get_peers_file()).expect("invalid peer database");
+
+        match args.subcommand_name() {
+            Some("list") => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                for peer in net_cfg.peers.iter() {
+                    println!("{}: {}", peer.name(), peer.peer());
+                    let address = match peer.get_native() { None => continue, Some(address) => address };
+                    let addrs = match peerdb::resolve(address) {
+                        Ok(addrs) => addrs,
+                        Err(err) => { println!("  cannot resolve {}: {:?}", address, err); continue },
+                    };
+                    for addr in addrs {
+                        match peerdb.get(&addr) {
+                            None => println!("  {} never connected", addr),
+                            Some(stats) => {
+                                let latency = stats.latency.map(|l| format!("{}ms", l)).unwrap_or("-".to_string());
+                                print!("  {} latency {} connections {} failures {}", addr, latency, stats.connections, stats.failures);
+                                if let Some(ref tip) = stats.last_tip {
+                                    print!(" tip {} {} difficulty {}", tip.date, tip.hash, tip.difficulty);
+                                }
+                                match stats.ban {
+                                    Some(ref ban) if stats.is_banned_at(now) => {
+                                        println!(" banned for {}s: {}", ban.until - now, ban.reason)
+                                    },
+                                    _ => println!(),
+                                }
+                            },
+                        }
+                    }
+                }
+            },
+            Some("add") => {
+                let name = value_t!(opts.value_of("peer-name"), String).unwrap();
+                let address = value_t!(opts.value_of("address"), String).unwrap();
+                if net_cfg.peers.get(&name).is_some() {
+                    println!("Error: peer `{}' already exists", name);
+                    ::std::process::exit(1);
+                }
+                let peer = net::Peer::new(address);
+                if let Some(address) = peer.get_native() {
+                    match peerdb::resolve(address) {
+                        Ok(addrs) => {
+                            for addr in addrs { println!("{} resolves to {}", name, addr) }
+                        },
+                        Err(err) => println!("Warning: cannot resolve {}: {:?}", address, err),
+                    }
+                }
+                net_cfg.peers.push(name, peer);
+                net_cfg.to_file(&netcfg_file);
+            },
+            Some("remove") => {
+                let name = value_t!(opts.value_of("peer-name"), String).unwrap();
+                if ! net_cfg.peers.remove(&name) {
+                    println!("Error: peer `{}' does not exist", name);
+                    ::std::process::exit(1);
+                }
+                net_cfg.to_file(&netcfg_file);
+                peerdb.forget(&name);
+                peerdb.save().unwrap();
+            },
+            _ => unreachable!(),
+        }
+    }
+}
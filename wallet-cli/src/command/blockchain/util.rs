@@ -43,6 +43,14 @@ pub fn blockchain_name_arg<'a, 'b>(index: u64) -> Arg<'a,'b> {
         .required(true)
 }
 
+pub fn capture_arg<'a, 'b>() -> Arg<'a,'b> {
+    Arg::with_name("capture")
+        .long("capture")
+        .help("record the ntt frames exchanged with the peer in the given file")
+        .takes_value(true)
+        .required(false)
+}
+
 pub fn resolv_network_by_name<'a>(opts: &ArgMatches<'a>) -> Config {
     let name = value_t!(opts.value_of("name"), String).unwrap();
     let mut config = Config::default();
@@ -480,17 +480,21 @@ impl Pretty for normal::TxPayload {
     fn to_pretty(&self) -> Val {
         Val::List(
             self.iter()
-                .map(|txaux| {
-                    Val::Tree(vec![
-                        ("tx", txaux.tx.to_pretty()),
-                        ("witnesses", txaux.witnesses.to_pretty()),
-                    ])
-                })
+                .map(|txaux| txaux.to_pretty())
                 .collect(),
         )
     }
 }
 
+impl Pretty for tx::TxAux {
+    fn to_pretty(&self) -> Val {
+        Val::Tree(vec![
+            ("tx", self.tx.to_pretty()),
+            ("witnesses", self.witnesses.to_pretty()),
+        ])
+    }
+}
+
 // XXX: impl for a parameterized generic type, Vec<..> not sure if idiomatic
 impl Pretty for Vec<tx::TxInWitness> {
     fn to_pretty(&self) -> Val {
@@ -7,6 +7,7 @@ use wallet_crypto::bip44;
 use wallet_crypto::util::hex;
 use wallet_crypto::tx::{TxIn, TxId, TxOut};
 use wallet_crypto::coin::Coin;
+use storage::addrindex::{AddressLookup};
 
 use super::log::{self, Log, LogReader, LogLock};
 
@@ -72,6 +73,10 @@ pub trait AddrLookup {
     /// an address is known of this wallet.
     ///
     fn acknowledge_address(&mut self, addr: &WalletAddr) -> Result<()>;
+
+    /// tell if the pack of the given address index may pay to one of the
+    /// addresses of the wallet, i.e. if the pack cannot be skipped
+    fn may_match(&self, index: &AddressLookup) -> bool;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -146,6 +151,12 @@ impl <T: AddrLookup> State<T> {
         Ok(Self::new(ptr, lookup_struct, utxos, wallet_name.as_ref().to_path_buf()))
     }
 
+    /// tell if the blocks of the pack of the given address index may
+    /// update the state: paying to the wallet or spending one of its utxos
+    pub fn may_be_affected(&self, index: &AddressLookup) -> bool {
+        self.lookup_struct.may_match(index) || self.utxos.keys().any(|txin| index.search_input(txin))
+    }
+
     /// update a given state with a set of blocks.
     ///
     /// The blocks need to be in blockchain order,
@@ -5,12 +5,14 @@ pub mod sequentialindex;
 pub mod accum;
 pub mod log;
 
-use blockchain::{BlockDate};
+use blockchain::{BlockDate, EpochId};
+use storage::{Storage, epoch, addrindex, pack::{MappedPack}};
 use command::{HasCommand};
 use clap::{ArgMatches, Arg, App};
 
 use super::config;
-use self::log::{LogLock};
+use self::log::{Log, LogLock};
+use self::lookup::{AddrLookup, State};
 
 pub struct Update;
 
@@ -67,34 +69,144 @@ impl HasCommand for Update {
         //    we also need to update the wallet state on the fly so
         //    we can display something to the user too
 
-        let latest_block_date = state.ptr.latest_block_date();
-        let (epoch_start, slot_start) = match &latest_block_date {
-            BlockDate::Genesis(epoch) => (*epoch, None),
-            BlockDate::Normal(slot)   => (slot.epoch, Some(slot.slotid)),
-        };
-        let mut iter = storage.iterate_from_epoch(epoch_start).unwrap();
-        info!("starting to update wallet state:");
-        info!("  from block- {}", latest_block_date);
-        info!("  known utxos {:?}", state.utxos);
-        debug!("epoch_start: {:?}, slot_start: {:?}", epoch_start, slot_start);
-        if slot_start.is_some() || epoch_start > 0 {
-            while let Some(blk) = iter.next_block().unwrap() {
-                let hdr = blk.get_header();
-                debug!("skipping: {}", hdr.get_blockdate());
-                if hdr.get_blockdate() >= latest_block_date {
-                    break;
-                }
-            }
-        }
         let lock = LogLock::acquire_wallet_log_lock(&wallet_name).unwrap();
         let mut log_writer = log::LogWriter::open(lock).unwrap();
-        while let Some(blk) = iter.next_block().unwrap() {
+        update_from_packs(&storage, &mut state, |ev| log_writer.append(&ev).unwrap());
+
+        unimplemented!()
+    }
+}
+
+/// forward the state with the blocks of the epoch packs of the storage,
+/// from the epoch of its latest known block, giving every resulting event
+/// to `on_event`
+///
+/// return the epochs whose pack has been read, the others being skipped as
+/// their address index tells they cannot affect the state.
+pub fn update_from_packs<T, F>(storage: &Storage, state: &mut State<T>, mut on_event: F) -> Vec<EpochId>
+    where T: AddrLookup, F: FnMut(Log)
+{
+    let latest_block_date = state.ptr.latest_block_date();
+    let (epoch_start, slot_start) = match &latest_block_date {
+        BlockDate::Genesis(epoch) => (*epoch, None),
+        BlockDate::Normal(slot)   => (slot.epoch, Some(slot.slotid)),
+    };
+    let resuming = slot_start.is_some() || epoch_start > 0;
+    info!("starting to update wallet state:");
+    info!("  from block- {}", latest_block_date);
+    info!("  known utxos {:?}", state.utxos);
+    debug!("epoch_start: {:?}, slot_start: {:?}", epoch_start, slot_start);
+    let mut read = Vec::new();
+    let mut epoch_id = epoch_start;
+    while let Ok(packref) = epoch::epoch_read_pack(storage, epoch_id) {
+        epoch_id += 1;
+
+        // skip the packs that certainly don't pay to the wallet nor
+        // spend any of its utxos
+        if addrindex::exist(storage, &packref) {
+            let lookup = addrindex::read(storage, &packref).unwrap();
+            if ! state.may_be_affected(&lookup) {
+                debug!("skipping epoch {}", epoch_id - 1);
+                continue;
+            }
+        }
+
+        read.push(epoch_id - 1);
+        let pack = MappedPack::open(storage, &packref).unwrap();
+        let mut blocks = pack.iter();
+        while let Some(rblk) = blocks.next_raw() {
+            let (_, rblk) = rblk.unwrap();
+            let blk = rblk.decode().unwrap();
+            if resuming && blk.get_header().get_blockdate() <= latest_block_date {
+                debug!("skipping: {}", blk.get_header().get_blockdate());
+                continue;
+            }
             let events = state.forward(&[blk]).unwrap();
             for ev in events {
-                log_writer.append(&ev).unwrap();
+                on_event(ev);
             }
         }
+    }
+    read
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::lookup::{Result, StatePtr, Utxo, Utxos, WalletAddr};
+    use protocol::mock::{Chain, fixture_address, fixture_transaction};
+    use storage::{backend::{MemoryBackend}, pack::{PackWriter}, types::{header_to_blockhash}};
+    use storage::addrindex::{AddressLookup};
+    use wallet_crypto::address::{ExtendedAddr};
+    use wallet_crypto::config::{ProtocolMagic};
+    use wallet_crypto::tx::{TxId, TxIn, TxOut};
+    use std::path::{PathBuf};
+
+    // a wallet of the given addresses
+    struct FixtureLookup(Vec<ExtendedAddr>);
+    impl AddrLookup for FixtureLookup {
+        fn lookup(&mut self, ptr: &StatePtr, outs: &[(TxId, u32, &TxOut)]) -> Result<Vec<Utxo>> {
+            Ok(outs.iter().filter(|o| self.0.contains(&o.2.address)).map(|o| Utxo {
+                txin: TxIn::new(o.0, o.1),
+                block_addr: ptr.clone(),
+                wallet_addr: WalletAddr::Accum,
+                coin: o.2.value,
+            }).collect())
+        }
+        fn acknowledge_address(&mut self, _: &WalletAddr) -> Result<()> { Ok(()) }
+        fn may_match(&self, index: &AddressLookup) -> bool {
+            self.0.iter().any(|address| index.search_address(address))
+        }
+    }
 
-        unimplemented!()
+    // write the blocks of the epoch (of 11 blocks) in its indexed epoch pack
+    fn write_epoch(storage: &mut Storage, chain: &Chain, epochid: usize) {
+        let mut writer = PackWriter::init(storage);
+        for height in (epochid * 11)..(epochid * 11 + 11) {
+            writer.append(&header_to_blockhash(&chain.hash(height)), chain.block(height).as_ref());
+        }
+        let (packhash, index) = writer.finalize();
+        storage.add_pack(&packhash, &index).unwrap();
+        epoch::epoch_create(storage, &packhash, epochid as EpochId);
+    }
+
+    #[test]
+    fn update_skips_the_epochs_not_affecting_the_wallet() {
+        // the epoch 0 pays to the wallet 1, the epoch 2 spends it and the
+        // epochs 1 and 3 have unrelated transactions
+        let mut chain = Chain::new(ProtocolMagic::default(), 10);
+        let pm = chain.get_protocol_magic();
+        let tx1 = fixture_transaction(pm, &[(TxIn::new(TxId::new(&[1]), 0), 2)], &[(1, 100)]);
+        let tx2 = fixture_transaction(pm, &[(TxIn::new(tx1.tx.id(), 0), 1)], &[(3, 90)]);
+        chain.extend(3);
+        chain.push_transactions(vec![tx1.clone()]);
+        chain.extend(7);
+        chain.push_transactions(vec![fixture_transaction(pm, &[(TxIn::new(TxId::new(&[2]), 0), 4)], &[(5, 10)])]);
+        chain.extend(9);
+        chain.push_transactions(vec![tx2.clone()]);
+        chain.extend(9);
+        chain.push_transactions(vec![fixture_transaction(pm, &[(TxIn::new(TxId::new(&[3]), 0), 5)], &[(4, 10)])]);
+        chain.extend(9);
+        let mut storage = Storage::memory(MemoryBackend::new()).unwrap();
+        for epochid in 0..4 {
+            write_epoch(&mut storage, &chain, epochid);
+        }
+
+        let ptr = StatePtr::new_before_genesis(chain.header(0).get_previous_header());
+        let mut state = State::new(ptr, FixtureLookup(vec![fixture_address(1)]), Utxos::new(), PathBuf::from("fixture"));
+        let mut events = Vec::new();
+        assert_eq!(update_from_packs(&storage, &mut state, |ev| events.push(ev)), vec![0, 2]);
+        let events : Vec<_> = events.iter().filter_map(|ev| match ev {
+            Log::Checkpoint(_) => None,
+            Log::ReceivedFund(utxo) => Some((true, utxo.txin.clone(), utxo.coin)),
+            Log::SpentFund(utxo) => Some((false, utxo.txin.clone(), utxo.coin)),
+        }).collect();
+        let utxo = (TxIn::new(tx1.tx.id(), 0), tx1.tx.outputs[0].value);
+        assert_eq!(events, vec![(true, utxo.0.clone(), utxo.1), (false, utxo.0, utxo.1)]);
+        assert!(state.utxos.is_empty());
+        assert_eq!(state.ptr.latest_block_date(), chain.header(32).get_blockdate());
+
+        // resuming from the epoch 2, the epoch 3 doesn't affect the wallet
+        assert_eq!(update_from_packs(&storage, &mut state, |_| panic!("unexpected event")), Vec::<EpochId>::new());
     }
 }
@@ -2,6 +2,7 @@ use wallet_crypto::hdwallet;
 use wallet_crypto::hdpayload;
 use wallet_crypto::address::ExtendedAddr;
 use wallet_crypto::tx::{TxIn, TxId, TxOut};
+use storage::addrindex::{AddressLookup};
 use super::lookup::{AddrLookup, Result, StatePtr, Utxo, WalletAddr};
 
 #[derive(Clone,Debug)]
@@ -42,4 +43,8 @@ impl AddrLookup for RandomIndexLookup {
     fn acknowledge_address(&mut self, _: &WalletAddr) -> Result<()> {
         Ok(())
     }
+
+    // the addresses of the wallet are only known once found, any pack
+    // may hold some
+    fn may_match(&self, _: &AddressLookup) -> bool { true }
 }
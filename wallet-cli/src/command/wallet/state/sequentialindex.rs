@@ -3,6 +3,7 @@ use wallet_crypto::wallet::Wallet;
 use std::collections::BTreeMap;
 use wallet_crypto::address::ExtendedAddr;
 use wallet_crypto::tx::{TxIn, TxId, TxOut};
+use storage::addrindex::{AddressLookup};
 use super::lookup::{AddrLookup, Result, WalletAddr, StatePtr, Utxo};
 
 #[derive(Clone,Debug)]
@@ -117,4 +118,10 @@ impl AddrLookup for SequentialBip44Lookup {
             _ => Ok(())
         }
     }
+
+    // the addresses after the expected ones are only used once the
+    // expected ones are found, see `threshold_generate`
+    fn may_match(&self, index: &AddressLookup) -> bool {
+        self.expected.keys().any(|addr| index.search_address(addr))
+    }
 }
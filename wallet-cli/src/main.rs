@@ -8,6 +8,8 @@ extern crate log;
 extern crate raw_cbor;
 extern crate env_logger;
 extern crate serde_yaml;
+#[macro_use]
+extern crate serde_json;
 extern crate rcw;
 extern crate wallet_crypto;
 extern crate exe_common;
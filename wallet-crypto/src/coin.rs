@@ -62,6 +62,9 @@ impl Coin {
         if v <= MAX_COIN { Ok(Coin(v)) } else { Err(Error::OutOfBound(v)) }
     }
 }
+impl From<Coin> for u64 {
+    fn from(c: Coin) -> u64 { c.0 }
+}
 impl fmt::Display for Coin {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
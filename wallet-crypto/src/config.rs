@@ -25,6 +25,9 @@ pub struct ProtocolMagic(u32);
 impl ProtocolMagic {
     pub fn new(val: u32) -> Self { ProtocolMagic(val) }
 }
+impl From<ProtocolMagic> for u32 {
+    fn from(pm: ProtocolMagic) -> Self { pm.0 }
+}
 impl fmt::Display for ProtocolMagic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
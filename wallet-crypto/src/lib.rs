@@ -17,7 +17,7 @@ extern crate rcw;
 #[macro_use]
 extern crate raw_cbor;
 
-mod crc32;
+pub mod crc32;
 pub mod util;
 pub mod config;
 pub mod hdwallet;